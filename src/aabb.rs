@@ -0,0 +1,63 @@
+use crate::float::Float;
+use crate::ray::Ray;
+use crate::vec::Vec3;
+
+/// Axis-aligned bounding box.
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    /// Box around `center` reaching `extent` along each axis in both directions.
+    pub fn around(center: Vec3, extent: Vec3) -> Aabb {
+        Aabb { min: center - extent, max: center + extent }
+    }
+
+    /// Smallest box that contains both boxes.
+    pub fn surrounding(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Vec3::new(self.min.x().min(other.min.x()), self.min.y().min(other.min.y()), self.min.z().min(other.min.z())),
+            max: Vec3::new(self.max.x().max(other.max.x()), self.max.y().max(other.max.y()), self.max.z().max(other.max.z())),
+        }
+    }
+
+    /// Whether the ray passes through the box anywhere between `t_min` and `t_max`.
+    pub fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> bool {
+        self.interval(r, t_min, t_max).is_some()
+    }
+
+    /// Part of [`t_min`, `t_max`] where the ray is inside the box (slab test).
+    pub fn interval(&self, r: &Ray, mut t_min: Float, mut t_max: Float) -> Option<(Float, Float)> {
+        for axis in 0..3 {
+            let inv_d = 1.0 / r.direction.e[axis];
+            let mut t0 = (self.min.e[axis] - r.origin.e[axis]) * inv_d;
+            let mut t1 = (self.max.e[axis] - r.origin.e[axis]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            // Written so that NaN, from a ray in the plane of a slab, does not shrink the interval
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_max < t_min {
+                return None;
+            }
+        }
+        Some((t_min, t_max))
+    }
+}
+
+/// Half extents along the axes of a disk with unit normal `normal`: along each axis the disk reaches
+/// `radius` times the sine of the angle between that axis and the normal.
+pub fn disk_extent(normal: &Vec3, radius: Float) -> Vec3 {
+    Vec3::new(
+        radius * (1.0 - normal.x() * normal.x()).max(0.0).sqrt(),
+        radius * (1.0 - normal.y() * normal.y()).max(0.0).sqrt(),
+        radius * (1.0 - normal.z() * normal.z()).max(0.0).sqrt(),
+    )
+}
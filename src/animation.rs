@@ -0,0 +1,240 @@
+use std::ops::{Add, Sub, Mul};
+use std::sync::Arc;
+
+use crate::float::Float;
+use crate::camera::{Camera, CameraBuilder, CameraError};
+use crate::hitable::{Hitable, HitableList, Instance};
+use crate::vec::{Transform, Vec3};
+
+/// Values that can be blended between keyframes.
+pub trait Interpolate: Copy + Add<Output = Self> + Sub<Output = Self> + Mul<Float, Output = Self> {}
+
+impl<T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Float, Output = T>> Interpolate for T {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    // Smooth spline through all keyframes, with tangents taken from the neighbouring keys
+    CatmullRom,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Keyframe<T> {
+    pub time: Float,
+    pub value: T,
+}
+
+/// Keyframed value over time, in seconds. Before the first and after the last key the value is
+/// held constant.
+#[derive(Debug, Clone)]
+pub struct Track<T> {
+    keys: Vec<Keyframe<T>>,
+    interpolation: Interpolation,
+}
+
+impl<T: Interpolate> Track<T> {
+    pub fn new(interpolation: Interpolation) -> Track<T> {
+        Track { keys: Vec::new(), interpolation }
+    }
+
+    /// Track that holds the same value all the time.
+    pub fn constant(value: T) -> Track<T> {
+        Track::new(Interpolation::Linear).key(0.0, value)
+    }
+
+    /// Add a keyframe, keeping the keys ordered by time.
+    pub fn key(mut self, time: Float, value: T) -> Track<T> {
+        let index = self.keys.partition_point(|k| k.time <= time);
+        self.keys.insert(index, Keyframe { time, value });
+        self
+    }
+
+    /// Value at `time`. Panics if the track has no keys.
+    pub fn at(&self, time: Float) -> T {
+        let keys = &self.keys;
+        assert!(!keys.is_empty(), "animation track without keyframes");
+        // Index of the first key after `time`
+        let next = keys.partition_point(|k| k.time <= time);
+        if next == 0 {
+            return keys[0].value;
+        }
+        if next == keys.len() {
+            return keys[keys.len() - 1].value;
+        }
+        let (k1, k2) = (&keys[next - 1], &keys[next]);
+        let span = k2.time - k1.time;
+        let t = if span > 0.0 { (time - k1.time) / span } else { 1.0 };
+        match self.interpolation {
+            Interpolation::Linear => k1.value + (k2.value - k1.value) * t,
+            Interpolation::CatmullRom => {
+                // Cubic Hermite segment with Catmull-Rom tangents, scaled to the length of this
+                // segment so that unevenly spaced keys stay smooth
+                let k0 = if next >= 2 { &keys[next - 2] } else { k1 };
+                let k3 = if next + 1 < keys.len() { &keys[next + 1] } else { k2 };
+                let m1 = tangent(k0, k2, span);
+                let m2 = tangent(k1, k3, span);
+                let (t2, t3) = (t * t, t * t * t);
+                let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+                let h10 = t3 - 2.0 * t2 + t;
+                let h01 = -2.0 * t3 + 3.0 * t2;
+                let h11 = t3 - t2;
+                k1.value * h00 + k2.value * h01 + m1 * h10 + m2 * h11
+            }
+        }
+    }
+}
+
+/// Slope between two keys, times the length of the segment it is used for.
+fn tangent<T: Interpolate>(before: &Keyframe<T>, after: &Keyframe<T>, span: Float) -> T {
+    let dt = after.time - before.time;
+    let scale = if dt > 0.0 { span / dt } else { 0.0 };
+    (after.value - before.value) * scale
+}
+
+/// Camera whose position, target, field of view, aperture and focus distance follow tracks.
+/// Everything without a track comes from the base builder.
+#[derive(Clone)]
+pub struct CameraAnimation {
+    base: CameraBuilder,
+    lookfrom: Option<Track<Vec3>>,
+    lookat: Option<Track<Vec3>>,
+    vfov: Option<Track<Float>>,
+    aperture: Option<Track<Float>>,
+    focus_dist: Option<Track<Float>>,
+}
+
+impl CameraAnimation {
+    pub fn new(base: CameraBuilder) -> CameraAnimation {
+        CameraAnimation { base, lookfrom: None, lookat: None, vfov: None, aperture: None, focus_dist: None }
+    }
+
+    pub fn lookfrom(mut self, track: Track<Vec3>) -> Self {
+        self.lookfrom = Some(track);
+        self
+    }
+
+    pub fn lookat(mut self, track: Track<Vec3>) -> Self {
+        self.lookat = Some(track);
+        self
+    }
+
+    pub fn vfov(mut self, track: Track<Float>) -> Self {
+        self.vfov = Some(track);
+        self
+    }
+
+    pub fn aperture(mut self, track: Track<Float>) -> Self {
+        self.aperture = Some(track);
+        self
+    }
+
+    pub fn focus_dist(mut self, track: Track<Float>) -> Self {
+        self.focus_dist = Some(track);
+        self
+    }
+
+    pub fn camera_at(&self, time: Float) -> Result<Camera, CameraError> {
+        let mut builder = self.base.clone();
+        if let Some(track) = &self.lookfrom {
+            builder = builder.lookfrom(track.at(time));
+        }
+        if let Some(track) = &self.lookat {
+            builder = builder.lookat(track.at(time));
+        }
+        if let Some(track) = &self.vfov {
+            builder = builder.vfov(track.at(time));
+        }
+        if let Some(track) = &self.aperture {
+            builder = builder.aperture(track.at(time));
+        }
+        if let Some(track) = &self.focus_dist {
+            builder = builder.focus_dist(track.at(time));
+        }
+        builder.build()
+    }
+}
+
+/// Keyframed placement of an object: uniform scale, then rotation around the Y axis (in degrees),
+/// then translation.
+#[derive(Clone)]
+pub struct ObjectAnimation {
+    object: Arc<dyn Hitable>,
+    pub translation: Track<Vec3>,
+    pub rotation_y: Track<Float>,
+    pub scale: Track<Float>,
+}
+
+impl ObjectAnimation {
+    pub fn new(object: Arc<dyn Hitable>) -> ObjectAnimation {
+        ObjectAnimation {
+            object,
+            translation: Track::constant(Vec3::new(0.0, 0.0, 0.0)),
+            rotation_y: Track::constant(0.0),
+            scale: Track::constant(1.0),
+        }
+    }
+
+    /// The object placed as it is at `time`, or `None` while it is scaled down to nothing.
+    pub fn at(&self, time: Float) -> Option<Instance> {
+        let scale = self.scale.at(time);
+        if scale.abs() < Float::MIN_POSITIVE || !scale.is_finite() {
+            return None;
+        }
+        let transform = Transform::scale(Vec3::new(scale, scale, scale))
+            .then(&Transform::rotation(Vec3::new(0.0, 1.0, 0.0), self.rotation_y.at(time)))
+            .then(&Transform::translation(self.translation.at(time)));
+        Some(Instance::new(self.object.clone(), transform))
+    }
+}
+
+/// The objects of `world` that stay in place, and every animated object as it is at `time`.
+pub fn world_at(world: &Arc<dyn Hitable>, animations: &[ObjectAnimation], time: Float) -> HitableList {
+    let mut list: Vec<Box<dyn Hitable>> = vec![Box::new(world.clone())];
+    for instance in animations.iter().filter_map(|animation| animation.at(time)) {
+        list.push(Box::new(instance));
+    }
+    HitableList { list }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn tracks_pass_through_keys () {
+        for interpolation in [Interpolation::Linear, Interpolation::CatmullRom] {
+            let track = Track::new(interpolation).key(0.0, 1.0).key(2.0, 3.0).key(1.0, 5.0).key(4.0, -1.0);
+            assert_eq!(track.at(-1.0), 1.0);
+            assert!((track.at(1.0) - 5.0).abs() < 1e-6);
+            assert!((track.at(2.0) - 3.0).abs() < 1e-6);
+            assert_eq!(track.at(10.0), -1.0);
+        }
+        let linear = Track::new(Interpolation::Linear).key(0.0, 0.0).key(2.0, 4.0);
+        assert!((linear.at(0.5) - 1.0).abs() < 1e-6);
+        // On a straight line Catmull-Rom stays on the line
+        let spline = Track::new(Interpolation::CatmullRom).key(0.0, 0.0).key(1.0, 1.0).key(2.0, 2.0).key(3.0, 3.0);
+        assert!((spline.at(1.5) - 1.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn animated_objects_move_between_frames () {
+        use crate::hitable::Sphere;
+        use crate::material::Material;
+        use crate::ray::Ray;
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        let world: Arc<dyn Hitable> = Arc::new(HitableList { list: vec![] });
+        let mut ball = ObjectAnimation::new(Arc::new(Sphere { center: Vec3::new(0.0, 0.0, 0.0), radius: 1.0, material }));
+        ball.translation = Track::new(Interpolation::Linear).key(0.0, Vec3::new(0.0, 0.0, 0.0)).key(1.0, Vec3::new(0.0, 0.0, -2.0));
+        let down_the_z_axis = Ray::new(Vec3::new(0.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit_at = |time| world_at(&world, &[ball.clone()], time).hit(&down_the_z_axis, 0.0, Float::MAX).unwrap().p;
+        assert!((hit_at(0.0).z() - 1.0).abs() < 1e-4);
+        assert!((hit_at(0.5).z() - 0.0).abs() < 1e-4);
+        assert!((hit_at(1.0).z() + 1.0).abs() < 1e-4);
+
+        // Growing from nothing, so that there is no object at the start
+        let mut growing = ball.clone();
+        growing.scale = Track::new(Interpolation::Linear).key(0.0, 0.0).key(1.0, 1e-4);
+        let grown_hit = |time| world_at(&world, &[growing.clone()], time).hit(&down_the_z_axis, 0.0, Float::MAX);
+        assert!(grown_hit(0.0).is_none());
+        assert!((grown_hit(1.0).unwrap().p.z() + 2.0 - 1e-4).abs() < 1e-5);
+    }
+}
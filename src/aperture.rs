@@ -0,0 +1,288 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::float::{Float, consts::PI};
+use crate::sampler::Sampler;
+use crate::sampling::{Distribution1D, Distribution2D};
+use crate::texture::ImageTexture;
+use crate::vec::sample_unit_disk;
+
+/// Shape of the lens opening, which is the shape out-of-focus highlights (bokeh) take on.
+/// Every shape fits in the unit disk, which the camera scales by the lens radius.
+#[derive(Clone)]
+pub enum Aperture {
+    Circle,
+    // Regular polygon formed by `blades` straight aperture blades, with its corners on the unit
+    // circle. `rotation` is in radians.
+    Polygon {
+        blades: u32,
+        rotation: Float
+    },
+    // Arbitrary shape from a grayscale image, where brighter pixels let through more light
+    Image(Arc<ApertureImage>),
+}
+
+/// Grayscale aperture mask covering the square [-1, 1]² around the unit disk. Pixels whose
+/// centers lie outside the disk are ignored.
+pub struct ApertureImage {
+    width: usize,
+    height: usize,
+    // Running sums of the weights along each row, `width + 1` per row starting from 0, so that
+    // the light through any run of pixels in a row is the difference of two entries
+    row_sums: Vec<Float>,
+    distribution: Distribution2D,
+}
+
+impl ApertureImage {
+    /// Fails for images without any light passing inside the unit disk.
+    pub fn new(image: &ImageTexture) -> io::Result<ApertureImage> {
+        let (width, height) = (image.width, image.height);
+        let weights: Vec<Float> = image.pixels.iter().enumerate().map(|(i, c)| {
+            let (x, y) = ApertureImage::pixel_center(i % width.max(1), i / width.max(1), width, height);
+            if x * x + y * y <= 1.0 { (c.r() + c.g() + c.b()) / 3.0 } else { 0.0 }
+        }).collect();
+        if width == 0 || height == 0 || weights.iter().all(|&w| w <= 0.0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "aperture image lets no light through the lens"));
+        }
+        let distribution = Distribution2D::new(&weights, width, height);
+        let row_sums: Vec<Float> = weights.chunks(width).flat_map(|row| {
+            std::iter::once(0.0).chain(row.iter().scan(0.0, |sum, &w| {
+                *sum += w;
+                Some(*sum)
+            }))
+        }).collect();
+        Ok(ApertureImage { width, height, row_sums, distribution })
+    }
+
+    /// Position of the center of pixel (x, y) on the lens.
+    fn pixel_center(x: usize, y: usize, width: usize, height: usize) -> (Float, Float) {
+        // Image rows run from the top down
+        (2.0 * (x as Float + 0.5) / width as Float - 1.0, 1.0 - 2.0 * (y as Float + 0.5) / height as Float)
+    }
+
+    /// Point on the image sampled from `distribution`, on the lens.
+    fn sample_from(distribution: &Distribution2D, u1: Float, u2: Float) -> (Float, Float) {
+        let (x, y, _) = distribution.sample(u1, u2);
+        (2.0 * x - 1.0, 1.0 - 2.0 * y)
+    }
+
+    /// Point sampled from the pixels whose centers lie inside the lens barrel at `offset`, or
+    /// `None` when the barrel blocks every pixel that lets light through.
+    fn sample_inside_barrel(&self, offset: (Float, Float), u1: Float, u2: Float) -> Option<(Float, Float)> {
+        // The barrel clips every row to a run of columns, so only the row sums are needed to pick
+        // a row, and the running sums of that row to pick a column in its run
+        let w = self.width as Float;
+        let runs: Vec<(usize, usize)> = (0..self.height).map(|y| {
+            let (_, center) = ApertureImage::pixel_center(0, y, self.width, self.height);
+            let dy = center - offset.1;
+            if dy.abs() > 1.0 {
+                return (0, 0);
+            }
+            // Columns x whose centers 2 (x + 0.5) / width - 1 are within `half` of the barrel axis
+            let half = (1.0 - dy * dy).sqrt();
+            let end = (((offset.0 + half + 1.0) * w / 2.0 - 0.5).floor() + 1.0).clamp(0.0, w) as usize;
+            let start = (((offset.0 - half + 1.0) * w / 2.0 - 0.5).ceil().max(0.0) as usize).min(end);
+            (start, end)
+        }).collect();
+        let row_light: Vec<Float> = runs.iter().enumerate()
+            .map(|(y, &(start, end))| (self.row(y)[end] - self.row(y)[start]).max(0.0))
+            .collect();
+        let rows = Distribution1D::new(&row_light);
+        if rows.integral() <= 0.0 {
+            return None;
+        }
+        let (y, _, row) = rows.sample(u2);
+        let (start, end) = runs[row];
+        let sums = self.row(row);
+        let target = sums[start] + u1 * (sums[end] - sums[start]);
+        // Last column in the run whose running sum before it is <= target
+        let column = (start + sums[start + 1..end].partition_point(|&s| s <= target)).min(end - 1);
+        let light = sums[column + 1] - sums[column];
+        let dx = if light > 0.0 { ((target - sums[column]) / light).clamp(0.0, 1.0) } else { 0.5 };
+        let x = (column as Float + dx) / w;
+        Some((2.0 * x - 1.0, 1.0 - 2.0 * y))
+    }
+
+    /// Running sums of row `y`, starting from 0.
+    fn row(&self, y: usize) -> &[Float] {
+        &self.row_sums[y * (self.width + 1)..(y + 1) * (self.width + 1)]
+    }
+}
+
+impl Aperture {
+    /// Polygonal aperture with the first corner `rotation` degrees counter-clockwise from the right.
+    pub fn polygon(blades: u32, rotation: Float) -> Aperture {
+        Aperture::Polygon { blades, rotation: rotation / 180.0 * PI }
+    }
+
+    /// Aperture shaped like a grayscale Netpbm image. The image is stretched over the square that
+    /// encloses the lens. Fails for images that let no light through inside the lens.
+    pub fn load_image<P: AsRef<Path>>(path: P) -> io::Result<Aperture> {
+        let image = ImageTexture::load(path)?;
+        Ok(Aperture::Image(Arc::new(ApertureImage::new(&image)?)))
+    }
+
+    /// Random point on the aperture, distributed by how much light passes through.
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> (Float, Float) {
+        match self {
+            Aperture::Circle => {
+                let (u1, u2) = sampler.get_2d();
+                let p = sample_unit_disk(u1, u2);
+                (p.x(), p.y())
+            }
+            Aperture::Polygon { blades, rotation } => {
+                // All triangles between the center and an edge have the same area, so pick one
+                // uniformly and then a uniform point in it
+                let n = *blades as Float;
+                let k = ((sampler.get_1d() * n) as u32).min(blades - 1) as Float;
+                let a0 = rotation + 2.0 * PI * k / n;
+                let a1 = rotation + 2.0 * PI * (k + 1.0) / n;
+                let (mut u1, mut u2) = sampler.get_2d();
+                if u1 + u2 > 1.0 {
+                    u1 = 1.0 - u1;
+                    u2 = 1.0 - u2;
+                }
+                (u1 * a0.cos() + u2 * a1.cos(), u1 * a0.sin() + u2 * a1.sin())
+            }
+            Aperture::Image(image) => {
+                let (u1, u2) = sampler.get_2d();
+                ApertureImage::sample_from(&image.distribution, u1, u2)
+            }
+        }
+    }
+
+    /// Whether `p` lies inside a circle or polygon aperture. Not meant for image apertures.
+    fn shape_contains(&self, p: (Float, Float)) -> bool {
+        match self {
+            Aperture::Polygon { blades, rotation } => {
+                // Distance from the center along the middle of the edge facing `p`, against the
+                // distance to that edge
+                let sector = 2.0 * PI / *blades as Float;
+                let angle = (p.1.atan2(p.0) - rotation).rem_euclid(sector);
+                (p.0 * p.0 + p.1 * p.1).sqrt() * (angle - sector / 2.0).cos() <= (sector / 2.0).cos()
+            }
+            _ => p.0 * p.0 + p.1 * p.1 <= 1.0,
+        }
+    }
+
+    /// Random point on the aperture as seen from image position (s, t), clipped by the lens barrel.
+    /// Towards the edges of the frame the barrel cuts off part of the aperture, and highlights take
+    /// on a cat's eye shape. `cat_eye` in [0, 1] is how far the barrel shifts at the image corners.
+    pub fn sample_vignetted(&self, s: Float, t: Float, cat_eye: Float, sampler: &mut dyn Sampler) -> (Float, Float) {
+        if cat_eye == 0.0 {
+            return self.sample(sampler);
+        }
+        let offset = (cat_eye * (2.0 * s - 1.0), cat_eye * (2.0 * t - 1.0));
+        let inside_barrel = |p: (Float, Float)| {
+            let (dx, dy) = (p.0 - offset.0, p.1 - offset.1);
+            dx * dx + dy * dy <= 1.0
+        };
+        // Rejection sampling is quick while the barrel covers much of the aperture. Accepted
+        // points and the fallbacks below follow the same distribution, so giving up is unbiased.
+        for _ in 0..16 {
+            let p = self.sample(sampler);
+            if inside_barrel(p) {
+                return p;
+            }
+        }
+        match self {
+            // Sample the pixels inside the barrel exactly
+            Aperture::Image(image) => {
+                let (u1, u2) = sampler.get_2d();
+                image.sample_inside_barrel(offset, u1, u2).unwrap_or_else(|| self.sample(sampler))
+            }
+            // The barrel can leave only a sliver of a polygon, for example a triangle with an edge
+            // towards a corner of the frame. Both shapes are uniform, so sample the lens-shaped
+            // overlap of the unit disk and the barrel through its bounding box instead, keeping
+            // points inside the aperture.
+            _ => {
+                let d = (offset.0 * offset.0 + offset.1 * offset.1).sqrt();
+                if d == 0.0 || d >= 2.0 {
+                    return self.sample(sampler);
+                }
+                let (ax, ay) = (offset.0 / d, offset.1 / d);
+                // The overlap runs from d - 1 to 1 along the offset, and is widest halfway
+                let half_width = (1.0 - d * d / 4.0).sqrt();
+                for _ in 0..256 {
+                    let (u1, u2) = sampler.get_2d();
+                    let along = d - 1.0 + u1 * (2.0 - d);
+                    let across = (2.0 * u2 - 1.0) * half_width;
+                    let p = (along * ax - across * ay, along * ay + across * ax);
+                    if inside_barrel(p) && self.shape_contains(p) {
+                        return p;
+                    }
+                }
+                // Almost nothing of the aperture is left, so let the barrel through
+                self.sample(sampler)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+    use crate::vec::Vec3;
+    #[test]
+    fn polygon_samples_stay_inside () {
+        let mut sampler = SamplerKind::Independent.create(1000, 1);
+        let aperture = Aperture::polygon(6, 15.0);
+        // Distance from the center to the middle of an edge of a hexagon inscribed in the unit circle
+        let apothem = (PI / 6.0).cos();
+        for index in 0..1000 {
+            sampler.start_pixel_sample(0, 0, index);
+            let (x, y) = aperture.sample(sampler.as_mut());
+            assert!(x * x + y * y <= 1.0 + 1e-5);
+            let angle = y.atan2(x) - Float::to_radians(15.0);
+            let sector = (angle / (PI / 3.0)).floor();
+            let to_edge_middle = sector * PI / 3.0 + PI / 6.0;
+            assert!((x * x + y * y).sqrt() * (angle - to_edge_middle).cos() <= apothem + 1e-5);
+        }
+    }
+
+    #[test]
+    fn image_apertures_need_light_inside_the_lens () {
+        let image = |width: usize, height: usize, pixels: Vec<Vec3>| ImageTexture { width, height, pixels };
+        assert!(ApertureImage::new(&image(0, 0, vec![])).is_err());
+        assert!(ApertureImage::new(&image(2, 2, vec![Vec3::new(0.0, 0.0, 0.0); 4])).is_err());
+        // Only the corners are bright, which are outside the unit disk
+        let mut corners = vec![Vec3::new(0.0, 0.0, 0.0); 16];
+        for i in [0, 3, 12, 15] {
+            corners[i] = Vec3::new(1.0, 1.0, 1.0);
+        }
+        assert!(ApertureImage::new(&image(4, 4, corners)).is_err());
+    }
+
+    #[test]
+    fn vignetted_image_samples_stay_inside_the_barrel () {
+        // Light only passes through the lens left of x = 0.25, and at the right edge of the frame
+        // the barrel moves over to the right, leaving a thin sliver where the two overlap
+        let pixels = (0..64 * 64).map(|i| if i % 64 < 40 { Vec3::new(1.0, 1.0, 1.0) } else { Vec3::new(0.0, 0.0, 0.0) }).collect();
+        let aperture = Aperture::Image(Arc::new(ApertureImage::new(&ImageTexture { width: 64, height: 64, pixels }).unwrap()));
+        let mut sampler = SamplerKind::Sobol.create(1000, 1);
+        for index in 0..1000 {
+            sampler.start_pixel_sample(0, 0, index);
+            let (x, y) = aperture.sample_vignetted(1.0, 0.5, 1.0, sampler.as_mut());
+            assert!(x * x + y * y <= 1.0 + 1e-5 && x < 0.25 + 1e-5);
+            // Within a pixel of the barrel
+            assert!((x - 1.0) * (x - 1.0) + y * y <= 1.0 + 0.1, "({}, {}) outside the barrel", x, y);
+        }
+    }
+
+    #[test]
+    fn vignetted_polygon_samples_stay_inside_the_barrel () {
+        // A triangle with an edge towards the top right corner of the frame, where the barrel
+        // leaves only a thin segment of it
+        let aperture = Aperture::polygon(3, 45.0 + 180.0);
+        let mut sampler = SamplerKind::Sobol.create(1000, 1);
+        for index in 0..1000 {
+            sampler.start_pixel_sample(0, 0, index);
+            let (x, y) = aperture.sample_vignetted(1.0, 1.0, 1.0, sampler.as_mut());
+            // Pulled towards the center by a hair against rounding on the edges
+            assert!(aperture.shape_contains((x * (1.0 - 1e-4), y * (1.0 - 1e-4))));
+            assert!((x - 1.0) * (x - 1.0) + (y - 1.0) * (y - 1.0) <= 1.0 + 1e-5, "({}, {}) outside the barrel", x, y);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Ray casting speed in rays per second, to compare ways of intersecting the same spheres: a
+//! plain list, a bounding volume hierarchy traced one ray at a time, and the same hierarchy traced
+//! by packets of rays.
+
+use std::convert::TryInto;
+use std::io::{self, Write};
+use std::time::Instant;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+
+use crate::float::Float;
+use crate::hitable::{Hitable, HitableList, Sphere};
+use crate::packet::{PACKET_SIZE, SphereSet};
+use crate::projection::Projection;
+use crate::ray::Ray;
+use crate::render::RenderSettings;
+use crate::vec::Vec3;
+
+/// A way of casting rays, returning how many hit.
+type Cast<'a> = &'a dyn Fn(&[Ray]) -> usize;
+
+/// Cast every ray through `world` one at a time, returning how many hit.
+fn cast_single(world: &dyn Hitable, rays: &[Ray]) -> usize {
+    rays.iter().filter(|r| world.hit(r, 0.0, Float::MAX).is_some()).count()
+}
+
+/// Cast the rays through `set` in packets, returning how many hit.
+fn cast_packets(set: &SphereSet, rays: &[Ray]) -> usize {
+    let packets = rays.chunks_exact(PACKET_SIZE);
+    let rest = cast_single(set, packets.remainder());
+    packets.map(|chunk| {
+        let packet: &[Ray; PACKET_SIZE] = chunk.try_into().unwrap();
+        set.hit_packet(packet, 0.0, Float::MAX).iter().filter(|h| h.is_some()).count()
+    }).sum::<usize>() + rest
+}
+
+/// Rays per second of `cast`, which casts `count` rays, timed over repeated runs of at least a
+/// second in total. Also returns how many rays hit, to check that every method agrees.
+fn measure<F: FnMut() -> usize>(count: usize, mut cast: F) -> (f64, usize) {
+    let start = Instant::now();
+    let mut runs = 0;
+    let mut hits = 0;
+    while runs < 3 || start.elapsed().as_secs_f64() < 1.0 {
+        hits = cast();
+        runs += 1;
+    }
+    ((count * runs) as f64 / start.elapsed().as_secs_f64(), hits)
+}
+
+/// Cast a primary ray through the center of every pixel of the image, and a diffusely bounced ray
+/// from every hit, at `spheres` in each of the ways and write the speeds to `out`. Runs on one
+/// thread.
+pub fn run<W: Write>(out: &mut W, spheres: Vec<Sphere>, cam: &dyn Projection, settings: &RenderSettings) -> io::Result<()> {
+    let mut sampler = settings.sampler.create(1, settings.seed);
+    let (width, height) = (settings.image_width, settings.image_height);
+    let mut primary = Vec::with_capacity((width * height) as usize);
+    for j in 0..height {
+        for i in 0..width {
+            sampler.start_pixel_sample(i, j, 0);
+            let (s, t) = ((i as Float + 0.5) / width as Float, (j as Float + 0.5) / height as Float);
+            primary.extend(cam.get_ray(s, t, sampler.as_mut()));
+        }
+    }
+
+    let list = HitableList { list: spheres.iter().map(|s| Box::new(s.clone()) as Box<dyn Hitable>).collect() };
+    let set = SphereSet::new(spheres);
+    let mut rng = StdRng::seed_from_u64(settings.seed);
+    let mut bounce: Vec<Ray> = primary.iter().filter_map(|r| set.hit(r, 0.0, Float::MAX)).map(|hit| {
+        let direction = hit.normal + Vec3::random_range(&mut rng, -1.0, 1.0);
+        hit.spawn_ray(direction)
+    }).collect();
+    // Shuffled, so that a packet holds bounces from all over the image as in a path tracer
+    bounce.shuffle(&mut rng);
+
+    writeln!(out, "{} spheres, {} primary rays and {} bounce rays, on one thread", list.list.len(), primary.len(), bounce.len())?;
+    writeln!(out, "{:<24}{:>17}{:>17}", "", "primary", "bounce")?;
+    let methods: [(&str, Cast); 3] = [
+        ("list, single rays", &|rays| cast_single(&list, rays)),
+        ("BVH, single rays", &|rays| cast_single(&set, rays)),
+        ("BVH, packets of 4", &|rays| cast_packets(&set, rays)),
+    ];
+    for (name, cast) in methods {
+        let (primary_speed, primary_hits) = measure(primary.len(), || cast(&primary));
+        let (bounce_speed, bounce_hits) = measure(bounce.len(), || cast(&bounce));
+        writeln!(out, "{:<24}{:>10.2} Mray/s{:>10.2} Mray/s   ({} and {} hits)",
+            name, primary_speed / 1e6, bounce_speed / 1e6, primary_hits, bounce_hits)?;
+    }
+    Ok(())
+}
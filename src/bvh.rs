@@ -0,0 +1,128 @@
+//! Bounding volume hierarchy over any set of primitives that have bounding boxes. It only stores
+//! indices, so the primitives themselves stay wherever their owner keeps them.
+
+use crate::float::Float;
+use crate::aabb::Aabb;
+use crate::packet::RayPacket;
+use crate::ray::Ray;
+use crate::simd::Float4;
+use crate::vec::Vec3;
+
+/// Most primitives in a leaf.
+const LEAF_SIZE: usize = 4;
+
+#[derive(Debug, Clone)]
+struct Node {
+    bounds: Aabb,
+    // For a leaf the range of `order` with its primitives. Otherwise `count` is zero, the left
+    // child is the next node and `first` is the index of the right child.
+    first: usize,
+    count: usize,
+    axis: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    // Primitive indices, grouped so that every leaf refers to a contiguous range
+    order: Vec<usize>,
+}
+
+impl Bvh {
+    /// Hierarchy over primitives with the given boxes, split at the median along the axis where
+    /// their centers spread the most.
+    pub fn new(boxes: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh { nodes: Vec::with_capacity(2 * boxes.len() / LEAF_SIZE + 1), order: (0..boxes.len()).collect() };
+        if !boxes.is_empty() {
+            let centers: Vec<Vec3> = boxes.iter().map(|b| 0.5 * (b.min + b.max)).collect();
+            bvh.build(boxes, &centers, 0, boxes.len());
+        }
+        bvh
+    }
+
+    fn build(&mut self, boxes: &[Aabb], centers: &[Vec3], start: usize, end: usize) -> usize {
+        let indices = &mut self.order[start..end];
+        let bounds = indices[1..].iter().fold(boxes[indices[0]], |acc, &i| acc.surrounding(&boxes[i]));
+        let node = self.nodes.len();
+        self.nodes.push(Node { bounds, first: start, count: end - start, axis: 0 });
+        if end - start <= LEAF_SIZE {
+            return node;
+        }
+        let first_center = centers[indices[0]];
+        let spread = indices.iter().fold(Aabb::new(first_center, first_center), |acc, &i| acc.surrounding(&Aabb::new(centers[i], centers[i])));
+        let extent = spread.max - spread.min;
+        let axis = if extent.x() > extent.y() && extent.x() > extent.z() { 0 } else if extent.y() > extent.z() { 1 } else { 2 };
+        let mid = (end - start) / 2;
+        indices.select_nth_unstable_by(mid, |&a, &b| centers[a].e[axis].total_cmp(&centers[b].e[axis]));
+        self.build(boxes, centers, start, start + mid);
+        let right = self.build(boxes, centers, start + mid, end);
+        self.nodes[node] = Node { bounds, first: right, count: 0, axis };
+        node
+    }
+
+    /// Box around all primitives, or `None` if there are none.
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bounds)
+    }
+
+    /// Call `visit` with every primitive whose box the ray passes through before the closest hit
+    /// found so far, nearer subtrees first. `visit` gets the primitive index and the current
+    /// `t_max`, and returns the distance of a closer hit to shrink the search.
+    pub fn traverse<F: FnMut(usize, Float) -> Option<Float>>(&self, r: &Ray, t_min: Float, mut t_max: Float, mut visit: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = [0; 64];
+        let mut depth = 1;
+        while depth > 0 {
+            depth -= 1;
+            let node = &self.nodes[stack[depth]];
+            if !node.bounds.hit(r, t_min, t_max) {
+                continue;
+            }
+            if node.count > 0 {
+                for &i in &self.order[node.first..node.first + node.count] {
+                    if let Some(t) = visit(i, t_max) {
+                        t_max = t;
+                    }
+                }
+                continue;
+            }
+            // Push the far child first, so that the near one is visited next
+            let left = stack[depth] + 1;
+            let (near, far) = if r.direction.e[node.axis] < 0.0 { (node.first, left) } else { (left, node.first) };
+            stack[depth] = far;
+            stack[depth + 1] = near;
+            depth += 2;
+        }
+    }
+
+    /// Like `traverse` for a packet of rays: a node is entered while any ray of the packet passes
+    /// through its box before that ray's closest hit so far. `visit` gets the primitive index and
+    /// the closest hit of every ray in `t_max`, and lowers those it finds closer hits for.
+    pub fn traverse_packet<F: FnMut(usize, &mut Float4)>(&self, packet: &RayPacket, t_min: Float, t_max: &mut Float4, mut visit: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = [0; 64];
+        let mut depth = 1;
+        while depth > 0 {
+            depth -= 1;
+            let node = &self.nodes[stack[depth]];
+            if !packet.hits_box(&node.bounds, t_min, *t_max).any() {
+                continue;
+            }
+            if node.count > 0 {
+                for &i in &self.order[node.first..node.first + node.count] {
+                    visit(i, t_max);
+                }
+                continue;
+            }
+            let left = stack[depth] + 1;
+            let (near, far) = if packet.is_negative(node.axis) { (node.first, left) } else { (left, node.first) };
+            stack[depth] = far;
+            stack[depth + 1] = near;
+            depth += 2;
+        }
+    }
+}
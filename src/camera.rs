@@ -1,21 +1,17 @@
-use crate::vec::{Vec3, Point3, cross, random_in_unit_disk};
+use crate::float::{Float, consts::PI};
+use crate::vec::{Vec3, Point3, cross};
+use crate::aperture::Aperture;
+use crate::stereo::Convergence;
 use crate::ray::Ray;
-use std::f32::consts::PI;
-use rand::prelude::ThreadRng;
+use crate::sampler::Sampler;
+use crate::projection::view_basis;
+use std::error::Error;
+use std::fmt;
 
-fn degrees_to_radians(rad: f32) -> f32 {
+fn degrees_to_radians(rad: Float) -> Float {
     rad / 180.0 * PI
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn degree_convert () {
-        assert_ne!(1.57, degrees_to_radians(90.0));
-    }
-}
-
 pub struct Camera {
     pub origin: Point3,
     pub lower_left_corner: Point3,
@@ -23,32 +19,243 @@ pub struct Camera {
     pub vertical: Vec3,
     u: Vec3,
     v: Vec3,
-    lens_radius: f32
+    lens_radius: Float,
+    aperture: Aperture,
+    cat_eye: Float
+}
+
+/// Camera configurations that cannot produce valid rays.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CameraError {
+    // `lookfrom` and `lookat` are the same point, so there is no viewing direction
+    ZeroLengthView,
+    // `vup` is zero or parallel to the viewing direction, so it does not define where up is
+    CollinearUp,
+    InvalidFov(Float),
+    InvalidAspectRatio(Float),
+    InvalidFocusDistance(Float),
+    NegativeAperture(Float),
+    // A polygonal aperture needs at least three blades
+    TooFewBlades(u32),
+    InvalidCatEye(Float),
+    InvalidConvergenceDistance(Float),
+}
+
+impl fmt::Display for CameraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CameraError::ZeroLengthView => write!(f, "lookfrom and lookat are the same point"),
+            CameraError::CollinearUp => write!(f, "vup is zero or parallel to the viewing direction"),
+            CameraError::InvalidFov(vfov) => write!(f, "vertical field of view must be between 0 and 180 degrees, got {}", vfov),
+            CameraError::InvalidAspectRatio(ratio) => write!(f, "aspect ratio must be positive, got {}", ratio),
+            CameraError::InvalidFocusDistance(dist) => write!(f, "focus distance must be positive, got {}", dist),
+            CameraError::NegativeAperture(aperture) => write!(f, "aperture must not be negative, got {}", aperture),
+            CameraError::TooFewBlades(blades) => write!(f, "a polygonal aperture needs at least 3 blades, got {}", blades),
+            CameraError::InvalidCatEye(strength) => write!(f, "cat's eye strength must be between 0 and 1, got {}", strength),
+            CameraError::InvalidConvergenceDistance(dist) => write!(f, "convergence distance must be positive, got {}", dist),
+        }
+    }
+}
+
+impl Error for CameraError {}
+
+/// Check that the view vectors define an orthonormal camera frame.
+pub fn validate_view(lookfrom: &Point3, lookat: &Point3, vup: &Vec3) -> Result<(), CameraError> {
+    let view = *lookfrom - *lookat;
+    let view_length = view.length();
+    if view_length == 0.0 || view_length.is_nan() {
+        return Err(CameraError::ZeroLengthView);
+    }
+    let vup_length = vup.length();
+    if vup_length == 0.0 || vup_length.is_nan() || cross(vup, &view.unit_vector()).length() <= 1e-6 * vup_length {
+        return Err(CameraError::CollinearUp);
+    }
+    Ok(())
+}
+
+/// Named parameters for a `Camera`, with defaults for everything that is not set.
+/// The default camera looks from the origin down -Z, with a 90° vertical field of view, a 16:9
+/// aspect ratio, a pinhole lens and the focus at `lookat`.
+#[derive(Clone)]
+pub struct CameraBuilder {
+    lookfrom: Point3,
+    lookat: Point3,
+    vup: Vec3,
+    vfov: Float,
+    aspect_ratio: Float,
+    aperture: Float,
+    focus_dist: Option<Float>,
+    aperture_shape: Aperture,
+    cat_eye: Float,
+    lens_shift: (Float, Float),
+    // Sideways offset of the eye, its convergence distance and how it converges
+    stereo_eye: Option<(Float, Float, Convergence)>,
+}
+
+impl Default for CameraBuilder {
+    fn default() -> Self {
+        CameraBuilder {
+            lookfrom: Point3::new(0.0, 0.0, 0.0),
+            lookat: Point3::new(0.0, 0.0, -1.0),
+            vup: Vec3::new(0.0, 1.0, 0.0),
+            vfov: 90.0,
+            aspect_ratio: 16.0 / 9.0,
+            aperture: 0.0,
+            focus_dist: None,
+            aperture_shape: Aperture::Circle,
+            cat_eye: 0.0,
+            lens_shift: (0.0, 0.0),
+            stereo_eye: None,
+        }
+    }
+}
+
+impl CameraBuilder {
+    pub fn lookfrom(mut self, lookfrom: Point3) -> Self {
+        self.lookfrom = lookfrom;
+        self
+    }
+
+    pub fn lookat(mut self, lookat: Point3) -> Self {
+        self.lookat = lookat;
+        self
+    }
+
+    pub fn vup(mut self, vup: Vec3) -> Self {
+        self.vup = vup;
+        self
+    }
+
+    /// Vertical field of view in degrees.
+    pub fn vfov(mut self, vfov: Float) -> Self {
+        self.vfov = vfov;
+        self
+    }
+
+    pub fn aspect_ratio(mut self, aspect_ratio: Float) -> Self {
+        self.aspect_ratio = aspect_ratio;
+        self
+    }
+
+    /// Diameter of the lens. Zero gives a pinhole camera where everything is in focus.
+    pub fn aperture(mut self, aperture: Float) -> Self {
+        self.aperture = aperture;
+        self
+    }
+
+    /// Distance to the plane in perfect focus. Defaults to the distance to `lookat`.
+    pub fn focus_dist(mut self, focus_dist: Float) -> Self {
+        self.focus_dist = Some(focus_dist);
+        self
+    }
+
+    /// Shape of the lens opening, which gives out-of-focus highlights their shape. Defaults to a
+    /// circle.
+    pub fn aperture_shape(mut self, aperture_shape: Aperture) -> Self {
+        self.aperture_shape = aperture_shape;
+        self
+    }
+
+    /// Optical vignetting towards the edges of the frame, from 0.0 (none) to 1.0 (strong), which
+    /// turns bokeh near the corners into cat's eye shapes.
+    pub fn cat_eye(mut self, strength: Float) -> Self {
+        self.cat_eye = strength;
+        self
+    }
+
+    /// Shift the image window sideways and up, as a fraction of its width and height, without
+    /// turning the camera. Like the shift of a tilt-shift lens, this keeps vertical lines parallel.
+    pub fn lens_shift(mut self, x: Float, y: Float) -> Self {
+        self.lens_shift = (x, y);
+        self
+    }
+
+    /// Turn this camera into one eye of a stereo pair, moved `offset` to the right (negative for
+    /// the left eye) and converging on the plane `convergence_distance` in front of the camera.
+    pub fn stereo_eye(mut self, offset: Float, convergence_distance: Float, convergence: Convergence) -> Self {
+        self.stereo_eye = Some((offset, convergence_distance, convergence));
+        self
+    }
+
+    pub fn build(self) -> Result<Camera, CameraError> {
+        validate_view(&self.lookfrom, &self.lookat, &self.vup)?;
+        if !(self.vfov > 0.0 && self.vfov < 180.0) {
+            return Err(CameraError::InvalidFov(self.vfov));
+        }
+        if !(self.aspect_ratio > 0.0 && self.aspect_ratio.is_finite()) {
+            return Err(CameraError::InvalidAspectRatio(self.aspect_ratio));
+        }
+        if self.aperture < 0.0 || self.aperture.is_nan() {
+            return Err(CameraError::NegativeAperture(self.aperture));
+        }
+        let focus_dist = self.focus_dist.unwrap_or_else(|| (self.lookfrom - self.lookat).length());
+        if !(focus_dist > 0.0 && focus_dist.is_finite()) {
+            return Err(CameraError::InvalidFocusDistance(focus_dist));
+        }
+        if let Aperture::Polygon { blades, .. } = self.aperture_shape {
+            if blades < 3 {
+                return Err(CameraError::TooFewBlades(blades));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.cat_eye) {
+            return Err(CameraError::InvalidCatEye(self.cat_eye));
+        }
+        let (mut lookfrom, mut lookat) = (self.lookfrom, self.lookat);
+        let mut lens_shift = self.lens_shift;
+        if let Some((offset, convergence_distance, convergence)) = self.stereo_eye {
+            if !(convergence_distance > 0.0 && convergence_distance.is_finite()) {
+                return Err(CameraError::InvalidConvergenceDistance(convergence_distance));
+            }
+            let (u, _, w) = view_basis(&lookfrom, &lookat, &self.vup);
+            let convergence_point = lookfrom - convergence_distance * w;
+            lookfrom += offset * u;
+            match convergence {
+                // Rotate the eye inwards to look at the center of the convergence plane
+                Convergence::ToeIn => lookat = convergence_point,
+                // Keep the eyes parallel and shift the image window so that both frame the same
+                // area on the convergence plane
+                Convergence::OffAxis => {
+                    lookat += offset * u;
+                    let view_width = convergence_distance * 2.0 * (degrees_to_radians(self.vfov) / 2.0).tan() * self.aspect_ratio;
+                    lens_shift.0 -= offset / view_width;
+                }
+            }
+        }
+        let mut camera = Camera::new(lookfrom, lookat, self.vup, self.vfov, self.aspect_ratio, self.aperture, focus_dist);
+        camera.lower_left_corner += lens_shift.0 * camera.horizontal + lens_shift.1 * camera.vertical;
+        camera.aperture = self.aperture_shape;
+        camera.cat_eye = self.cat_eye;
+        Ok(camera)
+    }
 }
 
 impl Camera {
+    pub fn builder() -> CameraBuilder {
+        CameraBuilder::default()
+    }
+
+    /// Thin lens camera from positional parameters, without any validation. Prefer
+    /// `Camera::builder`, which reports degenerate configurations instead of producing NaN rays.
     pub fn new(
         lookfrom: Point3,
         lookat: Point3,
         vup: Vec3,
-        vof: f32,
-        aspect_ratio: f32,
-        aperture: f32,
-        focust_dist: f32
+        vfov: Float,
+        aspect_ratio: Float,
+        aperture: Float,
+        focus_dist: Float
     ) -> Camera {
-        let theta = degrees_to_radians(vof);
+        let theta = degrees_to_radians(vfov);
         let h = (theta / 2.0).tan();
         let viewport_height = h * 2.0;
         let viewport_width = aspect_ratio * viewport_height;
 
-        let w = Vec3::unit_vector(&(lookfrom - lookat));
-        let u = Vec3::unit_vector(&cross(&vup, &w));
-        let v = cross(&w, &u);
+        let (u, v, w) = view_basis(&lookfrom, &lookat, &vup);
 
         let origin = lookfrom;
-        let horizontal = focust_dist * viewport_width * u;
-        let vertical = focust_dist * viewport_height * v;
-        let lower_left_corner = origin - horizontal / 2.0 - vertical / 2.0 - focust_dist*w;
+        let horizontal = focus_dist * viewport_width * u;
+        let vertical = focus_dist * viewport_height * v;
+        let lower_left_corner = origin - horizontal / 2.0 - vertical / 2.0 - focus_dist*w;
 
         let lens_radius = aperture / 2.0;
 
@@ -59,17 +266,42 @@ impl Camera {
             lower_left_corner,
             u,
             v,
-            lens_radius
+            lens_radius,
+            aperture: Aperture::Circle,
+            cat_eye: 0.0
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32, rng: &mut ThreadRng) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk(rng);
-        let offset = self.u * rd.x() + self.v * rd.y();
+    pub fn get_ray(&self, s: Float, t: Float, sampler: &mut dyn Sampler) -> Ray {
+        let (x, y) = self.aperture.sample_vignetted(s, t, self.cat_eye, sampler);
+        let offset = self.lens_radius * (self.u * x + self.v * y);
 
         Ray {
             origin: self.origin + offset,
             direction: self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn degree_convert () {
+        assert_ne!(1.57, degrees_to_radians(90.0));
+    }
+
+    #[test]
+    fn builder_rejects_degenerate_setups () {
+        let p = Point3::new(1.0, 2.0, 3.0);
+        assert_eq!(Camera::builder().lookfrom(p).lookat(p).build().err(), Some(CameraError::ZeroLengthView));
+        assert_eq!(
+            Camera::builder().lookfrom(p).lookat(Point3::new(1.0, 5.0, 3.0)).build().err(),
+            Some(CameraError::CollinearUp)
+        );
+        assert_eq!(Camera::builder().vfov(0.0).build().err(), Some(CameraError::InvalidFov(0.0)));
+        assert_eq!(Camera::builder().focus_dist(-1.0).build().err(), Some(CameraError::InvalidFocusDistance(-1.0)));
+        assert_eq!(Camera::builder().aperture(-0.1).build().err(), Some(CameraError::NegativeAperture(-0.1)));
+        assert!(Camera::builder().lookfrom(p).aperture(0.1).build().is_ok());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,170 @@
+//! Constructive solid geometry: shapes built from the union, intersection or difference of two
+//! closed `Hitable`s.
+//!
+//! Along a ray, each operand is a set of intervals where the ray is inside it. The crossings of
+//! both operands are merged in order, and a crossing becomes a surface of the result wherever it
+//! changes whether the ray is inside the combined shape.
+
+use crate::float::Float;
+use crate::aabb::Aabb;
+use crate::hitable::{HitRecord, Hitable};
+use crate::ray::Ray;
+use crate::vec::{Vec3, dot};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    // The first operand with the second one carved out of it
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            CsgOperation::Union => in_a || in_b,
+            CsgOperation::Intersection => in_a && in_b,
+            CsgOperation::Difference => in_a && !in_b,
+        }
+    }
+}
+
+/// Boolean combination of two closed objects whose normals point outwards. The surfaces keep the
+/// material of the operand they come from.
+pub struct Csg {
+    pub operation: CsgOperation,
+    pub a: Box<dyn Hitable>,
+    pub b: Box<dyn Hitable>,
+}
+
+impl Csg {
+    pub fn new(operation: CsgOperation, a: impl Hitable + 'static, b: impl Hitable + 'static) -> Csg {
+        Csg { operation, a: Box::new(a), b: Box::new(b) }
+    }
+
+    pub fn union(a: impl Hitable + 'static, b: impl Hitable + 'static) -> Csg {
+        Csg::new(CsgOperation::Union, a, b)
+    }
+
+    pub fn intersection(a: impl Hitable + 'static, b: impl Hitable + 'static) -> Csg {
+        Csg::new(CsgOperation::Intersection, a, b)
+    }
+
+    /// `a` with `b` carved out of it.
+    pub fn difference(a: impl Hitable + 'static, b: impl Hitable + 'static) -> Csg {
+        Csg::new(CsgOperation::Difference, a, b)
+    }
+}
+
+/// Whether the ray leaves the object at this crossing.
+fn leaving(r: &Ray, hit: &HitRecord) -> bool {
+    dot(&r.direction, &hit.normal) > 0.0
+}
+
+impl Hitable for Csg {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        self.hits(r, t_min, t_max).into_iter().next()
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let (a, b) = (self.a.bounding_box(), self.b.bounding_box());
+        match self.operation {
+            CsgOperation::Union => Some(a?.surrounding(&b?)),
+            CsgOperation::Intersection => match (a, b) {
+                (Some(a), Some(b)) => Some(Aabb::new(
+                    Vec3::new(a.min.x().max(b.min.x()), a.min.y().max(b.min.y()), a.min.z().max(b.min.z())),
+                    Vec3::new(a.max.x().min(b.max.x()), a.max.y().min(b.max.y()), a.max.z().min(b.max.z())),
+                )),
+                (a, b) => a.or(b),
+            },
+            CsgOperation::Difference => a,
+        }
+    }
+
+    fn hits(&self, r: &Ray, t_min: Float, t_max: Float) -> Vec<HitRecord> {
+        // The crossings past `t_max` are not reported, but without them a ray that ends inside an
+        // operand could not tell that it started inside
+        let a = self.a.hits(r, t_min, Float::MAX);
+        let b = self.b.hits(r, t_min, Float::MAX);
+        // A ray starts inside an operand when its first crossing leaves it
+        let mut in_a = a.first().is_some_and(|hit| leaving(r, hit));
+        let mut in_b = b.first().is_some_and(|hit| leaving(r, hit));
+        let mut inside = self.operation.inside(in_a, in_b);
+
+        let mut result = Vec::new();
+        let (mut a, mut b) = (a.into_iter().peekable(), b.into_iter().peekable());
+        loop {
+            let from_a = match (a.peek(), b.peek()) {
+                (Some(hit_a), Some(hit_b)) => hit_a.t <= hit_b.t,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            let mut hit = if from_a { a.next() } else { b.next() }.unwrap();
+            if hit.t >= t_max {
+                break;
+            }
+            if from_a {
+                in_a = !leaving(r, &hit);
+            } else {
+                in_b = !leaving(r, &hit);
+            }
+            let now_inside = self.operation.inside(in_a, in_b);
+            if now_inside != inside {
+                // Where the second operand is carved out its inside becomes the outside
+                if !from_a && self.operation == CsgOperation::Difference {
+                    hit.normal = -1.0 * hit.normal;
+                }
+                result.push(hit);
+                inside = now_inside;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::Sphere;
+    use crate::material::Material;
+    use crate::sdf::{Sdf, SdfHitable};
+    #[test]
+    fn carved_sphere_and_lens () {
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        let sphere = |x: Float, radius: Float| Sphere { center: Vec3::new(x, 0.0, 0.0), radius, material: material.clone() };
+        let along_x = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+
+        // Lens where two unit spheres overlap between x = -0.5 and x = 0.5
+        let lens = Csg::intersection(sphere(-0.5, 1.0), sphere(0.5, 1.0));
+        let hits = lens.hits(&along_x, 0.001, Float::MAX);
+        assert_eq!(hits.len(), 2);
+        assert!((hits[0].p.x() + 0.5).abs() < 1e-4 && (hits[1].p.x() - 0.5).abs() < 1e-4);
+        assert!(hits[0].normal.x() < 0.0 && hits[1].normal.x() > 0.0);
+        let bounds = lens.bounding_box().unwrap();
+        assert!((bounds.min.x() + 0.5).abs() < 1e-6 && (bounds.max.x() - 0.5).abs() < 1e-6);
+
+        // A box carved out of the middle of a sphere leaves a hollow from x = -0.25 to 0.25
+        let cube = SdfHitable::new(Sdf::cuboid(Vec3::new(0.25, 0.25, 0.25)), material.clone());
+        let carved = Csg::difference(sphere(0.0, 1.0), cube);
+        let hits = carved.hits(&along_x, 0.001, Float::MAX);
+        let xs: Vec<Float> = hits.iter().map(|hit| hit.p.x()).collect();
+        assert_eq!(xs.len(), 4, "crossings at {:?}", xs);
+        for (x, expected) in xs.iter().zip([-1.0, -0.25, 0.25, 1.0]) {
+            assert!((x - expected).abs() < 1e-3, "crossings at {:?}", xs);
+        }
+        // The walls of the hollow face into it
+        assert!(hits[1].normal.x() > 0.0 && hits[2].normal.x() < 0.0);
+
+        // Starting inside the hollow, the ray first enters the sphere's shell
+        let inside = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hit = carved.hit(&inside, 0.001, Float::MAX).unwrap();
+        assert!((hit.p.x() - 0.25).abs() < 1e-3 && hit.normal.x() < 0.0);
+
+        // The union has no crossing where the spheres overlap
+        let both = Csg::union(sphere(-0.5, 1.0), sphere(0.5, 1.0));
+        let xs: Vec<Float> = both.hits(&along_x, 0.001, Float::MAX).iter().map(|hit| hit.p.x()).collect();
+        assert_eq!(xs.len(), 2);
+        assert!((xs[0] + 1.5).abs() < 1e-4 && (xs[1] - 1.5).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,298 @@
+//! Thin curves for hair, fur, grass and cables: cubic Bézier or B-spline strands with a width that
+//! varies from root to tip.
+//!
+//! Segments are intersected as in pbrt: the control points are moved to a frame where the ray runs
+//! along +z, and the curve is split until each piece is nearly straight and can be tested as a
+//! flat strip facing the ray.
+
+use crate::float::{Float, consts};
+use crate::aabb::Aabb;
+use crate::bvh::Bvh;
+use crate::hitable::{HitRecord, Hitable, any_perpendicular};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::vec::{Vec3, Point3, dot, cross};
+
+/// How the control points of a strand define its curve.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CurveBasis {
+    // Segments of four points sharing their end points: 3n + 1 points for n segments
+    Bezier,
+    // Uniform cubic B-spline, smooth everywhere but not through its points: n + 3 points
+    BSpline,
+}
+
+/// How a curve is shaded across its width.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CurveShape {
+    // Flat strip that always faces the ray, like a blade of grass seen from the front. The hair
+    // material accounts for the round fiber itself.
+    Ribbon,
+    // Round tube: the normal turns around the curve from one edge to the other
+    Cylinder,
+}
+
+struct Segment {
+    // Bézier control points
+    points: [Point3; 4],
+    // Width at the start and end of the segment
+    widths: (Float, Float),
+    // Parameter along the whole strand at the start and end of the segment
+    range: (Float, Float),
+}
+
+/// Strands sharing a width profile, shape and material, in one bounding volume hierarchy. `u` runs
+/// along each strand from 0 at its first point to 1 at its last, `v` across it from 0 to 1.
+pub struct Curves {
+    segments: Vec<Segment>,
+    bvh: Bvh,
+    shape: CurveShape,
+    pub material: Material,
+}
+
+fn lerp(t: Float, a: Float, b: Float) -> Float {
+    (1.0 - t) * a + t * b
+}
+
+/// Point and derivative of a cubic Bézier curve at `u`.
+fn evaluate_bezier(p: &[Point3; 4], u: Float) -> (Point3, Vec3) {
+    let a = [lerp_point(u, p[0], p[1]), lerp_point(u, p[1], p[2]), lerp_point(u, p[2], p[3])];
+    let b = [lerp_point(u, a[0], a[1]), lerp_point(u, a[1], a[2])];
+    let derivative = if (b[1] - b[0]).squared_length() > 0.0 { 3.0 * (b[1] - b[0]) } else { p[3] - p[0] };
+    (lerp_point(u, b[0], b[1]), derivative)
+}
+
+fn lerp_point(t: Float, a: Point3, b: Point3) -> Point3 {
+    (1.0 - t) * a + t * b
+}
+
+/// The two halves of a Bézier curve split at its middle.
+fn split_bezier(p: &[Point3; 4]) -> [[Point3; 4]; 2] {
+    let mid = (p[0] + 3.0 * p[1] + 3.0 * p[2] + p[3]) / 8.0;
+    [
+        [p[0], (p[0] + p[1]) / 2.0, (p[0] + 2.0 * p[1] + p[2]) / 4.0, mid],
+        [mid, (p[1] + 2.0 * p[2] + p[3]) / 4.0, (p[2] + p[3]) / 2.0, p[3]],
+    ]
+}
+
+impl Curves {
+    /// Curves through the control points of each strand. `widths` are spread evenly from root to
+    /// tip and interpolated linearly in between, so a single width is constant and two taper.
+    /// Panics if a strand has the wrong number of points for `basis`, or there are no widths.
+    pub fn new(strands: &[Vec<Point3>], widths: &[Float], basis: CurveBasis, shape: CurveShape, material: Material) -> Curves {
+        assert!(!widths.is_empty(), "curves need at least one width");
+        let width_at = |s: Float| {
+            let x = s * (widths.len() - 1) as Float;
+            let i = (x as usize).min(widths.len().saturating_sub(2));
+            match widths.get(i + 1) {
+                Some(&next) => lerp(x - i as Float, widths[i], next),
+                None => widths[0],
+            }
+        };
+        let mut segments = Vec::new();
+        for points in strands {
+            let pieces: Vec<[Point3; 4]> = match basis {
+                CurveBasis::Bezier => {
+                    assert!(points.len() >= 4 && (points.len() - 1) % 3 == 0, "a Bézier strand needs 3n + 1 points, not {}", points.len());
+                    points.windows(4).step_by(3).map(|p| [p[0], p[1], p[2], p[3]]).collect()
+                }
+                CurveBasis::BSpline => {
+                    assert!(points.len() >= 4, "a B-spline strand needs at least 4 points, not {}", points.len());
+                    points.windows(4).map(|p| [
+                        (p[0] + 4.0 * p[1] + p[2]) / 6.0,
+                        (2.0 * p[1] + p[2]) / 3.0,
+                        (p[1] + 2.0 * p[2]) / 3.0,
+                        (p[1] + 4.0 * p[2] + p[3]) / 6.0,
+                    ]).collect()
+                }
+            };
+            let count = pieces.len() as Float;
+            for (i, points) in pieces.into_iter().enumerate() {
+                let range = (i as Float / count, (i + 1) as Float / count);
+                segments.push(Segment { points, widths: (width_at(range.0), width_at(range.1)), range });
+            }
+        }
+        // The curve stays inside the hull of its control points, so pad their box by half the width
+        let boxes: Vec<Aabb> = segments.iter().map(|s| {
+            let half = 0.5 * s.widths.0.max(s.widths.1);
+            s.points.iter().fold(Aabb::around(s.points[0], Vec3::new(half, half, half)), |acc, p| {
+                acc.surrounding(&Aabb::around(*p, Vec3::new(half, half, half)))
+            })
+        }).collect();
+        Curves { segments, bvh: Bvh::new(&boxes), shape, material }
+    }
+
+    /// Ray parameter and position along the segment of the closest hit with one segment.
+    fn intersect(&self, segment: usize, r: &Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        let segment = &self.segments[segment];
+        let length = r.direction.length();
+        let dz = r.direction / length;
+        let dx = any_perpendicular(&dz);
+        let dy = cross(&dz, &dx);
+        let to_ray = |p: &Point3| {
+            let d = *p - r.origin;
+            Vec3::new(dot(&d, &dx), dot(&d, &dy), dot(&d, &dz))
+        };
+        let points = segment.points.map(|p| to_ray(&p));
+
+        // Split until the pieces are flat to within a twentieth of the width
+        let max_width = segment.widths.0.max(segment.widths.1);
+        let mut l0: Float = 0.0;
+        for i in 0..2 {
+            let second = points[i] - 2.0 * points[i + 1] + points[i + 2];
+            l0 = l0.max(second.x().abs()).max(second.y().abs()).max(second.z().abs());
+        }
+        let depth = if l0 > 0.0 && max_width > 0.0 {
+            ((consts::SQRT_2 * 6.0 * l0 / (8.0 * 0.05 * max_width)).log2() / 2.0).clamp(0.0, 10.0) as u32
+        } else {
+            0
+        };
+        let mut closest = None;
+        let mut z_max = t_max * length;
+        recursive_intersect(segment, &points, (0.0, 1.0), depth, t_min * length, &mut z_max, &mut closest);
+        closest.map(|(z, u)| (z / length, u))
+    }
+
+}
+
+/// Split `points`, the part of the segment over `u_range` in ray space, `depth` more times and
+/// test the pieces, keeping the closest hit between `z_min` and `z_max` in `closest`.
+fn recursive_intersect(segment: &Segment, points: &[Point3; 4], u_range: (Float, Float), depth: u32, z_min: Float, z_max: &mut Float, closest: &mut Option<(Float, Float)>) {
+    let width = |u: Float| lerp(u, segment.widths.0, segment.widths.1);
+    if depth > 0 {
+        let halves = split_bezier(points);
+        let u_mid = 0.5 * (u_range.0 + u_range.1);
+        for (half, range) in halves.iter().zip([(u_range.0, u_mid), (u_mid, u_range.1)]) {
+            let half_width = 0.5 * width(range.0).max(width(range.1));
+            let (mut lo, mut hi) = (half[0], half[0]);
+            for p in &half[1..] {
+                for k in 0..3 {
+                    lo.e[k] = lo.e[k].min(p.e[k]);
+                    hi.e[k] = hi.e[k].max(p.e[k]);
+                }
+            }
+            // The ray runs along the z axis through the origin
+            if lo.x() - half_width > 0.0 || hi.x() + half_width < 0.0 || lo.y() - half_width > 0.0 || hi.y() + half_width < 0.0
+                || hi.z() + half_width < z_min || lo.z() - half_width > *z_max {
+                continue;
+            }
+            recursive_intersect(segment, half, range, depth - 1, z_min, z_max, closest);
+        }
+        return;
+    }
+
+    // The ray must pass between the lines perpendicular to the curve at both ends
+    let start_edge = (points[1].y() - points[0].y()) * -points[0].y() + points[0].x() * (points[0].x() - points[1].x());
+    let end_edge = (points[2].y() - points[3].y()) * -points[3].y() + points[3].x() * (points[3].x() - points[2].x());
+    if start_edge < 0.0 || end_edge < 0.0 {
+        return;
+    }
+    // Closest point to the ray on the straight line through the ends
+    let direction = Vec3::new(points[3].x() - points[0].x(), points[3].y() - points[0].y(), 0.0);
+    let denominator = direction.squared_length();
+    if denominator == 0.0 {
+        return;
+    }
+    let w = (-points[0].x() * direction.x() - points[0].y() * direction.y()) / denominator;
+    let u = lerp(w, u_range.0, u_range.1).clamp(u_range.0, u_range.1);
+    let hit_width = width(u);
+    let (center, _) = evaluate_bezier(points, w.clamp(0.0, 1.0));
+    if center.x() * center.x() + center.y() * center.y() > 0.25 * hit_width * hit_width {
+        return;
+    }
+    if center.z() <= z_min || center.z() >= *z_max {
+        return;
+    }
+    // A ray that starts on this fiber, such as one scattered off it, does not hit it again here
+    if center.squared_length() <= 0.25 * hit_width * hit_width * 1.01 {
+        return;
+    }
+    *z_max = center.z();
+    *closest = Some((center.z(), u));
+}
+
+impl Hitable for Curves {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let mut closest = None;
+        self.bvh.traverse(r, t_min, t_max, |segment, t_max| {
+            let (t, u) = self.intersect(segment, r, t_min, t_max)?;
+            closest = Some((segment, t, u));
+            Some(t)
+        });
+        let (segment, t, u) = closest?;
+        let segment = &self.segments[segment];
+        let (center, derivative) = evaluate_bezier(&segment.points, u);
+        let tangent = derivative.unit_vector();
+        let p = r.point_at_parameter(t);
+
+        // Side direction across the curve as seen along the ray, and the normal of the strip
+        // facing back along the ray
+        let across = cross(&tangent, &r.direction);
+        let side = if across.squared_length() > 1e-12 { across.unit_vector() } else { any_perpendicular(&tangent) };
+        let mut facing = cross(&side, &tangent);
+        if dot(&facing, &r.direction) > 0.0 {
+            facing = -1.0 * facing;
+        }
+        let half_width = 0.5 * lerp(u, segment.widths.0, segment.widths.1);
+        let offset = if half_width > 0.0 { (dot(&(p - center), &side) / half_width).clamp(-1.0, 1.0) } else { 0.0 };
+        let normal = match self.shape {
+            CurveShape::Ribbon => facing,
+            CurveShape::Cylinder => (1.0 - offset * offset).max(0.0).sqrt() * facing + offset * side,
+        };
+        // The hitpoint is only known to lie somewhere on the fiber, so bound it by twice its width
+        let width = 2.0 * half_width;
+        Some(HitRecord {
+            t,
+            p,
+            error: Vec3::new(2.0 * width, 2.0 * width, 2.0 * width),
+            normal,
+            u: lerp(u, segment.range.0, segment.range.1),
+            v: 0.5 + 0.5 * offset,
+            tangent,
+            on_edge: false,
+            material: self.material.clone(),
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn curves_hit_within_their_width () {
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        // An arch over the x axis, tapering from 0.2 to 0.1 wide
+        let arch = vec![Point3::new(-1.0, 0.0, 0.0), Point3::new(-1.0, 1.0, 0.0), Point3::new(1.0, 1.0, 0.0), Point3::new(1.0, 0.0, 0.0)];
+        let top = evaluate_bezier(&[arch[0], arch[1], arch[2], arch[3]], 0.5).0;
+        assert!((top - Point3::new(0.0, 0.75, 0.0)).length() < 1e-6);
+        let curves = Curves::new(&[arch], &[0.2, 0.1], CurveBasis::Bezier, CurveShape::Cylinder, material.clone());
+
+        // Straight through the top of the arch, and just off the center line at the tube's edge
+        let down = |x: Float, z: Float| Ray::new(Point3::new(x, 5.0, z), Vec3::new(0.0, -2.0, 0.0));
+        let hit = curves.hit(&down(0.0, 0.0), 0.001, Float::MAX).unwrap();
+        assert!((hit.t - 2.125).abs() < 1e-3, "{}", hit.t);
+        assert!((hit.u - 0.5).abs() < 1e-3 && (hit.v - 0.5).abs() < 0.05);
+        assert!(hit.normal.y() > 0.99 && hit.tangent.x().abs() > 0.99);
+        // Width 0.15 in the middle
+        let edge = curves.hit(&down(0.0, 0.07), 0.001, Float::MAX).unwrap();
+        assert!(edge.normal.z().abs() > 0.8);
+        assert!(curves.hit(&down(0.0, 0.08), 0.001, Float::MAX).is_none());
+        // Along the side: the ray passes under the arch
+        assert!(curves.hit(&Ray::new(Point3::new(0.0, 0.3, 5.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, Float::MAX).is_none());
+        assert!(curves.hit(&Ray::new(Point3::new(0.0, 0.74, 5.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, Float::MAX).is_some());
+
+        // A B-spline with its end points repeated three times ends exactly at them
+        let (start, end) = (Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0));
+        let cable = vec![start, start, start, Point3::new(1.0, 0.0, 0.0), end, end, end];
+        let spline = Curves::new(&[cable], &[0.1], CurveBasis::BSpline, CurveShape::Ribbon, material);
+        let bounds = spline.bounding_box().unwrap();
+        assert!(bounds.min.x() < 0.0 && bounds.max.x() > 2.0);
+        let hit = spline.hit(&Ray::new(Point3::new(1.9, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0)), 0.001, Float::MAX).unwrap();
+        assert!((hit.t - 3.0).abs() < 1e-3 && hit.u > 0.75);
+        assert!(spline.hit(&Ray::new(Point3::new(2.1, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0)), 0.001, Float::MAX).is_none());
+    }
+}
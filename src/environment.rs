@@ -0,0 +1,295 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::float::{Float, consts::PI};
+use crate::sampling::Distribution2D;
+use crate::vec::Vec3;
+
+/// Equirectangular HDR image that surrounds the scene and lights it from infinitely far away.
+/// The top row of the image is straight up (+Y), and the center column looks down -Z.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+    // Rotation around the Y axis, in radians
+    rotation: Float,
+    intensity: Float,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> EnvironmentMap {
+        // Importance sample by luminance, weighted by the solid angle the pixel covers
+        let mut luminance = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let sin_theta = (PI * (y as Float + 0.5) / height as Float).sin();
+            for x in 0..width {
+                luminance.push(EnvironmentMap::luminance(&pixels[y * width + x]) * sin_theta);
+            }
+        }
+        let distribution = Distribution2D::new(&luminance, width, height);
+        EnvironmentMap { width, height, pixels, rotation: 0.0, intensity: 1.0, distribution }
+    }
+
+    /// Load a Radiance RGBE (`.hdr`) or portable float map (`.pfm`) file, based on its header.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<EnvironmentMap> {
+        let bytes = fs::read(path)?;
+        EnvironmentMap::from_bytes(&bytes)
+    }
+
+    /// Decode an image file already in memory.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<EnvironmentMap> {
+        let (width, height, pixels) = if bytes.starts_with(b"PF") || bytes.starts_with(b"Pf") {
+            read_pfm(bytes)?
+        } else if bytes.starts_with(b"#?") {
+            read_radiance_hdr(bytes)?
+        } else {
+            return Err(invalid_data("not a Radiance HDR or PFM file"));
+        };
+        if width == 0 || height == 0 {
+            return Err(invalid_data("environment map without pixels"));
+        }
+        Ok(EnvironmentMap::new(width, height, pixels))
+    }
+
+    /// Rotate the map around the vertical axis by `degrees`.
+    pub fn with_rotation(mut self, degrees: Float) -> EnvironmentMap {
+        self.rotation = degrees / 180.0 * PI;
+        self
+    }
+
+    /// Scale the radiance of the whole map.
+    pub fn with_intensity(mut self, intensity: Float) -> EnvironmentMap {
+        self.intensity = intensity;
+        self
+    }
+
+    fn luminance(c: &Vec3) -> Float {
+        0.2126 * c.r() + 0.7152 * c.g() + 0.0722 * c.b()
+    }
+
+    /// Image coordinates in [0, 1]² of a world space direction.
+    fn direction_to_uv(&self, direction: &Vec3) -> (Float, Float) {
+        let d = direction.unit_vector();
+        let phi = d.x().atan2(-d.z()) - self.rotation;
+        let u = (0.5 + phi / (2.0 * PI)).rem_euclid(1.0);
+        let v = d.y().clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+
+    /// World space unit direction of image coordinates in [0, 1]², and sin(theta) at that point.
+    fn uv_to_direction(&self, u: Float, v: Float) -> (Vec3, Float) {
+        let phi = (u - 0.5) * 2.0 * PI + self.rotation;
+        let theta = v * PI;
+        let sin_theta = theta.sin();
+        (Vec3::new(sin_theta * phi.sin(), theta.cos(), -sin_theta * phi.cos()), sin_theta)
+    }
+
+    fn lookup(&self, u: Float, v: Float) -> Vec3 {
+        let x = ((u * self.width as Float) as usize).min(self.width - 1);
+        let y = ((v * self.height as Float) as usize).min(self.height - 1);
+        self.intensity * self.pixels[y * self.width + x]
+    }
+
+    /// Radiance arriving from `direction`.
+    pub fn radiance(&self, direction: &Vec3) -> Vec3 {
+        let (u, v) = self.direction_to_uv(direction);
+        self.lookup(u, v)
+    }
+
+    /// Pick a direction towards the environment proportional to its brightness. Returns the unit
+    /// direction, the radiance from there and the pdf with respect to solid angle.
+    pub fn sample(&self, u1: Float, u2: Float) -> (Vec3, Vec3, Float) {
+        let (u, v, pdf_uv) = self.distribution.sample(u1, u2);
+        let (direction, sin_theta) = self.uv_to_direction(u, v);
+        if pdf_uv == 0.0 || sin_theta == 0.0 {
+            return (direction, Vec3::new(0.0, 0.0, 0.0), 0.0);
+        }
+        // The image covers 2π × π radians, and a pixel row shrinks with sin(theta)
+        let pdf = pdf_uv / (2.0 * PI * PI * sin_theta);
+        (direction, self.lookup(u, v), pdf)
+    }
+
+    /// Solid angle pdf with which `sample` picks `direction`.
+    pub fn pdf(&self, direction: &Vec3) -> Float {
+        let (u, v) = self.direction_to_uv(direction);
+        let sin_theta = (v * PI).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Read a line of the header, without the newline.
+fn read_line<'a>(bytes: &'a [u8], pos: &mut usize) -> io::Result<&'a str> {
+    let start = *pos;
+    let end = bytes[start..].iter().position(|&b| b == b'\n').map(|i| start + i).ok_or_else(|| invalid_data("unexpected end of header"))?;
+    *pos = end + 1;
+    std::str::from_utf8(&bytes[start..end]).map_err(|_| invalid_data("header is not valid text"))
+}
+
+/// Decode a Radiance RGBE image, flat or with the adaptive run-length encoded scanlines.
+/// Only the standard `-Y height +X width` orientation is supported.
+fn read_radiance_hdr(bytes: &[u8]) -> io::Result<(usize, usize, Vec<Vec3>)> {
+    let mut pos = 0;
+    loop {
+        let line = read_line(bytes, &mut pos)?;
+        if line.starts_with("FORMAT=") && line.trim() != "FORMAT=32-bit_rle_rgbe" {
+            return Err(invalid_data("only the 32-bit_rle_rgbe format is supported"));
+        }
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+    let resolution: Vec<&str> = read_line(bytes, &mut pos)?.split_whitespace().collect();
+    let (height, width) = match resolution.as_slice() {
+        ["-Y", h, "+X", w] => (
+            h.parse::<usize>().map_err(|_| invalid_data("invalid image height"))?,
+            w.parse::<usize>().map_err(|_| invalid_data("invalid image width"))?,
+        ),
+        _ => return Err(invalid_data("unsupported image orientation")),
+    };
+
+    let size = width.checked_mul(height).ok_or_else(|| invalid_data("invalid image size"))?;
+    // The fewest bytes a scanline can take: four per pixel when flat, or when run-length encoded
+    // the four byte marker and a two byte run of at most 127 pixels per channel. Sizes the data
+    // cannot hold are rejected before allocating for them.
+    let rle_bytes = if (8..0x8000).contains(&width) { 4 + 8 * width.div_ceil(127) } else { usize::MAX };
+    let scanline_bytes = width.saturating_mul(4).min(rle_bytes);
+    if scanline_bytes.checked_mul(height).is_none_or(|n| n > bytes.len() - pos) {
+        return Err(invalid_data("image data is shorter than the image size"));
+    }
+
+    let mut next = || -> io::Result<u8> {
+        let b = *bytes.get(pos).ok_or_else(|| invalid_data("unexpected end of image data"))?;
+        pos += 1;
+        Ok(b)
+    };
+    let mut rgbe = vec![[0u8; 4]; size];
+    for y in 0..height {
+        let scanline = &mut rgbe[y * width..(y + 1) * width];
+        let first = [next()?, next()?, next()?, next()?];
+        let is_rle = (8..0x8000).contains(&width) && first[0] == 2 && first[1] == 2 && first[2] & 0x80 == 0;
+        if !is_rle {
+            // Flat scanline, the bytes we read were the first pixel
+            scanline[0] = first;
+            for pixel in scanline.iter_mut().skip(1) {
+                *pixel = [next()?, next()?, next()?, next()?];
+            }
+            continue;
+        }
+        if ((first[2] as usize) << 8 | first[3] as usize) != width {
+            return Err(invalid_data("scanline width mismatch"));
+        }
+        // Each of the four channels is run-length encoded separately
+        for channel in 0..4 {
+            let mut x = 0;
+            while x < width {
+                let count = next()? as usize;
+                if count > 128 {
+                    let count = count - 128;
+                    let value = next()?;
+                    if x + count > width {
+                        return Err(invalid_data("bad run-length encoding"));
+                    }
+                    for pixel in &mut scanline[x..x + count] {
+                        pixel[channel] = value;
+                    }
+                    x += count;
+                } else {
+                    if count == 0 || x + count > width {
+                        return Err(invalid_data("bad run-length encoding"));
+                    }
+                    for pixel in &mut scanline[x..x + count] {
+                        pixel[channel] = next()?;
+                    }
+                    x += count;
+                }
+            }
+        }
+    }
+
+    let pixels = rgbe
+        .iter()
+        .map(|p| {
+            if p[3] == 0 {
+                Vec3::new(0.0, 0.0, 0.0)
+            } else {
+                let f = Float::powi(2.0, p[3] as i32 - 136);
+                Vec3::new(p[0] as Float * f, p[1] as Float * f, p[2] as Float * f)
+            }
+        })
+        .collect();
+    Ok((width, height, pixels))
+}
+
+/// Decode a portable float map. Its rows are stored from the bottom to the top.
+fn read_pfm(bytes: &[u8]) -> io::Result<(usize, usize, Vec<Vec3>)> {
+    let mut pos = 0;
+    let channels = if read_line(bytes, &mut pos)?.trim() == "PF" { 3 } else { 1 };
+    let dims: Vec<usize> = read_line(bytes, &mut pos)?
+        .split_whitespace()
+        .map(|s| s.parse().map_err(|_| invalid_data("invalid image size")))
+        .collect::<io::Result<_>>()?;
+    let (width, height) = match dims.as_slice() {
+        [w, h] => (*w, *h),
+        _ => return Err(invalid_data("invalid image size")),
+    };
+    let scale: Float = read_line(bytes, &mut pos)?.trim().parse().map_err(|_| invalid_data("invalid scale"))?;
+    let little_endian = scale < 0.0;
+
+    let data = &bytes[pos..];
+    let size = width.checked_mul(height).and_then(|n| n.checked_mul(channels * 4))
+        .ok_or_else(|| invalid_data("invalid image size"))?;
+    if data.len() < size {
+        return Err(invalid_data("unexpected end of image data"));
+    }
+    let value = |i: usize| {
+        let b = [data[4 * i], data[4 * i + 1], data[4 * i + 2], data[4 * i + 3]];
+        if little_endian { f32::from_le_bytes(b) as Float } else { f32::from_be_bytes(b) as Float }
+    };
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let i = (y * width + x) * channels;
+            pixels.push(if channels == 3 {
+                Vec3::new(value(i), value(i + 1), value(i + 2))
+            } else {
+                Vec3::new(value(i), value(i), value(i))
+            });
+        }
+    }
+    Ok((width, height, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn sampled_direction_maps_back () {
+        let mut pixels = vec![Vec3::new(0.1, 0.1, 0.1); 16 * 8];
+        pixels[3 * 16 + 5] = Vec3::new(100.0, 100.0, 100.0);
+        let env = EnvironmentMap::new(16, 8, pixels).with_rotation(30.0);
+        let (direction, radiance, pdf) = env.sample(0.5, 0.5);
+        assert!(radiance.r() > 99.0);
+        assert!((env.pdf(&direction) - pdf).abs() / pdf < 1e-3);
+        assert!(env.radiance(&direction).r() > 99.0);
+    }
+
+    #[test]
+    fn maps_without_pixels_are_rejected () {
+        assert!(EnvironmentMap::from_bytes(b"PF\n0 0\n-1.0\n").is_err());
+        assert!(EnvironmentMap::from_bytes(b"#?RADIANCE\n\n-Y 0 +X 4\n").is_err());
+        // Sizes whose byte count overflows
+        assert!(EnvironmentMap::from_bytes(format!("PF\n{} 3\n-1.0\n", usize::MAX / 2).as_bytes()).is_err());
+        assert!(EnvironmentMap::from_bytes(b"#?RADIANCE\n\n-Y 3000000 +X 3000000\n\x02\x02").is_err());
+        let one_pixel = [b"Pf\n1 1\n-1.0\n".as_slice(), &2.0f32.to_le_bytes()].concat();
+        assert_eq!(EnvironmentMap::from_bytes(&one_pixel).unwrap().pixels[0].e, [2.0, 2.0, 2.0]);
+    }
+}
@@ -0,0 +1,140 @@
+
+use crate::float::{Float, consts::PI};
+
+/// Pixel reconstruction filter. Every sample is splatted into all pixels whose center lies within
+/// `radius` of it, weighted by the filter, and each pixel is normalized by the sum of its weights.
+/// All filters are separable, the product of a 1D filter in x and in y.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Box {
+        radius: Float
+    },
+    // Triangle that falls linearly to zero at the radius
+    Tent {
+        radius: Float
+    },
+    // Gaussian with falloff `alpha`, shifted down so that it reaches zero at the radius
+    Gaussian {
+        radius: Float,
+        alpha: Float
+    },
+    // Mitchell-Netravali cubic, stretched over the radius. B = C = 1/3 is the recommended balance
+    // between blurring and ringing.
+    Mitchell {
+        radius: Float,
+        b: Float,
+        c: Float
+    },
+    // Windowed sinc with `tau` cycles of the sinc inside the radius
+    Lanczos {
+        radius: Float,
+        tau: Float
+    },
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::box_filter()
+    }
+}
+
+impl Filter {
+    /// Averages the samples inside each pixel, like rendering without a filter.
+    pub fn box_filter() -> Filter {
+        Filter::Box { radius: 0.5 }
+    }
+
+    pub fn tent(radius: Float) -> Filter {
+        Filter::Tent { radius }
+    }
+
+    pub fn gaussian(radius: Float) -> Filter {
+        Filter::Gaussian { radius, alpha: 2.0 }
+    }
+
+    pub fn mitchell(radius: Float) -> Filter {
+        Filter::Mitchell { radius, b: 1.0 / 3.0, c: 1.0 / 3.0 }
+    }
+
+    pub fn lanczos(radius: Float) -> Filter {
+        Filter::Lanczos { radius, tau: radius }
+    }
+
+    /// Filter by name, `box`, `tent`, `gaussian`, `mitchell` or `lanczos`, with a radius in pixels.
+    pub fn from_name(name: &str, radius: Option<Float>) -> Option<Filter> {
+        match name {
+            "box" => Some(Filter::Box { radius: radius.unwrap_or(0.5) }),
+            "tent" => Some(Filter::tent(radius.unwrap_or(1.0))),
+            "gaussian" => Some(Filter::gaussian(radius.unwrap_or(1.5))),
+            "mitchell" => Some(Filter::mitchell(radius.unwrap_or(2.0))),
+            "lanczos" => Some(Filter::lanczos(radius.unwrap_or(3.0))),
+            _ => None,
+        }
+    }
+
+    pub fn radius(&self) -> Float {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius, .. } => radius,
+        }
+    }
+
+    /// Weight of a sample at offset (dx, dy) in pixels from a pixel center.
+    pub fn evaluate(&self, dx: Float, dy: Float) -> Float {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    /// Weight of the 1D filter at offset `x`, which the 2D filter is the product of.
+    pub fn evaluate_1d(&self, x: Float) -> Float {
+        let x = x.abs();
+        match *self {
+            Filter::Box { radius } => if x <= radius { 1.0 } else { 0.0 },
+            Filter::Tent { radius } => (radius - x).max(0.0),
+            Filter::Gaussian { radius, alpha } => ((-alpha * x * x).exp() - (-alpha * radius * radius).exp()).max(0.0),
+            Filter::Mitchell { radius, b, c } => {
+                if x >= radius {
+                    return 0.0;
+                }
+                // The cubic is defined over [0, 2)
+                let x = 2.0 * x / radius;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+                } else {
+                    ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+                }
+            }
+            Filter::Lanczos { radius, tau } => {
+                if x >= radius {
+                    return 0.0;
+                }
+                let x = x / radius;
+                sinc(x) * sinc(x * tau)
+            }
+        }
+    }
+}
+
+fn sinc(x: Float) -> Float {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn filters_peak_at_center_and_vanish_at_radius () {
+        for name in ["box", "tent", "gaussian", "mitchell", "lanczos"] {
+            let filter = Filter::from_name(name, None).unwrap();
+            let r = filter.radius();
+            assert!(filter.evaluate(0.0, 0.0) > 0.0);
+            assert!(filter.evaluate(0.0, 0.0) >= filter.evaluate(0.3, 0.1));
+            assert!(filter.evaluate(r + 0.01, 0.0).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,13 @@
+//! The floating-point type the whole tracer computes in: `f32` by default, or `f64` when built
+//! with the `f64` feature, which keeps large scenes free of rounding artifacts at some cost in
+//! speed and memory. File formats keep their own fixed types and convert at the boundary.
+
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(not(feature = "f64"))]
+pub use std::f32::consts;
+
+#[cfg(feature = "f64")]
+pub type Float = f64;
+#[cfg(feature = "f64")]
+pub use std::f64::consts;
@@ -0,0 +1,619 @@
+//! Importing glTF 2.0 scenes, as `.gltf` JSON with embedded or external buffers and images, or as
+//! binary `.glb` containers.
+//!
+//! Triangle meshes are placed by the node hierarchy, perspective cameras become `CameraBuilder`s,
+//! and metallic-roughness materials are mapped onto the closest `Material`. Base color,
+//! metallic-roughness and emissive textures are looked up with the texture coordinates of the mesh,
+//! and normal maps are kept. Shading normals are computed from the triangles rather than read from
+//! the file.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::float::Float;
+use crate::camera::{Camera, CameraBuilder};
+use crate::hitable::{Hitable, HitableList, Instance};
+use crate::json::Json;
+use crate::material::{Material, NormalMap};
+use crate::mesh::{Mesh, TriangleMesh};
+use crate::texture::{ImageTexture, Texture};
+use crate::vec::{Mat4, Point3, Quaternion, Transform, Vec3};
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("glTF: {}", msg))
+}
+
+/// Objects and cameras of the default scene of a glTF file, in world space.
+pub struct GltfScene {
+    pub objects: HitableList,
+    // Perspective cameras in the order the nodes are visited
+    pub cameras: Vec<CameraBuilder>,
+    // What was left out of the scene and why, such as images in formats that cannot be decoded
+    pub warnings: Vec<String>,
+}
+
+impl GltfScene {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<GltfScene> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        GltfScene::parse(&bytes, path.parent().unwrap_or_else(|| Path::new("")))
+    }
+
+    /// Parse a `.gltf` or `.glb` file, with external files resolved relative to `dir`.
+    pub fn parse(bytes: &[u8], dir: &Path) -> io::Result<GltfScene> {
+        let (json, binary) = if bytes.starts_with(b"glTF") { split_glb(bytes)? } else { (bytes, None) };
+        let text = std::str::from_utf8(json).map_err(|_| invalid_data("JSON is not UTF-8".to_string()))?;
+        let json = Json::parse(text)?;
+        let mut document = Document { json, buffers: Vec::new(), dir: dir.to_path_buf(), images: HashMap::new(), warnings: Vec::new() };
+        for (i, buffer) in document.json.get("buffers").map_or(&[][..], Json::elements).iter().enumerate() {
+            let data = match buffer.get("uri").and_then(Json::as_str) {
+                Some(uri) => document.read_uri(uri)?,
+                None if i == 0 => binary.ok_or_else(|| invalid_data("buffer without a uri outside of a .glb file".to_string()))?.to_vec(),
+                None => return Err(invalid_data(format!("buffer {} has no uri", i))),
+            };
+            document.buffers.push(data);
+        }
+
+        let materials = document.elements("materials").to_vec().iter().map(|m| document.material(m)).collect::<io::Result<Vec<_>>>()?;
+        // Primitives without a material get the one with every property at its default
+        let default_material = document.material(&Json::Object(Vec::new()))?;
+        let mut meshes: Vec<Vec<Arc<dyn Hitable>>> = Vec::new();
+        for mesh in document.elements("meshes") {
+            let mut primitives: Vec<Arc<dyn Hitable>> = Vec::new();
+            for primitive in mesh.get("primitives").map_or(&[][..], Json::elements) {
+                if let Some(triangles) = document.primitive(primitive)? {
+                    let material = match primitive.get("material").and_then(Json::as_usize) {
+                        Some(m) => materials.get(m).cloned().ok_or_else(|| invalid_data(format!("no material {}", m)))?,
+                        None => default_material.clone(),
+                    };
+                    primitives.push(Arc::new(TriangleMesh::new(&triangles, material)));
+                }
+            }
+            meshes.push(primitives);
+        }
+
+        let nodes = document.elements("nodes");
+        let roots: Vec<usize> = match document.json.get("scene").and_then(Json::as_usize).or(Some(0)).and_then(|s| document.elements("scenes").get(s)) {
+            Some(scene) => scene.get("nodes").map_or(&[][..], Json::elements).iter().filter_map(Json::as_usize).collect(),
+            // Without scenes, every node that is nobody's child is a root
+            None => (0..nodes.len())
+                .filter(|i| !nodes.iter().any(|n| n.get("children").map_or(&[][..], Json::elements).iter().any(|c| c.as_usize() == Some(*i))))
+                .collect(),
+        };
+        let warnings = std::mem::take(&mut document.warnings);
+        let mut scene = GltfScene { objects: HitableList { list: Vec::new() }, cameras: Vec::new(), warnings };
+        for root in roots {
+            document.visit(root, &Mat4::identity(), &meshes, &mut scene, 0)?;
+        }
+        Ok(scene)
+    }
+}
+
+/// The JSON and binary chunks of a `.glb` container.
+fn split_glb(bytes: &[u8]) -> io::Result<(&[u8], Option<&[u8]>)> {
+    let word = |at: usize| bytes.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    let mut pos = 12;
+    let (mut json, mut binary) = (None, None);
+    while let (Some(length), Some(kind)) = (word(pos), word(pos + 4)) {
+        let chunk = bytes.get(pos + 8..pos + 8 + length).ok_or_else(|| invalid_data("truncated .glb chunk".to_string()))?;
+        match kind {
+            0x4e4f_534a => json = Some(chunk),
+            0x004e_4942 => binary = Some(chunk),
+            _ => {}
+        }
+        pos += 8 + length;
+    }
+    Ok((json.ok_or_else(|| invalid_data(".glb without a JSON chunk".to_string()))?, binary))
+}
+
+fn base64_decode(text: &str) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes().filter(|&c| c != b'=' && !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return Err(invalid_data("bad base64 data".to_string())),
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Ok(out)
+}
+
+/// Undo `%XX` escapes in a relative URI.
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn srgb_to_linear(c: Float) -> Float {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn srgb_color_to_linear(c: Vec3) -> Vec3 {
+    Vec3::new(srgb_to_linear(c.r()), srgb_to_linear(c.g()), srgb_to_linear(c.b()))
+}
+
+/// A texture of `factor` times the texels of `image` after `convert`, or just `factor` without an
+/// image.
+fn scaled_texture(image: Option<Arc<ImageTexture>>, factor: Vec3, convert: fn(Vec3) -> Vec3) -> Texture {
+    match image {
+        Some(image) => Texture::Image(Arc::new(ImageTexture {
+            width: image.width,
+            height: image.height,
+            pixels: image.pixels.iter().map(|&p| factor * convert(p)).collect(),
+        })),
+        None => Texture::Solid(factor),
+    }
+}
+
+fn vec3(values: &[Float]) -> Option<Vec3> {
+    match values {
+        [x, y, z, ..] => Some(Vec3::new(*x, *y, *z)),
+        _ => None,
+    }
+}
+
+struct Document {
+    json: Json,
+    buffers: Vec<Vec<u8>>,
+    dir: PathBuf,
+    // Decoded images by index, `None` for those that could not be read
+    images: HashMap<usize, Option<Arc<ImageTexture>>>,
+    warnings: Vec<String>,
+}
+
+impl Document {
+    fn elements(&self, name: &str) -> &[Json] {
+        self.json.get(name).map_or(&[][..], Json::elements)
+    }
+
+    fn read_uri(&self, uri: &str) -> io::Result<Vec<u8>> {
+        if let Some(data) = uri.strip_prefix("data:") {
+            let (header, payload) = data.split_once(',').ok_or_else(|| invalid_data("malformed data URI".to_string()))?;
+            if !header.ends_with(";base64") {
+                return Err(invalid_data("only base64 data URIs are supported".to_string()));
+            }
+            base64_decode(payload)
+        } else {
+            fs::read(self.dir.join(percent_decode(uri)))
+        }
+    }
+
+    fn buffer_view(&self, index: usize) -> io::Result<(&[u8], Option<usize>)> {
+        let view = self.elements("bufferViews").get(index).ok_or_else(|| invalid_data(format!("no buffer view {}", index)))?;
+        let buffer = view.get("buffer").and_then(Json::as_usize).and_then(|b| self.buffers.get(b))
+            .ok_or_else(|| invalid_data(format!("buffer view {} has no buffer", index)))?;
+        let offset = view.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+        let length = view.get("byteLength").and_then(Json::as_usize).unwrap_or(0);
+        let bytes = offset.checked_add(length).and_then(|end| buffer.get(offset..end)).ok_or_else(|| invalid_data(format!("buffer view {} is out of bounds", index)))?;
+        Ok((bytes, view.get("byteStride").and_then(Json::as_usize)))
+    }
+
+    /// Values of an accessor, flattened, with the number of components of each element.
+    fn accessor(&self, index: usize) -> io::Result<(Vec<f64>, usize)> {
+        let accessor = self.elements("accessors").get(index).ok_or_else(|| invalid_data(format!("no accessor {}", index)))?;
+        let components = match accessor.get("type").and_then(Json::as_str) {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") | Some("MAT2") => 4,
+            Some("MAT3") => 9,
+            Some("MAT4") => 16,
+            _ => return Err(invalid_data(format!("accessor {} has an unknown type", index))),
+        };
+        let component_type = accessor.get("componentType").and_then(Json::as_usize).unwrap_or(0);
+        let size = match component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            _ => return Err(invalid_data(format!("accessor {} has an unknown component type", index))),
+        };
+        if accessor.get("sparse").is_some() {
+            return Err(invalid_data(format!("accessor {} is sparse, which is not supported", index)));
+        }
+        let count = accessor.get("count").and_then(Json::as_usize).unwrap_or(0);
+        let normalized = accessor.get("normalized").and_then(Json::as_bool).unwrap_or(false);
+        let out_of_bounds = || invalid_data(format!("accessor {} is out of bounds", index));
+        let length = count.checked_mul(components).ok_or_else(out_of_bounds)?;
+        let view = match accessor.get("bufferView").and_then(Json::as_usize) {
+            Some(view) => view,
+            // An accessor without data is all zeros. It can be no larger than if it had data in
+            // the buffers, so a count out of proportion with the file is not allocated for.
+            None => {
+                let buffered: usize = self.buffers.iter().map(Vec::len).sum();
+                if length.checked_mul(size).is_none_or(|n| n > buffered) {
+                    return Err(out_of_bounds());
+                }
+                return Ok((vec![0.0; length], components));
+            }
+        };
+        let (bytes, stride) = self.buffer_view(view)?;
+        let offset = accessor.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+        let stride = stride.unwrap_or(size * components);
+        // The last element has to end inside the buffer view, which also bounds the allocation
+        if count > 0 {
+            let end = (count - 1).checked_mul(stride)
+                .and_then(|n| n.checked_add(offset))
+                .and_then(|n| n.checked_add(components * size));
+            if end.is_none_or(|end| end > bytes.len()) {
+                return Err(out_of_bounds());
+            }
+        }
+        let mut values = Vec::with_capacity(length);
+        for element in 0..count {
+            for component in 0..components {
+                let at = offset + element * stride + component * size;
+                let b = &bytes[at..at + size];
+                let value = match component_type {
+                    // Normalized signed values map both of their lowest numbers to -1
+                    5120 if normalized => (b[0] as i8 as f64 / 127.0).max(-1.0),
+                    5120 => b[0] as i8 as f64,
+                    5121 => b[0] as f64 / if normalized { 255.0 } else { 1.0 },
+                    5122 if normalized => (i16::from_le_bytes([b[0], b[1]]) as f64 / 32767.0).max(-1.0),
+                    5122 => i16::from_le_bytes([b[0], b[1]]) as f64,
+                    5123 => u16::from_le_bytes([b[0], b[1]]) as f64 / if normalized { 65535.0 } else { 1.0 },
+                    5125 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    _ => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                };
+                values.push(value);
+            }
+        }
+        Ok((values, components))
+    }
+
+    /// Triangles of a mesh primitive, or `None` for points and lines.
+    fn primitive(&self, primitive: &Json) -> io::Result<Option<Mesh>> {
+        let attributes = primitive.get("attributes");
+        let position = attributes.and_then(|a| a.get("POSITION")).and_then(Json::as_usize)
+            .ok_or_else(|| invalid_data("primitive without positions".to_string()))?;
+        let (values, components) = self.accessor(position)?;
+        if components != 3 {
+            return Err(invalid_data("positions are not VEC3".to_string()));
+        }
+        let positions: Vec<Point3> = values.chunks_exact(3).map(|p| Vec3::new(p[0] as Float, p[1] as Float, p[2] as Float)).collect();
+        let indices: Vec<usize> = match primitive.get("indices").and_then(Json::as_usize) {
+            Some(indices) => self.accessor(indices)?.0.iter().map(|&i| i as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+        let faces: Vec<Vec<usize>> = match primitive.get("mode").and_then(Json::as_usize).unwrap_or(4) {
+            4 => indices.chunks_exact(3).map(|t| t.to_vec()).collect(),
+            // Strips alternate their winding to keep it consistent
+            5 => (2..indices.len()).map(|i| if i % 2 == 0 {
+                vec![indices[i - 2], indices[i - 1], indices[i]]
+            } else {
+                vec![indices[i - 1], indices[i - 2], indices[i]]
+            }).collect(),
+            6 => (2..indices.len()).map(|i| vec![indices[0], indices[i - 1], indices[i]]).collect(),
+            _ => return Ok(None),
+        };
+        if faces.iter().flatten().any(|&i| i >= positions.len()) {
+            return Err(invalid_data("vertex index out of range".to_string()));
+        }
+        let mut mesh = Mesh::new(positions, faces);
+        if let Some(texcoord) = attributes.and_then(|a| a.get("TEXCOORD_0")).and_then(Json::as_usize) {
+            // glTF puts the origin of textures at the top left, and v = 0 is the bottom row here
+            mesh.texcoords = self.accessor(texcoord)?.0.chunks_exact(2).map(|t| (t[0] as Float, 1.0 - t[1] as Float)).collect();
+        }
+        Ok(Some(mesh))
+    }
+
+    /// Decoded image of a texture reference such as `baseColorTexture`. Images that cannot be
+    /// decoded are left out, with a warning the first time.
+    fn texture(&mut self, reference: Option<&Json>) -> io::Result<Option<Arc<ImageTexture>>> {
+        let texture = match reference.and_then(|r| r.get("index")).and_then(Json::as_usize) {
+            Some(texture) => texture,
+            None => return Ok(None),
+        };
+        let source = self.elements("textures").get(texture).and_then(|t| t.get("source")).and_then(Json::as_usize)
+            .ok_or_else(|| invalid_data(format!("texture {} has no image", texture)))?;
+        if let Some(image) = self.images.get(&source) {
+            return Ok(image.clone());
+        }
+        let image = self.elements("images").get(source).ok_or_else(|| invalid_data(format!("no image {}", source)))?;
+        let bytes = match (image.get("uri").and_then(Json::as_str), image.get("bufferView").and_then(Json::as_usize)) {
+            (Some(uri), _) => self.read_uri(uri)?,
+            (None, Some(view)) => self.buffer_view(view)?.0.to_vec(),
+            _ => return Err(invalid_data(format!("image {} has no data", source))),
+        };
+        let decoded = match ImageTexture::from_bytes(&bytes) {
+            Ok(decoded) => Some(Arc::new(decoded)),
+            Err(e) => {
+                self.warnings.push(format!("skipped image {}: {}", source, e));
+                None
+            }
+        };
+        self.images.insert(source, decoded.clone());
+        Ok(decoded)
+    }
+
+    /// The closest `Material` to a glTF material: emissive materials become lights, transmissive
+    /// ones glass, metallic ones metal with the roughness as fuzz, and the rest diffuse.
+    fn material(&mut self, material: &Json) -> io::Result<Material> {
+        let pbr = material.get("pbrMetallicRoughness");
+        let factor = |name: &str, default: Float| pbr.and_then(|p| p.get(name)).and_then(Json::as_float).unwrap_or(default);
+        let base = pbr.and_then(|p| p.get("baseColorFactor")).and_then(Json::as_float_array).and_then(|c| vec3(&c)).unwrap_or(Vec3::new(1.0, 1.0, 1.0));
+        let (metallic, roughness) = (factor("metallicFactor", 1.0), factor("roughnessFactor", 1.0));
+        let base_texture = self.texture(pbr.and_then(|p| p.get("baseColorTexture")))?;
+        let metallic_roughness_texture = self.texture(pbr.and_then(|p| p.get("metallicRoughnessTexture")))?;
+
+        let extension = |name: &str, member: &str| material.get("extensions").and_then(|e| e.get(name)).and_then(|e| e.get(member));
+        let mut emissive = material.get("emissiveFactor").and_then(Json::as_float_array).and_then(|c| vec3(&c)).unwrap_or(Vec3::new(0.0, 0.0, 0.0));
+        emissive = emissive * extension("KHR_materials_emissive_strength", "emissiveStrength").and_then(Json::as_float).unwrap_or(1.0);
+        let emissive_texture = self.texture(material.get("emissiveTexture"))?;
+        let emits = emissive.e.iter().any(|&c| c > 0.0);
+        let transmission = extension("KHR_materials_transmission", "transmissionFactor").and_then(Json::as_float).unwrap_or(0.0);
+
+        let mapped = if transmission > 0.5 && !emits {
+            let ior = extension("KHR_materials_ior", "ior").and_then(Json::as_float).unwrap_or(1.5);
+            let attenuation = extension("KHR_materials_volume", "attenuationColor").and_then(Json::as_float_array).and_then(|c| vec3(&c));
+            let distance = extension("KHR_materials_volume", "attenuationDistance").and_then(Json::as_float);
+            match (attenuation, distance) {
+                (Some(color), Some(distance)) => Material::tinted_glass(ior, color, distance),
+                _ => Material::glass(ior),
+            }
+        } else if base_texture.is_some() || metallic_roughness_texture.is_some() || emissive_texture.is_some() {
+            // Color textures are sRGB, roughness is in the green channel and metalness in the blue one
+            Material::Textured {
+                base_color: scaled_texture(base_texture, base, srgb_color_to_linear),
+                metallic_roughness: scaled_texture(metallic_roughness_texture, Vec3::new(0.0, roughness, metallic), |p| p),
+                emissive: scaled_texture(emissive_texture, emissive, srgb_color_to_linear),
+            }
+        } else if emits {
+            Material::DiffuseLight { emit: emissive }
+        } else if metallic >= 0.5 {
+            Material::Metal { albedo: base, fuzz: roughness.clamp(0.0, 1.0) }
+        } else {
+            Material::Lambertian { albedo: base }
+        };
+        let normal_texture = material.get("normalTexture");
+        Ok(match self.texture(normal_texture)? {
+            Some(image) => {
+                let strength = normal_texture.and_then(|t| t.get("scale")).and_then(Json::as_float).unwrap_or(1.0);
+                Material::Perturbed {
+                    base: Box::new(mapped),
+                    normal_map: NormalMap::TangentSpace { texture: Texture::Image(image), strength: strength.clamp(0.0, 1.0) },
+                }
+            }
+            None => mapped,
+        })
+    }
+
+    /// Transform of a node relative to its parent, from its matrix or its translation, rotation and
+    /// scale. It may not be invertible, like a zero scale used to hide a node.
+    fn local_matrix(node: &Json) -> Mat4 {
+        if let Some(m) = node.get("matrix").and_then(Json::as_float_array).filter(|m| m.len() == 16) {
+            // Stored column by column
+            return Mat4 { m: std::array::from_fn(|row| std::array::from_fn(|column| m[column * 4 + row])) };
+        }
+        let array = |name: &str| node.get(name).and_then(Json::as_float_array);
+        let translation = array("translation").and_then(|t| vec3(&t)).unwrap_or(Vec3::new(0.0, 0.0, 0.0));
+        let scale = array("scale").and_then(|s| vec3(&s)).unwrap_or(Vec3::new(1.0, 1.0, 1.0));
+        let rotation = match array("rotation").as_deref() {
+            Some([x, y, z, w]) => Quaternion::new(*w, Vec3::new(*x, *y, *z)),
+            _ => Quaternion::new(1.0, Vec3::new(0.0, 0.0, 0.0)),
+        };
+        Mat4::translation(translation) * Mat4::from(rotation) * Mat4::scale(scale)
+    }
+
+    fn visit(&self, index: usize, parent: &Mat4, meshes: &[Vec<Arc<dyn Hitable>>], scene: &mut GltfScene, depth: usize) -> io::Result<()> {
+        // The hierarchy must be a tree, so anything this deep has a cycle
+        if depth > 256 {
+            return Err(invalid_data("node hierarchy is too deep or has a cycle".to_string()));
+        }
+        let node = self.elements("nodes").get(index).ok_or_else(|| invalid_data(format!("no node {}", index)))?;
+        let transform = *parent * Document::local_matrix(node);
+        // Nothing below a node scaled to nothing, or with a broken matrix, can be placed
+        let collapsed = (0..3).all(|i| (0..3).all(|j| transform.m[i][j] == 0.0));
+        if collapsed || transform.m.iter().flatten().any(|x| !x.is_finite()) {
+            scene.warnings.push(format!("skipped node {} and its children, whose transform leaves nothing to show", index));
+            return Ok(());
+        }
+        if let Some(mesh) = node.get("mesh").and_then(Json::as_usize) {
+            let primitives = meshes.get(mesh).ok_or_else(|| invalid_data(format!("no mesh {}", mesh)))?;
+            match Transform::new(transform) {
+                Some(transform) => {
+                    for primitive in primitives {
+                        scene.objects.list.push(Box::new(Instance::new(primitive.clone(), transform)));
+                    }
+                }
+                // Flattened onto a plane or a line, which rays cannot be transformed back from
+                None => scene.warnings.push(format!("skipped the mesh of node {}, whose transform cannot be inverted", index)),
+            }
+        }
+        if let Some(camera) = node.get("camera").and_then(Json::as_usize) {
+            let camera = self.elements("cameras").get(camera).ok_or_else(|| invalid_data(format!("no camera {}", camera)))?;
+            // Orthographic cameras have no counterpart in `Camera` and are left out
+            if let Some(perspective) = camera.get("perspective") {
+                let mut builder = Camera::builder()
+                    .lookfrom(transform.transform_point(&Point3::new(0.0, 0.0, 0.0)))
+                    .lookat(transform.transform_point(&Point3::new(0.0, 0.0, -1.0)))
+                    .vup(transform.transform_vector(&Vec3::new(0.0, 1.0, 0.0)))
+                    .vfov(perspective.get("yfov").and_then(Json::as_float).unwrap_or(0.8).to_degrees());
+                if let Some(aspect_ratio) = perspective.get("aspectRatio").and_then(Json::as_float) {
+                    builder = builder.aspect_ratio(aspect_ratio);
+                }
+                scene.cameras.push(builder);
+            }
+        }
+        for child in node.get("children").map_or(&[][..], Json::elements) {
+            let child = child.as_usize().ok_or_else(|| invalid_data("bad child index".to_string()))?;
+            self.visit(child, &transform, meshes, scene, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::Ray;
+
+    fn base64_encode(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        bytes.chunks(3).flat_map(|c| {
+            let bits = (c[0] as u32) << 16 | (*c.get(1).unwrap_or(&0) as u32) << 8 | *c.get(2).unwrap_or(&0) as u32;
+            (0..4).map(move |k| if k <= c.len() { ALPHABET[(bits >> (18 - 6 * k) & 63) as usize] as char } else { '=' })
+        }).collect()
+    }
+
+    #[test]
+    fn embedded_triangle_with_node_and_camera () {
+        // One triangle in the z = 0 plane, as three floats per vertex followed by u16 indices
+        let mut buffer = Vec::new();
+        for c in [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            buffer.extend_from_slice(&c.to_le_bytes());
+        }
+        for i in [0u16, 1, 2] {
+            buffer.extend_from_slice(&i.to_le_bytes());
+        }
+        let encoded = base64_encode(&buffer);
+        assert_eq!(base64_decode(&encoded).unwrap(), buffer);
+
+        let gltf = format!(r#"{{
+            "asset": {{"version": "2.0"}},
+            "scene": 0,
+            "scenes": [{{"nodes": [0, 2]}}],
+            "nodes": [
+                {{"translation": [0, 0, -5], "children": [1]}},
+                {{"mesh": 0, "scale": [2, 2, 2]}},
+                {{"camera": 0, "translation": [0.5, 0.5, 1], "rotation": [0, 0, 0, 1]}}
+            ],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}, "indices": 1, "material": 0}}]}}],
+            "materials": [{{"pbrMetallicRoughness": {{"baseColorFactor": [0.8, 0.2, 0.1, 1], "metallicFactor": 0}}}}],
+            "cameras": [{{"type": "perspective", "perspective": {{"yfov": 0.5, "aspectRatio": 1.5, "znear": 0.1}}}}],
+            "buffers": [{{"byteLength": 42, "uri": "data:application/octet-stream;base64,{}"}}],
+            "bufferViews": [{{"buffer": 0, "byteLength": 36}}, {{"buffer": 0, "byteOffset": 36, "byteLength": 6}}],
+            "accessors": [
+                {{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"}},
+                {{"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}}
+            ]
+        }}"#, encoded);
+        let scene = GltfScene::parse(gltf.as_bytes(), Path::new("")).unwrap();
+        assert_eq!((scene.objects.list.len(), scene.cameras.len()), (1, 1));
+
+        // The triangle is scaled by two and moved back by five
+        let hit = scene.objects.hit(&Ray::new(Vec3::new(1.5, 0.3, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, Float::MAX).unwrap();
+        assert!((hit.t - 5.0).abs() < 1e-4);
+        assert!(hit.normal.z() > 0.99);
+        match hit.material {
+            Material::Lambertian { albedo } => assert!((albedo - Vec3::new(0.8, 0.2, 0.1)).length() < 1e-6),
+            _ => panic!("expected a diffuse material"),
+        }
+        assert!(scene.objects.hit(&Ray::new(Vec3::new(2.5, 0.3, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, Float::MAX).is_none());
+        assert!(scene.cameras[0].clone().build().is_ok());
+    }
+
+    #[test]
+    fn flattened_nodes_and_oversized_accessors () {
+        let mut buffer = Vec::new();
+        for c in [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            buffer.extend_from_slice(&c.to_le_bytes());
+        }
+        let gltf = |nodes: &str, count: &str| format!(r#"{{
+            "asset": {{"version": "2.0"}},
+            "nodes": [{}],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}}}]}}],
+            "cameras": [{{"type": "perspective", "perspective": {{"yfov": 0.5, "znear": 0.1}}}}],
+            "buffers": [{{"byteLength": 36, "uri": "data:application/octet-stream;base64,{}"}}],
+            "bufferViews": [{{"buffer": 0, "byteLength": 36}}],
+            "accessors": [{{"bufferView": 0, "componentType": 5126, "count": {}, "type": "VEC3"}}]
+        }}"#, nodes, base64_encode(&buffer), count);
+        // A mesh flattened onto a plane is dropped, but the camera below it is kept. Nothing
+        // below a zero scale is.
+        let nodes = r#"
+            {"mesh": 0, "scale": [0, 1, 1], "children": [1]},
+            {"camera": 0},
+            {"scale": [0, 0, 0], "children": [3]},
+            {"camera": 0},
+            {"mesh": 0}"#;
+        let scene = GltfScene::parse(gltf(nodes, "3").as_bytes(), Path::new("")).unwrap();
+        assert_eq!((scene.objects.list.len(), scene.cameras.len(), scene.warnings.len()), (1, 1, 2));
+        // Counts beyond the buffer view are rejected before allocating for them
+        let nodes = r#"{"mesh": 0}"#;
+        assert!(GltfScene::parse(gltf(nodes, "4").as_bytes(), Path::new("")).is_err());
+        assert!(GltfScene::parse(gltf(nodes, "99999999999999999").as_bytes(), Path::new("")).is_err());
+        let without_view = gltf(nodes, "99999999999").replace(r#""bufferView": 0, "#, "");
+        assert!(GltfScene::parse(without_view.as_bytes(), Path::new("")).is_err());
+    }
+
+    #[test]
+    fn textures_follow_the_texture_coordinates () {
+        // The same triangle with texture coordinates matching x and y, then u16 indices
+        let mut buffer = Vec::new();
+        for c in [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 0.0] {
+            buffer.extend_from_slice(&c.to_le_bytes());
+        }
+        for i in [0u16, 1, 2] {
+            buffer.extend_from_slice(&i.to_le_bytes());
+        }
+        // Red on the left half and blue on the right, and a JPEG that cannot be decoded
+        let ppm = base64_encode(b"P3 2 1 255 255 0 0 0 0 255");
+        let jpeg = base64_encode(&[0xff, 0xd8, 0xff, 0xe0, 0, 16]);
+        let gltf = format!(r#"{{
+            "asset": {{"version": "2.0"}},
+            "nodes": [{{"mesh": 0, "translation": [0, 0, -2]}}],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0, "TEXCOORD_0": 1}}, "indices": 2, "material": 0}}]}}],
+            "materials": [{{
+                "pbrMetallicRoughness": {{"baseColorTexture": {{"index": 0}}, "baseColorFactor": [0.5, 1, 1, 1], "metallicFactor": 0}},
+                "emissiveTexture": {{"index": 1}}
+            }}],
+            "textures": [{{"source": 0}}, {{"source": 1}}],
+            "images": [
+                {{"uri": "data:image/x-portable-pixmap;base64,{}"}},
+                {{"uri": "data:image/jpeg;base64,{}"}}
+            ],
+            "buffers": [{{"byteLength": 66, "uri": "data:application/octet-stream;base64,{}"}}],
+            "bufferViews": [
+                {{"buffer": 0, "byteLength": 36}},
+                {{"buffer": 0, "byteOffset": 36, "byteLength": 24}},
+                {{"buffer": 0, "byteOffset": 60, "byteLength": 6}}
+            ],
+            "accessors": [
+                {{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"}},
+                {{"bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC2"}},
+                {{"bufferView": 2, "componentType": 5123, "count": 3, "type": "SCALAR"}}
+            ]
+        }}"#, ppm, jpeg, base64_encode(&buffer));
+        let scene = GltfScene::parse(gltf.as_bytes(), Path::new("")).unwrap();
+        assert_eq!(scene.warnings.len(), 1);
+
+        // Texel centers, where the color is not blended with the other half
+        let color_at = |x: Float| {
+            let hit = scene.objects.hit(&Ray::new(Vec3::new(x, 0.1, 0.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, Float::MAX).unwrap();
+            assert!((hit.t - 2.0).abs() < 1e-4);
+            match hit.material.at(hit.u, hit.v) {
+                Material::Lambertian { albedo } => albedo,
+                _ => panic!("expected a diffuse material"),
+            }
+        };
+        assert!((color_at(0.25) - Vec3::new(0.5, 0.0, 0.0)).length() < 1e-4);
+        assert!((color_at(0.75) - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-4);
+    }
+}
@@ -0,0 +1,313 @@
+//! Scattering from hair fibers, after d'Eon et al. (2011) and Chiang et al. (2016).
+//!
+//! A fiber is a rough dielectric cylinder with tilted cuticle scales and a pigmented interior.
+//! Light is reflected (R), transmitted twice (TT), reflected inside once (TRT), with the rest lumped
+//! into one last term. Directions are expressed in a frame with x along the fiber.
+
+use crate::float::{Float, consts::{LN_2, PI}};
+use crate::vec::Vec3;
+
+// Number of explicitly modeled scattering paths; the last term covers all longer ones
+const P_MAX: usize = 3;
+
+// Absorption per unit distance (relative to the fiber radius) of the two kinds of melanin
+const EUMELANIN_SIGMA_A: (Float, Float, Float) = (0.419, 0.697, 1.37);
+const PHEOMELANIN_SIGMA_A: (Float, Float, Float) = (0.187, 0.4, 1.05);
+
+/// Absorption of a fiber with the given concentrations of the dark brown eumelanin and the reddish
+/// pheomelanin. About 8 is black hair, 1.3 brown and 0.3 blonde.
+pub fn sigma_a_from_melanin(eumelanin: Float, pheomelanin: Float) -> Vec3 {
+    Vec3::new(
+        eumelanin * EUMELANIN_SIGMA_A.0 + pheomelanin * PHEOMELANIN_SIGMA_A.0,
+        eumelanin * EUMELANIN_SIGMA_A.1 + pheomelanin * PHEOMELANIN_SIGMA_A.1,
+        eumelanin * EUMELANIN_SIGMA_A.2 + pheomelanin * PHEOMELANIN_SIGMA_A.2,
+    )
+}
+
+/// Absorption that gives hair of roughly the `color` seen from afar, for azimuthal roughness
+/// `beta_n`.
+pub fn sigma_a_from_color(color: Vec3, beta_n: Float) -> Vec3 {
+    let denominator = 5.969 - 0.215 * beta_n + 2.532 * beta_n.powi(2) - 10.73 * beta_n.powi(3)
+        + 5.574 * beta_n.powi(4) + 0.245 * beta_n.powi(5);
+    let channel = |c: Float| (c.max(1e-4).ln() / denominator).powi(2);
+    Vec3::new(channel(color.r()), channel(color.g()), channel(color.b()))
+}
+
+/// The hair BSDF at one point across a fiber.
+pub struct HairBsdf {
+    // Offset across the fiber, from -1 to 1, and the matching angle inside the circle
+    h: Float,
+    gamma_o: Float,
+    eta: Float,
+    sigma_a: Vec3,
+    // Longitudinal variance of each path, and the azimuthal logistic scale
+    v: [Float; P_MAX + 1],
+    s: Float,
+    // sin and cos of 2^k times the scale tilt, for k = 0, 1, 2
+    sin_2k_alpha: [Float; 3],
+    cos_2k_alpha: [Float; 3],
+}
+
+impl HairBsdf {
+    /// `beta_m` and `beta_n` are the longitudinal and azimuthal roughness in [0, 1], and `alpha`
+    /// the tilt of the cuticle scales in degrees.
+    pub fn new(h: Float, eta: Float, sigma_a: Vec3, beta_m: Float, beta_n: Float, alpha: Float) -> HairBsdf {
+        let h = h.clamp(-1.0, 1.0);
+        let v0 = (0.726 * beta_m + 0.812 * beta_m * beta_m + 3.7 * beta_m.powi(20)).powi(2);
+        let mut v = [4.0 * v0; P_MAX + 1];
+        v[0] = v0;
+        v[1] = 0.25 * v0;
+        let s = (PI / 8.0).sqrt() * (0.265 * beta_n + 1.194 * beta_n * beta_n + 5.372 * beta_n.powi(22));
+        let mut sin_2k_alpha = [alpha.to_radians().sin(), 0.0, 0.0];
+        let mut cos_2k_alpha = [safe_sqrt(1.0 - sin_2k_alpha[0] * sin_2k_alpha[0]), 0.0, 0.0];
+        for i in 1..3 {
+            sin_2k_alpha[i] = 2.0 * cos_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+            cos_2k_alpha[i] = cos_2k_alpha[i - 1].powi(2) - sin_2k_alpha[i - 1].powi(2);
+        }
+        HairBsdf { h, gamma_o: h.asin(), eta, sigma_a, v, s, sin_2k_alpha, cos_2k_alpha }
+    }
+
+    /// Angle of the path inside the fiber, and the transmittance along one pass through it.
+    fn refracted(&self, sin_theta_o: Float, cos_theta_o: Float) -> (Float, Vec3) {
+        let sin_theta_t = sin_theta_o / self.eta;
+        let cos_theta_t = safe_sqrt(1.0 - sin_theta_t * sin_theta_t);
+        // Modified index of refraction for the projection onto the normal plane
+        let etap = (self.eta * self.eta - sin_theta_o * sin_theta_o).sqrt() / cos_theta_o;
+        let sin_gamma_t = (self.h / etap).clamp(-1.0, 1.0);
+        let cos_gamma_t = safe_sqrt(1.0 - sin_gamma_t * sin_gamma_t);
+        let transmittance = (-2.0 * cos_gamma_t / cos_theta_t * self.sigma_a).exp();
+        (sin_gamma_t.asin(), transmittance)
+    }
+
+    /// Attenuation of each path.
+    fn ap(&self, cos_theta_o: Float, transmittance: Vec3) -> [Vec3; P_MAX + 1] {
+        let cos_gamma_o = safe_sqrt(1.0 - self.h * self.h);
+        let f = fresnel_dielectric(cos_theta_o * cos_gamma_o, self.eta);
+        let mut ap = [Vec3::new(f, f, f); P_MAX + 1];
+        ap[1] = (1.0 - f) * (1.0 - f) * transmittance;
+        for p in 2..P_MAX {
+            ap[p] = f * ap[p - 1] * transmittance;
+        }
+        let tf = f * transmittance;
+        ap[P_MAX] = ap[P_MAX - 1] * tf / (Vec3::new(1.0, 1.0, 1.0) - tf);
+        ap
+    }
+
+    /// Probability of sampling each path, proportional to its attenuation.
+    fn ap_pdf(&self, cos_theta_o: Float) -> [Float; P_MAX + 1] {
+        let sin_theta_o = safe_sqrt(1.0 - cos_theta_o * cos_theta_o);
+        let (_, transmittance) = self.refracted(sin_theta_o, cos_theta_o);
+        let ap = self.ap(cos_theta_o, transmittance);
+        let weights = ap.map(|a| (a.r() + a.g() + a.b()) / 3.0);
+        let sum: Float = weights.iter().sum();
+        weights.map(|w| if sum > 0.0 { w / sum } else { 1.0 / (P_MAX + 1) as Float })
+    }
+
+    /// The outgoing elevation tilted by the cuticle scales for path `p`.
+    fn tilted(&self, p: usize, sin_theta_o: Float, cos_theta_o: Float) -> (Float, Float) {
+        let (sin_theta_op, cos_theta_op) = match p {
+            0 => (sin_theta_o * self.cos_2k_alpha[1] - cos_theta_o * self.sin_2k_alpha[1],
+                  cos_theta_o * self.cos_2k_alpha[1] + sin_theta_o * self.sin_2k_alpha[1]),
+            1 => (sin_theta_o * self.cos_2k_alpha[0] + cos_theta_o * self.sin_2k_alpha[0],
+                  cos_theta_o * self.cos_2k_alpha[0] - sin_theta_o * self.sin_2k_alpha[0]),
+            2 => (sin_theta_o * self.cos_2k_alpha[2] + cos_theta_o * self.sin_2k_alpha[2],
+                  cos_theta_o * self.cos_2k_alpha[2] - sin_theta_o * self.sin_2k_alpha[2]),
+            _ => (sin_theta_o, cos_theta_o),
+        };
+        (sin_theta_op, cos_theta_op.abs())
+    }
+
+    /// BSDF times the cosine to the surface normal (z), for light arriving from `wi` and leaving
+    /// towards `wo`, both unit vectors in the fiber frame.
+    pub fn f_cos(&self, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        let (sin_theta_o, cos_theta_o, phi_o) = angles(wo);
+        let (sin_theta_i, cos_theta_i, phi_i) = angles(wi);
+        let (gamma_t, transmittance) = self.refracted(sin_theta_o, cos_theta_o);
+        let ap = self.ap(cos_theta_o, transmittance);
+        let phi = phi_i - phi_o;
+        let mut sum = Vec3::new(0.0, 0.0, 0.0);
+        for (p, a) in ap.iter().enumerate().take(P_MAX) {
+            let (sin_theta_op, cos_theta_op) = self.tilted(p, sin_theta_o, cos_theta_o);
+            let m = mp(cos_theta_i, cos_theta_op, sin_theta_i, sin_theta_op, self.v[p]);
+            sum += m * np(phi, p, self.s, self.gamma_o, gamma_t) * *a;
+        }
+        sum += mp(cos_theta_i, cos_theta_o, sin_theta_i, sin_theta_o, self.v[P_MAX]) / (2.0 * PI) * ap[P_MAX];
+        sum
+    }
+
+    pub fn pdf(&self, wo: &Vec3, wi: &Vec3) -> Float {
+        let (sin_theta_o, cos_theta_o, phi_o) = angles(wo);
+        let (sin_theta_i, cos_theta_i, phi_i) = angles(wi);
+        let (gamma_t, _) = self.refracted(sin_theta_o, cos_theta_o);
+        let ap_pdf = self.ap_pdf(cos_theta_o);
+        let phi = phi_i - phi_o;
+        let mut pdf = 0.0;
+        for (p, a) in ap_pdf.iter().enumerate().take(P_MAX) {
+            let (sin_theta_op, cos_theta_op) = self.tilted(p, sin_theta_o, cos_theta_o);
+            let m = mp(cos_theta_i, cos_theta_op, sin_theta_i, sin_theta_op, self.v[p]);
+            pdf += m * a * np(phi, p, self.s, self.gamma_o, gamma_t);
+        }
+        pdf + mp(cos_theta_i, cos_theta_o, sin_theta_i, sin_theta_o, self.v[P_MAX]) * ap_pdf[P_MAX] / (2.0 * PI)
+    }
+
+    /// Sample the incoming direction for outgoing `wo` from four uniform numbers. Returns the
+    /// direction, the BSDF times the cosine and the pdf.
+    pub fn sample(&self, wo: &Vec3, u: [Float; 4]) -> (Vec3, Vec3, Float) {
+        let (sin_theta_o, cos_theta_o, phi_o) = angles(wo);
+        // Pick a path in proportion to how much light it carries
+        let ap_pdf = self.ap_pdf(cos_theta_o);
+        let mut pick = u[0];
+        let mut p = 0;
+        while p < P_MAX && pick >= ap_pdf[p] {
+            pick -= ap_pdf[p];
+            p += 1;
+        }
+        let (sin_theta_op, cos_theta_op) = self.tilted(p, sin_theta_o, cos_theta_o);
+
+        // Longitudinal angle from M_p
+        let u1 = u[1].max(1e-5);
+        let v = self.v[p];
+        let cos_theta = 1.0 + v * (u1 + (1.0 - u1) * (-2.0 / v).exp()).ln();
+        let sin_theta = safe_sqrt(1.0 - cos_theta * cos_theta);
+        let cos_phi = (2.0 * PI * u[2]).cos();
+        let sin_theta_i = (-cos_theta * sin_theta_op + sin_theta * cos_phi * cos_theta_op).clamp(-1.0, 1.0);
+        let cos_theta_i = safe_sqrt(1.0 - sin_theta_i * sin_theta_i);
+
+        // Azimuthal angle from N_p
+        let (gamma_t, _) = self.refracted(sin_theta_o, cos_theta_o);
+        let dphi = if p < P_MAX {
+            phi(p, self.gamma_o, gamma_t) + sample_trimmed_logistic(u[3], self.s, -PI, PI)
+        } else {
+            2.0 * PI * u[3]
+        };
+        let phi_i = phi_o + dphi;
+        let wi = Vec3::new(sin_theta_i, cos_theta_i * phi_i.cos(), cos_theta_i * phi_i.sin());
+        (wi, self.f_cos(wo, &wi), self.pdf(wo, &wi))
+    }
+}
+
+/// sin θ, cos θ and φ of a direction in the fiber frame, with θ measured from the normal plane.
+fn angles(w: &Vec3) -> (Float, Float, Float) {
+    let sin_theta = w.x().clamp(-1.0, 1.0);
+    (sin_theta, safe_sqrt(1.0 - sin_theta * sin_theta), w.y().atan2(w.z()))
+}
+
+fn safe_sqrt(x: Float) -> Float {
+    x.max(0.0).sqrt()
+}
+
+/// Fresnel reflectance of unpolarized light arriving at `cos_theta_i` from outside a dielectric.
+fn fresnel_dielectric(cos_theta_i: Float, eta: Float) -> Float {
+    let (cos_theta_i, eta) = if cos_theta_i < 0.0 { (-cos_theta_i, 1.0 / eta) } else { (cos_theta_i, eta) };
+    let sin_theta_t = safe_sqrt(1.0 - cos_theta_i * cos_theta_i) / eta;
+    if sin_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_t = safe_sqrt(1.0 - sin_theta_t * sin_theta_t);
+    let parallel = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let perpendicular = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+/// Modified Bessel function of the first kind, of order zero.
+fn i0(x: Float) -> Float {
+    let (mut value, mut x2i, mut factorial, mut four_i) = (0.0, 1.0, 1.0, 1.0);
+    for i in 0..10 {
+        if i > 1 {
+            factorial *= i as Float;
+        }
+        value += x2i / (four_i * factorial * factorial);
+        x2i *= x * x;
+        four_i *= 4.0;
+    }
+    value
+}
+
+fn log_i0(x: Float) -> Float {
+    if x > 12.0 {
+        x + 0.5 * (-(2.0 * PI).ln() + (1.0 / x).ln() + 1.0 / (8.0 * x))
+    } else {
+        i0(x).ln()
+    }
+}
+
+/// Longitudinal scattering function with variance `v`.
+fn mp(cos_theta_i: Float, cos_theta_o: Float, sin_theta_i: Float, sin_theta_o: Float, v: Float) -> Float {
+    let a = cos_theta_i * cos_theta_o / v;
+    let b = sin_theta_i * sin_theta_o / v;
+    if v <= 0.1 {
+        // Low roughness overflows the direct form
+        (log_i0(a) - b - 1.0 / v + LN_2 + (1.0 / (2.0 * v)).ln()).exp()
+    } else {
+        (-b).exp() * i0(a) / ((1.0 / v).sinh() * 2.0 * v)
+    }
+}
+
+/// Azimuthal direction of the center of path `p`.
+fn phi(p: usize, gamma_o: Float, gamma_t: Float) -> Float {
+    let p = p as Float;
+    2.0 * p * gamma_t - 2.0 * gamma_o + p * PI
+}
+
+fn logistic(x: Float, s: Float) -> Float {
+    let e = (-x.abs() / s).exp();
+    e / (s * (1.0 + e) * (1.0 + e))
+}
+
+fn logistic_cdf(x: Float, s: Float) -> Float {
+    1.0 / (1.0 + (-x / s).exp())
+}
+
+fn trimmed_logistic(x: Float, s: Float, a: Float, b: Float) -> Float {
+    logistic(x, s) / (logistic_cdf(b, s) - logistic_cdf(a, s))
+}
+
+fn sample_trimmed_logistic(u: Float, s: Float, a: Float, b: Float) -> Float {
+    let k = logistic_cdf(b, s) - logistic_cdf(a, s);
+    let x = -s * (1.0 / (u * k + logistic_cdf(a, s)) - 1.0).ln();
+    x.clamp(a, b)
+}
+
+/// Azimuthal scattering function of path `p`.
+fn np(phi_difference: Float, p: usize, s: Float, gamma_o: Float, gamma_t: Float) -> Float {
+    let mut dphi = phi_difference - phi(p, gamma_o, gamma_t);
+    while dphi > PI {
+        dphi -= 2.0 * PI;
+    }
+    while dphi < -PI {
+        dphi += 2.0 * PI;
+    }
+    trimmed_logistic(dphi, s, -PI, PI)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    #[test]
+    fn white_hair_conserves_energy_and_sampling_matches_pdf () {
+        let mut rng = StdRng::seed_from_u64(7);
+        for &(beta_m, beta_n) in &[(0.2, 0.3), (0.5, 0.5), (0.8, 0.9)] {
+            let mut sum = 0.0;
+            let count = 20000;
+            for _ in 0..count {
+                let hair = HairBsdf::new(rng.gen_range(-1.0..1.0), 1.55, Vec3::new(0.0, 0.0, 0.0), beta_m, beta_n, 2.0);
+                let wo = Vec3::random_range(&mut rng, -1.0, 1.0).unit_vector();
+                let (wi, f_cos, pdf) = hair.sample(&wo, [rng.gen(), rng.gen(), rng.gen(), rng.gen()]);
+                assert!((wi.length() - 1.0).abs() < 1e-4);
+                if pdf > 0.0 {
+                    assert!((hair.pdf(&wo, &wi) - pdf).abs() <= 1e-3 * pdf.max(1.0));
+                    sum += f_cos.g() / pdf;
+                }
+            }
+            // Without absorption every bit of light is scattered somewhere
+            let average = sum / count as Float;
+            assert!((average - 1.0).abs() < 0.05, "beta_m {} beta_n {}: {}", beta_m, beta_n, average);
+        }
+        // Melanin darkens the red channel least
+        let brown = sigma_a_from_melanin(1.3, 0.0);
+        assert!(brown.r() < brown.g() && brown.g() < brown.b());
+        let red = sigma_a_from_color(Vec3::new(0.6, 0.2, 0.1), 0.3);
+        assert!(red.r() < red.g() && red.g() < red.b());
+    }
+}
@@ -0,0 +1,384 @@
+//! Terrain from a regular grid of elevations. Rays find the surface by descending a quadtree of
+//! the minimum and maximum height in each block of cells, so only the few cells near the ray are
+//! tested, and inside a cell the surface is the bilinear patch through its four corners.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::float::Float;
+use crate::aabb::Aabb;
+use crate::hitable::{HitRecord, Hitable, any_perpendicular};
+use crate::material::Material;
+use crate::ray::{Ray, gamma};
+use crate::roots::solve_quadratic;
+use crate::texture::ImageTexture;
+use crate::vec::{Vec3, Point3};
+
+/// Elevations sampled on a regular grid, row by row from the north. Samples without data are NaN.
+#[derive(Debug, Clone)]
+pub struct ElevationGrid {
+    pub columns: usize,
+    pub rows: usize,
+    // Distance between samples along x (east) and z (south), in world units
+    pub cell_size: (Float, Float),
+    pub heights: Vec<Float>,
+}
+
+impl ElevationGrid {
+    /// Heights from the brightness of a grayscale image, from 0 for black to `vertical_scale` for
+    /// white.
+    pub fn from_image(image: &ImageTexture, cell_size: (Float, Float), vertical_scale: Float) -> ElevationGrid {
+        let heights = image.pixels.iter().map(|c| vertical_scale * (c.r() + c.g() + c.b()) / 3.0).collect();
+        ElevationGrid { columns: image.width, rows: image.height, cell_size, heights }
+    }
+
+    /// Load an ESRI ASCII grid (`.asc`) digital elevation model.
+    pub fn load_asc<P: AsRef<Path>>(path: P) -> io::Result<ElevationGrid> {
+        ElevationGrid::parse_asc(&fs::read_to_string(path)?)
+    }
+
+    /// Parse an ESRI ASCII grid: a header of `ncols`, `nrows`, the lower left corner, `cellsize`
+    /// (or separate `dx` and `dy`) and an optional `NODATA_value`, then the rows from the north.
+    /// The position of the corner is not kept, the grid starts at the origin.
+    pub fn parse_asc(text: &str) -> io::Result<ElevationGrid> {
+        let mut words = text.split_whitespace().peekable();
+        let (mut columns, mut rows, mut cell_size, mut no_data) = (None, None, (None, None), None);
+        while let Some(key) = words.peek().filter(|w| w.starts_with(|c: char| c.is_ascii_alphabetic())) {
+            let key = key.to_ascii_lowercase();
+            words.next();
+            let value = words.next().ok_or_else(|| invalid_data(format!("missing value for {}", key)))?;
+            let number: Float = value.parse().map_err(|_| invalid_data(format!("bad value '{}' for {}", value, key)))?;
+            match key.as_str() {
+                "ncols" => columns = Some(number as usize),
+                "nrows" => rows = Some(number as usize),
+                "cellsize" => cell_size = (Some(number), Some(number)),
+                "dx" => cell_size.0 = Some(number),
+                "dy" => cell_size.1 = Some(number),
+                "nodata_value" => no_data = Some(number),
+                // xllcorner, yllcorner, xllcenter, yllcenter
+                _ => {}
+            }
+        }
+        let missing = |what: &str| invalid_data(format!("grid header without {}", what));
+        let columns = columns.ok_or_else(|| missing("ncols"))?;
+        let rows = rows.ok_or_else(|| missing("nrows"))?;
+        let cell_size = (cell_size.0.ok_or_else(|| missing("cellsize"))?, cell_size.1.ok_or_else(|| missing("cellsize"))?);
+        if columns < 2 || rows < 2 {
+            return Err(invalid_data(format!("a {}x{} grid has no cells", columns, rows)));
+        }
+        let heights = words
+            .map(|w| match w.parse::<Float>() {
+                Ok(h) if Some(h) == no_data => Ok(Float::NAN),
+                Ok(h) => Ok(h),
+                Err(_) => Err(invalid_data(format!("bad elevation '{}'", w))),
+            })
+            .collect::<io::Result<Vec<Float>>>()?;
+        if heights.len() != columns * rows {
+            return Err(invalid_data(format!("expected {} elevations, found {}", columns * rows, heights.len())));
+        }
+        Ok(ElevationGrid { columns, rows, cell_size, heights })
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Lowest and highest height in each block of cells at one level of the quadtree. Blocks with
+/// missing data in every cell are empty, with a minimum above the maximum.
+struct MinMaxLevel {
+    columns: usize,
+    rows: usize,
+    ranges: Vec<(Float, Float)>,
+}
+
+/// Terrain surface over an elevation grid, with the first sample at the origin, x to the east and
+/// z to the south. Cells with a missing corner are holes. The normals are interpolated from the
+/// slopes at the samples, and (u, v) run over the grid with v = 1 on the first row, so that an
+/// image of the same area drapes over it the right way up.
+pub struct Heightfield {
+    grid: ElevationGrid,
+    // Unit normal at every sample
+    normals: Vec<Vec3>,
+    // Level 0 has one block per cell, each level above halves both counts
+    levels: Vec<MinMaxLevel>,
+    pub material: Material,
+}
+
+impl Heightfield {
+    /// Panics if the grid has fewer than two samples in either direction or the number of heights
+    /// does not match its size.
+    pub fn new(grid: ElevationGrid, material: Material) -> Heightfield {
+        assert!(grid.columns >= 2 && grid.rows >= 2, "a heightfield needs at least 2x2 samples");
+        assert_eq!(grid.heights.len(), grid.columns * grid.rows, "elevation count does not match the grid size");
+        let normals = sample_normals(&grid);
+        let levels = min_max_levels(&grid);
+        Heightfield { grid, normals, levels, material }
+    }
+
+    fn height(&self, column: usize, row: usize) -> Float {
+        self.grid.heights[row * self.grid.columns + column]
+    }
+
+    /// Ray parameter where the ray passes through a block of the quadtree, if it does before
+    /// `t_max`. The ends of every slab are pushed out by their rounding error: the slab of a flat
+    /// block has no thickness, and without the slack a ray could slip between two blocks or find
+    /// the surface a rounding error outside the block.
+    fn block_interval(&self, level: usize, column: usize, row: usize, r: &Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        let blocks = &self.levels[level];
+        let (low, high) = blocks.ranges[row * blocks.columns + column];
+        if low > high {
+            return None;
+        }
+        let (cx, cz) = self.grid.cell_size;
+        let size = (1 << level) as Float;
+        let min = Point3::new(column as Float * size * cx, low, row as Float * size * cz);
+        let max = Point3::new(
+            ((column + 1) << level).min(self.grid.columns - 1) as Float * cx,
+            high,
+            ((row + 1) << level).min(self.grid.rows - 1) as Float * cz,
+        );
+        let (mut enter, mut exit) = (t_min, t_max);
+        for axis in 0..3 {
+            let inv_d = 1.0 / r.direction.e[axis];
+            let mut t0 = (min.e[axis] - r.origin.e[axis]) * inv_d;
+            let mut t1 = (max.e[axis] - r.origin.e[axis]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            // Scaled rather than offset, so that infinite ends stay infinite
+            let slack = 2.0 * gamma(3);
+            let t0 = if t0 > 0.0 { t0 * (1.0 - slack) } else { t0 * (1.0 + slack) };
+            let t1 = if t1 > 0.0 { t1 * (1.0 + slack) } else { t1 * (1.0 - slack) };
+            // Written so that NaN, from a ray in the plane of a slab, does not shrink the interval
+            enter = if t0 > enter { t0 } else { enter };
+            exit = if t1 < exit { t1 } else { exit };
+            if exit < enter {
+                return None;
+            }
+        }
+        Some((enter, exit))
+    }
+
+    /// Closest hit in a block and the blocks below it.
+    fn hit_block(&self, level: usize, column: usize, row: usize, r: &Ray, t_min: Float, t_max: Float) -> Option<(Float, usize, usize)> {
+        let (enter, exit) = self.block_interval(level, column, row, r, t_min, t_max)?;
+        if level == 0 {
+            return self.hit_cell(column, row, r, enter, exit).map(|t| (t, column, row));
+        }
+        // The children the ray passes through, nearest first
+        let below = &self.levels[level - 1];
+        let mut children = [(0.0, 0, 0); 4];
+        let mut count = 0;
+        for (dc, dr) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let (c, rw) = (2 * column + dc, 2 * row + dr);
+            if c < below.columns && rw < below.rows {
+                if let Some((child_enter, _)) = self.block_interval(level - 1, c, rw, r, t_min, t_max) {
+                    children[count] = (child_enter, c, rw);
+                    count += 1;
+                }
+            }
+        }
+        children[..count].sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut closest: Option<(Float, usize, usize)> = None;
+        for &(child_enter, c, rw) in &children[..count] {
+            let limit = closest.map_or(t_max, |hit| hit.0);
+            if child_enter >= limit {
+                break;
+            }
+            if let Some(hit) = self.hit_block(level - 1, c, rw, r, t_min, limit) {
+                closest = Some(hit);
+            }
+        }
+        closest
+    }
+
+    /// First hit with the bilinear patch of a cell between `enter` and `exit`.
+    fn hit_cell(&self, column: usize, row: usize, r: &Ray, enter: Float, exit: Float) -> Option<Float> {
+        let (cx, cz) = self.grid.cell_size;
+        let h00 = self.height(column, row) as f64;
+        let h10 = self.height(column + 1, row) as f64;
+        let h01 = self.height(column, row + 1) as f64;
+        let h11 = self.height(column + 1, row + 1) as f64;
+        // Height over the cell is a + b·fx + c·fz + d·fx·fz, with fx and fz from 0 to 1 across it
+        let (a, b, c, d) = (h00, h10 - h00, h01 - h00, h00 - h10 - h01 + h11);
+        let px = (r.origin.x() / cx) as f64 - column as f64;
+        let pz = (r.origin.z() / cz) as f64 - row as f64;
+        let (qx, qz) = ((r.direction.x() / cx) as f64, (r.direction.z() / cz) as f64);
+        let (oy, dy) = (r.origin.y() as f64, r.direction.y() as f64);
+        // The ray height minus the surface height along the ray, as a polynomial in t
+        let c0 = oy - a - b * px - c * pz - d * px * pz;
+        let c1 = dy - b * qx - c * qz - d * (px * qz + qx * pz);
+        let c2 = -d * qx * qz;
+        let (t0, t1) = solve_quadratic(c0, c1, c2)?;
+        [t0, t1].iter().map(|&t| t as Float).find(|&t| t >= enter && t <= exit)
+    }
+}
+
+/// Unit normals at the samples from the slopes to their neighbours, one-sided at the edges and
+/// next to missing data.
+fn sample_normals(grid: &ElevationGrid) -> Vec<Vec3> {
+    let (cx, cz) = grid.cell_size;
+    let height = |column: usize, row: usize| grid.heights[row * grid.columns + column];
+    // Slope along one direction between the valid neighbours on either side
+    let slope = |h: Float, before: Option<Float>, after: Option<Float>, spacing: Float| {
+        match (before.filter(|b| !b.is_nan()), after.filter(|a| !a.is_nan())) {
+            (Some(b), Some(a)) => (a - b) / (2.0 * spacing),
+            (Some(b), None) => (h - b) / spacing,
+            (None, Some(a)) => (a - h) / spacing,
+            (None, None) => 0.0,
+        }
+    };
+    let mut normals = Vec::with_capacity(grid.heights.len());
+    for row in 0..grid.rows {
+        for column in 0..grid.columns {
+            let h = height(column, row);
+            let west = column.checked_sub(1).map(|c| height(c, row));
+            let east = (column + 1 < grid.columns).then(|| height(column + 1, row));
+            let north = row.checked_sub(1).map(|r| height(column, r));
+            let south = (row + 1 < grid.rows).then(|| height(column, row + 1));
+            let (dx, dz) = (slope(h, west, east, cx), slope(h, north, south, cz));
+            normals.push(Vec3::new(-dx, 1.0, -dz).unit_vector());
+        }
+    }
+    normals
+}
+
+fn min_max_levels(grid: &ElevationGrid) -> Vec<MinMaxLevel> {
+    let (columns, rows) = (grid.columns - 1, grid.rows - 1);
+    let height = |column: usize, row: usize| grid.heights[row * grid.columns + column];
+    let ranges = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .map(|(column, row)| {
+            let corners = [height(column, row), height(column + 1, row), height(column, row + 1), height(column + 1, row + 1)];
+            if corners.iter().any(|h| h.is_nan()) {
+                (Float::INFINITY, Float::NEG_INFINITY)
+            } else {
+                corners.iter().fold((Float::INFINITY, Float::NEG_INFINITY), |(low, high), &h| (low.min(h), high.max(h)))
+            }
+        })
+        .collect();
+    let mut levels = vec![MinMaxLevel { columns, rows, ranges }];
+    while let Some(last) = levels.last().filter(|l| l.columns > 1 || l.rows > 1) {
+        let (columns, rows) = (last.columns.div_ceil(2), last.rows.div_ceil(2));
+        let mut ranges = vec![(Float::INFINITY, Float::NEG_INFINITY); columns * rows];
+        for row in 0..last.rows {
+            for column in 0..last.columns {
+                let (low, high) = last.ranges[row * last.columns + column];
+                let parent = &mut ranges[(row / 2) * columns + column / 2];
+                *parent = (parent.0.min(low), parent.1.max(high));
+            }
+        }
+        levels.push(MinMaxLevel { columns, rows, ranges });
+    }
+    levels
+}
+
+impl Hitable for Heightfield {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (t, column, row) = self.hit_block(self.levels.len() - 1, 0, 0, r, t_min, t_max)?;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+        let (p, error) = r.point_with_error(t);
+        let (cx, cz) = self.grid.cell_size;
+        let fx = (p.x() / cx - column as Float).clamp(0.0, 1.0);
+        let fz = (p.z() / cz - row as Float).clamp(0.0, 1.0);
+        let n = |c: usize, rw: usize| self.normals[rw * self.grid.columns + c];
+        let normal = ((1.0 - fx) * (1.0 - fz) * n(column, row)
+            + fx * (1.0 - fz) * n(column + 1, row)
+            + (1.0 - fx) * fz * n(column, row + 1)
+            + fx * fz * n(column + 1, row + 1))
+            .unit_vector();
+        let east = Vec3::new(1.0, 0.0, 0.0) - normal.x() * normal;
+        let tangent = if east.squared_length() > 1e-12 { east.unit_vector() } else { any_perpendicular(&normal) };
+        let u = (column as Float + fx) / (self.grid.columns - 1) as Float;
+        let v = 1.0 - (row as Float + fz) / (self.grid.rows - 1) as Float;
+        Some(HitRecord { t, p, error, normal, u, v, tangent, on_edge: false, material: self.material.clone() })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let (low, high) = self.levels.last()?.ranges[0];
+        if low > high {
+            return None;
+        }
+        let (cx, cz) = self.grid.cell_size;
+        let far = Point3::new((self.grid.columns - 1) as Float * cx, high, (self.grid.rows - 1) as Float * cz);
+        Some(Aabb::new(Point3::new(0.0, low, 0.0), far))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn heightfield_matches_every_cell () {
+        let asc = "ncols 4\nnrows 3\nxllcorner 1000.0\nyllcorner 2000.0\ncellsize 10\nNODATA_value -9999\n\
+                   1 2 3 4\n2 3 -9999 5\n3 4 5 6\n";
+        let grid = ElevationGrid::parse_asc(asc).unwrap();
+        assert_eq!((grid.columns, grid.rows, grid.cell_size), (4, 3, (10.0, 10.0)));
+        assert!(grid.heights[6].is_nan());
+        assert!(ElevationGrid::parse_asc("ncols 2\nnrows 2\ncellsize 1\n1 2 3\n").is_err());
+
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        // A plane rising by 0.1 per unit east and 0.1 per unit south is its own bilinear patch
+        let plane = Heightfield::new(grid.clone(), material.clone());
+        let hit = plane.hit(&Ray::new(Point3::new(5.0, 100.0, 5.0), Vec3::new(0.0, -1.0, 0.0)), 0.001, Float::MAX).unwrap();
+        assert!((hit.p.y() - 2.0).abs() < 1e-4 && (hit.normal - Vec3::new(-0.1, 1.0, -0.1).unit_vector()).length() < 1e-4);
+        // The cells around the missing sample are holes
+        let hole = Ray::new(Point3::new(15.0, 100.0, 15.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(plane.hit(&hole, 0.001, Float::MAX).is_none());
+
+        // Rays through a larger bumpy grid find the same hits as testing every cell
+        let (columns, rows) = (37, 23);
+        let heights = (0..columns * rows).map(|i| ((i % columns) as Float * 0.7).sin() + ((i / columns) as Float * 0.4).cos()).collect();
+        let terrain = Heightfield::new(ElevationGrid { columns, rows, cell_size: (0.5, 0.8), heights }, material);
+        for i in 0..200 {
+            let f = i as Float;
+            let origin = Point3::new(-5.0 + (f * 0.37) % 30.0, 4.0, -3.0 + (f * 0.71) % 25.0);
+            let direction = Vec3::new((f * 1.3).sin(), -0.6 - 0.3 * (f * 0.9).cos(), (f * 2.1).cos());
+            let r = Ray::new(origin, direction);
+            let mut expected: Option<Float> = None;
+            for row in 0..rows - 1 {
+                for column in 0..columns - 1 {
+                    if let Some(t) = terrain.hit_cell(column, row, &r, 0.001, Float::MAX) {
+                        let p = r.point_at_parameter(t);
+                        let (fx, fz) = (p.x() / 0.5 - column as Float, p.z() / 0.8 - row as Float);
+                        if (-1e-4..=1.0001).contains(&fx) && (-1e-4..=1.0001).contains(&fz) && expected.is_none_or(|e| t < e) {
+                            expected = Some(t);
+                        }
+                    }
+                }
+            }
+            let found = terrain.hit(&r, 0.001, Float::MAX).map(|hit| hit.t);
+            match (found, expected) {
+                (Some(a), Some(b)) => assert!((a - b).abs() < 1e-3, "ray {}: {} instead of {}", i, a, b),
+                (a, b) => assert_eq!(a.is_some(), b.is_some(), "ray {}", i),
+            }
+        }
+    }
+
+    #[test]
+    fn flat_ground_is_never_missed () {
+        assert!(ElevationGrid::parse_asc("ncols 1\nnrows 3\ncellsize 1\n1 2 3\n").is_err());
+
+        // Flat everywhere, and flat only on the western half
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        let (columns, rows) = (9, 7);
+        let flat = vec![0.3; columns * rows];
+        let half = (0..columns * rows).map(|i| if i % columns < 4 { 0.3 } else { 0.3 + 0.1 * (i % columns) as Float }).collect();
+        for heights in [flat, half] {
+            let terrain = Heightfield::new(ElevationGrid { columns, rows, cell_size: (0.7, 0.45), heights }, material.clone());
+            for i in 0..2000 {
+                let f = i as Float;
+                // Aim at points on the flat part from the west, where nothing is in the way
+                let target = Point3::new(0.05 + (f * 0.173) % 2.0, 0.3, 0.05 + (f * 0.311) % 2.6);
+                let origin = Point3::new(target.x() - 3.0 * (f * 1.7).sin().abs(), 0.5 + (f * 0.37) % 5.0, target.z() + 3.0 * (f * 1.3).cos());
+                let r = Ray::new(origin, target - origin);
+                let hit = terrain.hit(&r, 0.001, Float::MAX);
+                assert!(hit.is_some_and(|hit| (hit.p.y() - 0.3).abs() < 1e-3), "ray {} missed", i);
+            }
+        }
+    }
+}
@@ -1,72 +1,233 @@
-use crate::vec::{Vec3, dot};
-use crate::ray::Ray;
+use crate::float::{Float, consts::PI};
+use crate::aabb::Aabb;
+use crate::vec::{Vec3, Transform, dot, cross};
+use crate::ray::{Ray, gamma, offset_origin};
 use crate::material::Material;
+use crate::roots::solve_quadratic;
+use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct HitRecord {
-    pub t: f32,
+    pub t: Float,
     pub p: Vec3,
+    // Bound on the absolute error of each coordinate of `p`, from the rounding in computing it
+    pub error: Vec3,
     pub normal: Vec3,
+    // Surface parametrization at the hitpoint, used for texture lookups
+    pub u: Float,
+    pub v: Float,
+    // Unit direction of increasing `u` on the surface (dp/du), used to build the tangent frame
+    // for bump and normal mapping
+    pub tangent: Vec3,
     pub on_edge: bool,
     pub material: Material,
 }
 
+impl Default for HitRecord {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HitRecord {
     pub fn new() -> Self {
         HitRecord {
             t: 0.0,
             p: Vec3::new(0.0, 0.0, 0.0),
+            error: Vec3::new(0.0, 0.0, 0.0),
             normal: Vec3::new(0.0, 0.0, 0.0),
+            u: 0.0,
+            v: 0.0,
+            tangent: Vec3::new(0.0, 0.0, 0.0),
             on_edge: false,
             material: Material::Lambertian {albedo: Vec3::new(0.0,0.0,0.0)}
         }
     }
+
+    /// Unit vector along dp/dv, completing the (tangent, bitangent, normal) frame.
+    pub fn bitangent(&self) -> Vec3 {
+        cross(&self.normal, &self.tangent)
+    }
+
+    /// Ray leaving the hitpoint in `direction`, starting just far enough off the surface that it
+    /// cannot hit it again.
+    pub fn spawn_ray(&self, direction: Vec3) -> Ray {
+        Ray::new(offset_origin(&self.p, &self.error, &self.normal, &direction), direction)
+    }
+}
+
+/// Pick an arbitrary unit vector that is perpendicular to `n`.
+pub fn any_perpendicular(n: &Vec3) -> Vec3 {
+    let helper = if n.x().abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+    cross(&helper, n).unit_vector()
+}
+
+pub trait Hitable: Send + Sync {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord>;
+
+    /// Box that encloses the whole object, or `None` if it is unbounded.
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+
+    /// Every crossing of the surface between `t_min` and `t_max`, ordered along the ray. On a
+    /// closed object with outward normals these alternate between entering and leaving it.
+    fn hits(&self, r: &Ray, t_min: Float, t_max: Float) -> Vec<HitRecord> {
+        let mut hits = Vec::new();
+        let mut t = t_min;
+        // Each hit starts the search for the next one. The cap guards against surfaces that keep
+        // reporting nearly the same hit.
+        while hits.len() < MAX_CROSSINGS {
+            match self.hit(r, t, t_max) {
+                Some(hit_record) if hit_record.t > t => {
+                    t = hit_record.t;
+                    hits.push(hit_record);
+                }
+                _ => break,
+            }
+        }
+        hits
+    }
 }
 
-pub trait Hitable {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+/// Most crossings `Hitable::hits` reports along one ray by default.
+const MAX_CROSSINGS: usize = 64;
+
+// Geometry shared between several places in the scene
+impl<T: Hitable + ?Sized> Hitable for Arc<T> {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        self.as_ref().hit(r, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.as_ref().bounding_box()
+    }
+
+    fn hits(&self, r: &Ray, t_min: Float, t_max: Float) -> Vec<HitRecord> {
+        self.as_ref().hits(r, t_min, t_max)
+    }
 }
 
+#[derive(Clone)]
 pub struct Sphere {
     pub center: Vec3,
-    pub radius: f32,
+    pub radius: Float,
     pub material: Material
 }
 
+impl Sphere {
+    /// Texture coordinates and dp/du direction for a point on the unit sphere, given by its
+    /// outward normal. `u` goes around the Y axis starting from -X, `v` from the bottom to the top.
+    fn uv_and_tangent(normal: &Vec3) -> (Float, Float, Vec3) {
+        let theta = (-normal.y()).clamp(-1.0, 1.0).acos();
+        let phi = (-normal.z()).atan2(normal.x()) + PI;
+        let dpdu = Vec3::new(normal.z(), 0.0, -normal.x());
+        let tangent = if dpdu.squared_length() > 1e-12 { dpdu.unit_vector() } else { any_perpendicular(normal) };
+        (phi / (2.0 * PI), theta / PI, tangent)
+    }
+
+    /// Hit record for the hit at ray parameter `t`.
+    pub fn hit_record(&self, r: &Ray, t: Float, on_edge: bool) -> HitRecord {
+        // Project the hitpoint back onto the sphere, which leaves only the rounding of that
+        // projection rather than the error of t
+        let offset = r.point_at_parameter(t) - self.center;
+        let offset = self.radius.abs() / offset.length() * offset;
+        let p = self.center + offset;
+        let error = gamma(5) * (offset.abs() + p.abs());
+        let normal: Vec3 = offset / self.radius;
+        let (u, v, tangent) = Sphere::uv_and_tangent(&normal);
+        HitRecord{t, p, error, normal, u, v, tangent, on_edge, material: self.material.clone()}
+    }
+}
+
 impl Hitable for Sphere {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let oc: Vec3 = &r.origin - &self.center; // origin coordinate
-        let a: f32 = dot(&r.direction, &r.direction);
-        let b: f32 = 2.0 * dot(&oc, &r.direction);
-        let c: f32 = dot(&oc, &oc) - &self.radius * &self.radius;
-        let discriminant: f32 = b*b - 4_f32*a*c;
-        if discriminant > 0.0 {
-            let mut hitpoint = (-b - discriminant.sqrt()) / (2.0 * a);
-            if hitpoint < t_max && hitpoint > t_min {
-                let t: f32 = hitpoint;
-                let p: Vec3 = r.point_at_parameter(t);
-                let normal: Vec3 = &(&p - &self.center) / self.radius;
-                if discriminant < 0.0005 {
-                    return Some(HitRecord{t, p, normal, on_edge: true, material: self.material.clone()});
-                }
-                else {
-                    return Some(HitRecord{t, p, normal, on_edge: false, material: self.material.clone()});
-                }
-            }
-            hitpoint = (-b + discriminant.sqrt()) / (2.0 * a);
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        // In double precision and without the cancellation of the textbook formula, so that small
+        // spheres and distant ones are hit where they are
+        let f64v = |v: &Vec3| [v.x() as f64, v.y() as f64, v.z() as f64];
+        let dot3 = |a: &[f64; 3], b: &[f64; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+        let (origin, center, d) = (f64v(&r.origin), f64v(&self.center), f64v(&r.direction));
+        let oc = [origin[0] - center[0], origin[1] - center[1], origin[2] - center[2]]; // origin coordinate
+        let radius = self.radius as f64;
+        let a = dot3(&d, &d);
+        let (t0, t1) = solve_quadratic(dot3(&oc, &oc) - radius * radius, 2.0 * dot3(&oc, &d), a)?;
+        // The discriminant b² - 4ac, from the distance between the roots
+        let on_edge = a * a * (t1 - t0) * (t1 - t0) < 0.0005;
+        for hitpoint in [t0 as Float, t1 as Float] {
             if hitpoint < t_max && hitpoint > t_min {
-                let t: f32 = hitpoint;
-                let p: Vec3 = r.point_at_parameter(t);
-                let normal: Vec3 = &(&p - &self.center) / self.radius;
-                if discriminant < 0.0005 {
-                    return Some(HitRecord{t, p, normal, on_edge: true, material: self.material.clone()});
-                }
-                else {
-                    return Some(HitRecord{t, p, normal, on_edge: false, material: self.material.clone()});
-                }
+                return Some(self.hit_record(r, hitpoint, on_edge));
             }
         }
         None
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = Vec3::new(self.radius, self.radius, self.radius);
+        Some(Aabb::around(self.center, extent))
+    }
+}
+
+/// Shared geometry placed in the scene with a transform. Many instances can reference the same
+/// object without copying it, and non-uniform scaling turns a `Sphere` into an ellipsoid.
+#[derive(Clone)]
+pub struct Instance {
+    pub object: Arc<dyn Hitable>,
+    pub transform: Transform,
+}
+
+impl Instance {
+    pub fn new(object: Arc<dyn Hitable>, transform: Transform) -> Instance {
+        Instance { object, transform }
+    }
+
+    /// The ray in object space, and how far along it its origin was moved. Its direction is not
+    /// normalized, so that apart from that shift the ray parameter t is the same in both spaces.
+    fn object_ray(&self, r: &Ray) -> (Ray, Float) {
+        let inverse = self.transform.inverse();
+        let (origin, error) = inverse.point_with_error(&r.origin, &Vec3::new(0.0, 0.0, 0.0));
+        let direction = inverse.vector(&r.direction);
+        // Move the origin past the error of transforming it, so that rounding cannot put it back
+        // behind the surface the ray is leaving
+        let shift = dot(&direction.abs(), &error) / direction.squared_length();
+        (Ray::new(origin + shift * direction, direction), shift)
+    }
+
+    fn to_world(&self, shift: Float, mut hit_record: HitRecord) -> HitRecord {
+        hit_record.t += shift;
+        (hit_record.p, hit_record.error) = self.transform.point_with_error(&hit_record.p, &hit_record.error);
+        hit_record.normal = self.transform.normal(&hit_record.normal).unit_vector();
+        hit_record.tangent = self.transform.vector(&hit_record.tangent).unit_vector();
+        hit_record
+    }
+}
+
+impl Hitable for Instance {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (object_ray, shift) = self.object_ray(r);
+        let hit_record = self.object.hit(&object_ray, (t_min - shift).max(0.0), t_max - shift)?;
+        Some(self.to_world(shift, hit_record))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Box around the transformed corners of the object's box
+        let b = self.object.bounding_box()?;
+        let corner = |i: usize| Vec3::new(
+            if i & 1 == 0 { b.min.x() } else { b.max.x() },
+            if i & 2 == 0 { b.min.y() } else { b.max.y() },
+            if i & 4 == 0 { b.min.z() } else { b.max.z() },
+        );
+        let first = self.transform.point(&corner(0));
+        Some((1..8).fold(Aabb::new(first, first), |acc, i| {
+            let p = self.transform.point(&corner(i));
+            acc.surrounding(&Aabb::new(p, p))
+        }))
+    }
+
+    fn hits(&self, r: &Ray, t_min: Float, t_max: Float) -> Vec<HitRecord> {
+        let (object_ray, shift) = self.object_ray(r);
+        let hits = self.object.hits(&object_ray, (t_min - shift).max(0.0), t_max - shift);
+        hits.into_iter().map(|hit_record| self.to_world(shift, hit_record)).collect()
+    }
 }
 
 pub struct HitableList {
@@ -74,7 +235,7 @@ pub struct HitableList {
 }
 
 impl Hitable for HitableList {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
         let mut closest_so_far = t_max;
         let mut temp_rec: Option<HitRecord> = None;
 
@@ -86,4 +247,67 @@ impl Hitable for HitableList {
         }
         temp_rec
     }
-}
\ No newline at end of file
+    fn bounding_box(&self) -> Option<Aabb> {
+        let mut boxes = self.list.iter().map(|h| h.bounding_box());
+        let first = boxes.next()??;
+        boxes.try_fold(first, |acc, b| Some(acc.surrounding(&b?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn instances_transform_shared_geometry () {
+        // Ellipsoid with semi-axes 2, 1 and 0.5, placed twice from one sphere
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        let sphere: Arc<dyn Hitable> = Arc::new(Sphere { center: Vec3::new(0.0, 0.0, 0.0), radius: 1.0, material });
+        let stretch = Transform::scale(Vec3::new(2.0, 1.0, 0.5));
+        let near = Instance::new(sphere.clone(), stretch);
+        let far = Instance::new(sphere, stretch.then(&Transform::translation(Vec3::new(0.0, 0.0, -10.0))));
+        let along_x = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hit = near.hit(&along_x, 0.001, Float::MAX).unwrap();
+        assert!((hit.t - 3.0).abs() < 1e-5 && (hit.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-5);
+        let along_z = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!((near.hit(&along_z, 0.001, Float::MAX).unwrap().t - 4.5).abs() < 1e-5);
+        assert!((far.hit(&along_z, 5.0, Float::MAX).unwrap().t - 14.5).abs() < 1e-4);
+        // Off the axes the normal is not the direction from the center
+        let diagonal = Ray::new(Vec3::new(1.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let hit = near.hit(&diagonal, 0.001, Float::MAX).unwrap();
+        let expected = Vec3::new(hit.p.x() / 4.0, hit.p.y(), hit.p.z() * 4.0).unit_vector();
+        assert!((hit.normal - expected).length() < 1e-5);
+        let bounds = far.bounding_box().unwrap();
+        assert!((bounds.max - Vec3::new(2.0, 1.0, -9.5)).length() < 1e-5);
+    }
+
+    #[test]
+    fn spawned_rays_leave_spheres_at_any_scale () {
+        use rand::SeedableRng;
+        use rand::rngs::StdRng;
+        let mut rng = StdRng::seed_from_u64(3);
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        for scale in [1e-3, 1.0, 1e4] {
+            // Far from the origin compared to the radius, where rounding hurts the most
+            let center = 100.0 * scale * Vec3::new(3.0, -2.0, 7.0);
+            let sphere: Arc<dyn Hitable> = Arc::new(Sphere { center: Vec3::new(0.0, 0.0, 0.0), radius: scale, material: material.clone() });
+            let shapes: [Box<dyn Hitable>; 2] = [
+                Box::new(Sphere { center, radius: scale, material: material.clone() }),
+                Box::new(Instance::new(sphere, Transform::scale(Vec3::new(1.0, 0.5, 2.0)).then(&Transform::translation(center)))),
+            ];
+            for shape in &shapes {
+                for _ in 0..1000 {
+                    let origin = center + 5.0 * scale * Vec3::random_range(&mut rng, -1.0, 1.0).unit_vector();
+                    let aim = center + 0.4 * scale * Vec3::random_range(&mut rng, -1.0, 1.0);
+                    let hit = shape.hit(&Ray::new(origin, aim - origin), 0.0, Float::MAX).expect("aimed at the sphere");
+                    // Leaving the outside never hits the surface again, and passing through it
+                    // only hits the far side
+                    let out = 2.0 * hit.normal + Vec3::random_range(&mut rng, -1.0, 1.0);
+                    assert!(shape.hit(&hit.spawn_ray(out), 0.0, Float::MAX).is_none(), "scale {}", scale);
+                    let through = hit.spawn_ray(aim - origin);
+                    let far = shape.hit(&through, 0.0, Float::MAX).expect("the far side");
+                    assert!((far.p - hit.p).length() > 0.5 * scale, "scale {}", scale);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::float::Float;
+use crate::filter::Filter;
+use crate::vec::Vec3;
+
+/// Linear RGBA image with `Float` channels, stored row by row from the top. This is what the
+/// renderer produces and what post-processing and the file encoders work on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    width: u32,
+    height: u32,
+    // Four channels per pixel: red, green, blue and alpha
+    data: Vec<Float>,
+}
+
+impl Image {
+    /// Black image with an alpha of one everywhere.
+    pub fn new(width: u32, height: u32) -> Image {
+        let mut data = vec![0.0; width as usize * height as usize * 4];
+        for pixel in data.chunks_exact_mut(4) {
+            pixel[3] = 1.0;
+        }
+        Image { width, height, data }
+    }
+
+    /// Opaque image from colors row by row from the top. Panics if the number of colors does not
+    /// match the size.
+    pub fn from_pixels(width: u32, height: u32, pixels: &[Vec3]) -> Image {
+        assert_eq!(pixels.len(), width as usize * height as usize, "pixel count does not match the image size");
+        let data = pixels.iter().flat_map(|c| [c.r(), c.g(), c.b(), 1.0]).collect();
+        Image { width, height, data }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        assert!(x < self.width && y < self.height, "pixel ({}, {}) outside a {}x{} image", x, y, self.width, self.height);
+        (y as usize * self.width as usize + x as usize) * 4
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Vec3 {
+        let i = self.index(x, y);
+        Vec3::new(self.data[i], self.data[i + 1], self.data[i + 2])
+    }
+
+    pub fn alpha(&self, x: u32, y: u32) -> Float {
+        self.data[self.index(x, y) + 3]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, color: Vec3) {
+        let i = self.index(x, y);
+        self.data[i..i + 3].copy_from_slice(&color.e);
+    }
+
+    pub fn set_alpha(&mut self, x: u32, y: u32, alpha: Float) {
+        let i = self.index(x, y);
+        self.data[i + 3] = alpha;
+    }
+
+    /// Colors of all pixels, row by row from the top.
+    pub fn pixels(&self) -> impl Iterator<Item = Vec3> + '_ {
+        self.data.chunks_exact(4).map(|p| Vec3::new(p[0], p[1], p[2]))
+    }
+
+    /// Add another image of the same size, channel by channel.
+    pub fn add(&mut self, other: &Image) {
+        assert_eq!((self.width, self.height), (other.width, other.height), "adding images of different sizes");
+        for (a, b) in self.data.iter_mut().zip(other.data.iter()) {
+            *a += b;
+        }
+    }
+
+    /// Multiply the colors by `factor`, leaving alpha alone.
+    pub fn scale(&mut self, factor: Float) {
+        for pixel in self.data.chunks_exact_mut(4) {
+            pixel[0] *= factor;
+            pixel[1] *= factor;
+            pixel[2] *= factor;
+        }
+    }
+
+    /// The `width` × `height` rectangle with its top left corner at (x, y), clamped to the image.
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Image {
+        let (x0, y0) = (x.min(self.width), y.min(self.height));
+        let x1 = x0.saturating_add(width).min(self.width);
+        let y1 = y0.saturating_add(height).min(self.height);
+        let mut data = Vec::with_capacity((x1 - x0) as usize * (y1 - y0) as usize * 4);
+        for row in y0..y1 {
+            let start = (row as usize * self.width as usize + x0 as usize) * 4;
+            data.extend_from_slice(&self.data[start..start + (x1 - x0) as usize * 4]);
+        }
+        Image { width: x1 - x0, height: y1 - y0, data }
+    }
+
+    /// Scale to `width` × `height` pixels, blending the source pixels with `filter`. When
+    /// shrinking the filter is widened to cover all source pixels that fall into a target pixel.
+    /// An empty image has nothing to blend and gives a black one.
+    pub fn resample(&self, width: u32, height: u32, filter: &Filter) -> Image {
+        if self.width == 0 || self.height == 0 || width == 0 || height == 0 {
+            return Image::new(width, height);
+        }
+        let horizontal = resample_weights(self.width, width, filter);
+        let vertical = resample_weights(self.height, height, filter);
+        // Rows first, then columns
+        let mut rows = vec![0.0; width as usize * self.height as usize * 4];
+        for y in 0..self.height as usize {
+            for (x, weights) in horizontal.iter().enumerate() {
+                let to = (y * width as usize + x) * 4;
+                for &(source, weight) in weights {
+                    let from = (y * self.width as usize + source) * 4;
+                    for c in 0..4 {
+                        rows[to + c] += weight * self.data[from + c];
+                    }
+                }
+            }
+        }
+        let mut data = vec![0.0; width as usize * height as usize * 4];
+        for (y, weights) in vertical.iter().enumerate() {
+            for x in 0..width as usize {
+                let to = (y * width as usize + x) * 4;
+                for &(source, weight) in weights {
+                    let from = (source * width as usize + x) * 4;
+                    for c in 0..4 {
+                        data[to + c] += weight * rows[from + c];
+                    }
+                }
+            }
+        }
+        Image { width, height, data }
+    }
+
+    /// 8-bit colors with 'gamma 2' applied and clamped to [0, 1], row by row from the top.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.width as usize * self.height as usize * 3);
+        for pixel in self.data.chunks_exact(4) {
+            for &c in &pixel[..3] {
+                // Apply 'gamma 2' correction --> raise the color to the power of 1/gamma
+                let c = if c.is_nan() { 0.0 } else { c.clamp(0.0, 1.0).sqrt() };
+                bytes.push((255.99 * c) as u8);
+            }
+        }
+        bytes
+    }
+
+    /// Write as a plain text PPM (P3), with 'gamma 2' applied and the colors clamped to [0, 1].
+    pub fn write_ppm<P: AsRef<Path>>(&self, filename: P) -> io::Result<()> {
+        let mut f = BufWriter::new(File::create(filename)?);
+        write!(f, "P3\n{} {}\n255\n", self.width, self.height)?;
+        for rgb in self.to_bytes().chunks_exact(3) {
+            writeln!(f, "{} {} {}", rgb[0], rgb[1], rgb[2])?;
+        }
+        f.flush()
+    }
+
+    /// Write as a binary PPM (P6), which is a third of the size of the plain one and faster to
+    /// read and write.
+    pub fn write_binary_ppm<P: AsRef<Path>>(&self, filename: P) -> io::Result<()> {
+        let mut f = BufWriter::new(File::create(filename)?);
+        write!(f, "P6\n{} {}\n255\n", self.width, self.height)?;
+        f.write_all(&self.to_bytes())?;
+        f.flush()
+    }
+
+    /// Write the linear colors as a little-endian PFM, which keeps values above 1.0.
+    pub fn write_pfm<P: AsRef<Path>>(&self, filename: P) -> io::Result<()> {
+        let mut f = BufWriter::new(File::create(filename)?);
+        // A negative scale marks little-endian data
+        write!(f, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        let mut bytes = Vec::with_capacity(self.width as usize * self.height as usize * 12);
+        // PFM stores the rows from the bottom up
+        for row in self.data.chunks_exact((self.width as usize * 4).max(4)).rev() {
+            for pixel in row.chunks_exact(4) {
+                for c in &pixel[..3] {
+                    bytes.extend_from_slice(&(*c as f32).to_le_bytes());
+                }
+            }
+        }
+        f.write_all(&bytes)?;
+        f.flush()
+    }
+
+    /// Write in the format given by the file extension: `.pfm` for floating point, anything else
+    /// as plain PPM.
+    pub fn save<P: AsRef<Path>>(&self, filename: P) -> io::Result<()> {
+        let filename = filename.as_ref();
+        match filename.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("pfm") => self.write_pfm(filename),
+            _ => self.write_ppm(filename),
+        }
+    }
+}
+
+/// For every target pixel along one axis, the source pixels that contribute to it and their
+/// normalized weights.
+fn resample_weights(from: u32, to: u32, filter: &Filter) -> Vec<Vec<(usize, Float)>> {
+    let ratio = from as Float / to as Float;
+    // Size of a target pixel in source pixels, at least one so that enlarging interpolates
+    let footprint = ratio.max(1.0);
+    let radius = filter.radius() * footprint;
+    (0..to)
+        .map(|x| {
+            let center = (x as Float + 0.5) * ratio;
+            let first = (center - radius - 0.5).ceil().max(0.0) as usize;
+            let last = ((center + radius - 0.5).floor() as isize).min(from as isize - 1);
+            let mut weights: Vec<(usize, Float)> = (first as isize..=last)
+                .map(|s| (s as usize, filter.evaluate_1d((s as Float + 0.5 - center) / footprint)))
+                .filter(|&(_, w)| w != 0.0)
+                .collect();
+            let total: Float = weights.iter().map(|&(_, w)| w).sum();
+            if total.abs() > 1e-6 {
+                for w in &mut weights {
+                    w.1 /= total;
+                }
+            } else {
+                // The filter is too narrow to reach any source pixel, take the nearest one
+                let nearest = (center as usize).min(from as usize - 1);
+                weights = vec![(nearest, 1.0)];
+            }
+            weights
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn crop_and_resample_keep_colors () {
+        let mut image = Image::new(40000, 2);
+        image.set(39999, 1, Vec3::new(0.5, 0.25, 1.0));
+        let corner = image.crop(39998, 1, 10, 10);
+        assert_eq!((corner.width(), corner.height()), (2, 1));
+        assert_eq!(corner.get(1, 0).g(), 0.25);
+
+        // A flat image stays flat when scaled either way
+        let mut flat = Image::new(6, 4);
+        for y in 0..4 {
+            for x in 0..6 {
+                flat.set(x, y, Vec3::new(0.2, 0.4, 0.6));
+            }
+        }
+        flat.scale(2.0);
+        for (w, h) in [(3, 2), (13, 9)] {
+            let resized = flat.resample(w, h, &Filter::mitchell(2.0));
+            assert!(resized.pixels().all(|c| (c - Vec3::new(0.4, 0.8, 1.2)).length() < 1e-4));
+        }
+        // Nothing to blend from or into
+        let from_empty = Image::new(0, 4).resample(3, 2, &Filter::mitchell(2.0));
+        assert_eq!((from_empty.width(), from_empty.height()), (3, 2));
+        assert_eq!(flat.resample(0, 5, &Filter::mitchell(2.0)).height(), 5);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::float::Float;
+use crate::hitable::{Hitable, HitRecord};
+use crate::material;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sampling::power_heuristic;
+use crate::scene::{Background, Scene};
+use crate::vec::Vec3;
+
+const MAX_DEPTH: i32 = 50;
+
+/// One surface interaction along a logged path.
+#[derive(Debug, Clone)]
+pub struct Bounce {
+    pub depth: i32,
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub material: String,
+    // Light emitted by the surface, and light sampled directly from the lights and environment
+    pub emitted: Vec3,
+    pub direct: Vec3,
+    // Direction the path continues in, `None` if it was absorbed here
+    pub scattered: Option<Vec3>,
+    // Product of the attenuations from the camera up to and including this bounce
+    pub throughput: Vec3,
+}
+
+/// Every bounce of a path, and where it finally escaped to the background.
+#[derive(Debug, Clone, Default)]
+pub struct PathLog {
+    pub bounces: Vec<Bounce>,
+    // Direction of the ray that left the scene, and the background radiance it picked up
+    pub escaped: Option<(Vec3, Vec3)>,
+}
+
+/// Radiance arriving along `ray_in`, with every random choice along the path drawn from `sampler`.
+pub fn color(ray_in: &Ray, scene: &Scene, depth: i32, sampler: &mut dyn Sampler) -> Vec3 {
+    trace(ray_in, scene, depth, None, sampler, None)
+}
+
+/// Like `color`, but records every bounce of the path in `log`.
+pub fn color_logged(ray_in: &Ray, scene: &Scene, depth: i32, sampler: &mut dyn Sampler, log: &mut PathLog) -> Vec3 {
+    trace(ray_in, scene, depth, None, sampler, Some(log))
+}
+
+/// `bsdf_pdf` is the pdf with which the previous bounce picked `ray_in`, if it bounced off a
+/// diffuse surface where lights and the environment were also sampled directly. The two estimates
+/// are then combined with multiple importance sampling.
+fn trace(ray_in: &Ray, scene: &Scene, depth: i32, bsdf_pdf: Option<Float>, sampler: &mut dyn Sampler, mut log: Option<&mut PathLog>) -> Vec3 {
+    if let Some(hit_record) = scene.world.hit(ray_in, 0.0, Float::MAX) {
+        let mut emitted = material::emitted(&hit_record.material, ray_in, &hit_record);
+        if let Some(pdf) = bsdf_pdf {
+            if emitted.squared_length() > 0.0 {
+                emitted = power_heuristic(pdf, scene.light_pdf(&ray_in.origin, &ray_in.direction)) * emitted;
+            }
+        }
+        if depth < MAX_DEPTH {
+            let (attenuation, scattered_ray, should_scatter) = material::scatter(&hit_record.material, ray_in, &hit_record, sampler);
+            let direct = sample_lights(ray_in, scene, &hit_record, sampler) + sample_environment(ray_in, scene, &hit_record, sampler);
+            if let Some(log) = log.as_deref_mut() {
+                log_bounce(log, depth, &hit_record, emitted, direct, should_scatter.then_some(scattered_ray.direction), attenuation);
+            }
+            if should_scatter {
+                let pdf = material::evaluate(&hit_record.material, ray_in, &hit_record, &scattered_ray.direction).map(|(_, pdf)| pdf);
+                return emitted + direct + attenuation * trace(&scattered_ray, scene, depth + 1, pdf, sampler, log);
+            }
+            return emitted + direct;
+        }
+        if let Some(log) = log {
+            log_bounce(log, depth, &hit_record, emitted, Vec3::new(0.0, 0.0, 0.0), None, Vec3::new(0.0, 0.0, 0.0));
+        }
+        emitted
+    } else {
+        let radiance = scene.background.radiance(&ray_in.direction);
+        let radiance = match (&scene.background, bsdf_pdf) {
+            (Background::Environment(env), Some(pdf)) => power_heuristic(pdf, env.pdf(&ray_in.direction)) * radiance,
+            _ => radiance,
+        };
+        if let Some(log) = log {
+            log.escaped = Some((ray_in.direction, radiance));
+        }
+        radiance
+    }
+}
+
+fn log_bounce(log: &mut PathLog, depth: i32, hit_record: &HitRecord, emitted: Vec3, direct: Vec3, scattered: Option<Vec3>, attenuation: Vec3) {
+    let before = log.bounces.last().map_or(Vec3::new(1.0, 1.0, 1.0), |b| b.throughput);
+    let throughput = if scattered.is_some() { before * attenuation } else { Vec3::new(0.0, 0.0, 0.0) };
+    log.bounces.push(Bounce {
+        depth,
+        point: hit_record.p,
+        normal: hit_record.normal,
+        material: hit_record.material.to_string(),
+        emitted,
+        direct,
+        scattered,
+        throughput,
+    });
+}
+
+/// Next-event estimation of the light arriving from one uniformly chosen light of the scene at
+/// a diffuse hit.
+fn sample_lights(ray_in: &Ray, scene: &Scene, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Vec3 {
+    let black = Vec3::new(0.0, 0.0, 0.0);
+    if scene.lights.is_empty() {
+        return black;
+    }
+    let index = ((sampler.get_1d() * scene.lights.len() as Float) as usize).min(scene.lights.len() - 1);
+    let light = &scene.lights[index];
+    let select_pdf = 1.0 / scene.lights.len() as Float;
+    let (u1, u2) = sampler.get_2d();
+    let sample = match light.sample(&hit_record.p, u1, u2) {
+        Some(sample) if sample.pdf > 0.0 => sample,
+        _ => return black,
+    };
+    let (bsdf_cos, bsdf_pdf) = match material::evaluate(&hit_record.material, ray_in, hit_record, &sample.direction) {
+        Some(value) => value,
+        None => return black,
+    };
+    if bsdf_cos.squared_length() == 0.0 {
+        return black;
+    }
+    // Stop the shadow ray just short of the light, so that it does not hit the light itself
+    let shadow_ray = hit_record.spawn_ray(sample.direction);
+    let t_max = if sample.distance.is_finite() { sample.distance * (1.0 - 1e-3) } else { Float::MAX };
+    if scene.world.hit(&shadow_ray, 0.0, t_max).is_some() {
+        return black;
+    }
+    let light_pdf = select_pdf * sample.pdf;
+    let weight = if sample.is_delta { 1.0 } else { power_heuristic(light_pdf, bsdf_pdf) };
+    weight / light_pdf * (bsdf_cos * sample.radiance)
+}
+
+/// Next-event estimation of the light arriving from the environment map at a diffuse hit.
+fn sample_environment(ray_in: &Ray, scene: &Scene, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Vec3 {
+    let black = Vec3::new(0.0, 0.0, 0.0);
+    let env = match &scene.background {
+        Background::Environment(env) => env,
+        Background::Gradient => return black,
+    };
+    let (u1, u2) = sampler.get_2d();
+    let (direction, radiance, light_pdf) = env.sample(u1, u2);
+    if light_pdf == 0.0 {
+        return black;
+    }
+    let (bsdf_cos, bsdf_pdf) = match material::evaluate(&hit_record.material, ray_in, hit_record, &direction) {
+        Some(value) => value,
+        None => return black,
+    };
+    if bsdf_cos.squared_length() == 0.0 {
+        return black;
+    }
+    let shadow_ray = hit_record.spawn_ray(direction);
+    if scene.world.hit(&shadow_ray, 0.0, Float::MAX).is_some() {
+        return black;
+    }
+    power_heuristic(light_pdf, bsdf_pdf) / light_pdf * (bsdf_cos * radiance)
+}
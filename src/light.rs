@@ -0,0 +1,259 @@
+use crate::float::{Float, consts::PI};
+use crate::hitable::{Hitable, Sphere};
+use crate::material::Material;
+use crate::planar::Quad;
+use crate::ray::Ray;
+use crate::vec::{Vec3, Point3, dot, cross};
+
+/// A light source that the integrator samples explicitly with shadow rays. Point, spot and
+/// directional lights are infinitely small and can only be found this way. Sphere and quad lights
+/// also have a surface that rays can hit, see `Light::hitable`.
+#[derive(Clone)]
+pub enum Light {
+    Point {
+        position: Point3,
+        intensity: Vec3
+    },
+    // Like a point light, but only shining into a cone around `direction`. The light falls off
+    // smoothly from full intensity at `cos_inner` to zero at `cos_outer`.
+    Spot {
+        position: Point3,
+        direction: Vec3,
+        intensity: Vec3,
+        cos_inner: Float,
+        cos_outer: Float
+    },
+    // Parallel light travelling in `direction`, like the sun
+    Directional {
+        direction: Vec3,
+        irradiance: Vec3
+    },
+    Sphere {
+        center: Point3,
+        radius: Float,
+        radiance: Vec3
+    },
+    // Parallelogram spanned by the two edges from `corner`, emitting on the side of
+    // cross(edge_u, edge_v). Build it with `Light::quad`, which also makes the emitting `quad`
+    // that rays hit and the pdf is measured with.
+    Quad {
+        corner: Point3,
+        edge_u: Vec3,
+        edge_v: Vec3,
+        radiance: Vec3,
+        quad: Box<Quad>
+    }
+}
+
+/// Light arriving at a point from a sampled position on a light.
+pub struct LightSample {
+    // Unit direction from the shaded point towards the light
+    pub direction: Vec3,
+    // Distance to the sampled point on the light, infinite for directional lights
+    pub distance: Float,
+    pub radiance: Vec3,
+    // Pdf with respect to solid angle, or 1.0 for lights that are a delta distribution
+    pub pdf: Float,
+    pub is_delta: bool,
+}
+
+impl Light {
+    /// Spot light at `position` aimed at `target`, with the cone angles in degrees.
+    pub fn spot(position: Point3, target: Point3, intensity: Vec3, inner_angle: Float, outer_angle: Float) -> Light {
+        Light::Spot {
+            position,
+            direction: (target - position).unit_vector(),
+            intensity,
+            cos_inner: (inner_angle / 180.0 * PI).cos(),
+            cos_outer: (outer_angle / 180.0 * PI).cos(),
+        }
+    }
+
+    /// Parallelogram light spanned by the two edges from `corner`, emitting on the side of
+    /// cross(edge_u, edge_v).
+    pub fn quad(corner: Point3, edge_u: Vec3, edge_v: Vec3, radiance: Vec3) -> Light {
+        let quad = Box::new(Quad::new(corner, edge_u, edge_v, Material::DiffuseLight { emit: radiance }));
+        Light::Quad { corner, edge_u, edge_v, radiance, quad }
+    }
+
+    /// Pick a point on the light as seen from `p`, using the two uniform numbers for area lights.
+    pub fn sample(&self, p: &Point3, u1: Float, u2: Float) -> Option<LightSample> {
+        match *self {
+            Light::Point { position, intensity } => {
+                let to_light = position - *p;
+                let inv_distance_squared = 1.0 / to_light.squared_length();
+                if !inv_distance_squared.is_finite() {
+                    // At the light itself, where the falloff has no finite value
+                    return None;
+                }
+                let distance = to_light.length();
+                Some(LightSample {
+                    direction: to_light / distance,
+                    distance,
+                    radiance: inv_distance_squared * intensity,
+                    pdf: 1.0,
+                    is_delta: true,
+                })
+            }
+            Light::Spot { position, direction, intensity, cos_inner, cos_outer } => {
+                let to_light = position - *p;
+                let inv_distance_squared = 1.0 / to_light.squared_length();
+                if !inv_distance_squared.is_finite() {
+                    return None;
+                }
+                let distance = to_light.length();
+                let cos_theta = -dot(&(to_light / distance), &direction);
+                let falloff = smoothstep(cos_outer, cos_inner, cos_theta);
+                if falloff == 0.0 {
+                    return None;
+                }
+                Some(LightSample {
+                    direction: to_light / distance,
+                    distance,
+                    radiance: falloff * inv_distance_squared * intensity,
+                    pdf: 1.0,
+                    is_delta: true,
+                })
+            }
+            Light::Directional { direction, irradiance } => Some(LightSample {
+                direction: -1.0 * direction.unit_vector(),
+                distance: Float::INFINITY,
+                radiance: irradiance,
+                pdf: 1.0,
+                is_delta: true,
+            }),
+            Light::Sphere { center, radius, radiance } => {
+                // Sample the cone of directions the sphere subtends, which is uniform in solid angle
+                let to_center = center - *p;
+                let distance_squared = to_center.squared_length();
+                if distance_squared <= radius * radius {
+                    return None;
+                }
+                let cos_theta_max = (1.0 - radius * radius / distance_squared).sqrt();
+                let cos_theta = 1.0 - u1 * (1.0 - cos_theta_max);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * u2;
+                let w = to_center.unit_vector();
+                let u = crate::hitable::any_perpendicular(&w);
+                let v = cross(&w, &u);
+                let direction = (sin_theta * phi.cos()) * u + (sin_theta * phi.sin()) * v + cos_theta * w;
+                // Distance to the near side of the sphere along the sampled direction
+                let b = dot(&to_center, &direction);
+                let distance = b - (b * b - distance_squared + radius * radius).max(0.0).sqrt();
+                Some(LightSample {
+                    direction,
+                    distance,
+                    radiance,
+                    pdf: 1.0 / (2.0 * PI * (1.0 - cos_theta_max)),
+                    is_delta: false,
+                })
+            }
+            Light::Quad { corner, edge_u, edge_v, radiance, .. } => {
+                let point = corner + u1 * edge_u + u2 * edge_v;
+                let to_light = point - *p;
+                let distance = to_light.length();
+                if distance == 0.0 {
+                    return None;
+                }
+                let direction = to_light / distance;
+                let normal = cross(&edge_u, &edge_v);
+                let area = normal.length();
+                let cos_light = -dot(&direction, &normal) / area;
+                if cos_light <= 0.0 {
+                    // We are behind the emitting side
+                    return None;
+                }
+                Some(LightSample {
+                    direction,
+                    distance,
+                    radiance,
+                    pdf: distance * distance / (cos_light * area),
+                    is_delta: false,
+                })
+            }
+        }
+    }
+
+    /// Solid angle pdf with which `sample` picks `direction` from `origin`. Zero for delta lights
+    /// and for directions that miss the light.
+    pub fn pdf(&self, origin: &Point3, direction: &Vec3) -> Float {
+        match *self {
+            Light::Point { .. } | Light::Spot { .. } | Light::Directional { .. } => 0.0,
+            Light::Sphere { center, radius, .. } => {
+                let to_center = center - *origin;
+                let distance_squared = to_center.squared_length();
+                if distance_squared <= radius * radius {
+                    return 0.0;
+                }
+                let cos_theta_max = (1.0 - radius * radius / distance_squared).sqrt();
+                if dot(&direction.unit_vector(), &to_center.unit_vector()) < cos_theta_max {
+                    return 0.0;
+                }
+                1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+            }
+            Light::Quad { edge_u, edge_v, ref quad, .. } => {
+                let direction = direction.unit_vector();
+                match quad.hit(&Ray::new(*origin, direction), 0.0, Float::MAX) {
+                    Some(hit_record) => {
+                        let normal = cross(&edge_u, &edge_v);
+                        let area = normal.length();
+                        let cos_light = -dot(&direction, &normal) / area;
+                        if cos_light <= 0.0 { 0.0 } else { hit_record.t * hit_record.t / (cos_light * area) }
+                    }
+                    None => 0.0,
+                }
+            }
+        }
+    }
+
+    /// Emitting geometry of area lights, to be added to the world so that rays can hit them.
+    pub fn hitable(&self) -> Option<Box<dyn Hitable>> {
+        match self {
+            Light::Sphere { center, radius, radiance } => {
+                Some(Box::new(Sphere { center: *center, radius: *radius, material: Material::DiffuseLight { emit: *radiance } }))
+            }
+            Light::Quad { quad, .. } => Some(quad.clone()),
+            _ => None,
+        }
+    }
+}
+
+fn smoothstep(edge0: Float, edge1: Float, x: Float) -> Float {
+    if edge0 >= edge1 {
+        return if x >= edge1 { 1.0 } else { 0.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn area_light_pdf_matches_sample () {
+        let p = Point3::new(0.0, 0.0, 0.0);
+        let lights = [
+            Light::Sphere { center: Point3::new(0.0, 5.0, 1.0), radius: 1.0, radiance: Vec3::new(1.0, 1.0, 1.0) },
+            Light::quad(Point3::new(-1.0, 4.0, -1.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0), Vec3::new(1.0, 1.0, 1.0)),
+        ];
+        for light in lights.iter() {
+            let sample = light.sample(&p, 0.3, 0.7).unwrap();
+            let pdf = light.pdf(&p, &sample.direction);
+            assert!((pdf - sample.pdf).abs() / sample.pdf < 1e-3);
+        }
+    }
+
+    #[test]
+    fn no_light_from_points_on_the_light () {
+        let at = Point3::new(1.0, 2.0, 3.0);
+        let lights = [
+            Light::Point { position: at, intensity: Vec3::new(1.0, 1.0, 1.0) },
+            Light::spot(at, Point3::new(1.0, 0.0, 3.0), Vec3::new(1.0, 1.0, 1.0), 20.0, 30.0),
+        ];
+        for light in lights.iter() {
+            assert!(light.sample(&at, 0.5, 0.5).is_none());
+            let near = light.sample(&(at + Vec3::new(0.0, -1e-3, 0.0)), 0.5, 0.5).unwrap();
+            assert!(near.radiance.r().is_finite() && near.direction.y() > 0.99);
+        }
+    }
+}
@@ -1,182 +1,361 @@
-use std::fs::{OpenOptions, remove_file};
-use std::path::Path;
-use std::io::Write;
+// Casts between `Float` and the fixed float types do nothing in one of the two precisions
+#![allow(clippy::unnecessary_cast)]
+
+use std::ops::Range;
+use std::sync::Arc;
 use rand::prelude::*;
 
+pub mod float;
+pub mod simd;
 pub mod vec;
 pub mod ray;
 pub mod hitable;
+pub mod aabb;
+pub mod roots;
+pub mod primitives;
+pub mod planar;
+pub mod mesh;
+pub mod ply;
+pub mod gltf;
+pub mod json;
+pub mod png;
+pub mod bvh;
+pub mod packet;
+pub mod subdivision;
+pub mod heightfield;
+pub mod curve;
+pub mod sdf;
+pub mod csg;
 pub mod camera;
+pub mod projection;
+pub mod aperture;
 pub mod material;
+pub mod hair;
+pub mod texture;
+pub mod sampling;
+pub mod environment;
+pub mod scene;
+pub mod integrator;
+pub mod light;
+pub mod render;
+pub mod filter;
+pub mod image;
+pub mod sampler;
+pub mod animation;
+pub mod stereo;
+pub mod benchmark;
 
-use crate::vec::{Point3, Vec3};
-use crate::ray::Ray;
-use crate::hitable::{Sphere, Hitable, HitableList};
+use crate::float::Float;
+use crate::vec::{Point3, Transform, Vec3};
+use crate::hitable::{Sphere, Hitable, HitableList, Instance};
+use crate::packet::SphereSet;
+use crate::planar::Plane;
+use crate::mesh::{Mesh, TriangleMesh};
+use crate::gltf::GltfScene;
+use crate::subdivision::Subdivision;
 use crate::camera::Camera;
+use crate::projection::Projection;
 use crate::material::Material;
+use crate::environment::EnvironmentMap;
+use crate::scene::{Background, Scene};
+use crate::render::{Crop, RenderSettings, debug_pixel, render_sequence, render_to_file};
+use crate::filter::Filter;
+use crate::sampler::SamplerKind;
+use crate::animation::{CameraAnimation, Interpolation, ObjectAnimation, Track, world_at};
+use crate::stereo::{Convergence, StereoLayout, StereoRig, render_stereo};
 
 
-fn color(ray_in: &Ray, world: &HitableList, depth: i32) -> Vec3 {
-    if let Some(hit_record) = world.hit(ray_in, 0.001, f32::MAX) {
-        if depth < 50 {
-            let (attenuation, scattered_ray, should_scatter) = material::scatter(&hit_record.material, ray_in, &hit_record);
-            if should_scatter {
-                return &attenuation * &color(&scattered_ray, world, depth + 1);
-            }
-        }
-        Vec3::new(0.0, 0.0, 0.0)
-    } else {
-        let unit_direction: Vec3 = ray_in.direction.unit_vector();
-        let t: f32 = 0.5 * (unit_direction.y() + 1.0);
-        &((1.0_f32 - t) * &Vec3::new(1.0, 1.0, 1.0)) + &(t * &Vec3::new(0.5, 0.7, 1.0))
-    }
-}
-
-fn random_scene() -> HitableList {
-    let ground_material = Material::Lambertian {albedo: Vec3::new(0.5, 0.5, 0.5)};
-    let mut objects: Vec<Box<dyn Hitable>> = Vec::new();
-    objects.push(
-        Box::new(Sphere {
-            center: Point3::new(0.0, -1000.0, 0.0),
-            radius: 1000.0,
-            material: ground_material,
-                }
-        ));
-
-    let mut rng = rand::thread_rng();
+/// The spheres from the cover of the book, laid out randomly from `seed`.
+fn random_spheres(seed: u64) -> Vec<Sphere> {
+    let mut objects = Vec::new();
+    let mut rng = StdRng::seed_from_u64(seed);
     let refpoint = Point3::new(4.0, 0.2, 0.0);
 
     for a in -11..11 {
         for b in -11..11 {
-            let choose_mat: f32 = rng.gen();
-            let center = Point3::new(a as f32 + 0.9*rng.gen::<f32>(), 0.2, b as f32 + 0.9*rng.gen::<f32>());
+            let choose_mat: Float = rng.gen();
+            let center = Point3::new(a as Float + 0.9*rng.gen::<Float>(), 0.2, b as Float + 0.9*rng.gen::<Float>());
 
             if (center - refpoint).length() > 0.9 {
                 if choose_mat < 0.8 {
                     // diffuse
-                    let albedo = Vec3::random() * Vec3::random();
-                    objects.push(
-                        Box::new(Sphere {
-                            center,
-                            radius: 0.2,
-                            material: Material::Lambertian {albedo},
-                        }
-                        )
-                    );
+                    let albedo = Vec3::random(&mut rng) * Vec3::random(&mut rng);
+                    objects.push(Sphere {
+                        center,
+                        radius: 0.2,
+                        material: Material::Lambertian {albedo},
+                    });
                 } else if choose_mat < 0.95 {
                     // metal
-                    let albedo = Vec3::random_range(0.5, 1.0);
+                    let albedo = Vec3::random_range(&mut rng, 0.5, 1.0);
                     let fuzz = rng.gen();
-                    objects.push(
-                        Box::new(Sphere {
-                            center,
-                            radius: 0.2,
-                            material: Material::Metal {albedo, fuzz},
-                        }
-                        )
-                    );
+                    objects.push(Sphere {
+                        center,
+                        radius: 0.2,
+                        material: Material::Metal {albedo, fuzz},
+                    });
                 } else {
                     // glass
-                    objects.push(
-                        Box::new(Sphere {
-                            center,
-                            radius: 0.2,
-                            material: Material::Dielectric {refractive_idx: 1.5},
-                        }
-                        )
-                    );
+                    objects.push(Sphere {
+                        center,
+                        radius: 0.2,
+                        material: Material::glass(1.5),
+                    });
                 }
             }
         }
     }
 
-    objects.push(
-        Box::new(Sphere {
-            center: Point3::new(0.0, 1.0, 0.0),
-            radius: 1.0,
-            material: Material::Dielectric {refractive_idx: 1.5},
-        }
-        )
-    );
-    objects.push(
-        Box::new(Sphere {
-            center: Point3::new(-4.0, 1.0, 0.0),
-            radius: 1.0,
-            material: Material::Lambertian {albedo: Vec3::new(0.4, 0.2, 0.1)},
-        }
-        )
-    );
-    objects.push(
-        Box::new(Sphere {
-            center: Point3::new(4.0, 1.0, 0.0),
-            radius: 1.0,
-            material: Material::Metal {albedo: Vec3::new(0.7, 0.6, 0.5), fuzz: 0.0},
-        }
-        )
-    );
+    objects.push(Sphere {
+        center: Point3::new(0.0, 1.0, 0.0),
+        radius: 1.0,
+        material: Material::glass(1.5),
+    });
+    objects.push(Sphere {
+        center: Point3::new(-4.0, 1.0, 0.0),
+        radius: 1.0,
+        material: Material::Lambertian {albedo: Vec3::new(0.4, 0.2, 0.1)},
+    });
+    objects.push(Sphere {
+        center: Point3::new(4.0, 1.0, 0.0),
+        radius: 1.0,
+        material: Material::Metal {albedo: Vec3::new(0.7, 0.6, 0.5), fuzz: 0.0},
+    });
+
+    objects
+}
 
+/// The scene of spheres from the cover of the book on a ground plane.
+fn random_scene(seed: u64) -> HitableList {
+    let ground_material = Material::Lambertian {albedo: Vec3::new(0.5, 0.5, 0.5)};
     HitableList {
-        list: objects
+        list: vec![
+            Box::new(Plane::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), ground_material)),
+            Box::new(SphereSet::new(random_spheres(seed))),
+        ]
     }
 }
 
 fn main() -> std::io::Result<()> {
+    // Command line: [--frames START-END] [--stereo sbs|ou|anaglyph] [--filter NAME[:RADIUS]]
+    //               [--sampler NAME] [--seed N] [--crop X,Y,WIDTH,HEIGHT] [--debug-pixel X,Y]
+    //               [--mesh FILE.obj|FILE.ply] [--subdivide SCHEME[:LEVELS]] [--scene FILE.gltf|FILE.glb]
+    //               [--benchmark] [ENVIRONMENT_MAP [ROTATION [INTENSITY]]]
+    // An equirectangular .hdr or .pfm environment map to light the scene with can be given,
+    // optionally followed by its rotation in degrees and its intensity. With `--frames` a camera
+    // turntable of frames START up to (not including) END is rendered as an image sequence, in
+    // which the `--mesh` model also turns around.
+    // With `--stereo` a stereo pair is rendered, packed side-by-side, over-under or as anaglyph.
+    // `--filter` picks the pixel reconstruction filter: box (the default), tent, gaussian,
+    // mitchell or lanczos, optionally with its radius in pixels. `--sampler` picks where the
+    // random numbers come from: independent, stratified, halton, sobol (the default) or bluenoise.
+    // `--seed` changes both the layout of the scene and the sampler. `--crop` renders only part of
+    // the image, and `--debug-pixel` prints every bounce of every sample of one pixel instead of
+    // rendering, both in pixels from the top left of the image. `--mesh` adds a model standing
+    // in the front of the scene, subdivided when loading with `--subdivide` catmull-clark or
+    // loop, two levels unless given and at most six. `--scene` renders a glTF scene instead of
+    // the spheres, seen through its first camera if it has one. `--benchmark` prints how many
+    // rays per second hit the spheres alone in a list, in a bounding volume hierarchy, and in it
+    // by packets of rays, and cannot be combined with `--scene` or `--mesh`.
+    let mut frames: Option<Range<u32>> = None;
+    let mut stereo: Option<StereoLayout> = None;
+    let mut filter = Filter::default();
+    let mut sampler = SamplerKind::Sobol;
+    let mut seed = 0;
+    let mut crop = None;
+    let mut debug: Option<(u32, u32)> = None;
+    let mut mesh_path: Option<String> = None;
+    let mut subdivision: Option<(Subdivision, u32)> = None;
+    let mut scene_path: Option<String> = None;
+    let mut benchmark = false;
+    let mut args: Vec<String> = Vec::new();
+    let mut arg_iter = std::env::args().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        if arg == "--frames" {
+            let range = arg_iter.next().unwrap_or_default();
+            let bounds: Vec<u32> = range.split('-').filter_map(|b| b.parse().ok()).collect();
+            match bounds.as_slice() {
+                [start, end] => frames = Some(*start..*end),
+                _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--frames expects START-END")),
+            }
+        } else if arg == "--stereo" {
+            stereo = match arg_iter.next().as_deref() {
+                Some("sbs") => Some(StereoLayout::SideBySide),
+                Some("ou") => Some(StereoLayout::OverUnder),
+                Some("anaglyph") => Some(StereoLayout::Anaglyph),
+                _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--stereo expects sbs, ou or anaglyph")),
+            };
+        } else if arg == "--filter" {
+            let spec = arg_iter.next().unwrap_or_default();
+            let mut parts = spec.splitn(2, ':');
+            let name = parts.next().unwrap_or_default();
+            let radius = parts.next().and_then(|r| r.parse().ok());
+            filter = Filter::from_name(name, radius)
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "unknown --filter"))?;
+        } else if arg == "--sampler" {
+            sampler = SamplerKind::from_name(&arg_iter.next().unwrap_or_default())
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "unknown --sampler"))?;
+        } else if arg == "--seed" {
+            seed = arg_iter.next().and_then(|s| s.parse().ok())
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "--seed expects a number"))?;
+        } else if arg == "--crop" {
+            let numbers: Vec<u32> = arg_iter.next().unwrap_or_default().split(',').filter_map(|n| n.parse().ok()).collect();
+            match numbers.as_slice() {
+                [x, y, width, height] => crop = Some(Crop { x: *x, y: *y, width: *width, height: *height }),
+                _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--crop expects X,Y,WIDTH,HEIGHT")),
+            }
+        } else if arg == "--debug-pixel" {
+            let numbers: Vec<u32> = arg_iter.next().unwrap_or_default().split(',').filter_map(|n| n.parse().ok()).collect();
+            match numbers.as_slice() {
+                [x, y] => debug = Some((*x, *y)),
+                _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--debug-pixel expects X,Y")),
+            }
+        } else if arg == "--mesh" {
+            mesh_path = Some(arg_iter.next()
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "--mesh expects a file"))?);
+        } else if arg == "--scene" {
+            scene_path = Some(arg_iter.next()
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "--scene expects a file"))?);
+        } else if arg == "--subdivide" {
+            let spec = arg_iter.next().unwrap_or_default();
+            let mut parts = spec.splitn(2, ':');
+            let scheme = Subdivision::from_name(parts.next().unwrap_or_default())
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "--subdivide expects catmull-clark or loop"))?;
+            // Every level multiplies the number of faces by four, so more than six runs out of memory
+            let levels = match parts.next() {
+                Some(levels) => levels.parse().ok().filter(|&l| l <= 6)
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "--subdivide expects 0 to 6 levels"))?,
+                None => 2,
+            };
+            subdivision = Some((scheme, levels));
+        } else if arg == "--benchmark" {
+            benchmark = true;
+        } else {
+            args.push(arg);
+        }
+    }
+
+    // The benchmark always uses its own spheres, so a scene or mesh would be silently ignored
+    if benchmark && (scene_path.is_some() || mesh_path.is_some()) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--benchmark cannot be combined with --scene or --mesh"));
+    }
+    if subdivision.is_some() && mesh_path.is_none() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--subdivide needs a --mesh to subdivide"));
+    }
+
     // World
-    let world = random_scene();
+    let mut scene_camera = None;
+    let world = match scene_path {
+        Some(path) => {
+            let imported = GltfScene::load(&path)?;
+            for warning in &imported.warnings {
+                eprintln!("{}: {}", path, warning);
+            }
+            scene_camera = imported.cameras.into_iter().next();
+            imported.objects
+        }
+        None => random_scene(seed),
+    };
+    let mut animations = Vec::new();
+    if let Some(path) = mesh_path {
+        let mut mesh = if path.to_lowercase().ends_with(".ply") { Mesh::load_ply(&path)? } else { Mesh::load_obj(&path)? };
+        if let Some((scheme, levels)) = subdivision {
+            mesh = scheme.apply(&mesh, levels);
+        }
+        // Scale the model to 1.5 units tall and stand it on the ground
+        let bounds = mesh.bounding_box()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "mesh without vertices"))?;
+        let size = bounds.max - bounds.min;
+        let scale = 1.5 / size.x().max(size.y()).max(size.z());
+        let bottom_center = Point3::new(0.5 * (bounds.min.x() + bounds.max.x()), bounds.min.y(), 0.5 * (bounds.min.z() + bounds.max.z()));
+        let standing = Transform::translation(-1.0 * bottom_center)
+            .then(&Transform::scale(Vec3::new(scale, scale, scale)));
+        let material = Material::Metal {albedo: Vec3::new(0.8, 0.6, 0.3), fuzz: 0.2};
+        // Turn the model once around its own axis in the four seconds of the turntable
+        let mut model = ObjectAnimation::new(Arc::new(Instance::new(Arc::new(TriangleMesh::new(&mesh, material)), standing)));
+        model.translation = Track::constant(Point3::new(0.0, 0.0, 2.5));
+        model.rotation_y = Track::new(Interpolation::Linear).key(0.0, 0.0).key(4.0, 360.0);
+        animations.push(model);
+    }
+    let world: Arc<dyn Hitable> = Arc::new(world);
+    let mut background = Background::Gradient;
+    if let Some(path) = args.first() {
+        let rotation: Float = args.get(1).and_then(|a| a.parse().ok()).unwrap_or(0.0);
+        let intensity: Float = args.get(2).and_then(|a| a.parse().ok()).unwrap_or(1.0);
+        let env = EnvironmentMap::load(path)?.with_rotation(rotation).with_intensity(intensity);
+        background = Background::Environment(Arc::new(env));
+    }
+    let scene_at = |time: Float| {
+        let mut scene = Scene::new(world_at(&world, &animations, time));
+        if let Background::Environment(env) = &background {
+            scene.background = Background::Environment(env.clone());
+        }
+        scene
+    };
 
     // Image
-    let aspect_ratio: f32 = 3.0 / 2.0;
-    let image_width: i16 = 1200;
-    let image_height: i16 = (image_width as f32 / aspect_ratio) as i16;
+    let aspect_ratio: Float = 3.0 / 2.0;
+    let image_width: u32 = 1200;
+    let image_height: u32 = (image_width as Float / aspect_ratio) as u32;
     let samples_per_pixel = 200;
     let max_depth = 0;
+    let settings = RenderSettings { image_width, image_height, samples_per_pixel, max_depth, filter, sampler, seed, crop };
 
     // Camera
     let lookfrom = Point3::new(13.0, 2.0, 3.0);
     let lookat = Point3::new(0.0, 0.0, 0.0);
     let vup = Vec3::new(0.0, 1.0, 0.0);
-    let aperture: f32 = 0.1;
+    let aperture: Float = 0.1;
     let dist_to_focus = 10.0;
 
-    let cam = Camera::new(lookfrom, lookat, vup, 20.0, aspect_ratio, aperture, dist_to_focus);
+    let cam_builder = match scene_camera {
+        // Keep the imported view, framed to the image
+        Some(camera) => camera.aspect_ratio(aspect_ratio),
+        None => Camera::builder()
+            .lookfrom(lookfrom)
+            .lookat(lookat)
+            .vup(vup)
+            .vfov(20.0)
+            .aspect_ratio(aspect_ratio)
+            .aperture(aperture)
+            .focus_dist(dist_to_focus),
+    };
 
-    // Render
-    let filename = "basic.ppm";
-    if Path::new(filename).exists() {
-        remove_file(filename)?;
-    }
-    let mut f = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(filename)?;
-
-    let _ = f.write_all("P3\n".as_bytes());
-    let _ = f.write_all((format!("{} {}\n", image_width, image_height)).as_bytes());
-    let _ = f.write_all((format!("255\n")).as_bytes());
-
-    let mut rng = rand::thread_rng();
-    for j in (0..image_height).rev() {
-        for i in 0..image_width {
-            let mut col = Vec3::new(0.0, 0.0, 0.0);
-            // Antialiasing: For a given pixel we have several samples (`ns`) within that pixel and
-            // send rays through each of the samples. The colors of these rays are then averaged.
-            for _ in 0..samples_per_pixel {
-                let u = (i as f32 + rng.gen::<f32>()) / image_width as f32;
-                let v = (j as f32 + rng.gen::<f32>()) / image_height as f32;
-                let r = cam.get_ray(u, v, &mut rng);
-                col += color(&r, &world, max_depth);
-            }
-            // Now take the average of the color samples inside the pixel.
-            col = &col / samples_per_pixel as f32;
-            // Apply 'gamma 2' correction --> raise the color to the power of 1/gamma
-            col = Vec3::new(col.e[0].sqrt(), col.e[1].sqrt(), col.e[2].sqrt());
-            let ir: i16 = (255.99 * col.e[0]) as i16;
-            let ig: i16 = (255.99 * col.e[1]) as i16;
-            let ib: i16 = (255.99 * col.e[2]) as i16;
-            let _ = f.write_all((format!("{} {} {}\n", ir, ig, ib)).as_bytes());
+    if let Some(frames) = frames {
+        // Turntable: one full orbit around the scene in four seconds, keeping the focus on the center
+        let fps = 24.0;
+        let radius = (lookfrom.x() * lookfrom.x() + lookfrom.z() * lookfrom.z()).sqrt();
+        let start_angle = lookfrom.z().atan2(lookfrom.x());
+        let mut orbit = Track::new(Interpolation::CatmullRom);
+        for key in 0..=16 {
+            let angle = start_angle + key as Float / 16.0 * 2.0 * crate::float::consts::PI;
+            orbit = orbit.key(key as Float / 4.0, Point3::new(radius * angle.cos(), lookfrom.y(), radius * angle.sin()));
         }
+        let animation = CameraAnimation::new(cam_builder).lookfrom(orbit);
+        return render_sequence("basic", frames, fps, scene_at, &animation, &settings);
+    }
+
+    if let Some(layout) = stereo {
+        // Converge on the focus plane, with the eyes a bit wider apart than human eyes to show
+        // depth at the scale of this scene
+        let rig = StereoRig::new(cam_builder, 0.3, dist_to_focus, Convergence::OffAxis);
+        let (left, right) = rig.eyes().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        return render_stereo("basic.ppm", &scene_at(0.0), &left, &right, layout, &settings);
+    }
+
+    let cam = cam_builder
+        .build()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let cam: Box<dyn Projection> = Box::new(cam);
+
+    if let Some((x, y)) = debug {
+        return debug_pixel(&mut std::io::stdout().lock(), &scene_at(0.0), cam.as_ref(), &settings, x, y);
+    }
+
+    if benchmark {
+        return benchmark::run(&mut std::io::stdout().lock(), random_spheres(seed), cam.as_ref(), &settings);
     }
-    Ok(())
+
+    // Render
+    render_to_file("basic.ppm", &scene_at(0.0), cam.as_ref(), &settings)
 }
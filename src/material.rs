@@ -1,8 +1,12 @@
-use rand::prelude::*;
+use std::fmt;
 
+use crate::float::{Float, consts::PI};
 use crate::ray::Ray;
+use crate::hair::HairBsdf;
 use crate::hitable::HitRecord;
-use crate::vec::{Vec3, dot};
+use crate::sampler::Sampler;
+use crate::texture::Texture;
+use crate::vec::{Vec3, dot, cross};
 
 #[derive(Clone)]
 pub enum Material {
@@ -12,37 +16,245 @@ pub enum Material {
     },
     Metal {
         albedo: Vec3,
-        fuzz: f32
+        fuzz: Float
     },
     Dielectric {
-        refractive_idx: f32
+        refractive_idx: Float,
+        // Absorption coefficient of the medium per unit distance, applied with the Beer-Lambert
+        // law to rays that travel through the inside of the object. Zero is perfectly clear glass.
+        absorption: Vec3
+    },
+    // Emits light from its front side and does not scatter
+    DiffuseLight {
+        emit: Vec3
+    },
+    // Hair fibers, for curves whose tangent runs along the fiber and `v` across it. `sigma_a` is the
+    // absorption inside the fiber (see `hair::sigma_a_from_melanin`), `beta_m` and `beta_n` the
+    // longitudinal and azimuthal roughness in [0, 1], and `alpha` the tilt of the scales in degrees.
+    Hair {
+        sigma_a: Vec3,
+        beta_m: Float,
+        beta_n: Float,
+        alpha: Float,
+        eta: Float
+    },
+    // Any of the materials above, with its shading normal perturbed by a bump or normal map
+    Perturbed {
+        base: Box<Material>,
+        normal_map: NormalMap
+    },
+    // Surface whose properties are looked up at (u, v) of the hit, like the metallic-roughness
+    // materials of glTF: the linear color, the roughness in green and the metalness in blue, and
+    // the emitted light. At every hit it acts as the material `at` gives.
+    Textured {
+        base_color: Texture,
+        metallic_roughness: Texture,
+        emissive: Texture
+    }
+}
+
+/// Perturbation of the shading normal, applied in the tangent frame of the hit.
+#[derive(Clone)]
+pub enum NormalMap {
+    // Height field over (u, v); the normal follows its gradient. `scale` is the height of a texture
+    // value of 1.0, relative to a unit step in (u, v).
+    Bump {
+        height: Texture,
+        scale: Float
+    },
+    // Tangent-space normals encoded as RGB in [0, 1], with blue pointing along the surface normal.
+    // `strength` blends between the geometric normal (0.0) and the mapped normal (1.0).
+    TangentSpace {
+        texture: Texture,
+        strength: Float
     }
 }
 
-pub fn scatter(material: &Material, ray_in: &Ray, hit_record: &HitRecord) -> (Vec3, Ray, bool) {
+impl NormalMap {
+    /// Shading normal at the hit, before any safeguards are applied.
+    fn perturb(&self, hit_record: &HitRecord) -> Vec3 {
+        let (n, t, b) = (hit_record.normal, hit_record.tangent, hit_record.bitangent());
+        let (u, v) = (hit_record.u, hit_record.v);
+        match self {
+            NormalMap::Bump { height, scale } => {
+                let (du, dv) = height.texel_size();
+                let dhdu = (height.scalar(u + du, v) - height.scalar(u - du, v)) / (2.0 * du);
+                let dhdv = (height.scalar(u, v + dv) - height.scalar(u, v - dv)) / (2.0 * dv);
+                (n - *scale * (dhdu * t + dhdv * b)).unit_vector()
+            }
+            NormalMap::TangentSpace { texture, strength } => {
+                let c = 2.0 * texture.value(u, v) - Vec3::new(1.0, 1.0, 1.0);
+                let mapped = c.x() * t + c.y() * b + c.z() * n;
+                ((1.0 - *strength) * n + *strength * mapped).unit_vector()
+            }
+        }
+    }
+}
+
+/// Keep a perturbed shading normal from facing away from the viewer or from the geometric surface.
+/// Either would let scattered rays pass below the geometry and leak light.
+fn safeguard_normal(shading: Vec3, geometric: &Vec3, incoming: &Vec3) -> Vec3 {
+    let min_cos = 0.05;
+    // The shading normal must stay in the same hemisphere as the geometric one
+    let mut n = shading;
+    let cos_geometric = dot(&n, geometric);
+    if cos_geometric < min_cos {
+        n = (n + (min_cos - cos_geometric) * *geometric).unit_vector();
+    }
+    // The viewer must be on the same side of both normals. If not, bend the shading normal
+    // towards the viewer until the reflection about it stays above the surface.
+    let wo = -1.0 * incoming.unit_vector();
+    let side = dot(&wo, geometric).signum();
+    let cos_view = side * dot(&wo, &n);
+    if cos_view < min_cos {
+        n = (n + side * (min_cos - cos_view) * wo).unit_vector();
+    }
+    n
+}
+
+/// Short description for logs, such as `metal albedo (0.70, 0.60, 0.50) fuzz 0.00`.
+impl fmt::Display for Material {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Material::Lambertian { albedo } => write!(f, "lambertian albedo {:.2}", albedo),
+            Material::Metal { albedo, fuzz } => write!(f, "metal albedo {:.2} fuzz {:.2}", albedo, fuzz),
+            Material::Dielectric { refractive_idx, absorption } => {
+                write!(f, "dielectric index {:.2} absorption {:.2}", refractive_idx, absorption)
+            }
+            Material::DiffuseLight { emit } => write!(f, "diffuse light {:.2}", emit),
+            Material::Hair { sigma_a, beta_m, beta_n, .. } => {
+                write!(f, "hair absorption {:.2} roughness {:.2}/{:.2}", sigma_a, beta_m, beta_n)
+            }
+            Material::Perturbed { base, normal_map: NormalMap::Bump { .. } } => write!(f, "{} with bump map", base),
+            Material::Perturbed { base, normal_map: NormalMap::TangentSpace { .. } } => write!(f, "{} with normal map", base),
+            Material::Textured { .. } => write!(f, "textured"),
+        }
+    }
+}
+
+impl Material {
+    /// Clear glass that does not absorb any light.
+    pub fn glass(refractive_idx: Float) -> Material {
+        Material::Dielectric { refractive_idx, absorption: Vec3::new(0.0, 0.0, 0.0) }
+    }
+
+    /// Tinted glass where light that travelled `distance` through the medium comes out with the
+    /// `transmission` color. Thicker objects get darker and more saturated, thinner ones lighter.
+    /// Glass cannot add light, so transmission channels are clamped to at most 1, and to just
+    /// above 0 so that black still gives a finite absorption. `distance` must be positive.
+    pub fn tinted_glass(refractive_idx: Float, transmission: Vec3, distance: Float) -> Material {
+        assert!(distance > 0.0, "tinted glass needs a positive distance, not {}", distance);
+        // Beer-Lambert: transmission = exp(-absorption * distance)
+        let absorption = Vec3::new(
+            -transmission.r().clamp(Float::MIN_POSITIVE, 1.0).ln() / distance,
+            -transmission.g().clamp(Float::MIN_POSITIVE, 1.0).ln() / distance,
+            -transmission.b().clamp(Float::MIN_POSITIVE, 1.0).ln() / distance,
+        );
+        Material::Dielectric { refractive_idx, absorption }
+    }
+
+    /// Hair with the given absorption and typical roughness, scale tilt and index of refraction.
+    pub fn hair(sigma_a: Vec3) -> Material {
+        Material::Hair { sigma_a, beta_m: 0.3, beta_n: 0.3, alpha: 2.0, eta: 1.55 }
+    }
+
+    /// The plain material a `Textured` one is at (u, v): a light where it emits, metal where it is
+    /// mostly metallic with the roughness as fuzz, and diffuse elsewhere. Other materials are the
+    /// same everywhere and are returned as they are.
+    pub fn at(&self, u: Float, v: Float) -> Material {
+        match self {
+            Material::Textured { base_color, metallic_roughness, emissive } => {
+                let emit = emissive.value(u, v);
+                let properties = metallic_roughness.value(u, v);
+                if emit.e.iter().any(|&c| c > 0.0) {
+                    Material::DiffuseLight { emit }
+                } else if properties.b() >= 0.5 {
+                    Material::Metal { albedo: base_color.value(u, v), fuzz: properties.g().clamp(0.0, 1.0) }
+                } else {
+                    Material::Lambertian { albedo: base_color.value(u, v) }
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+}
+
+/// Frame of a hair fiber with x along the fiber and z facing back along the ray, and the outgoing
+/// direction in that frame. The offset across the fiber comes from `v` instead of the normal.
+fn fiber_frame(ray_in: &Ray, hit_record: &HitRecord) -> ([Vec3; 3], Vec3) {
+    let x = hit_record.tangent;
+    let wo = -1.0 * ray_in.direction.unit_vector();
+    let across = wo - dot(&wo, &x) * x;
+    let z = if across.squared_length() > 1e-12 { across.unit_vector() } else { hit_record.normal };
+    let y = cross(&z, &x);
+    ([x, y, z], Vec3::new(dot(&wo, &x), dot(&wo, &y), dot(&wo, &z)))
+}
+
+/// Pick the direction light continues in after hitting the surface, drawing the random choices
+/// from `sampler`. Returns the attenuation, the scattered ray and whether the light scatters at all.
+pub fn scatter(material: &Material, ray_in: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> (Vec3, Ray, bool) {
     match *material {
         Material::Lambertian { albedo } => {
-            // Center of unit radius sphere that is tangent to the hitpoint
-            let unit_center: Vec3 = &hit_record.p + &hit_record.normal;
-            let target: Vec3 = &unit_center + &random_in_unit_sphere();
-            // Diffuse material: pick a random point from the unit radius sphere that is tangent to
-            // the hitpoint, and send a ray from the hitpoint 'p' to the random point.
-            let scattered_ray = Ray { origin: hit_record.p, direction: &target - &hit_record.p };
+            // Diffuse material: pick a random point on the unit radius sphere that is tangent to
+            // the hitpoint, and send a ray from the hitpoint 'p' to the random point. This gives
+            // a cosine weighted distribution of directions (see `evaluate`). The sphere's center
+            // is at p + normal; the direction is formed relative to p, which stays accurate far
+            // away from the origin of the scene.
+            let mut direction = hit_record.normal + random_unit_vector(sampler);
+            if direction.squared_length() < 1e-12 {
+                // The random point ended up opposite the normal
+                direction = hit_record.normal;
+            }
+            let scattered_ray = hit_record.spawn_ray(direction);
             let attenuation = albedo;
             let should_scatter = true;
             (attenuation, scattered_ray, should_scatter)
         }
         Material::Metal { albedo, fuzz } => {
             let reflected: Vec3 = reflect(&ray_in.direction.unit_vector(), &hit_record.normal);
-            let scattered_ray = Ray { origin: hit_record.p, direction: &reflected + &(fuzz * &random_in_unit_sphere())};
+            let scattered_ray = hit_record.spawn_ray(reflected + fuzz * random_in_unit_sphere(sampler));
             let attenuation = albedo;
             let should_scatter = dot(&scattered_ray.direction, &hit_record.normal) > 0.0;
             (attenuation, scattered_ray, should_scatter)
         }
-        Material::Dielectric {refractive_idx} => {
+        Material::DiffuseLight { .. } => {
+            (Vec3::new(0.0, 0.0, 0.0), hit_record.spawn_ray(ray_in.direction), false)
+        }
+        Material::Hair { sigma_a, beta_m, beta_n, alpha, eta } => {
+            let bsdf = HairBsdf::new(2.0 * hit_record.v - 1.0, eta, sigma_a, beta_m, beta_n, alpha);
+            let ([x, y, z], wo) = fiber_frame(ray_in, hit_record);
+            let (u1, u2) = sampler.get_2d();
+            let (u3, u4) = sampler.get_2d();
+            let (wi, f_cos, pdf) = bsdf.sample(&wo, [u1, u2, u3, u4]);
+            let direction = wi.x() * x + wi.y() * y + wi.z() * z;
+            let attenuation = if pdf > 0.0 { f_cos / pdf } else { Vec3::new(0.0, 0.0, 0.0) };
+            (attenuation, hit_record.spawn_ray(direction), pdf > 0.0)
+        }
+        Material::Perturbed { ref base, ref normal_map } => {
+            let geometric = hit_record.normal;
+            let mut shading_record = hit_record.clone();
+            shading_record.normal = safeguard_normal(normal_map.perturb(hit_record), &geometric, &ray_in.direction);
+            let (attenuation, scattered_ray, should_scatter) = scatter(base, ray_in, &shading_record, sampler);
+            // Leave the surface along the geometric normal, which the error bound is measured on
+            let scattered_ray = hit_record.spawn_ray(scattered_ray.direction);
+            // A direction on a different side of the geometric surface than of the shading
+            // surface would pass through the geometry, so absorb it instead.
+            let scattered_side = dot(&scattered_ray.direction, &geometric) > 0.0;
+            let shading_side = dot(&scattered_ray.direction, &shading_record.normal) > 0.0;
+            (attenuation, scattered_ray, should_scatter && scattered_side == shading_side)
+        }
+        Material::Textured { .. } => scatter(&material.at(hit_record.u, hit_record.v), ray_in, hit_record, sampler),
+        Material::Dielectric {refractive_idx, absorption} => {
             let reflected: Vec3 = reflect(&ray_in.direction, &hit_record.normal);
-            let attenuation = Vec3::new(1.0, 1.0, 1.0);
-            let direction_dot_normal: f32 = dot(&ray_in.direction, &hit_record.normal);
+            let direction_dot_normal: Float = dot(&ray_in.direction, &hit_record.normal);
+            // When the ray leaves the object it has travelled from the entry point to this hit
+            // inside the medium, so attenuate it by the distance covered.
+            let attenuation = if direction_dot_normal > 0.0 {
+                let distance = hit_record.t * ray_in.direction.length();
+                (-distance * absorption).exp()
+            } else {
+                Vec3::new(1.0, 1.0, 1.0)
+            };
             let (outward_normal, ni_over_nt, cosine) = if direction_dot_normal > 0.0 {
                 let rev = Vec3::new(-hit_record.normal.e[0], -hit_record.normal.e[1], -hit_record.normal.e[2]);
                 (rev, refractive_idx, refractive_idx * direction_dot_normal / ray_in.direction.length())
@@ -58,47 +270,150 @@ pub fn scatter(material: &Material, ray_in: &Ray, hit_record: &HitRecord) -> (Ve
                 1.0
             };
 
-            let mut rng = rand::thread_rng();
-            if rng.gen::<f32>() < reflect_prob {
-                (attenuation, Ray { origin: hit_record.p, direction: reflected}, true)
+            if sampler.get_1d() < reflect_prob {
+                (attenuation, hit_record.spawn_ray(reflected), true)
             } else {
-                (attenuation, Ray { origin: hit_record.p, direction: refracted}, true)
+                (attenuation, hit_record.spawn_ray(refracted), true)
             }
         }
     }
 }
 
-fn random_in_unit_sphere() -> Vec3 {
-    // Rejection method algorithm for picking a random point in a unit radius sphere centered
-    // at the origin. Diffuse materials have a random reflection.
-    let mut p = Vec3::new(1.0, 1.0, 1.0);
-    let mut rng = rand::thread_rng();
-    // Try if the point is outside the sphere
-    while p.squared_length() >= 1.0 {
-        // Pick a random point in the unit cube where x,y,z range from -1 to +1
-        let pt_in_unit_cube = Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>());
-        p = &(2.0 * &pt_in_unit_cube) - &Vec3::new(1.0, 1.0, 1.0);
+/// For materials that scatter diffusely, the BSDF times the cosine term for light scattered from
+/// `direction` towards the incoming ray, together with the pdf with which `scatter` picks
+/// `direction`. Returns `None` for specular materials, which can only be sampled.
+pub fn evaluate(material: &Material, ray_in: &Ray, hit_record: &HitRecord, direction: &Vec3) -> Option<(Vec3, Float)> {
+    match *material {
+        Material::Lambertian { albedo } => {
+            let cosine = dot(&direction.unit_vector(), &hit_record.normal).max(0.0);
+            Some((cosine / PI * albedo, cosine / PI))
+        }
+        Material::Perturbed { ref base, ref normal_map } => {
+            let geometric = hit_record.normal;
+            let mut shading_record = hit_record.clone();
+            shading_record.normal = safeguard_normal(normal_map.perturb(hit_record), &geometric, &ray_in.direction);
+            let (value, pdf) = evaluate(base, ray_in, &shading_record, direction)?;
+            let leaks = (dot(direction, &geometric) > 0.0) != (dot(direction, &shading_record.normal) > 0.0);
+            Some((if leaks { Vec3::new(0.0, 0.0, 0.0) } else { value }, pdf))
+        }
+        Material::Hair { sigma_a, beta_m, beta_n, alpha, eta } => {
+            let bsdf = HairBsdf::new(2.0 * hit_record.v - 1.0, eta, sigma_a, beta_m, beta_n, alpha);
+            let ([x, y, z], wo) = fiber_frame(ray_in, hit_record);
+            let d = direction.unit_vector();
+            let wi = Vec3::new(dot(&d, &x), dot(&d, &y), dot(&d, &z));
+            Some((bsdf.f_cos(&wo, &wi), bsdf.pdf(&wo, &wi)))
+        }
+        Material::Textured { .. } => evaluate(&material.at(hit_record.u, hit_record.v), ray_in, hit_record, direction),
+        Material::Metal { .. } | Material::Dielectric { .. } | Material::DiffuseLight { .. } => None,
+    }
+}
+
+/// Light emitted by the surface towards the incoming ray.
+pub fn emitted(material: &Material, ray_in: &Ray, hit_record: &HitRecord) -> Vec3 {
+    match *material {
+        Material::DiffuseLight { emit } if dot(&ray_in.direction, &hit_record.normal) < 0.0 => emit,
+        Material::Perturbed { ref base, .. } => emitted(base, ray_in, hit_record),
+        Material::Textured { .. } => emitted(&material.at(hit_record.u, hit_record.v), ray_in, hit_record),
+        _ => Vec3::new(0.0, 0.0, 0.0),
     }
-    p
+}
+
+fn random_unit_vector(sampler: &mut dyn Sampler) -> Vec3 {
+    // Uniform point on the unit sphere: z is uniform in [-1, 1], the angle around the z axis is
+    // uniform in [0, 2π)
+    let (u1, u2) = sampler.get_2d();
+    let z = 1.0 - 2.0 * u1;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+    // Random point in a unit radius sphere centered at the origin. The volume inside radius r
+    // grows with r³, so the radius is the cube root of a uniform number.
+    let direction = random_unit_vector(sampler);
+    sampler.get_1d().cbrt() * direction
 }
 
 fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
-    v - &(2.0 * dot(v, n) * n)
+    *v - 2.0 * dot(v, n) * n
 }
 
-fn refract(v: &Vec3, n: &Vec3, ni_over_nt: f32) -> (Vec3, bool) {
+fn refract(v: &Vec3, n: &Vec3, ni_over_nt: Float) -> (Vec3, bool) {
     let uv = v.unit_vector();
     let dt = dot(&uv,n);
     let discriminant = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
     if discriminant > 0.0 {
-        (&(ni_over_nt * &(&uv - &(n * dt))) - &(n * discriminant.sqrt()), true)
+        (ni_over_nt * (uv - n * dt) - n * discriminant.sqrt(), true)
     } else {
         (Vec3::new(0.0, 0.0, 0.0), false)
     }
 }
 
-fn schlick(cosine: f32, refractive_idx: f32) -> f32 {
+fn schlick(cosine: Float, refractive_idx: Float) -> Float {
     let mut r0 = (1.0 - refractive_idx) / (1.0 + refractive_idx);
     r0 = r0 * r0;
     r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::texture::ImageTexture;
+    #[test]
+    fn tinted_glass_transmission_at_distance () {
+        if let Material::Dielectric { absorption, .. } = Material::tinted_glass(1.5, Vec3::new(0.8, 0.5, 0.2), 2.0) {
+            let transmission = (-2.0 * absorption).exp();
+            assert!((transmission.r() - 0.8).abs() < 1e-5);
+            assert!((transmission.g() - 0.5).abs() < 1e-5);
+            assert!((transmission.b() - 0.2).abs() < 1e-5);
+        } else {
+            panic!("tinted glass must be a dielectric");
+        }
+        // Out of range channels neither amplify light nor absorb infinitely
+        if let Material::Dielectric { absorption, .. } = Material::tinted_glass(1.5, Vec3::new(1.5, 0.0, -1.0), 1.0) {
+            assert_eq!(absorption.r(), 0.0);
+            assert!(absorption.g().is_finite() && absorption.g() > 0.0);
+            assert!(absorption.b().is_finite() && absorption.b() > 0.0);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn tinted_glass_needs_positive_distance () {
+        Material::tinted_glass(1.5, Vec3::new(0.8, 0.5, 0.2), 0.0);
+    }
+
+    #[test]
+    fn normal_maps_tilt_the_shading_normal () {
+        let mut hit_record = HitRecord::new();
+        hit_record.normal = Vec3::new(0.0, 0.0, 1.0);
+        hit_record.tangent = Vec3::new(1.0, 0.0, 0.0);
+        hit_record.u = 0.4;
+        hit_record.v = 0.5;
+        // Heights rising along u tilt the normal back against u
+        let ramp = ImageTexture { width: 8, height: 1, pixels: (0..8).map(|x| Vec3::new(1.0, 1.0, 1.0) * (x as Float / 8.0)).collect() };
+        let bump = NormalMap::Bump { height: Texture::Image(Arc::new(ramp)), scale: 0.5 };
+        let n = bump.perturb(&hit_record);
+        assert!(n.x() < -0.05 && n.y().abs() < 1e-5 && (n.length() - 1.0).abs() < 1e-5, "{}", n);
+        // A tangent-space normal along the tangent, half blended in
+        let mapped = NormalMap::TangentSpace { texture: Texture::Solid(Vec3::new(1.0, 0.5, 0.5)), strength: 0.5 };
+        let n = mapped.perturb(&hit_record);
+        assert!((n - Vec3::new(1.0, 0.0, 1.0).unit_vector()).length() < 1e-5, "{}", n);
+        // Zero strength keeps the surface normal
+        let flat = NormalMap::TangentSpace { texture: Texture::Solid(Vec3::new(1.0, 0.5, 0.5)), strength: 0.0 };
+        assert!((flat.perturb(&hit_record) - hit_record.normal).length() < 1e-6);
+    }
+
+    #[test]
+    fn safeguarded_normal_faces_viewer () {
+        let geometric = Vec3::new(0.0, 1.0, 0.0);
+        // Grazing view with a shading normal tilted away from the viewer
+        let incoming = Vec3::new(1.0, -0.1, 0.0);
+        let shading = Vec3::new(0.9, 0.3, 0.0).unit_vector();
+        let n = safeguard_normal(shading, &geometric, &incoming);
+        assert!(dot(&n, &geometric) > 0.0);
+        assert!(dot(&(-1.0 * incoming), &n) > 0.0);
+    }
+}
@@ -0,0 +1,335 @@
+//! Polygon meshes: loading them from OBJ files and rendering them as triangles.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::float::Float;
+use crate::aabb::Aabb;
+use crate::bvh::Bvh;
+use crate::hitable::{HitRecord, Hitable, any_perpendicular};
+use crate::material::Material;
+use crate::ray::{Ray, gamma};
+use crate::vec::{Vec3, Point3, dot, cross};
+
+/// Polygons sharing vertices, with optional sharp edges for subdivision.
+#[derive(Debug, Clone, Default)]
+pub struct Mesh {
+    pub positions: Vec<Point3>,
+    // Vertex indices of each polygon, counterclockwise seen from the outside
+    pub faces: Vec<Vec<usize>>,
+    // Sharpness of creased edges, keyed by `edge_key`. Subdivision keeps an edge of sharpness s
+    // sharp for s levels and then softens it.
+    pub creases: HashMap<(usize, usize), Float>,
+    // Texture coordinates of each vertex, or empty. Subdivision does not carry them over.
+    pub texcoords: Vec<(Float, Float)>,
+}
+
+/// Key of the edge between two vertices, the same in both directions.
+pub fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Point3>, faces: Vec<Vec<usize>>) -> Mesh {
+        Mesh { positions, faces, creases: HashMap::new(), texcoords: Vec::new() }
+    }
+
+    /// Mark the edge between vertices `a` and `b` as a crease. `Float::INFINITY` keeps it sharp at
+    /// every level.
+    pub fn with_crease(mut self, a: usize, b: usize, sharpness: Float) -> Mesh {
+        self.creases.insert(edge_key(a, b), sharpness);
+        self
+    }
+
+    pub fn crease(&self, a: usize, b: usize) -> Float {
+        self.creases.get(&edge_key(a, b)).copied().unwrap_or(0.0)
+    }
+
+    /// Read the vertices and faces of a Wavefront OBJ file. Texture coordinates, normals, groups
+    /// and materials are ignored.
+    pub fn load_obj<P: AsRef<Path>>(path: P) -> io::Result<Mesh> {
+        Mesh::parse_obj(BufReader::new(File::open(path)?))
+    }
+
+    /// Parse OBJ text. Creases are read from OpenSubdiv's `t crease 2/1/0 A B SHARPNESS` tags, with
+    /// zero-based vertex indices.
+    pub fn parse_obj<R: BufRead>(reader: R) -> io::Result<Mesh> {
+        let mut mesh = Mesh::default();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+            let invalid = |what: &str| invalid_data(format!("line {}: {}", number + 1, what));
+            match words.next() {
+                Some("v") => {
+                    let coordinates: Vec<Float> = words.take(3).filter_map(|w| w.parse().ok()).collect();
+                    match coordinates.as_slice() {
+                        [x, y, z] => mesh.positions.push(Vec3::new(*x, *y, *z)),
+                        _ => return Err(invalid("a vertex needs three coordinates")),
+                    }
+                }
+                Some("f") => {
+                    let mut face = Vec::new();
+                    for word in words {
+                        // Only the position index of v/vt/vn, counting from 1 or from the end
+                        let index: isize = word.split('/').next().and_then(|i| i.parse().ok()).ok_or_else(|| invalid("bad face index"))?;
+                        let count = mesh.positions.len() as isize;
+                        let index = if index < 0 { count + index } else { index - 1 };
+                        if !(0..count).contains(&index) {
+                            return Err(invalid("face index out of range"));
+                        }
+                        face.push(index as usize);
+                    }
+                    if face.len() < 3 {
+                        return Err(invalid("a face needs at least three vertices"));
+                    }
+                    mesh.faces.push(face);
+                }
+                Some("t") if words.next() == Some("crease") => {
+                    let values: Vec<&str> = words.skip(1).collect();
+                    match values.as_slice() {
+                        [a, b, sharpness] => {
+                            let parse_index = |w: &str| w.parse::<usize>().ok().filter(|&i| i < mesh.positions.len());
+                            let a = parse_index(a).ok_or_else(|| invalid("bad crease vertex"))?;
+                            let b = parse_index(b).ok_or_else(|| invalid("bad crease vertex"))?;
+                            let sharpness = sharpness.parse().map_err(|_| invalid("bad crease sharpness"))?;
+                            mesh.creases.insert(edge_key(a, b), sharpness);
+                        }
+                        _ => return Err(invalid("a crease needs two vertices and a sharpness")),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(mesh)
+    }
+
+    /// The polygons split into fans of triangles.
+    pub fn triangles(&self) -> Vec<[usize; 3]> {
+        self.faces.iter().flat_map(|face| (1..face.len() - 1).map(move |i| [face[0], face[i], face[i + 1]])).collect()
+    }
+
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        let first = *self.positions.first()?;
+        Some(self.positions.iter().fold(Aabb::new(first, first), |acc, p| acc.surrounding(&Aabb::new(*p, *p))))
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Triangles of a mesh in a bounding volume hierarchy, shaded with normals interpolated from the
+/// vertices. `u` and `v` are interpolated from the texture coordinates of the mesh, or without them
+/// the barycentric coordinates of the second and third vertex.
+pub struct TriangleMesh {
+    positions: Vec<Point3>,
+    // Area-weighted average of the normals of the triangles around each vertex
+    normals: Vec<Vec3>,
+    texcoords: Vec<(Float, Float)>,
+    triangles: Vec<[usize; 3]>,
+    bvh: Bvh,
+    pub material: Material,
+}
+
+impl TriangleMesh {
+    pub fn new(mesh: &Mesh, material: Material) -> TriangleMesh {
+        let triangles = mesh.triangles();
+        let mut normals = vec![Vec3::new(0.0, 0.0, 0.0); mesh.positions.len()];
+        for &[a, b, c] in &triangles {
+            let p = &mesh.positions;
+            // Twice the area times the unit normal
+            let n = cross(&(p[b] - p[a]), &(p[c] - p[a]));
+            for i in [a, b, c] {
+                normals[i] += n;
+            }
+        }
+        for n in &mut normals {
+            if n.squared_length() > 0.0 {
+                *n = n.unit_vector();
+            }
+        }
+        let boxes: Vec<Aabb> = triangles.iter().map(|&[a, b, c]| {
+            let p = &mesh.positions;
+            Aabb::new(p[a], p[a]).surrounding(&Aabb::new(p[b], p[b])).surrounding(&Aabb::new(p[c], p[c]))
+        }).collect();
+        let texcoords = if mesh.texcoords.len() == mesh.positions.len() { mesh.texcoords.clone() } else { Vec::new() };
+        TriangleMesh { positions: mesh.positions.clone(), normals, texcoords, triangles, bvh: Bvh::new(&boxes), material }
+    }
+
+    fn intersect(&self, triangle: usize, r: &Ray, t_min: Float, t_max: Float) -> Option<(Float, Float, Float)> {
+        let [a, b, c] = self.triangles[triangle];
+        intersect_triangle([self.positions[a], self.positions[b], self.positions[c]], r, t_min, t_max)
+    }
+}
+
+/// Ray parameter and barycentric coordinates of the second and third vertex of the hit with a
+/// triangle. The test is watertight (Woop, Benthin and Wald 2013): a ray through an edge or
+/// vertex shared by several triangles hits at least one of them, so no rays slip through the
+/// cracks of a mesh.
+pub fn intersect_triangle(vertices: [Point3; 3], r: &Ray, t_min: Float, t_max: Float) -> Option<(Float, Float, Float)> {
+    // Move into ray space: the origin at zero, the axes permuted so that the largest component
+    // of the direction is z, and sheared so that the direction is +z
+    let d = r.direction.abs();
+    let kz = if d.x() > d.y() && d.x() > d.z() { 0 } else if d.y() > d.z() { 1 } else { 2 };
+    let (kx, ky) = ((kz + 1) % 3, (kz + 2) % 3);
+    let permute = |v: &Vec3| Vec3::new(v.e[kx], v.e[ky], v.e[kz]);
+    let d = permute(&r.direction);
+    let (sx, sy, sz) = (-d.x() / d.z(), -d.y() / d.z(), 1.0 / d.z());
+    let to_ray_space = |p: &Point3| {
+        let p = permute(&(*p - r.origin));
+        Vec3::new(p.x() + sx * p.z(), p.y() + sy * p.z(), sz * p.z())
+    };
+    let p = vertices.map(|v| to_ray_space(&v));
+
+    // Edge functions, twice the signed area of the triangle each edge makes with the ray. A
+    // zero may be rounding, so those are redone in double precision to get the sign right.
+    let edge = |i: usize, j: usize| {
+        let e = p[i].x() * p[j].y() - p[i].y() * p[j].x();
+        if e != 0.0 {
+            return e;
+        }
+        (p[i].x() as f64 * p[j].y() as f64 - p[i].y() as f64 * p[j].x() as f64) as Float
+    };
+    let e = [edge(1, 2), edge(2, 0), edge(0, 1)];
+    if e.iter().any(|&e| e < 0.0) && e.iter().any(|&e| e > 0.0) {
+        return None;
+    }
+    let det = e[0] + e[1] + e[2];
+    if det == 0.0 {
+        return None;
+    }
+    let t = (e[0] * p[0].z() + e[1] * p[1].z() + e[2] * p[2].z()) / det;
+    if t <= t_min || t >= t_max {
+        return None;
+    }
+
+    // Reject hits that are not certainly in front of the origin once the rounding of all of
+    // the above is accounted for (Pharr, Jakob and Humphreys, section 3.9.6)
+    let max_abs = |k: usize| p.iter().map(|q| q.e[k].abs()).fold(0.0, Float::max);
+    let (max_x, max_y, max_z) = (max_abs(0), max_abs(1), max_abs(2));
+    let delta_z = gamma(3) * max_z;
+    let delta_x = gamma(5) * (max_x + max_z);
+    let delta_y = gamma(5) * (max_y + max_z);
+    let delta_e = 2.0 * (gamma(2) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+    let max_e = e.iter().fold(0.0, |acc: Float, e| acc.max(e.abs()));
+    let delta_t = 3.0 * (gamma(3) * max_e * max_z + delta_e * max_z + delta_z * max_e) / det.abs();
+    if t <= delta_t {
+        return None;
+    }
+    Some((t, e[1] / det, e[2] / det))
+}
+
+
+impl Hitable for TriangleMesh {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let mut closest = None;
+        self.bvh.traverse(r, t_min, t_max, |triangle, t_max| {
+            let (t, u, v) = self.intersect(triangle, r, t_min, t_max)?;
+            closest = Some((triangle, t, u, v));
+            Some(t)
+        });
+        let (triangle, t, u, v) = closest?;
+        let [a, b, c] = self.triangles[triangle];
+        let mut normal = (1.0 - u - v) * self.normals[a] + u * self.normals[b] + v * self.normals[c];
+        if normal.squared_length() == 0.0 {
+            normal = cross(&(self.positions[b] - self.positions[a]), &(self.positions[c] - self.positions[a]));
+        }
+        let normal = normal.unit_vector();
+        let (e1, e2) = (self.positions[b] - self.positions[a], self.positions[c] - self.positions[a]);
+        let (mut tex_u, mut tex_v, mut edge) = (u, v, e1);
+        if !self.texcoords.is_empty() {
+            let (ta, tb, tc) = (self.texcoords[a], self.texcoords[b], self.texcoords[c]);
+            tex_u = (1.0 - u - v) * ta.0 + u * tb.0 + v * tc.0;
+            tex_v = (1.0 - u - v) * ta.1 + u * tb.1 + v * tc.1;
+            // The tangent follows the texture's u direction, dp/du, so normal maps line up
+            let (du1, dv1, du2, dv2) = (tb.0 - ta.0, tb.1 - ta.1, tc.0 - ta.0, tc.1 - ta.1);
+            let det = du1 * dv2 - du2 * dv1;
+            if det.abs() > 1e-12 {
+                edge = (dv2 * e1 - dv1 * e2) / det;
+            }
+        }
+        let along = edge - dot(&edge, &normal) * normal;
+        let tangent = if along.squared_length() > 1e-12 { along.unit_vector() } else { any_perpendicular(&normal) };
+        // The hitpoint from the barycentric coordinates lies on the triangle up to rounding
+        let weighted = [(1.0 - u - v) * self.positions[a], u * self.positions[b], v * self.positions[c]];
+        let p = weighted[0] + weighted[1] + weighted[2];
+        let error = gamma(7) * (weighted[0].abs() + weighted[1].abs() + weighted[2].abs());
+        Some(HitRecord { t, p, error, normal, u: tex_u, v: tex_v, tangent, on_edge: false, material: self.material.clone() })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::HitableList;
+    use crate::planar::Triangle;
+    #[test]
+    fn obj_cube_renders_as_triangles () {
+        let obj = "# unit cube\n\
+            v -1 -1 -1\nv 1 -1 -1\nv 1 1 -1\nv -1 1 -1\nv -1 -1 1\nv 1 -1 1\nv 1 1 1\nv -1 1 1\n\
+            f 1 4 3 2\nf 5 6 7 8\nf 1 2 6 5\nf 3 4 8 7\nf 2/1 3/2 7/3 6/4\nf -8//1 -4//1 -1//1 -5//1\n\
+            t crease 2/1/0 0 1 3.5\n";
+        let mesh = Mesh::parse_obj(obj.as_bytes()).unwrap();
+        assert_eq!((mesh.positions.len(), mesh.faces.len(), mesh.triangles().len()), (8, 6, 12));
+        assert_eq!((mesh.crease(1, 0), mesh.crease(0, 2)), (3.5, 0.0));
+        assert!(Mesh::parse_obj("v 0 0 0\nf 1 2 3\n".as_bytes()).is_err());
+
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        let cube = TriangleMesh::new(&mesh, material);
+        // Every face is hit from outside, at the right distance
+        for axis in 0..3 {
+            for side in [-1.0, 1.0] {
+                let mut origin = Vec3::new(0.2, -0.3, 0.1);
+                origin.e[axis] = 5.0 * side;
+                let mut direction = Vec3::new(0.0, 0.0, 0.0);
+                direction.e[axis] = -side;
+                let hit = cube.hit(&Ray::new(origin, direction), 0.001, Float::MAX).unwrap();
+                assert!((hit.t - 4.0).abs() < 1e-5);
+                // Vertex normals of a cube point out diagonally, but still away from the face
+                assert!(hit.normal.e[axis] * side > 0.3);
+            }
+        }
+        let bounds = cube.bounding_box().unwrap();
+        assert!((bounds.max - Vec3::new(1.0, 1.0, 1.0)).length() < 1e-6);
+        let miss = Ray::new(Vec3::new(1.5, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(cube.hit(&miss, 0.001, Float::MAX).is_none());
+    }
+
+    #[test]
+    fn rays_through_shared_edges_always_hit () {
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        for (scale, offset) in [(1e-3, Vec3::new(0.0, 0.0, 0.0)), (1.0, Vec3::new(-3.0, 1.0, 2.0)), (1e3, Vec3::new(5e3, 2e3, -7e3))] {
+            // A tilted parallelogram split along its diagonal, from (0, 0) to (1, 1)
+            let corner = |x: Float, z: Float| offset + scale * (x * Vec3::new(1.0, 0.3, 0.2) + z * Vec3::new(-0.2, 0.4, 1.1));
+            let mesh = Mesh {
+                positions: vec![corner(0.0, 0.0), corner(1.0, 0.0), corner(1.0, 1.0), corner(0.0, 1.0)],
+                faces: vec![vec![0, 1, 2, 3]],
+                ..Mesh::default()
+            };
+            // The same square as two separate triangles
+            let triangles = HitableList { list: vec![
+                Box::new(Triangle::new(corner(0.0, 0.0), corner(1.0, 0.0), corner(1.0, 1.0), material.clone())),
+                Box::new(Triangle::new(corner(0.0, 0.0), corner(1.0, 1.0), corner(0.0, 1.0), material.clone())),
+            ] };
+            let squares: [&dyn Hitable; 2] = [&TriangleMesh::new(&mesh, material.clone()), &triangles];
+            let direction = Vec3::new(0.3, -1.0, 0.1);
+            for square in squares {
+                for i in 1..1000 {
+                    let on_diagonal = corner(i as Float / 1000.0, i as Float / 1000.0);
+                    let r = Ray::new(on_diagonal - 2.0 * scale * direction, direction);
+                    let hit = square.hit(&r, 0.0, Float::MAX).expect("a ray through the shared edge hits");
+                    // Neither the reflected ray nor the one passing through hits the square again
+                    for leaving in [Vec3::new(0.3, 1.0, 0.1), direction] {
+                        assert!(square.hit(&hit.spawn_ray(leaving), 0.0, Float::MAX).is_none());
+                    }
+                }
+            }
+        }
+    }
+}
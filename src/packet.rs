@@ -0,0 +1,174 @@
+//! Spheres traced four rays at a time. A `SphereSet` keeps its spheres in a bounding volume
+//! hierarchy that the rays of a `RayPacket` traverse together, and intersects every sphere it
+//! reaches with all of them at once, one ray per SIMD lane. Coherent rays, such as the primary
+//! rays of neighbouring pixels, visit mostly the same nodes, so the packet pays for one traversal
+//! instead of four.
+//!
+//! Only the benchmark traces packets. Bounced rays scatter too much to share a traversal, and are
+//! slower in packets than one by one, while the renderer traces one path per sample through any
+//! kind of geometry, not just spheres.
+
+use crate::aabb::Aabb;
+use crate::bvh::Bvh;
+use crate::float::Float;
+use crate::hitable::{HitRecord, Hitable, Sphere};
+use crate::ray::Ray;
+use crate::simd::{Float4, Mask4};
+
+/// Number of rays in a packet.
+pub const PACKET_SIZE: usize = 4;
+
+/// Rays with their coordinates spread over the lanes: `origin[0]` holds the x of every origin.
+pub struct RayPacket {
+    origin: [Float4; 3],
+    direction: [Float4; 3],
+    inv_direction: [Float4; 3],
+}
+
+impl RayPacket {
+    pub fn new(rays: &[Ray; PACKET_SIZE]) -> RayPacket {
+        let origin = std::array::from_fn(|axis| Float4(std::array::from_fn(|i| rays[i].origin.e[axis])));
+        let direction: [Float4; 3] = std::array::from_fn(|axis| Float4(std::array::from_fn(|i| rays[i].direction.e[axis])));
+        let inv_direction = direction.map(|d| Float4::splat(1.0) / d);
+        RayPacket { origin, direction, inv_direction }
+    }
+
+    /// Whether the first ray goes towards negative `axis`, which picks the order the packet
+    /// visits the children of a node in.
+    pub fn is_negative(&self, axis: usize) -> bool {
+        self.direction[axis].0[0] < 0.0
+    }
+
+    /// The rays that pass through the box between `t_min` and their own `t_max` (slab test).
+    pub fn hits_box(&self, bounds: &Aabb, t_min: Float, t_max: Float4) -> Mask4 {
+        let (mut near, mut far) = (Float4::splat(t_min), t_max);
+        for axis in 0..3 {
+            let t0 = (Float4::splat(bounds.min.e[axis]) - self.origin[axis]) * self.inv_direction[axis];
+            let t1 = (Float4::splat(bounds.max.e[axis]) - self.origin[axis]) * self.inv_direction[axis];
+            // `min` and `max` return their second operand for NaN, from a ray in the plane of a
+            // slab, so that it does not shrink the interval
+            near = t0.min(t1).max(near);
+            far = t0.max(t1).min(far);
+        }
+        near.le(far)
+    }
+}
+
+/// Ray parameters of the first hit of each ray of the packet with `sphere` inside
+/// (`t_min`, `t_max`), infinite for rays that miss it, and the rays that only graze its outline.
+fn intersect(sphere: &Sphere, packet: &RayPacket, t_min: Float, t_max: Float4) -> (Float4, Mask4) {
+    let [ox, oy, oz]: [Float4; 3] = std::array::from_fn(|axis| packet.origin[axis] - Float4::splat(sphere.center.e[axis]));
+    let [dx, dy, dz] = packet.direction;
+    let a = dx * dx + dy * dy + dz * dz;
+    let half_b = ox * dx + oy * dy + oz * dz;
+    let c = ox * ox + oy * oy + oz * oz - Float4::splat(sphere.radius * sphere.radius);
+    // b²/4 - ac from the distance between the center and the line of the ray, which keeps the
+    // precision that the textbook formula loses for small or distant spheres
+    let k = half_b / a;
+    let (fx, fy, fz) = (ox - k * dx, oy - k * dy, oz - k * dz);
+    let distance = (fx * fx + fy * fy + fz * fz).sqrt();
+    let radius = Float4::splat(sphere.radius.abs());
+    let discriminant = a * (radius - distance) * (radius + distance);
+    // Rays that miss have a negative discriminant, which turns everything below into NaN and
+    // fails every comparison
+    let root = discriminant.sqrt();
+    let zero = Float4::splat(0.0);
+    let q = Float4::select(half_b.lt(zero), root - half_b, zero - half_b - root);
+    let (t0, t1) = (c / q, q / a);
+    let (near, far) = (t0.min(t1), t0.max(t1));
+    let t_min = Float4::splat(t_min);
+    let near_hit = near.gt(t_min).and(near.lt(t_max));
+    let far_hit = far.gt(t_min).and(far.lt(t_max));
+    let t = Float4::select(near_hit, near, Float4::select(far_hit, far, Float4::splat(Float::INFINITY)));
+    // The same threshold on b² - 4ac as `Sphere::hit`
+    (t, discriminant.lt(Float4::splat(0.0005 / 4.0)))
+}
+
+/// Spheres in a bounding volume hierarchy, for many rays through many spheres.
+pub struct SphereSet {
+    spheres: Vec<Sphere>,
+    bvh: Bvh,
+}
+
+impl SphereSet {
+    pub fn new(spheres: Vec<Sphere>) -> SphereSet {
+        let boxes: Vec<Aabb> = spheres.iter().filter_map(|s| s.bounding_box()).collect();
+        SphereSet { bvh: Bvh::new(&boxes), spheres }
+    }
+
+    /// Closest hit of each ray of the packet, as `hit` would find it for each ray on its own.
+    pub fn hit_packet(&self, rays: &[Ray; PACKET_SIZE], t_min: Float, t_max: Float) -> [Option<HitRecord>; PACKET_SIZE] {
+        let packet = RayPacket::new(rays);
+        let mut t_max = Float4::splat(t_max);
+        let mut closest = [None; PACKET_SIZE];
+        self.bvh.traverse_packet(&packet, t_min, &mut t_max, |i, t_max| {
+            let (t, on_edge) = intersect(&self.spheres[i], &packet, t_min, *t_max);
+            let closer = t.lt(*t_max);
+            for ((closest, &closer), &on_edge) in closest.iter_mut().zip(&closer.0).zip(&on_edge.0) {
+                if closer {
+                    *closest = Some((i, on_edge));
+                }
+            }
+            *t_max = t.min(*t_max);
+        });
+        std::array::from_fn(|lane| closest[lane].map(|(i, on_edge)| self.spheres[i].hit_record(&rays[lane], t_max.0[lane], on_edge)))
+    }
+}
+
+impl Hitable for SphereSet {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let mut closest = None;
+        self.bvh.traverse(r, t_min, t_max, |i, t_max| {
+            let hit_record = self.spheres[i].hit(r, t_min, t_max)?;
+            let t = hit_record.t;
+            closest = Some(hit_record);
+            Some(t)
+        });
+        closest
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use crate::vec::{Point3, Vec3};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    #[test]
+    fn packets_find_the_same_hits_as_single_rays () {
+        let mut rng = StdRng::seed_from_u64(5);
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        let spheres = (0..200).map(|_| Sphere {
+            center: Vec3::random_range(&mut rng, -10.0, 10.0),
+            radius: rng.gen_range(0.1..1.5),
+            material: material.clone(),
+        }).collect();
+        let set = SphereSet::new(spheres);
+        // Coherent packets through a small patch of directions, and incoherent ones in any direction
+        for spread in [0.05, 2.0] {
+            for _ in 0..500 {
+                let origin = Point3::new(0.0, 0.0, 25.0) + Vec3::random_range(&mut rng, -5.0, 5.0);
+                let towards = Vec3::random_range(&mut rng, -5.0, 5.0) - origin;
+                let rays = std::array::from_fn(|_| Ray::new(origin, towards + spread * towards.length() * Vec3::random_range(&mut rng, -1.0, 1.0)));
+                let hits = set.hit_packet(&rays, 0.0, Float::MAX);
+                for (ray, packet_hit) in rays.iter().zip(hits) {
+                    match (set.hit(ray, 0.0, Float::MAX), packet_hit) {
+                        (None, None) => {}
+                        (Some(a), Some(b)) => assert!((a.p - b.p).length() < 1e-3, "{} and {}", a.p, b.p),
+                        (a, b) => panic!("single ray hit: {}, packet hit: {}", a.is_some(), b.is_some()),
+                    }
+                }
+            }
+        }
+        // Nothing is hit before t_min or after t_max
+        let r = Ray::new(Point3::new(0.0, 0.0, 25.0), Vec3::new(0.0, 0.0, -1.0));
+        let rays = [Ray::new(r.origin, r.direction), Ray::new(r.origin, r.direction), Ray::new(r.origin, r.direction), Ray::new(r.origin, r.direction)];
+        assert!(set.hit_packet(&rays, 0.0, 1.0).iter().all(|h| h.is_none()));
+        assert!(set.hit_packet(&rays, 50.0, Float::MAX).iter().all(|h| h.is_none()));
+    }
+}
@@ -0,0 +1,215 @@
+//! Flat shapes: infinite planes, parallelograms, triangles and disks. Except for triangles, which
+//! use the watertight test of meshes, they intersect the ray with their plane first and then decide
+//! from the coordinates of the hitpoint in the plane whether it lies inside the shape.
+
+use crate::float::{Float, consts::PI};
+use crate::aabb::{Aabb, disk_extent};
+use crate::hitable::{HitRecord, Hitable, any_perpendicular};
+use crate::material::Material;
+use crate::mesh::intersect_triangle;
+use crate::ray::{Ray, gamma};
+use crate::vec::{Vec3, Point3, dot, cross};
+
+/// Plane through `origin` spanned by two edges, with points origin + a·edge_u + b·edge_v. The
+/// normal is along cross(edge_u, edge_v) and the same on both sides.
+#[derive(Debug, Clone, Copy)]
+struct PlanarFrame {
+    origin: Point3,
+    edge_u: Vec3,
+    edge_v: Vec3,
+    normal: Vec3,
+    // n / |n|² for n = cross(edge_u, edge_v), which turns cross products into the coordinates a, b
+    w: Vec3,
+}
+
+impl PlanarFrame {
+    fn new(origin: Point3, edge_u: Vec3, edge_v: Vec3) -> PlanarFrame {
+        let n = cross(&edge_u, &edge_v);
+        PlanarFrame { origin, edge_u, edge_v, normal: n.unit_vector(), w: n / dot(&n, &n) }
+    }
+
+    /// Ray parameter of the hit with the plane and the coordinates (a, b) of the hitpoint in the
+    /// basis of the edges.
+    fn intersect(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<(Float, Float, Float)> {
+        let denom = dot(&self.normal, &r.direction);
+        if denom.abs() < 1e-12 {
+            return None;
+        }
+        let t = dot(&self.normal, &(self.origin - r.origin)) / denom;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+        let d = r.point_at_parameter(t) - self.origin;
+        Some((t, dot(&self.w, &cross(&d, &self.edge_v)), dot(&self.w, &cross(&self.edge_u, &d))))
+    }
+
+    /// Hit record for the hit at plane coordinates (a, b). The hitpoint is rebuilt from them, which
+    /// puts it on the plane up to the rounding of that sum, however inaccurate `t` is.
+    fn hit_record(&self, t: Float, (a, b): (Float, Float), u: Float, v: Float, tangent: Vec3, material: &Material) -> HitRecord {
+        let (along_u, along_v) = (a * self.edge_u, b * self.edge_v);
+        let p = self.origin + along_u + along_v;
+        let error = gamma(3) * (self.origin.abs() + along_u.abs() + along_v.abs());
+        HitRecord { t, p, error, normal: self.normal, u, v, tangent, on_edge: false, material: material.clone() }
+    }
+
+    /// Box around the points with the given plane coordinates.
+    fn bounds(&self, corners: &[(Float, Float)]) -> Aabb {
+        let point = |&(a, b): &(Float, Float)| self.origin + a * self.edge_u + b * self.edge_v;
+        let first = point(&corners[0]);
+        corners[1..].iter().fold(Aabb::new(first, first), |acc, c| {
+            let p = point(c);
+            acc.surrounding(&Aabb::new(p, p))
+        })
+    }
+}
+
+/// Infinite plane through `point`. The texture coordinates are distances along two perpendicular
+/// directions in the plane, so textures repeat every unit.
+pub struct Plane {
+    frame: PlanarFrame,
+    pub material: Material,
+}
+
+impl Plane {
+    pub fn new(point: Point3, normal: Vec3, material: Material) -> Plane {
+        let normal = normal.unit_vector();
+        let edge_u = any_perpendicular(&normal);
+        Plane { frame: PlanarFrame::new(point, edge_u, cross(&normal, &edge_u)), material }
+    }
+}
+
+impl Hitable for Plane {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (t, a, b) = self.frame.intersect(r, t_min, t_max)?;
+        Some(self.frame.hit_record(t, (a, b), a, b, self.frame.edge_u, &self.material))
+    }
+}
+
+/// Parallelogram spanned by two edges from `corner`, with its normal along cross(edge_u, edge_v).
+/// `u` and `v` run from 0 to 1 along the edges.
+#[derive(Clone)]
+pub struct Quad {
+    frame: PlanarFrame,
+    pub material: Material,
+}
+
+impl Quad {
+    pub fn new(corner: Point3, edge_u: Vec3, edge_v: Vec3, material: Material) -> Quad {
+        Quad { frame: PlanarFrame::new(corner, edge_u, edge_v), material }
+    }
+}
+
+impl Hitable for Quad {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (t, a, b) = self.frame.intersect(r, t_min, t_max)?;
+        if !(0.0..=1.0).contains(&a) || !(0.0..=1.0).contains(&b) {
+            return None;
+        }
+        Some(self.frame.hit_record(t, (a, b), a, b, self.frame.edge_u.unit_vector(), &self.material))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.frame.bounds(&[(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]))
+    }
+}
+
+/// Triangle with its normal along cross(b - a, c - a). `u` and `v` are the barycentric
+/// coordinates of `b` and `c`. It is hit with the same watertight test as the triangles of a
+/// `TriangleMesh`, so triangles sharing an edge leave no gap along it.
+pub struct Triangle {
+    vertices: [Point3; 3],
+    frame: PlanarFrame,
+    pub material: Material,
+}
+
+impl Triangle {
+    pub fn new(a: Point3, b: Point3, c: Point3, material: Material) -> Triangle {
+        Triangle { vertices: [a, b, c], frame: PlanarFrame::new(a, b - a, c - a), material }
+    }
+}
+
+impl Hitable for Triangle {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (t, a, b) = intersect_triangle(self.vertices, r, t_min, t_max)?;
+        Some(self.frame.hit_record(t, (a, b), a, b, self.frame.edge_u.unit_vector(), &self.material))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.frame.bounds(&[(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]))
+    }
+}
+
+/// Flat disk, or an annulus with a hole in the middle. Its normal points along `normal` on both
+/// sides. `u` goes around the center, `v` from the inner to the outer rim.
+pub struct Disk {
+    // Edges of length `radius`, so that the rim is at distance one in plane coordinates
+    frame: PlanarFrame,
+    inner_radius: Float,
+    radius: Float,
+    pub material: Material,
+}
+
+impl Disk {
+    pub fn new(center: Point3, normal: Vec3, radius: Float, material: Material) -> Disk {
+        Disk::annulus(center, normal, 0.0, radius, material)
+    }
+
+    /// Ring between `inner_radius` and `radius`.
+    pub fn annulus(center: Point3, normal: Vec3, inner_radius: Float, radius: Float, material: Material) -> Disk {
+        assert!(0.0 <= inner_radius && inner_radius < radius, "invalid annulus radii {} and {}", inner_radius, radius);
+        let normal = normal.unit_vector();
+        let edge_u = any_perpendicular(&normal);
+        let frame = PlanarFrame::new(center, radius * edge_u, radius * cross(&normal, &edge_u));
+        Disk { frame, inner_radius, radius, material }
+    }
+}
+
+impl Hitable for Disk {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (t, a, b) = self.frame.intersect(r, t_min, t_max)?;
+        let rho = (a * a + b * b).sqrt() * self.radius;
+        if rho > self.radius || rho < self.inner_radius {
+            return None;
+        }
+        let u = (b.atan2(a) + PI) / (2.0 * PI);
+        let v = (rho - self.inner_radius) / (self.radius - self.inner_radius);
+        let around = -b * self.frame.edge_u + a * self.frame.edge_v;
+        let tangent = if around.squared_length() > 1e-12 { around.unit_vector() } else { self.frame.edge_u.unit_vector() };
+        Some(self.frame.hit_record(t, (a, b), u, v, tangent, &self.material))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::around(self.frame.origin, disk_extent(&self.frame.normal, self.radius)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn planar_shapes_share_coordinates () {
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        let down = |x: Float, z: Float| Ray::new(Point3::new(x, 5.0, z), Vec3::new(0.0, -1.0, 0.0));
+        // Lying flat at y = 1 and facing up
+        let quad = Quad::new(Point3::new(0.0, 1.0, 2.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -2.0), material.clone());
+        let triangle = Triangle::new(Point3::new(0.0, 1.0, 2.0), Point3::new(2.0, 1.0, 2.0), Point3::new(0.0, 1.0, 0.0), material.clone());
+
+        let hit = quad.hit(&down(1.5, 1.5), 0.001, Float::MAX).unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-5 && (hit.normal - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-6);
+        assert!((hit.u - 0.75).abs() < 1e-5 && (hit.v - 0.25).abs() < 1e-5);
+        // Past the diagonal of the triangle
+        assert!(triangle.hit(&down(1.5, 1.0), 0.001, Float::MAX).is_none());
+        let hit = triangle.hit(&down(0.5, 1.5), 0.001, Float::MAX).unwrap();
+        assert!((hit.u - 0.25).abs() < 1e-5 && (hit.v - 0.25).abs() < 1e-5);
+        assert!(quad.hit(&down(2.5, 1.0), 0.001, Float::MAX).is_none());
+        let bounds = triangle.bounding_box().unwrap();
+        assert!(bounds.hit(&down(0.5, 1.5), 0.001, Float::MAX));
+
+        // The ground plane is hit anywhere, however far away, and not from parallel rays
+        let ground = Plane::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), material.clone());
+        let hit = ground.hit(&down(1e5, -3e4), 0.001, Float::MAX).unwrap();
+        assert!((hit.t - 5.0).abs() < 1e-5 && hit.normal.y() > 0.999);
+        let level = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(ground.hit(&level, 0.001, Float::MAX).is_none());
+    }
+}
@@ -0,0 +1,257 @@
+//! Loading meshes from PLY (Stanford polygon) files, in ASCII or binary format.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::float::Float;
+use crate::mesh::Mesh;
+use crate::vec::Vec3;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("PLY: {}", msg))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn from_name(name: &str) -> Option<Scalar> {
+        match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+struct Property {
+    name: String,
+    // Type of the length of a list property, `None` for single values
+    list_count: Option<Scalar>,
+    value: Scalar,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Reads the values of the body one at a time, whatever the format.
+struct Body<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    format: Format,
+}
+
+impl<'a> Body<'a> {
+    fn read(&mut self, scalar: Scalar) -> io::Result<f64> {
+        if self.format == Format::Ascii {
+            while self.bytes.get(self.pos).is_some_and(|b| b.is_ascii_whitespace()) {
+                self.pos += 1;
+            }
+            let start = self.pos;
+            while self.bytes.get(self.pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+                self.pos += 1;
+            }
+            return std::str::from_utf8(&self.bytes[start..self.pos])
+                .ok()
+                .and_then(|w| w.parse().ok())
+                .ok_or_else(|| invalid_data(format!("bad value at byte {}", start)));
+        }
+        let size = scalar.size();
+        let raw = self.bytes.get(self.pos..self.pos + size).ok_or_else(|| invalid_data("unexpected end of data".to_string()))?;
+        self.pos += size;
+        let mut b = [0u8; 8];
+        b[..size].copy_from_slice(raw);
+        if self.format == Format::BigEndian {
+            b[..size].reverse();
+        }
+        Ok(match scalar {
+            Scalar::I8 => b[0] as i8 as f64,
+            Scalar::U8 => b[0] as f64,
+            Scalar::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::F64 => f64::from_le_bytes(b),
+        })
+    }
+}
+
+impl Mesh {
+    /// Read the vertices, faces and texture coordinates of a PLY file.
+    pub fn load_ply<P: AsRef<Path>>(path: P) -> io::Result<Mesh> {
+        Mesh::parse_ply(&fs::read(path)?)
+    }
+
+    /// Parse a PLY file. Vertices need `x`, `y` and `z`; texture coordinates are read from `u` and
+    /// `v` (or `s` and `t`), and faces from the `vertex_indices` list. Other elements and properties
+    /// are skipped.
+    pub fn parse_ply(bytes: &[u8]) -> io::Result<Mesh> {
+        const END: &[u8] = b"end_header";
+        let header_end = bytes.windows(END.len()).position(|w| w == END).ok_or_else(|| invalid_data("no end_header".to_string()))?;
+        let header = std::str::from_utf8(&bytes[..header_end]).map_err(|_| invalid_data("header is not text".to_string()))?;
+        // The body starts after the line ending of end_header
+        let mut body_start = header_end + END.len();
+        while bytes.get(body_start).is_some_and(|&b| b == b'\r' || b == b' ') {
+            body_start += 1;
+        }
+        body_start += 1;
+
+        let mut lines = header.lines();
+        if lines.next().map(str::trim) != Some("ply") {
+            return Err(invalid_data("missing 'ply' magic".to_string()));
+        }
+        let mut format = None;
+        let mut elements: Vec<Element> = Vec::new();
+        for line in lines {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let scalar = |name: &str| Scalar::from_name(name).ok_or_else(|| invalid_data(format!("unknown type '{}'", name)));
+            match words.as_slice() {
+                ["format", "ascii", _] => format = Some(Format::Ascii),
+                ["format", "binary_little_endian", _] => format = Some(Format::LittleEndian),
+                ["format", "binary_big_endian", _] => format = Some(Format::BigEndian),
+                ["element", name, count] => elements.push(Element {
+                    name: name.to_string(),
+                    count: count.parse().map_err(|_| invalid_data(format!("bad count in '{}'", line)))?,
+                    properties: Vec::new(),
+                }),
+                ["property", "list", count, value, name] => {
+                    let property = Property { name: name.to_string(), list_count: Some(scalar(count)?), value: scalar(value)? };
+                    elements.last_mut().ok_or_else(|| invalid_data("property before any element".to_string()))?.properties.push(property);
+                }
+                ["property", value, name] => {
+                    let property = Property { name: name.to_string(), list_count: None, value: scalar(value)? };
+                    elements.last_mut().ok_or_else(|| invalid_data("property before any element".to_string()))?.properties.push(property);
+                }
+                ["comment", ..] | ["obj_info", ..] | [] => {}
+                _ => return Err(invalid_data(format!("unexpected header line '{}'", line))),
+            }
+        }
+        let format = format.ok_or_else(|| invalid_data("missing format".to_string()))?;
+
+        let mut body = Body { bytes, pos: body_start.min(bytes.len()), format };
+        let mut mesh = Mesh::default();
+        let vertex_count = elements.iter().find(|e| e.name == "vertex").map_or(0, |e| e.count);
+        for element in &elements {
+            let find = |names: &[&str]| element.properties.iter().position(|p| names.contains(&p.name.as_str()));
+            let (x, y, z) = (find(&["x"]), find(&["y"]), find(&["z"]));
+            let (u, v) = (find(&["u", "s", "texture_u"]), find(&["v", "t", "texture_v"]));
+            let indices = find(&["vertex_indices", "vertex_index"]);
+            for _ in 0..element.count {
+                let mut values = Vec::with_capacity(element.properties.len());
+                let mut list = Vec::new();
+                for (i, property) in element.properties.iter().enumerate() {
+                    match property.list_count {
+                        None => values.push(body.read(property.value)?),
+                        Some(count) => {
+                            values.push(0.0);
+                            let count = body.read(count)? as usize;
+                            for _ in 0..count {
+                                let value = body.read(property.value)?;
+                                if Some(i) == indices {
+                                    list.push(value);
+                                }
+                            }
+                        }
+                    }
+                }
+                match element.name.as_str() {
+                    "vertex" => {
+                        let (x, y, z) = match (x, y, z) {
+                            (Some(x), Some(y), Some(z)) => (x, y, z),
+                            _ => return Err(invalid_data("vertices need x, y and z".to_string())),
+                        };
+                        mesh.positions.push(Vec3::new(values[x] as Float, values[y] as Float, values[z] as Float));
+                        if let (Some(u), Some(v)) = (u, v) {
+                            mesh.texcoords.push((values[u] as Float, values[v] as Float));
+                        }
+                    }
+                    "face" => {
+                        if list.len() < 3 {
+                            return Err(invalid_data("a face needs at least three vertices".to_string()));
+                        }
+                        let face = list.iter()
+                            .map(|&i| {
+                                // `as i64` saturates, so that only whole numbers in range get through
+                                Some(i).filter(|i| i.fract() == 0.0).and_then(|i| usize::try_from(i as i64).ok())
+                                    .filter(|&i| i < vertex_count)
+                                    .ok_or_else(|| invalid_data(format!("face index {} out of range", i)))
+                            })
+                            .collect::<io::Result<Vec<usize>>>()?;
+                        mesh.faces.push(face);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(mesh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn ascii_and_binary_ply_agree () {
+        let ascii = "ply\nformat ascii 1.0\ncomment a unit square\nelement vertex 4\n\
+            property float x\nproperty float y\nproperty float z\nproperty uchar red\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+            0 0 0 255\n1 0 0 0\n1 1 0 0\n0 1 0 0\n4 0 1 2 3\n";
+        let mut binary = b"ply\nformat binary_big_endian 1.0\nelement vertex 4\n\
+            property float x\nproperty float y\nproperty float z\nproperty uchar red\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n".to_vec();
+        for [x, y] in [[0.0f32, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]] {
+            for c in [x, y, 0.0] {
+                binary.extend_from_slice(&c.to_be_bytes());
+            }
+            binary.push(0);
+        }
+        binary.push(4);
+        for i in 0..4i32 {
+            binary.extend_from_slice(&i.to_be_bytes());
+        }
+        for bytes in [ascii.as_bytes(), &binary] {
+            let mesh = Mesh::parse_ply(bytes).unwrap();
+            assert_eq!(mesh.faces, vec![vec![0, 1, 2, 3]]);
+            assert!((mesh.positions[2] - Vec3::new(1.0, 1.0, 0.0)).length() < 1e-6);
+        }
+        assert!(Mesh::parse_ply(ascii.replace("4 0 1 2 3", "3 0 1 9").as_bytes()).is_err());
+        assert!(Mesh::parse_ply(ascii.replace("4 0 1 2 3", "3 0 1 -1").as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+//! PNG decoding, with the inflate algorithm it needs, for textures that come with models.
+//!
+//! All color types and bit depths are read, alpha is dropped. Interlaced images are not supported.
+
+use std::io;
+
+use crate::float::Float;
+use crate::texture::ImageTexture;
+use crate::vec::Vec3;
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+pub fn is_png(bytes: &[u8]) -> bool {
+    bytes.starts_with(SIGNATURE)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("PNG: {}", msg))
+}
+
+/// Decode a PNG file into an image with channel values in [0, 1], not gamma corrected.
+pub fn decode(bytes: &[u8]) -> io::Result<ImageTexture> {
+    if !is_png(bytes) {
+        return Err(invalid_data("missing signature"));
+    }
+    let mut pos = SIGNATURE.len();
+    let (mut width, mut height, mut depth, mut color_type) = (0, 0, 0, 0);
+    let mut palette: Vec<Vec3> = Vec::new();
+    let mut compressed = Vec::new();
+    while pos + 8 <= bytes.len() {
+        let length = u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let kind = &bytes[pos + 4..pos + 8];
+        let data = bytes.get(pos + 8..pos + 8 + length).ok_or_else(|| invalid_data("truncated chunk"))?;
+        // Skip the data and the checksum
+        pos += 12 + length;
+        match kind {
+            b"IHDR" => {
+                if data.len() < 13 {
+                    return Err(invalid_data("short header"));
+                }
+                width = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                height = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+                depth = data[8] as usize;
+                color_type = data[9];
+                if data[12] != 0 {
+                    return Err(invalid_data("interlaced images are not supported"));
+                }
+            }
+            b"PLTE" => palette = data.chunks_exact(3).map(|c| Vec3::new(c[0] as Float, c[1] as Float, c[2] as Float) / 255.0).collect(),
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+    let channels = match color_type {
+        0 | 3 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return Err(invalid_data("unknown color type")),
+    };
+    if width == 0 || height == 0 || ![1, 2, 4, 8, 16].contains(&depth) {
+        return Err(invalid_data("bad image size or bit depth"));
+    }
+    if compressed.len() < 2 {
+        return Err(invalid_data("no image data"));
+    }
+    // Skip the two byte zlib header
+    let raw = inflate(&compressed[2..])?;
+
+    let bits_per_pixel = channels * depth;
+    let stride = (width * bits_per_pixel).div_ceil(8);
+    // Distance to the corresponding byte of the pixel to the left, at least one
+    let bpp = bits_per_pixel.div_ceil(8);
+    if raw.len() < height * (stride + 1) {
+        return Err(invalid_data("image data too short"));
+    }
+    let mut rows = vec![0u8; height * stride];
+    for y in 0..height {
+        let filter = raw[y * (stride + 1)];
+        let line = &raw[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        let (done, current) = rows.split_at_mut(y * stride);
+        let previous = if y > 0 { &done[(y - 1) * stride..] } else { &[][..] };
+        let current = &mut current[..stride];
+        for x in 0..stride {
+            let a = if x >= bpp { current[x - bpp] as i16 } else { 0 };
+            let b = previous.get(x).map_or(0, |&b| b as i16);
+            let c = if x >= bpp { previous.get(x - bpp).map_or(0, |&c| c as i16) } else { 0 };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => (a + b) / 2,
+                4 => {
+                    let p = a + b - c;
+                    let (pa, pb, pc) = ((p - a).abs(), (p - b).abs(), (p - c).abs());
+                    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+                }
+                _ => return Err(invalid_data("unknown filter")),
+            };
+            current[x] = line[x].wrapping_add(predicted as u8);
+        }
+    }
+
+    let max = ((1u32 << depth) - 1) as Float;
+    let sample = |row: &[u8], index: usize| -> u32 {
+        match depth {
+            16 => u16::from_be_bytes([row[2 * index], row[2 * index + 1]]) as u32,
+            8 => row[index] as u32,
+            _ => {
+                let bit = index * depth;
+                ((row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8) as u32
+            }
+        }
+    };
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in rows.chunks_exact(stride) {
+        for x in 0..width {
+            let s = |c: usize| sample(row, x * channels + c);
+            pixels.push(match color_type {
+                3 => *palette.get(s(0) as usize).ok_or_else(|| invalid_data("palette index out of range"))?,
+                0 | 4 => {
+                    let g = s(0) as Float / max;
+                    Vec3::new(g, g, g)
+                }
+                _ => Vec3::new(s(0) as Float / max, s(1) as Float / max, s(2) as Float / max),
+            });
+        }
+    }
+    Ok(ImageTexture { width, height, pixels })
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self.bytes.get(self.pos).ok_or_else(|| invalid_data("compressed data ends early"))?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+}
+
+/// Canonical Huffman code, stored as the number of codes of each length and the symbols in code
+/// order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<(u8, u16)> = lengths.iter().enumerate().filter(|(_, &l)| l > 0).map(|(s, &l)| (l, s as u16)).collect();
+        symbols.sort();
+        Huffman { counts, symbols: symbols.into_iter().map(|(_, s)| s).collect() }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid_data("bad Huffman code"))
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// Order in which the lengths of the code length code are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Decompress a raw deflate stream (RFC 1951).
+pub fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut reader = BitReader { bytes: data, pos: 0, bit: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                // Stored block, starting at the next byte
+                if reader.bit > 0 {
+                    reader.bit = 0;
+                    reader.pos += 1;
+                }
+                let header = data.get(reader.pos..reader.pos + 4).ok_or_else(|| invalid_data("truncated stored block"))?;
+                let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let block = data.get(reader.pos + 4..reader.pos + 4 + length).ok_or_else(|| invalid_data("truncated stored block"))?;
+                out.extend_from_slice(block);
+                reader.pos += 4 + length;
+            }
+            1 => {
+                let mut lengths = [8u8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                inflate_block(&mut reader, &mut out, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            }
+            2 => {
+                let literal_count = reader.bits(5)? as usize + 257;
+                let distance_count = reader.bits(5)? as usize + 1;
+                let code_length_count = reader.bits(4)? as usize + 4;
+                let mut code_lengths = [0u8; 19];
+                for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+                    code_lengths[i] = reader.bits(3)? as u8;
+                }
+                let code_length_code = Huffman::new(&code_lengths);
+                let mut lengths = Vec::with_capacity(literal_count + distance_count);
+                while lengths.len() < literal_count + distance_count {
+                    let symbol = code_length_code.decode(&mut reader)?;
+                    let (value, repeat) = match symbol {
+                        0..=15 => (symbol as u8, 1),
+                        16 => (*lengths.last().ok_or_else(|| invalid_data("repeat without a length"))?, 3 + reader.bits(2)?),
+                        17 => (0, 3 + reader.bits(3)?),
+                        _ => (0, 11 + reader.bits(7)?),
+                    };
+                    lengths.extend(std::iter::repeat_n(value, repeat as usize));
+                }
+                let literals = Huffman::new(&lengths[..literal_count]);
+                let distances = Huffman::new(&lengths[literal_count..literal_count + distance_count]);
+                inflate_block(&mut reader, &mut out, &literals, &distances)?;
+            }
+            _ => return Err(invalid_data("bad block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn inflate_block(reader: &mut BitReader, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> io::Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                if i >= LENGTH_BASE.len() {
+                    return Err(invalid_data("bad length code"));
+                }
+                let length = LENGTH_BASE[i] as usize + reader.bits(LENGTH_EXTRA[i] as u32)? as usize;
+                let d = distances.decode(reader)? as usize;
+                if d >= DISTANCE_BASE.len() {
+                    return Err(invalid_data("bad distance code"));
+                }
+                let distance = DISTANCE_BASE[d] as usize + reader.bits(DISTANCE_EXTRA[d] as u32)? as usize;
+                if distance > out.len() {
+                    return Err(invalid_data("distance before the start of the data"));
+                }
+                // Copies may overlap their own output, so go byte by byte
+                let start = out.len() - distance;
+                for k in 0..length {
+                    out.push(out[start + k]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn decodes_filtered_images_and_inflates () {
+        // 2x2 RGB image with Sub and Up filters, in a stored deflate block
+        let raw = [1, 255, 0, 0, 1, 0, 255, 2, 0, 0, 255, 0, 0, 0];
+        let mut png = SIGNATURE.to_vec();
+        let mut chunk = |kind: &[u8], data: &[u8]| {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            png.extend_from_slice(kind);
+            png.extend_from_slice(data);
+            png.extend_from_slice(&[0; 4]);
+        };
+        chunk(b"IHDR", &[0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        let mut zlib = vec![0x78, 0x01, 1, raw.len() as u8, 0, !(raw.len() as u8), 0xff];
+        zlib.extend_from_slice(&raw);
+        chunk(b"IDAT", &zlib);
+        chunk(b"IEND", &[]);
+        let image = decode(&png).unwrap();
+        assert_eq!((image.width, image.height), (2, 2));
+        let expected = [(1.0, 0.0, 0.0), (0.0, 0.0, 1.0), (1.0, 0.0, 1.0), (0.0, 0.0, 1.0)];
+        for (pixel, (r, g, b)) in image.pixels.iter().zip(expected) {
+            assert!((*pixel - Vec3::new(r, g, b)).length() < 1e-6, "{}", pixel);
+        }
+
+        // Fixed Huffman block with a back reference: "abcabcabc"
+        let compressed = [0x4b, 0x4c, 0x4a, 0x4e, 0x04, 0x23, 0x00];
+        assert_eq!(inflate(&compressed).unwrap(), b"abcabcabc");
+    }
+}
@@ -0,0 +1,335 @@
+use crate::float::{Float, consts::PI};
+use crate::aabb::{Aabb, disk_extent};
+use crate::hitable::{HitRecord, Hitable, any_perpendicular};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::roots::{solve_quadratic, solve_quartic};
+use crate::vec::{Vec3, Point3, dot, cross};
+
+/// Orthonormal frame of a shape that is symmetric around an axis. The shapes below are intersected
+/// in this frame, where the axis is the local y axis.
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    origin: Point3,
+    u: Vec3,
+    axis: Vec3,
+    w: Vec3,
+}
+
+impl Frame {
+    fn new(origin: Point3, axis: Vec3) -> Frame {
+        let axis = axis.unit_vector();
+        let u = any_perpendicular(&axis);
+        let w = cross(&u, &axis);
+        Frame { origin, u, axis, w }
+    }
+
+    fn local(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(dot(v, &self.u), dot(v, &self.axis), dot(v, &self.w))
+    }
+
+    /// The ray in local coordinates. The frame is orthonormal, so ray parameters stay the same.
+    fn local_ray(&self, r: &Ray) -> Ray {
+        Ray::new(self.local(&(r.origin - self.origin)), self.local(&r.direction))
+    }
+
+    fn world(&self, v: &Vec3) -> Vec3 {
+        v.x() * self.u + v.y() * self.axis + v.z() * self.w
+    }
+
+    /// Hit record from a hit found in local coordinates.
+    fn hit_record(&self, r: &Ray, t: Float, local: LocalHit, material: &Material) -> HitRecord {
+        let (p, error) = r.point_with_error(t);
+        HitRecord {
+            t,
+            p,
+            error,
+            normal: self.world(&local.normal).unit_vector(),
+            u: local.u,
+            v: local.v,
+            tangent: self.world(&local.tangent),
+            on_edge: false,
+            material: material.clone(),
+        }
+    }
+}
+
+/// Surface attributes of a hit in the local frame of a shape.
+struct LocalHit {
+    normal: Vec3,
+    u: Float,
+    v: Float,
+    tangent: Vec3,
+}
+
+/// Angle around the local y axis as a texture coordinate in [0, 1], starting from -x like on a
+/// sphere, and the direction in which it increases.
+fn around_axis(p: &Vec3) -> (Float, Vec3) {
+    let phi = (-p.z()).atan2(p.x()) + PI;
+    let dpdu = Vec3::new(p.z(), 0.0, -p.x());
+    let tangent = if dpdu.squared_length() > 1e-12 { dpdu.unit_vector() } else { Vec3::new(1.0, 0.0, 0.0) };
+    (phi / (2.0 * PI), tangent)
+}
+
+/// Truncated cone around the local y axis, with radius `bottom_radius` at y = 0 and `top_radius`
+/// at y = `height`. Covers cylinders (equal radii) and cones (a zero radius).
+#[derive(Debug, Clone, Copy)]
+struct Frustum {
+    frame: Frame,
+    bottom_radius: Float,
+    top_radius: Float,
+    height: Float,
+    capped: bool,
+}
+
+impl Frustum {
+    fn new(bottom: Point3, top: Point3, bottom_radius: Float, top_radius: Float) -> Frustum {
+        let axis = top - bottom;
+        assert!(axis.length() > 0.0, "the top of a cylinder or cone must differ from its bottom");
+        assert!(bottom_radius >= 0.0 && top_radius >= 0.0 && bottom_radius.max(top_radius) > 0.0,
+            "invalid radii {} and {}", bottom_radius, top_radius);
+        Frustum { frame: Frame::new(bottom, axis), bottom_radius, top_radius, height: axis.length(), capped: true }
+    }
+
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<(Float, LocalHit)> {
+        let local = self.frame.local_ray(r);
+        let (o, d) = (local.origin, local.direction);
+        let (r0, h) = (self.bottom_radius, self.height);
+        // Radius shrinks by k per unit of height: x² + z² = (r0 - k·y)²
+        let k = (self.bottom_radius - self.top_radius) / h;
+        let mut nearest: Option<(Float, LocalHit)> = None;
+
+        let radius_at_origin = (r0 - k * o.y()) as f64;
+        let (ox, oz, dx, dy, dz) = (o.x() as f64, o.z() as f64, d.x() as f64, d.y() as f64, d.z() as f64);
+        let (k, kk) = (k as f64, (k * k) as f64);
+        let a = dx * dx + dz * dz - kk * dy * dy;
+        let b = 2.0 * (ox * dx + oz * dz + k * radius_at_origin * dy);
+        let c = ox * ox + oz * oz - radius_at_origin * radius_at_origin;
+        if let Some((t0, t1)) = solve_quadratic(c, b, a) {
+            for t in [t0 as Float, t1 as Float] {
+                let y = o.y() + t * d.y();
+                if t > t_min && t < t_max && (0.0..=h).contains(&y) && nearest.is_none() {
+                    let p = local.point_at_parameter(t);
+                    let radius = r0 - k as Float * y;
+                    let (u, tangent) = around_axis(&p);
+                    let mut normal = Vec3::new(p.x(), k as Float * radius, p.z());
+                    if normal.squared_length() == 0.0 {
+                        // Right at the tip of a cone, point along the axis away from the cone
+                        normal = Vec3::new(0.0, k.signum() as Float, 0.0);
+                    }
+                    nearest = Some((t, LocalHit { normal, u, v: y / h, tangent }));
+                }
+            }
+        }
+
+        if self.capped && d.y() != 0.0 {
+            for (y, radius, normal_y) in [(0.0, r0, -1.0), (h, self.top_radius, 1.0)] {
+                // The tip of a cone has no cap
+                if radius == 0.0 {
+                    continue;
+                }
+                let t = (y - o.y()) / d.y();
+                let limit = nearest.as_ref().map_or(t_max, |n| n.0);
+                if t <= t_min || t >= limit {
+                    continue;
+                }
+                let p = local.point_at_parameter(t);
+                let rho = (p.x() * p.x() + p.z() * p.z()).sqrt();
+                if rho <= radius {
+                    let (u, tangent) = around_axis(&p);
+                    nearest = Some((t, LocalHit { normal: Vec3::new(0.0, normal_y, 0.0), u, v: rho / radius, tangent }));
+                }
+            }
+        }
+        nearest
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let top = self.frame.origin + self.height * self.frame.axis;
+        Aabb::around(self.frame.origin, disk_extent(&self.frame.axis, self.bottom_radius))
+            .surrounding(&Aabb::around(top, disk_extent(&self.frame.axis, self.top_radius)))
+    }
+}
+
+/// Cylinder between two points, closed by flat caps unless made `open`. `u` goes around the axis,
+/// `v` from the bottom to the top on the side and from the center to the rim on the caps.
+pub struct Cylinder {
+    shape: Frustum,
+    pub material: Material,
+}
+
+impl Cylinder {
+    pub fn new(bottom: Point3, top: Point3, radius: Float, material: Material) -> Cylinder {
+        Cylinder { shape: Frustum::new(bottom, top, radius, radius), material }
+    }
+
+    /// Leave out the caps, for a tube.
+    pub fn open(mut self) -> Cylinder {
+        self.shape.capped = false;
+        self
+    }
+}
+
+impl Hitable for Cylinder {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (t, local) = self.shape.hit(r, t_min, t_max)?;
+        Some(self.shape.frame.hit_record(r, t, local, &self.material))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.shape.bounding_box())
+    }
+}
+
+/// Cone with a circular base, or a truncated cone between two radii. Texture coordinates as for
+/// `Cylinder`.
+pub struct Cone {
+    shape: Frustum,
+    pub material: Material,
+}
+
+impl Cone {
+    /// Cone from the center of its base to its tip, closed at the base.
+    pub fn new(base: Point3, apex: Point3, radius: Float, material: Material) -> Cone {
+        Cone { shape: Frustum::new(base, apex, radius, 0.0), material }
+    }
+
+    /// Truncated cone between two circles around the line from `bottom` to `top`.
+    pub fn frustum(bottom: Point3, top: Point3, bottom_radius: Float, top_radius: Float, material: Material) -> Cone {
+        Cone { shape: Frustum::new(bottom, top, bottom_radius, top_radius), material }
+    }
+
+    pub fn open(mut self) -> Cone {
+        self.shape.capped = false;
+        self
+    }
+}
+
+impl Hitable for Cone {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (t, local) = self.shape.hit(r, t_min, t_max)?;
+        Some(self.shape.frame.hit_record(r, t, local, &self.material))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.shape.bounding_box())
+    }
+}
+
+/// Ring-shaped torus around `axis`: a tube of radius `minor_radius` whose center line is a circle
+/// of radius `major_radius`. `u` goes around the axis, `v` around the tube starting from the
+/// inside.
+pub struct Torus {
+    frame: Frame,
+    major_radius: Float,
+    minor_radius: Float,
+    pub material: Material,
+}
+
+impl Torus {
+    pub fn new(center: Point3, axis: Vec3, major_radius: Float, minor_radius: Float, material: Material) -> Torus {
+        Torus { frame: Frame::new(center, axis), major_radius, minor_radius, material }
+    }
+}
+
+impl Hitable for Torus {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let local = self.frame.local_ray(r);
+        let (big_r, small_r) = (self.major_radius as f64, self.minor_radius as f64);
+        let o = [local.origin.x() as f64, local.origin.y() as f64, local.origin.z() as f64];
+        let length = local.direction.length() as f64;
+        let d = [local.direction.x() as f64 / length, local.direction.y() as f64 / length, local.direction.z() as f64 / length];
+        let dot3 = |a: &[f64; 3], b: &[f64; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+
+        // Only look for roots inside the bounding sphere, and move the origin next to it. Far away
+        // origins make the quartic coefficients span a huge range and lose the roots to rounding.
+        let bound = big_r + small_r;
+        let (s0, s1) = solve_quadratic(dot3(&o, &o) - bound * bound, 2.0 * dot3(&o, &d), 1.0)?;
+        let lo = s0.max(t_min as f64 * length);
+        let hi = s1.min(t_max as f64 * length);
+        if lo >= hi {
+            return None;
+        }
+        let o = [o[0] + lo * d[0], o[1] + lo * d[1], o[2] + lo * d[2]];
+
+        // (|p|² - R² - r²)² - 4R²(r² - y²) = 0 along p = o + s·d, with |d| = 1
+        let e = dot3(&o, &o) - big_r * big_r - small_r * small_r;
+        let f = dot3(&o, &d);
+        let four_rr = 4.0 * big_r * big_r;
+        let coefficients = [
+            e * e - four_rr * (small_r * small_r - o[1] * o[1]),
+            4.0 * f * e + 2.0 * four_rr * o[1] * d[1],
+            2.0 * e + 4.0 * f * f + four_rr * d[1] * d[1],
+            4.0 * f,
+            1.0,
+        ];
+        let (roots, count) = solve_quartic(coefficients, 0.0, hi - lo);
+        let t = roots[..count].iter().map(|s| ((lo + s) / length) as Float).find(|&t| t > t_min && t < t_max)?;
+
+        let p = local.point_at_parameter(t);
+        // Normal: away from the nearest point on the center circle of the tube
+        let rho = (p.x() * p.x() + p.z() * p.z()).sqrt();
+        let center = if rho > 0.0 { self.major_radius / rho * Vec3::new(p.x(), 0.0, p.z()) } else { Vec3::new(0.0, 0.0, 0.0) };
+        let (u, tangent) = around_axis(&p);
+        let theta = p.y().atan2(self.major_radius - rho);
+        let local_hit = LocalHit { normal: p - center, u, v: (theta + PI) / (2.0 * PI), tangent };
+        Some(self.frame.hit_record(r, t, local_hit, &self.material))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let tube = Vec3::new(self.minor_radius, self.minor_radius, self.minor_radius);
+        Some(Aabb::around(self.frame.origin, disk_extent(&self.frame.axis, self.major_radius) + tube))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planar::Disk;
+    #[test]
+    fn shapes_hit_with_outward_normals () {
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let shapes: Vec<Box<dyn Hitable>> = vec![
+            Box::new(Cylinder::new(Point3::new(0.0, -1.0, 0.0), Point3::new(0.0, 1.0, 0.0), 0.5, material.clone())),
+            Box::new(Cone::frustum(Point3::new(0.0, -1.0, 0.0), Point3::new(0.0, 1.0, 0.0), 0.7, 0.3, material.clone())),
+            Box::new(Disk::annulus(Point3::new(-0.5, 0.0, 0.3), Vec3::new(-1.0, 0.0, 0.0), 0.2, 0.5, material.clone())),
+            Box::new(Torus::new(Point3::new(0.0, 0.0, 0.0), up, 0.35, 0.15, material.clone())),
+        ];
+        // From the side along +x, every shape is first hit at x = -0.5 with its normal facing back
+        let r = Ray::new(Point3::new(-1e4, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        for shape in &shapes {
+            let hit = shape.hit(&r, 0.001, Float::MAX).unwrap();
+            assert!((hit.p.x() + 0.5).abs() < 1e-3, "hit at {}", hit.p);
+            // The cone is slanted
+            assert!(dot(&hit.normal, &Vec3::new(-1.0, 0.0, 0.0)) > 0.98);
+            assert!((0.0..=1.0).contains(&hit.u) && (0.0..=1.0).contains(&hit.v));
+            let bounds = shape.bounding_box().unwrap();
+            assert!(bounds.hit(&r, 0.001, Float::MAX));
+        }
+        // Through the hole of the torus and the annulus
+        let down = Ray::new(Point3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(shapes[3].hit(&down, 0.001, Float::MAX).is_none());
+        let along = Ray::new(Point3::new(-5.0, 0.0, 0.3), Vec3::new(1.0, 0.0, 0.0));
+        assert!(shapes[2].hit(&along, 0.001, Float::MAX).is_none());
+        // Capped cylinder seen from above hits the top cap
+        let hit = shapes[0].hit(&down, 0.001, Float::MAX).unwrap();
+        assert!((hit.p.y() - 1.0).abs() < 1e-5 && (hit.normal - up).length() < 1e-5);
+    }
+
+    #[test]
+    fn degenerate_shapes_are_rejected () {
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        let p = Point3::new(1.0, 2.0, 3.0);
+        let up = p + Vec3::new(0.0, 1.0, 0.0);
+        assert!(std::panic::catch_unwind(|| Cone::frustum(p, p, 1.0, 0.5, material.clone())).is_err());
+        assert!(std::panic::catch_unwind(|| Cylinder::new(p, up, 0.0, material.clone())).is_err());
+        assert!(std::panic::catch_unwind(|| Cone::frustum(p, up, -1.0, 0.5, material.clone())).is_err());
+        assert!(std::panic::catch_unwind(|| crate::planar::Disk::annulus(p, up, 1.0, 1.0, material.clone())).is_err());
+        // Looking down on the tip of a cone hits its side, without a cap there and with a normal
+        // along the axis
+        let cone = Cone::new(p, up, 1.0, material);
+        let hit = cone.hit(&Ray::new(up + Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0)), 0.0, Float::MAX).unwrap();
+        assert!(hit.u.is_finite() && hit.v.is_finite() && hit.normal.y() > 0.0, "{} {} {} {}", hit.p, hit.normal, hit.u, hit.v);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::float::{Float, consts::PI};
+use crate::camera::Camera;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::vec::{Vec3, Point3, cross};
+
+/// Anything that turns a position on the image into a primary ray. `s` runs from the left to the
+/// right edge of the image and `t` from the bottom to the top, both in [0, 1].
+pub trait Projection: Send + Sync {
+    /// The ray through image position (s, t), or `None` if that position does not see the scene,
+    /// such as the corners outside the image circle of a fisheye lens. Lens sampling draws from
+    /// `sampler`.
+    fn get_ray(&self, s: Float, t: Float, sampler: &mut dyn Sampler) -> Option<Ray>;
+}
+
+impl Projection for Camera {
+    fn get_ray(&self, s: Float, t: Float, sampler: &mut dyn Sampler) -> Option<Ray> {
+        Some(Camera::get_ray(self, s, t, sampler))
+    }
+}
+
+/// Right, up and backward unit vectors of a camera at `lookfrom` looking at `lookat`.
+pub fn view_basis(lookfrom: &Point3, lookat: &Point3, vup: &Vec3) -> (Vec3, Vec3, Vec3) {
+    let w = Vec3::unit_vector(&(*lookfrom - *lookat));
+    let u = Vec3::unit_vector(&cross(vup, &w));
+    let v = cross(&w, &u);
+    (u, v, w)
+}
+
+/// Parallel projection, where every ray travels in the viewing direction. Sizes do not shrink with
+/// distance, which is what technical drawings need.
+pub struct OrthographicCamera {
+    lower_left_corner: Point3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    direction: Vec3,
+}
+
+impl OrthographicCamera {
+    /// `view_height` is the height of the visible area in world units.
+    pub fn new(lookfrom: Point3, lookat: Point3, vup: Vec3, view_height: Float, aspect_ratio: Float) -> OrthographicCamera {
+        let (u, v, w) = view_basis(&lookfrom, &lookat, &vup);
+        let horizontal = aspect_ratio * view_height * u;
+        let vertical = view_height * v;
+        OrthographicCamera {
+            lower_left_corner: lookfrom - horizontal / 2.0 - vertical / 2.0,
+            horizontal,
+            vertical,
+            direction: -1.0 * w,
+        }
+    }
+}
+
+impl Projection for OrthographicCamera {
+    fn get_ray(&self, s: Float, t: Float, _sampler: &mut dyn Sampler) -> Option<Ray> {
+        Some(Ray::new(self.lower_left_corner + s * self.horizontal + t * self.vertical, self.direction))
+    }
+}
+
+/// Equidistant fisheye, where the angle from the viewing direction grows linearly with the
+/// distance from the image center. The image circle touches the top and bottom of the image.
+pub struct FisheyeCamera {
+    origin: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    // Angle covered by the image circle, in radians
+    fov: Float,
+    aspect_ratio: Float,
+}
+
+impl FisheyeCamera {
+    /// `fov` is the angle covered by the image circle in degrees, 180 for a classic fisheye.
+    pub fn new(lookfrom: Point3, lookat: Point3, vup: Vec3, fov: Float, aspect_ratio: Float) -> FisheyeCamera {
+        let (u, v, w) = view_basis(&lookfrom, &lookat, &vup);
+        FisheyeCamera { origin: lookfrom, u, v, w, fov: fov / 180.0 * PI, aspect_ratio }
+    }
+}
+
+impl Projection for FisheyeCamera {
+    fn get_ray(&self, s: Float, t: Float, _sampler: &mut dyn Sampler) -> Option<Ray> {
+        let x = (s - 0.5) * self.aspect_ratio;
+        let y = t - 0.5;
+        // Distance from the center, 1.0 on the image circle
+        let r = (x * x + y * y).sqrt() / 0.5;
+        if r > 1.0 {
+            return None;
+        }
+        let theta = r * self.fov / 2.0;
+        let phi = y.atan2(x);
+        let direction = theta.sin() * (phi.cos() * self.u + phi.sin() * self.v) - theta.cos() * self.w;
+        Some(Ray::new(self.origin, direction))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PanoramaLayout {
+    // Longitude along the width and latitude along the height, for a 2:1 image
+    Equirectangular,
+    // Six 90° faces in a 3:2 grid. The top row holds the left, front and right faces, the bottom
+    // row the back, up and down faces. The up face is seen with the back direction on top, the down
+    // face with the front direction on top.
+    Cubemap,
+}
+
+/// Full 360° view around the camera position, for VR previews.
+pub struct PanoramicCamera {
+    origin: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    layout: PanoramaLayout,
+    // Sideways offset of the eye for omni-directional stereo, zero for a mono panorama
+    eye_offset: Float,
+}
+
+impl PanoramicCamera {
+    /// The center of the panorama (or the front face of a cubemap) looks from `lookfrom` at `lookat`.
+    pub fn new(lookfrom: Point3, lookat: Point3, vup: Vec3, layout: PanoramaLayout) -> PanoramicCamera {
+        let (u, v, w) = view_basis(&lookfrom, &lookat, &vup);
+        PanoramicCamera { origin: lookfrom, u, v, w, layout, eye_offset: 0.0 }
+    }
+
+    /// One eye of an omni-directional stereo panorama. Every ray starts `eye_offset` to the right
+    /// of `lookfrom` as seen along that ray (negative for the left eye), so that the eyes circle
+    /// around the center while looking around. The offset fades out towards the poles.
+    pub fn omni_stereo(lookfrom: Point3, lookat: Point3, vup: Vec3, layout: PanoramaLayout, eye_offset: Float) -> PanoramicCamera {
+        PanoramicCamera { eye_offset, ..PanoramicCamera::new(lookfrom, lookat, vup, layout) }
+    }
+
+    /// Direction in the (right, up, forward) frame of the camera.
+    fn local_direction(&self, s: Float, t: Float) -> Vec3 {
+        match self.layout {
+            PanoramaLayout::Equirectangular => {
+                let phi = (s - 0.5) * 2.0 * PI;
+                let theta = (1.0 - t) * PI;
+                Vec3::new(theta.sin() * phi.sin(), theta.cos(), theta.sin() * phi.cos())
+            }
+            PanoramaLayout::Cubemap => {
+                let column = ((s * 3.0) as usize).min(2);
+                let row = (((1.0 - t) * 2.0) as usize).min(1);
+                // Position on the face, in [-1, 1] from left to right and bottom to top
+                let a = 2.0 * (s * 3.0 - column as Float) - 1.0;
+                let b = 2.0 * (t * 2.0 - (1 - row) as Float) - 1.0;
+                match (row, column) {
+                    (0, 0) => Vec3::new(-1.0, b, a),   // left
+                    (0, 1) => Vec3::new(a, b, 1.0),    // front
+                    (0, 2) => Vec3::new(1.0, b, -a),   // right
+                    (1, 0) => Vec3::new(-a, b, -1.0),  // back
+                    (1, 1) => Vec3::new(a, 1.0, -b),   // up
+                    _ => Vec3::new(a, -1.0, b),        // down
+                }
+            }
+        }
+    }
+}
+
+impl Projection for PanoramicCamera {
+    fn get_ray(&self, s: Float, t: Float, _sampler: &mut dyn Sampler) -> Option<Ray> {
+        let d = self.local_direction(s, t);
+        let direction = d.x() * self.u + d.y() * self.v - d.z() * self.w;
+        if self.eye_offset == 0.0 {
+            return Some(Ray::new(self.origin, direction));
+        }
+        // Horizontal vector to the right of the ray, with length sin(theta) for the pole fade
+        let d = d.unit_vector();
+        let right = d.z() * self.u + d.x() * self.w;
+        Some(Ray::new(self.origin + self.eye_offset * right, direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+    #[test]
+    fn image_center_looks_at_target () {
+        let mut sampler = SamplerKind::Independent.create(1, 1);
+        let lookfrom = Point3::new(1.0, 2.0, 3.0);
+        let lookat = Point3::new(4.0, 2.0, -1.0);
+        let forward = (lookat - lookfrom).unit_vector();
+        let cameras: Vec<Box<dyn Projection>> = vec![
+            Box::new(OrthographicCamera::new(lookfrom, lookat, Vec3::new(0.0, 1.0, 0.0), 2.0, 1.5)),
+            Box::new(FisheyeCamera::new(lookfrom, lookat, Vec3::new(0.0, 1.0, 0.0), 180.0, 1.5)),
+            Box::new(PanoramicCamera::new(lookfrom, lookat, Vec3::new(0.0, 1.0, 0.0), PanoramaLayout::Equirectangular)),
+            Box::new(PanoramicCamera::new(lookfrom, lookat, Vec3::new(0.0, 1.0, 0.0), PanoramaLayout::Cubemap)),
+        ];
+        for (camera, (s, t)) in cameras.iter().zip([(0.5, 0.5), (0.5, 0.5), (0.5, 0.5), (0.5, 0.75)]) {
+            let ray = camera.get_ray(s, t, sampler.as_mut()).unwrap();
+            assert!((ray.direction.unit_vector() - forward).length() < 1e-5);
+        }
+        assert!(cameras[1].get_ray(0.0, 0.0, sampler.as_mut()).is_none());
+    }
+}
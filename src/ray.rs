@@ -1,4 +1,5 @@
-use crate::vec::{Vec3, Point3};
+use crate::float::Float;
+use crate::vec::{Vec3, Point3, dot};
 
 pub struct Ray {
     pub origin: Point3,
@@ -13,7 +14,44 @@ impl Ray {
         }
     }
 
-    pub fn point_at_parameter(&self, t: f32) -> Point3 {
-        &self.origin + &((&self.direction) * t)
+    pub fn point_at_parameter(&self, t: Float) -> Point3 {
+        self.origin + self.direction * t
     }
-}
\ No newline at end of file
+
+    /// `point_at_parameter` together with a bound on its error, for hits whose `t` is only known
+    /// to about float precision. Shapes that can reproject the hit onto their surface get much
+    /// tighter bounds that way.
+    pub fn point_with_error(&self, t: Float) -> (Point3, Vec3) {
+        let p = self.point_at_parameter(t);
+        (p, gamma(8) * (self.origin.abs() + (t * self.direction).abs()))
+    }
+}
+
+/// Largest relative rounding error of one float operation, half the gap between 1 and the next
+/// float.
+pub const MACHINE_EPSILON: Float = 0.5 * Float::EPSILON;
+
+/// Bound on the relative error after `n` rounded operations: (1 ± ε)ⁿ lies within 1 ± γ(n).
+pub fn gamma(n: u32) -> Float {
+    let n = n as Float * MACHINE_EPSILON;
+    n / (1.0 - n)
+}
+
+/// Origin for a ray leaving the surface at `p` in `direction`. The point is pushed along the
+/// normal, to the side the ray leaves on, past the box `p ± error` that the true surface point is
+/// known to lie in, so the ray cannot hit the surface it starts from. This replaces a fixed
+/// `t_min`, which is too large for tiny scenes and too small for huge ones.
+pub fn offset_origin(p: &Point3, error: &Vec3, normal: &Vec3, direction: &Vec3) -> Point3 {
+    let distance = dot(&normal.abs(), error);
+    let offset = if dot(direction, normal) < 0.0 { -distance * *normal } else { distance * *normal };
+    let mut origin = *p + offset;
+    // Round away from p, as the addition may have rounded back towards it
+    for (o, &d) in origin.e.iter_mut().zip(offset.e.iter()) {
+        if d > 0.0 {
+            *o = o.next_up();
+        } else if d < 0.0 {
+            *o = o.next_down();
+        }
+    }
+    origin
+}
@@ -0,0 +1,381 @@
+use std::ops::Range;
+use std::path::Path;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::float::Float;
+use crate::vec::Vec3;
+use crate::animation::CameraAnimation;
+use crate::filter::Filter;
+use crate::image::Image;
+use crate::projection::Projection;
+use crate::sampler::{Sampler, SamplerKind};
+use crate::scene::Scene;
+use crate::integrator::{PathLog, color, color_logged};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenderSettings {
+    pub image_width: u32,
+    pub image_height: u32,
+    pub samples_per_pixel: i32,
+    pub max_depth: i32,
+    pub filter: Filter,
+    pub sampler: SamplerKind,
+    // Seed of the sampler. The same seed gives every pixel sample the same random numbers.
+    pub seed: u64,
+    // Render only this part of the image
+    pub crop: Option<Crop>,
+}
+
+/// Rectangle of the image in pixels, with rows counted from the top like in an image viewer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl RenderSettings {
+    /// The same settings for image `pass` of several rendered from one scene, such as a frame of
+    /// an animation or an eye of a stereo pair. Each pass gets its own seed, so that the noise
+    /// pattern (and the blue noise mask) changes between them. Pass 0 keeps the seed.
+    pub fn for_pass(&self, pass: u64) -> RenderSettings {
+        RenderSettings { seed: self.seed.wrapping_add(pass.wrapping_mul(0x9e37_79b9_7f4a_7c15)), ..*self }
+    }
+
+    /// The part of the image that is rendered, as (x, y, width, height) with rows counted from the
+    /// bottom. The whole image without a crop, and clamped to the image with one.
+    fn region(&self) -> (usize, usize, usize, usize) {
+        let (width, height) = (self.image_width as usize, self.image_height as usize);
+        match self.crop {
+            None => (0, 0, width, height),
+            Some(crop) => {
+                let (x0, y0) = ((crop.x as usize).min(width), (crop.y as usize).min(height));
+                let x1 = (x0 + crop.width as usize).min(width);
+                let y1 = (y0 + crop.height as usize).min(height);
+                (x0, height - y1, x1 - x0, y1 - y0)
+            }
+        }
+    }
+
+    /// Width and height of the images `render_image` returns.
+    pub fn output_size(&self) -> (usize, usize) {
+        let (_, _, width, height) = self.region();
+        (width, height)
+    }
+}
+
+/// Size of the square tiles the image is split into for rendering in parallel, in pixels.
+const TILE_SIZE: usize = 32;
+
+/// Filtered samples for a rectangle of pixels. Pixel coordinates count rows from the bottom of
+/// the image, like the `v` coordinate of the camera.
+struct Accumulator {
+    x0: usize,
+    y0: usize,
+    width: usize,
+    height: usize,
+    // Sums of the weighted radiance and of the weights
+    sums: Vec<Vec3>,
+    weights: Vec<Float>,
+}
+
+impl Accumulator {
+    fn new(x0: usize, y0: usize, width: usize, height: usize) -> Accumulator {
+        Accumulator {
+            x0,
+            y0,
+            width,
+            height,
+            sums: vec![Vec3::new(0.0, 0.0, 0.0); width * height],
+            weights: vec![0.0; width * height],
+        }
+    }
+
+    /// Add a sample at continuous pixel coordinates (x, y) to every pixel of this accumulator
+    /// that the filter reaches.
+    fn splat(&mut self, x: Float, y: Float, radiance: Vec3, filter: &Filter) {
+        let radius = filter.radius();
+        // Pixel centers are at half-integer coordinates
+        let x_min = ((x - 0.5 - radius).ceil().max(self.x0 as Float)) as usize;
+        let y_min = ((y - 0.5 - radius).ceil().max(self.y0 as Float)) as usize;
+        let x_max = ((x - 0.5 + radius).floor() as isize).min((self.x0 + self.width) as isize - 1);
+        let y_max = ((y - 0.5 + radius).floor() as isize).min((self.y0 + self.height) as isize - 1);
+        for py in y_min as isize..=y_max {
+            for px in x_min as isize..=x_max {
+                let weight = filter.evaluate(px as Float + 0.5 - x, py as Float + 0.5 - y);
+                if weight == 0.0 {
+                    continue;
+                }
+                let index = (py as usize - self.y0) * self.width + (px as usize - self.x0);
+                self.sums[index] += weight * radiance;
+                self.weights[index] += weight;
+            }
+        }
+    }
+
+    /// Add the samples of another accumulator that covers part of this one.
+    fn merge(&mut self, other: &Accumulator) {
+        for y in 0..other.height {
+            for x in 0..other.width {
+                let from = y * other.width + x;
+                let to = (other.y0 + y - self.y0) * self.width + (other.x0 + x - self.x0);
+                self.sums[to] += other.sums[from];
+                self.weights[to] += other.weights[from];
+            }
+        }
+    }
+}
+
+/// Render the scene as seen through `cam` into an image of linear colors. With a crop only the
+/// cropped part is returned, made of the same samples as in a full render with the same seed.
+///
+/// The image is rendered in tiles on all available cores. Samples near the edge of a tile also
+/// reach pixels of the neighbouring tiles through the filter, so each tile collects its samples in
+/// a buffer that extends beyond the tile by the filter radius. These are then summed into the
+/// image before normalizing by the filter weights.
+pub fn render_image(scene: &Scene, cam: &dyn Projection, settings: &RenderSettings) -> Image {
+    render_tiled(scene, cam, settings, TILE_SIZE)
+}
+
+/// `render_image` with square tiles of `tile_size` pixels.
+fn render_tiled(scene: &Scene, cam: &dyn Projection, settings: &RenderSettings, tile_size: usize) -> Image {
+    let (width, height) = (settings.image_width as usize, settings.image_height as usize);
+    let (x0, y0, region_width, region_height) = settings.region();
+    // Pixels around the region also send samples into it through the filter
+    let margin = settings.filter.radius().ceil() as usize;
+    let (sx0, sy0) = (x0.saturating_sub(margin), y0.saturating_sub(margin));
+    let sx1 = (x0 + region_width + margin).min(width);
+    let sy1 = (y0 + region_height + margin).min(height);
+    let tiles_x = (sx1 - sx0).div_ceil(tile_size);
+    let tiles_y = (sy1 - sy0).div_ceil(tile_size);
+    let next_tile = AtomicUsize::new(0);
+    let image = Mutex::new(Accumulator::new(x0, y0, region_width, region_height));
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+    if region_width > 0 && region_height > 0 {
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    let mut sampler = settings.sampler.create(settings.samples_per_pixel as u32, settings.seed);
+                    loop {
+                        let tile = next_tile.fetch_add(1, Ordering::Relaxed);
+                        if tile >= tiles_x * tiles_y {
+                            break;
+                        }
+                        let tx = sx0 + (tile % tiles_x) * tile_size;
+                        let ty = sy0 + (tile / tiles_x) * tile_size;
+                        let tile_width = tile_size.min(sx1 - tx);
+                        let tile_height = tile_size.min(sy1 - ty);
+                        // The part of the region that the samples of this tile can reach
+                        let ax0 = tx.saturating_sub(margin).max(x0);
+                        let ay0 = ty.saturating_sub(margin).max(y0);
+                        let ax1 = (tx + tile_width + margin).min(x0 + region_width);
+                        let ay1 = (ty + tile_height + margin).min(y0 + region_height);
+                        if ax1 <= ax0 || ay1 <= ay0 {
+                            continue;
+                        }
+                        let mut accumulator = Accumulator::new(ax0, ay0, ax1 - ax0, ay1 - ay0);
+                        render_tile(scene, cam, settings, sampler.as_mut(), tx..tx + tile_width, ty..ty + tile_height, &mut accumulator);
+                        image.lock().unwrap().merge(&accumulator);
+                    }
+                });
+            }
+        });
+    }
+
+    let accumulator = image.into_inner().unwrap();
+    let mut image = Image::new(region_width as u32, region_height as u32);
+    for j in 0..region_height {
+        for i in 0..region_width {
+            let weight = accumulator.weights[j * region_width + i];
+            if weight > 0.0 {
+                // Accumulator rows count from the bottom, image rows from the top
+                image.set(i as u32, (region_height - 1 - j) as u32, accumulator.sums[j * region_width + i] / weight);
+            }
+        }
+    }
+    image
+}
+
+/// Trace the samples of the pixels in the ranges `xs` and `ys`, into an accumulator that also
+/// covers the pixels around them that the filter reaches.
+fn render_tile(
+    scene: &Scene,
+    cam: &dyn Projection,
+    settings: &RenderSettings,
+    sampler: &mut dyn Sampler,
+    xs: Range<usize>,
+    ys: Range<usize>,
+    accumulator: &mut Accumulator
+) {
+    let RenderSettings { image_width, image_height, samples_per_pixel, max_depth, filter, .. } = *settings;
+    for j in ys {
+        for i in xs.clone() {
+            // Antialiasing: For a given pixel we have several samples (`ns`) within that pixel and
+            // send rays through each of the samples. The filter then blends them into the image.
+            for index in 0..samples_per_pixel {
+                sampler.start_pixel_sample(i as u32, j as u32, index as u32);
+                let (dx, dy) = sampler.get_2d();
+                let x = i as Float + dx;
+                let y = j as Float + dy;
+                let u = x / image_width as Float;
+                let v = y / image_height as Float;
+                let radiance = match cam.get_ray(u, v, sampler) {
+                    Some(r) => color(&r, scene, max_depth, sampler),
+                    None => Vec3::new(0.0, 0.0, 0.0),
+                };
+                accumulator.splat(x, y, radiance, &filter);
+            }
+        }
+    }
+}
+
+/// Trace every sample of the single pixel at (x, y), counted from the top left, with the same
+/// random numbers as a render with these settings, and write each bounce of each path to `out`.
+/// Useful to find out where a firefly comes from.
+pub fn debug_pixel<W: Write>(out: &mut W, scene: &Scene, cam: &dyn Projection, settings: &RenderSettings, x: u32, y: u32) -> io::Result<()> {
+    let RenderSettings { image_width, image_height, samples_per_pixel, max_depth, .. } = *settings;
+    if x >= image_width || y >= image_height {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixel outside the image"));
+    }
+    let (i, j) = (x, image_height - 1 - y);
+    let mut sampler = settings.sampler.create(samples_per_pixel as u32, settings.seed);
+    for index in 0..samples_per_pixel {
+        sampler.start_pixel_sample(i, j, index as u32);
+        let (dx, dy) = sampler.get_2d();
+        let u = (i as Float + dx) / image_width as Float;
+        let v = (j as Float + dy) / image_height as Float;
+        let ray = match cam.get_ray(u, v, sampler.as_mut()) {
+            Some(ray) => ray,
+            None => {
+                writeln!(out, "sample {} at ({:.3}, {:.3}): outside the image", index, x as Float + dx, y as Float + 1.0 - dy)?;
+                continue;
+            }
+        };
+        let mut log = PathLog::default();
+        let radiance = color_logged(&ray, scene, max_depth, sampler.as_mut(), &mut log);
+        writeln!(out, "sample {} at ({:.3}, {:.3}): radiance {:.4}", index, x as Float + dx, y as Float + 1.0 - dy, radiance)?;
+        writeln!(out, "  camera ray from {:.4} towards {:.4}", ray.origin, ray.direction.unit_vector())?;
+        for bounce in &log.bounces {
+            writeln!(out, "  bounce {}: hit {:.4} normal {:.4} {}", bounce.depth, bounce.point, bounce.normal, bounce.material)?;
+            write!(out, "    emitted {:.4} direct {:.4} ", bounce.emitted, bounce.direct)?;
+            match bounce.scattered {
+                Some(direction) => writeln!(out, "scattered towards {:.4} throughput {:.4}", direction.unit_vector(), bounce.throughput)?,
+                None => writeln!(out, "absorbed")?,
+            }
+        }
+        if let Some((direction, radiance)) = log.escaped {
+            writeln!(out, "  escaped towards {:.4} background {:.4}", direction.unit_vector(), radiance)?;
+        }
+    }
+    Ok(())
+}
+
+/// Render the scene as seen through `cam` into an image file, in the format given by the extension
+/// of `filename` (see `Image::save`).
+pub fn render_to_file<P: AsRef<Path>>(filename: P, scene: &Scene, cam: &dyn Projection, settings: &RenderSettings) -> std::io::Result<()> {
+    render_image(scene, cam, settings).save(filename)
+}
+
+/// Render `frames` of an animation played back at `fps` frames per second into a numbered image
+/// sequence `<prefix>_0000.ppm`, `<prefix>_0001.ppm`, ... `scene_at` builds the scene at a time
+/// in seconds, with any animated objects in place.
+pub fn render_sequence<F: Fn(Float) -> Scene>(
+    prefix: &str,
+    frames: Range<u32>,
+    fps: Float,
+    scene_at: F,
+    camera: &CameraAnimation,
+    settings: &RenderSettings
+) -> std::io::Result<()> {
+    for frame in frames {
+        let time = frame as Float / fps;
+        let scene = scene_at(time);
+        let cam = camera.camera_at(time).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let filename = format!("{}_{:04}.ppm", prefix, frame);
+        eprintln!("Rendering frame {} to {}", frame, filename);
+        render_to_file(&filename, &scene, &cam, &settings.for_pass(frame as u64))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::hitable::{HitableList, Sphere};
+    use crate::material::Material;
+    use crate::vec::Point3;
+    #[test]
+    fn crop_matches_full_render () {
+        let sphere = Sphere { center: Point3::new(0.0, 0.0, -1.0), radius: 0.5, material: Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) } };
+        let scene = Scene::new(HitableList { list: vec![Box::new(sphere)] });
+        let cam = Camera::builder().aspect_ratio(1.5).build().unwrap();
+        let settings = RenderSettings {
+            image_width: 12,
+            image_height: 8,
+            samples_per_pixel: 4,
+            max_depth: 0,
+            filter: Filter::tent(1.0),
+            sampler: SamplerKind::Sobol,
+            seed: 3,
+            crop: None,
+        };
+        let full = render_image(&scene, &cam, &settings);
+        let crop = Crop { x: 5, y: 2, width: 10, height: 3 };
+        let cropped = render_image(&scene, &cam, &RenderSettings { crop: Some(crop), ..settings });
+        // Clamped to the right edge of the image
+        assert_eq!(RenderSettings { crop: Some(crop), ..settings }.output_size(), (7, 3));
+        assert_eq!((cropped.width(), cropped.height()), (7, 3));
+        assert!(cropped.pixels().zip(full.crop(5, 2, 10, 3).pixels()).all(|(a, b)| (a - b).length() < 1e-5));
+    }
+
+    #[test]
+    fn tiles_blend_into_an_untiled_render () {
+        let sphere = Sphere { center: Point3::new(0.0, 0.0, -1.0), radius: 0.5, material: Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) } };
+        let scene = Scene::new(HitableList { list: vec![Box::new(sphere)] });
+        let cam = Camera::builder().aspect_ratio(1.5).build().unwrap();
+        for filter in [Filter::box_filter(), Filter::mitchell(2.0), Filter::lanczos(3.0)] {
+            let settings = RenderSettings {
+                image_width: 15,
+                image_height: 10,
+                samples_per_pixel: 4,
+                max_depth: 2,
+                filter,
+                sampler: SamplerKind::Sobol,
+                seed: 5,
+                crop: None,
+            };
+            // Tiles smaller than the filter, so that samples reach across several tile borders
+            let untiled = render_tiled(&scene, &cam, &settings, 15);
+            let tiled = render_tiled(&scene, &cam, &settings, 2);
+            assert!(tiled.pixels().zip(untiled.pixels()).all(|(a, b)| (a - b).length() < 1e-5));
+        }
+    }
+
+    #[test]
+    fn passes_get_their_own_noise () {
+        let settings = RenderSettings {
+            image_width: 4,
+            image_height: 4,
+            samples_per_pixel: 1,
+            max_depth: 0,
+            filter: Filter::box_filter(),
+            sampler: SamplerKind::BlueNoise,
+            seed: 3,
+            crop: None,
+        };
+        assert_eq!(settings.for_pass(0).seed, settings.seed);
+        let values: Vec<Float> = (0..4).map(|pass| {
+            let pass_settings = settings.for_pass(pass);
+            let mut sampler = pass_settings.sampler.create(1, pass_settings.seed);
+            sampler.start_pixel_sample(1, 2, 0);
+            sampler.get_1d()
+        }).collect();
+        assert!(values.iter().enumerate().all(|(i, a)| values[i + 1..].iter().all(|b| a != b)), "{:?}", values);
+    }
+}
@@ -0,0 +1,143 @@
+//! Real roots of polynomials, for intersecting rays with implicit surfaces.
+//!
+//! Coefficients are given from the constant term up, so `[c0, c1, c2]` is c0 + c1·t + c2·t².
+
+/// Value of the polynomial at `t`, by Horner's rule.
+pub fn evaluate(coefficients: &[f64], t: f64) -> f64 {
+    coefficients.iter().rev().fold(0.0, |acc, &c| acc * t + c)
+}
+
+/// Roots of the quadratic c0 + c1·t + c2·t² in increasing order, or `None` if it has no real
+/// roots. Avoids the cancellation of the textbook formula when c1² is much larger than 4·c0·c2.
+pub fn solve_quadratic(c0: f64, c1: f64, c2: f64) -> Option<(f64, f64)> {
+    if c2 == 0.0 {
+        if c1 == 0.0 {
+            return None;
+        }
+        let t = -c0 / c1;
+        return Some((t, t));
+    }
+    let discriminant = c1 * c1 - 4.0 * c2 * c0;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let q = -0.5 * (c1 + c1.signum() * discriminant.sqrt());
+    let (t0, t1) = if q == 0.0 { (0.0, 0.0) } else { (q / c2, c0 / q) };
+    Some(if t0 < t1 { (t0, t1) } else { (t1, t0) })
+}
+
+/// Up to four real roots of a polynomial of degree four or less inside [`lo`, `hi`], in
+/// increasing order. Returns the roots and how many were found.
+///
+/// The roots of the derivative split the interval into pieces where the polynomial is monotonic,
+/// and each piece with a sign change holds exactly one root, found with safeguarded Newton
+/// iteration. This never misses a simple root, unlike the closed-form solutions which lose roots
+/// to cancellation when the coefficients span a wide range, as they do for distant rays.
+/// Double roots, where the polynomial only touches zero, are not reported.
+pub fn solve_quartic(coefficients: [f64; 5], lo: f64, hi: f64) -> ([f64; 4], usize) {
+    let mut degree = 4;
+    while degree > 0 && coefficients[degree] == 0.0 {
+        degree -= 1;
+    }
+    let mut roots = [0.0; 4];
+    let count = roots_in(&coefficients[..=degree], lo, hi, &mut roots);
+    (roots, count)
+}
+
+fn roots_in(coefficients: &[f64], lo: f64, hi: f64, roots: &mut [f64; 4]) -> usize {
+    let degree = coefficients.len() - 1;
+    if degree == 0 {
+        return 0;
+    }
+    if degree == 1 {
+        let t = -coefficients[0] / coefficients[1];
+        if (lo..=hi).contains(&t) {
+            roots[0] = t;
+            return 1;
+        }
+        return 0;
+    }
+    // Split [lo, hi] at the roots of the derivative
+    let mut derivative = [0.0; 4];
+    for i in 1..=degree {
+        derivative[i - 1] = i as f64 * coefficients[i];
+    }
+    let mut critical = [0.0; 4];
+    let critical_count = roots_in(&derivative[..degree], lo, hi, &mut critical);
+    let mut bounds = [lo; 6];
+    bounds[1..=critical_count].copy_from_slice(&critical[..critical_count]);
+    bounds[critical_count + 1] = hi;
+
+    let mut count = 0;
+    for piece in bounds[..critical_count + 2].windows(2) {
+        if let Some(t) = monotonic_root(coefficients, piece[0], piece[1]) {
+            // A root at a shared bound would show up in both neighbouring pieces
+            if count == 0 || t - roots[count - 1] > 1e-12 * t.abs().max(1.0) {
+                roots[count] = t;
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+/// The root of a polynomial that is monotonic on [`lo`, `hi`], if its sign changes there.
+fn monotonic_root(coefficients: &[f64], mut lo: f64, mut hi: f64) -> Option<f64> {
+    let (f_lo, f_hi) = (evaluate(coefficients, lo), evaluate(coefficients, hi));
+    if f_lo == 0.0 {
+        return Some(lo);
+    }
+    if f_hi == 0.0 {
+        return Some(hi);
+    }
+    if (f_lo > 0.0) == (f_hi > 0.0) {
+        return None;
+    }
+    let increasing = f_hi > 0.0;
+    let mut t = 0.5 * (lo + hi);
+    for _ in 0..100 {
+        let f = evaluate(coefficients, t);
+        if f == 0.0 {
+            return Some(t);
+        }
+        // Keep the root bracketed
+        if (f > 0.0) == increasing {
+            hi = t;
+        } else {
+            lo = t;
+        }
+        let df = coefficients.iter().enumerate().skip(1).rev().fold(0.0, |acc, (i, &c)| acc * t + i as f64 * c);
+        let newton = t - f / df;
+        // Fall back to bisection when Newton's step leaves the bracket
+        let next = if newton > lo && newton < hi { newton } else { 0.5 * (lo + hi) };
+        if (next - t).abs() <= 1e-14 * t.abs().max(1.0) || hi - lo <= 1e-14 * t.abs().max(1.0) {
+            return Some(next);
+        }
+        t = next;
+    }
+    Some(t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn quartic_roots () {
+        // (t - 1)(t - 2)(t - 3)(t - 4)
+        let (roots, count) = solve_quartic([24.0, -50.0, 35.0, -10.0, 1.0], -10.0, 10.0);
+        assert_eq!(count, 4);
+        for (root, expected) in roots.iter().zip([1.0, 2.0, 3.0, 4.0]) {
+            assert!((root - expected).abs() < 1e-9);
+        }
+        // Only the roots in the interval
+        let (roots, count) = solve_quartic([24.0, -50.0, 35.0, -10.0, 1.0], 2.5, 10.0);
+        assert_eq!((count, (roots[0] - 3.0).abs() < 1e-9), (2, true));
+        // Close roots far from zero: (t - 1000)(t - 1000.001)(t² + 1)
+        let p = [1000.0 * 1000.001, -2000.001, 1.0];
+        let q = [p[0], p[1], p[2] + p[0], p[1], p[2]];
+        let (roots, count) = solve_quartic(q, 0.0, 2000.0);
+        assert_eq!(count, 2);
+        assert!((roots[0] - 1000.0).abs() < 1e-6 && (roots[1] - 1000.001).abs() < 1e-6);
+        assert_eq!(solve_quadratic(-1.0, 0.0, 1.0), Some((-1.0, 1.0)));
+    }
+}
@@ -0,0 +1,442 @@
+use std::sync::OnceLock;
+
+use crate::float::Float;
+
+/// Source of the uniform random numbers in [0, 1) that a path consumes, one dimension after the
+/// other: the position in the pixel, on the lens, then the choices at every bounce. Samplers that
+/// know which pixel sample and dimension a number is for can spread the samples of a pixel more
+/// evenly than independent random numbers, which makes the image converge faster.
+pub trait Sampler {
+    /// Start sample `index` of the pixel at (x, y), going back to the first dimension.
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32);
+    fn get_1d(&mut self) -> Float;
+    fn get_2d(&mut self) -> (Float, Float);
+}
+
+/// The available samplers, to pick one in the render settings. Every render thread creates its
+/// own sampler from it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerKind {
+    // Independent uniform random numbers
+    Independent,
+    // One jittered sample in each cell of a grid over the pixel, shuffled between dimensions
+    Stratified,
+    // Halton sequence, decorrelated between pixels by a random shift
+    Halton,
+    // Sobol sequence with hash-based Owen scrambling
+    Sobol,
+    // Sobol sequence shifted per pixel by a blue-noise mask, so that the remaining error at low
+    // sample counts looks like fine, even noise
+    BlueNoise,
+}
+
+impl SamplerKind {
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        match name {
+            "independent" => Some(SamplerKind::Independent),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            "bluenoise" => Some(SamplerKind::BlueNoise),
+            _ => None,
+        }
+    }
+
+    /// A sampler for `samples_per_pixel` samples of each pixel. The same seed gives the same
+    /// numbers for the same pixel sample.
+    pub fn create(&self, samples_per_pixel: u32, seed: u64) -> Box<dyn Sampler> {
+        let state = SampleState { seed, pixel: 0, index: 0, dimension: 0 };
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler { state }),
+            SamplerKind::Stratified => Box::new(StratifiedSampler { state, samples_per_pixel: samples_per_pixel.max(1) }),
+            SamplerKind::Halton => Box::new(HaltonSampler { state }),
+            SamplerKind::Sobol => Box::new(SobolSampler { state }),
+            SamplerKind::BlueNoise => Box::new(BlueNoiseSampler { state, x: 0, y: 0 }),
+        }
+    }
+}
+
+/// Where a sampler is: which pixel sample and which dimension of it.
+struct SampleState {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: u32,
+}
+
+impl SampleState {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = (x as u64) << 32 | y as u64;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    /// Hash of the pixel, the current dimension and `salt`, independent of the sample index.
+    fn pixel_hash(&self, salt: u64) -> u64 {
+        mix(self.seed ^ mix(self.pixel ^ mix(self.dimension as u64 ^ mix(salt))))
+    }
+
+    /// Hash of the current dimension and `salt`, the same for every pixel and sample index.
+    fn dimension_hash(&self, salt: u64) -> u64 {
+        mix(self.seed ^ mix(self.dimension as u64 ^ mix(salt)))
+    }
+
+    /// Hash of the pixel sample and the current dimension, then move on to the next dimension.
+    fn next_hash(&mut self) -> u64 {
+        let h = mix(self.pixel_hash(0) ^ mix(self.index as u64 + 1));
+        self.dimension += 1;
+        h
+    }
+}
+
+/// 64-bit finalizer of MurmurHash3, which turns similar inputs into unrelated outputs.
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    h
+}
+
+/// Map the high bits of a hash to [0, 1).
+fn to_unit(h: u64) -> Float {
+    ((h >> 40) as Float * (1.0 / (1u64 << 24) as Float)).min(ONE_MINUS_EPSILON)
+}
+
+const ONE_MINUS_EPSILON: Float = 1.0 - Float::EPSILON / 2.0;
+
+pub struct IndependentSampler {
+    state: SampleState,
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> Float {
+        to_unit(self.state.next_hash())
+    }
+
+    fn get_2d(&mut self) -> (Float, Float) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+/// Random permutation of `i` in 0..n, which is the same for the same seed (Kensler 2013).
+fn permute(mut i: u32, n: u32, seed: u32) -> u32 {
+    if n <= 1 {
+        return 0;
+    }
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % n
+}
+
+pub struct StratifiedSampler {
+    state: SampleState,
+    samples_per_pixel: u32,
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> Float {
+        let n = self.samples_per_pixel;
+        let stratum = permute(self.state.index % n, n, self.state.pixel_hash(1) as u32);
+        let jitter = to_unit(self.state.next_hash());
+        ((stratum as Float + jitter) / n as Float).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (Float, Float) {
+        // Grid with at least as many cells as samples, close to square
+        let n = self.samples_per_pixel;
+        let nx = (n as Float).sqrt().ceil() as u32;
+        let ny = n.div_ceil(nx);
+        let cell = permute(self.state.index % (nx * ny), nx * ny, self.state.pixel_hash(2) as u32);
+        let jx = to_unit(self.state.next_hash());
+        let jy = to_unit(self.state.next_hash());
+        (
+            (((cell % nx) as Float + jx) / nx as Float).min(ONE_MINUS_EPSILON),
+            (((cell / nx) as Float + jy) / ny as Float).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Van der Corput radical inverse of `i` in `base`: its digits mirrored around the decimal point.
+fn radical_inverse(mut i: u32, base: u32) -> Float {
+    let inv_base = 1.0 / base as f64;
+    let mut inv = inv_base;
+    let mut result = 0.0;
+    while i > 0 {
+        result += (i % base) as f64 * inv;
+        i /= base;
+        inv *= inv_base;
+    }
+    (result as Float).min(ONE_MINUS_EPSILON)
+}
+
+pub struct HaltonSampler {
+    state: SampleState,
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> Float {
+        let dimension = self.state.dimension as usize;
+        if dimension >= PRIMES.len() {
+            // High bases are badly distributed for the first samples, use random numbers instead
+            return to_unit(self.state.next_hash());
+        }
+        // Random per-pixel shift so that neighbouring pixels do not see the same pattern
+        let shift = to_unit(self.state.pixel_hash(3));
+        self.state.dimension += 1;
+        let x = radical_inverse(self.state.index, PRIMES[dimension]) + shift;
+        (x - x.floor()).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (Float, Float) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+/// Direction numbers of the first two dimensions of the Sobol sequence. The first is the
+/// van der Corput sequence in base 2, the second uses the primitive polynomial x + 1.
+fn sobol_matrices() -> &'static [[u32; 32]; 2] {
+    static MATRICES: OnceLock<[[u32; 32]; 2]> = OnceLock::new();
+    MATRICES.get_or_init(|| {
+        let (mut first, mut second) = ([0u32; 32], [0u32; 32]);
+        let mut m: u32 = 1;
+        for k in 0..32 {
+            first[k] = 1 << (31 - k);
+            second[k] = m << (31 - k);
+            // m_k = 2 m_{k-1} xor m_{k-1}
+            m = (m << 1) ^ m;
+        }
+        [first, second]
+    })
+}
+
+/// Dimension `dimension` (0 or 1) of Sobol point `index`, as a 32-bit fixed point fraction.
+fn sobol(mut index: u32, dimension: usize) -> u32 {
+    let matrix = &sobol_matrices()[dimension];
+    let mut v = 0;
+    let mut k = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            v ^= matrix[k];
+        }
+        index >>= 1;
+        k += 1;
+    }
+    v
+}
+
+/// Hash-based Owen scrambling (Burley 2020). Scrambling the reversed bits with this permutation
+/// randomly permutes every level of the binary tree of intervals, keeping the stratification.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+
+fn fixed_to_unit(x: u32) -> Float {
+    (x as Float * (1.0 / 4294967296.0)).min(ONE_MINUS_EPSILON)
+}
+
+/// Owen-scrambled Sobol points. Every 1D or 2D request uses the first one or two Sobol dimensions
+/// with its own scrambling seed and shuffled sample order, which keeps each pair of dimensions
+/// well distributed without needing tables for high dimensions.
+pub struct SobolSampler {
+    state: SampleState,
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> Float {
+        let seed = self.state.pixel_hash(4);
+        self.state.dimension += 1;
+        let index = nested_uniform_scramble(self.state.index, seed as u32);
+        fixed_to_unit(nested_uniform_scramble(sobol(index, 0), (seed >> 32) as u32))
+    }
+
+    fn get_2d(&mut self) -> (Float, Float) {
+        let seed = self.state.pixel_hash(5);
+        let seed_y = mix(seed);
+        self.state.dimension += 1;
+        let index = nested_uniform_scramble(self.state.index, seed as u32);
+        (
+            fixed_to_unit(nested_uniform_scramble(sobol(index, 0), (seed >> 32) as u32)),
+            fixed_to_unit(nested_uniform_scramble(sobol(index, 1), (seed_y >> 32) as u32)),
+        )
+    }
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Tileable blue noise mask with values in [0, 1), generated once. Pixels are ranked one by one,
+/// each time picking the unranked pixel in the largest void: the one with the least Gaussian
+/// weighted energy from the pixels ranked before it. This is a greedy simplification of the
+/// ranking in the void-and-cluster method (Ulichney 1993), without its initial pattern or cluster
+/// removal. Neighbouring pixels get values that are as different as possible. Every step scans
+/// all pixels, so it takes O(n⁴) for an n × n mask, 17 million comparisons at 64 × 64, once.
+fn blue_noise() -> &'static [Float] {
+    static MASK: OnceLock<Vec<Float>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        let n = BLUE_NOISE_SIZE;
+        let sigma: Float = 1.5;
+        let radius = 6isize;
+        let kernel: Vec<Float> = (-radius..=radius)
+            .flat_map(|dy| (-radius..=radius).map(move |dx| (-((dx * dx + dy * dy) as Float) / (2.0 * sigma * sigma)).exp()))
+            .collect();
+        let side = (2 * radius + 1) as usize;
+        // Energy of every pixel: how crowded its neighbourhood is with already ranked pixels
+        let mut energy = vec![0.0 as Float; n * n];
+        let mut rank = vec![u32::MAX; n * n];
+        let add = |energy: &mut Vec<Float>, index: usize| {
+            let (x, y) = ((index % n) as isize, (index / n) as isize);
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let px = (x + dx).rem_euclid(n as isize) as usize;
+                    let py = (y + dy).rem_euclid(n as isize) as usize;
+                    energy[py * n + px] += kernel[(dy + radius) as usize * side + (dx + radius) as usize];
+                }
+            }
+        };
+        // Start from a deterministic pseudo-random pixel, then keep filling the largest void
+        let mut next = (mix(0x5eed) % (n * n) as u64) as usize;
+        for r in 0..(n * n) as u32 {
+            rank[next] = r;
+            add(&mut energy, next);
+            next = (0..n * n)
+                .filter(|&i| rank[i] == u32::MAX)
+                .min_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap().then(a.cmp(&b)))
+                .unwrap_or(0);
+        }
+        rank.iter().map(|&r| (r as Float + 0.5) / (n * n) as Float).collect()
+    })
+}
+
+/// Sobol points that are the same for every pixel, shifted per pixel and dimension by a blue
+/// noise mask (Cranley-Patterson rotation). Every dimension scrambles the points and their order
+/// with its own seed, so that dimensions are not in lockstep, but the seed does not depend on
+/// the pixel, or neighbouring pixels would no longer differ by just the blue noise shift.
+pub struct BlueNoiseSampler {
+    state: SampleState,
+    x: u32,
+    y: u32,
+}
+
+impl BlueNoiseSampler {
+    fn mask(&self, salt: u64) -> Float {
+        // Look the mask up at a different offset for every dimension
+        let offset = self.state.dimension_hash(salt);
+        let x = (self.x as usize + (offset as usize & 0xffff)) % BLUE_NOISE_SIZE;
+        let y = (self.y as usize + ((offset >> 16) as usize & 0xffff)) % BLUE_NOISE_SIZE;
+        blue_noise()[y * BLUE_NOISE_SIZE + x]
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.state.start(x, y, index);
+        self.x = x;
+        self.y = y;
+    }
+
+    fn get_1d(&mut self) -> Float {
+        let shift = self.mask(6);
+        let seed = self.state.dimension_hash(9);
+        self.state.dimension += 1;
+        let index = nested_uniform_scramble(self.state.index, seed as u32);
+        let v = fixed_to_unit(nested_uniform_scramble(sobol(index, 0), (seed >> 32) as u32)) + shift;
+        (v - v.floor()).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (Float, Float) {
+        let (shift_x, shift_y) = (self.mask(7), self.mask(8));
+        let seed = self.state.dimension_hash(10);
+        let seed_y = mix(seed);
+        self.state.dimension += 1;
+        let index = nested_uniform_scramble(self.state.index, seed as u32);
+        let x = fixed_to_unit(nested_uniform_scramble(sobol(index, 0), (seed >> 32) as u32)) + shift_x;
+        let y = fixed_to_unit(nested_uniform_scramble(sobol(index, 1), (seed_y >> 32) as u32)) + shift_y;
+        ((x - x.floor()).min(ONE_MINUS_EPSILON), (y - y.floor()).min(ONE_MINUS_EPSILON))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn samplers_are_stratified_and_deterministic () {
+        let kinds = [SamplerKind::Independent, SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol, SamplerKind::BlueNoise];
+        for kind in kinds {
+            let mut a = kind.create(16, 7);
+            let mut b = kind.create(16, 7);
+            let mut cells = [0; 4];
+            let mut shifts = Vec::new();
+            for index in 0..16 {
+                a.start_pixel_sample(3, 5, index);
+                b.start_pixel_sample(3, 5, index);
+                let (x, y) = a.get_2d();
+                assert_eq!((x, y), b.get_2d());
+                assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y));
+                cells[(x * 2.0) as usize + 2 * (y * 2.0) as usize] += 1;
+                // The next dimension should not just be this one shifted
+                let next = a.get_1d();
+                shifts.push((next - x).rem_euclid(1.0));
+            }
+            assert!(shifts.iter().any(|&s| (s - shifts[0]).abs() > 1e-3), "{:?} dimensions move in lockstep", kind);
+            if kind == SamplerKind::Stratified || kind == SamplerKind::Sobol {
+                // Every quadrant of the pixel gets its share
+                assert_eq!(cells, [4, 4, 4, 4], "{:?}", kind);
+            }
+        }
+    }
+}
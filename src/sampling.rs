@@ -0,0 +1,123 @@
+
+use crate::float::Float;
+
+/// Piecewise-constant 1D distribution over [0, 1], built from non-negative function values.
+/// Sampling it picks values proportional to the function.
+pub struct Distribution1D {
+    func: Vec<Float>,
+    cdf: Vec<Float>,
+    integral: Float,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[Float]) -> Distribution1D {
+        assert!(!func.is_empty(), "distribution over no values");
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1].max(0.0) / n as Float;
+        }
+        let integral = cdf[n];
+        if integral == 0.0 {
+            // Nothing to importance sample, fall back to a uniform distribution
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as Float / n as Float;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        }
+        Distribution1D { func: func.iter().map(|f| f.max(0.0)).collect(), cdf, integral }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    /// Integral of the function over [0, 1].
+    pub fn integral(&self) -> Float {
+        self.integral
+    }
+
+    /// Map a uniform `u` in [0, 1) to a value in [0, 1). Returns the value, its pdf and the index
+    /// of the segment it fell into.
+    pub fn sample(&self, u: Float) -> (Float, Float, usize) {
+        // Last index where cdf[i] <= u
+        let offset = self.cdf.partition_point(|&c| c <= u).clamp(1, self.count()) - 1;
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+        let x = ((offset as Float + du) / self.count() as Float).min(1.0 - Float::EPSILON);
+        (x, self.pdf_at(offset), offset)
+    }
+
+    fn pdf_at(&self, index: usize) -> Float {
+        if self.integral > 0.0 { self.func[index] / self.integral } else { 1.0 }
+    }
+
+    /// Pdf of sampling `x` in [0, 1].
+    pub fn pdf(&self, x: Float) -> Float {
+        let index = ((x * self.count() as Float) as usize).min(self.count() - 1);
+        self.pdf_at(index)
+    }
+}
+
+/// Piecewise-constant 2D distribution over [0, 1]², given as `width` × `height` function values
+/// in row-major order. Rows are sampled from the marginal distribution, columns conditionally.
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[Float], width: usize, height: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> = func.chunks(width).take(height).map(Distribution1D::new).collect();
+        let row_integrals: Vec<Float> = conditional.iter().map(|d| d.integral()).collect();
+        Distribution2D { conditional, marginal: Distribution1D::new(&row_integrals) }
+    }
+
+    /// Integral of the function over [0, 1]².
+    pub fn integral(&self) -> Float {
+        self.marginal.integral()
+    }
+
+    /// Map two uniform numbers to a point (x, y) in [0, 1)², returning the point and its pdf.
+    pub fn sample(&self, u1: Float, u2: Float) -> (Float, Float, Float) {
+        let (y, pdf_y, row) = self.marginal.sample(u2);
+        let (x, pdf_x, _) = self.conditional[row].sample(u1);
+        (x, y, pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, x: Float, y: Float) -> Float {
+        let rows = self.conditional.len();
+        let row = ((y * rows as Float) as usize).min(rows - 1);
+        self.marginal.pdf(y) * self.conditional[row].pdf(x)
+    }
+}
+
+/// Power heuristic (β = 2) weight for multiple importance sampling, for a sample drawn from the
+/// strategy with `pdf_f` and an alternative strategy with `pdf_g`.
+pub fn power_heuristic(pdf_f: Float, pdf_g: Float) -> Float {
+    let f = pdf_f * pdf_f;
+    let g = pdf_g * pdf_g;
+    if f + g == 0.0 { 0.0 } else { f / (f + g) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn samples_follow_function () {
+        let d = Distribution1D::new(&[0.0, 3.0, 1.0, 0.0]);
+        let (x, pdf, index) = d.sample(0.5);
+        assert_eq!(index, 1);
+        assert!((0.25..0.5).contains(&x));
+        assert!((pdf - 3.0).abs() < 1e-5);
+        assert_eq!(d.pdf(0.1), 0.0);
+        let (_, _, index) = d.sample(0.9);
+        assert_eq!(index, 2);
+    }
+}
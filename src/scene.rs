@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use crate::float::Float;
+use crate::environment::EnvironmentMap;
+use crate::hitable::HitableList;
+use crate::light::Light;
+use crate::vec::{Vec3, Point3};
+
+/// What rays see when they leave the scene without hitting anything.
+pub enum Background {
+    // White at the horizon to light blue at the zenith
+    Gradient,
+    Environment(Arc<EnvironmentMap>),
+}
+
+impl Background {
+    pub fn radiance(&self, direction: &Vec3) -> Vec3 {
+        match self {
+            Background::Gradient => {
+                let unit_direction: Vec3 = direction.unit_vector();
+                let t: Float = 0.5 * (unit_direction.y() + 1.0);
+                (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0)
+            }
+            Background::Environment(env) => env.radiance(direction),
+        }
+    }
+}
+
+pub struct Scene {
+    pub world: HitableList,
+    pub background: Background,
+    // Emitters that are sampled directly at every diffuse bounce
+    pub lights: Vec<Light>,
+}
+
+impl Scene {
+    pub fn new(world: HitableList) -> Scene {
+        Scene { world, background: Background::Gradient, lights: Vec::new() }
+    }
+
+    /// Add a light to the light list, and the emitting surface of area lights to the world.
+    pub fn add_light(&mut self, light: Light) {
+        if let Some(hitable) = light.hitable() {
+            self.world.list.push(hitable);
+        }
+        self.lights.push(light);
+    }
+
+    /// Solid angle pdf of picking `direction` from `origin` by sampling a uniformly chosen light.
+    pub fn light_pdf(&self, origin: &Point3, direction: &Vec3) -> Float {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let sum: Float = self.lights.iter().map(|light| light.pdf(origin, direction)).sum();
+        sum / self.lights.len() as Float
+    }
+}
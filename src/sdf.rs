@@ -0,0 +1,472 @@
+use std::sync::Arc;
+
+use crate::float::Float;
+use crate::aabb::Aabb;
+use crate::hitable::{HitRecord, Hitable, any_perpendicular};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::vec::{Vec3, Point3, dot, cross};
+
+/// Signed distance field: for every point, the distance to the nearest surface, negative inside.
+/// Shapes are combined and transformed with the methods below, which build a tree of these.
+/// Smooth combinations and fractals only give a lower bound of the distance, which sphere tracing
+/// handles with some extra steps.
+#[derive(Clone)]
+pub enum Sdf {
+    Sphere {
+        radius: Float
+    },
+    // Box centered on the origin, reaching `half_extents` along each axis
+    Cuboid {
+        half_extents: Vec3
+    },
+    // Ring around the Y axis, like `primitives::Torus`
+    Torus {
+        major_radius: Float,
+        minor_radius: Float
+    },
+    // Segment from `a` to `b`, thickened by `radius`
+    Capsule {
+        a: Point3,
+        b: Point3,
+        radius: Float
+    },
+    // Mandelbulb fractal, a little over unit size. `power` 8 gives the classic shape.
+    Mandelbulb {
+        power: Float,
+        iterations: u32
+    },
+    Union(Box<Sdf>, Box<Sdf>),
+    Intersection(Box<Sdf>, Box<Sdf>),
+    // The first shape with the second cut out of it
+    Subtraction(Box<Sdf>, Box<Sdf>),
+    // Combinations that blend the surfaces together over a distance of about `k`
+    SmoothUnion {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        k: Float
+    },
+    SmoothIntersection {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        k: Float
+    },
+    SmoothSubtraction {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        k: Float
+    },
+    // Grows the shape by `radius`, rounding off its edges
+    Round {
+        sdf: Box<Sdf>,
+        radius: Float
+    },
+    // Hollow shell of the surface, `thickness` thick
+    Onion {
+        sdf: Box<Sdf>,
+        thickness: Float
+    },
+    Translate {
+        sdf: Box<Sdf>,
+        offset: Vec3
+    },
+    // Rotation by `angle` radians counter-clockwise around the unit vector `axis`
+    Rotate {
+        sdf: Box<Sdf>,
+        axis: Vec3,
+        angle: Float
+    },
+    // Uniform scale, which keeps the distances exact
+    Scale {
+        sdf: Box<Sdf>,
+        factor: Float
+    },
+    // Endless copies of the shape, one in every cell of size `period` around the origin
+    Repeat {
+        sdf: Box<Sdf>,
+        period: Vec3
+    },
+    // Any distance function, with its bounding box if it has one
+    Custom {
+        distance: Arc<dyn Fn(&Point3) -> Float + Send + Sync>,
+        bounds: Option<Aabb>
+    },
+}
+
+impl Sdf {
+    pub fn sphere(radius: Float) -> Sdf {
+        Sdf::Sphere { radius }
+    }
+
+    pub fn cuboid(half_extents: Vec3) -> Sdf {
+        Sdf::Cuboid { half_extents }
+    }
+
+    /// Box with its edges rounded off by `radius`, keeping its outer size. Panics if the radius is
+    /// negative or larger than the smallest half extent.
+    pub fn round_box(half_extents: Vec3, radius: Float) -> Sdf {
+        let smallest = half_extents.x().min(half_extents.y()).min(half_extents.z());
+        assert!(radius >= 0.0 && radius <= smallest, "rounding radius must be between 0 and the smallest half extent");
+        let inner = half_extents - Vec3::new(radius, radius, radius);
+        Sdf::cuboid(inner).round(radius)
+    }
+
+    pub fn torus(major_radius: Float, minor_radius: Float) -> Sdf {
+        Sdf::Torus { major_radius, minor_radius }
+    }
+
+    pub fn capsule(a: Point3, b: Point3, radius: Float) -> Sdf {
+        Sdf::Capsule { a, b, radius }
+    }
+
+    pub fn mandelbulb(power: Float, iterations: u32) -> Sdf {
+        Sdf::Mandelbulb { power, iterations }
+    }
+
+    /// Shape from a user-provided distance function. Without bounds, rays are traced up to the
+    /// maximum distance of the `SdfHitable`.
+    pub fn custom<F: Fn(&Point3) -> Float + Send + Sync + 'static>(distance: F, bounds: Option<Aabb>) -> Sdf {
+        Sdf::Custom { distance: Arc::new(distance), bounds }
+    }
+
+    pub fn union(self, other: Sdf) -> Sdf {
+        Sdf::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersection(self, other: Sdf) -> Sdf {
+        Sdf::Intersection(Box::new(self), Box::new(other))
+    }
+
+    pub fn subtract(self, other: Sdf) -> Sdf {
+        Sdf::Subtraction(Box::new(self), Box::new(other))
+    }
+
+    pub fn smooth_union(self, other: Sdf, k: Float) -> Sdf {
+        Sdf::SmoothUnion { a: Box::new(self), b: Box::new(other), k }
+    }
+
+    pub fn smooth_intersection(self, other: Sdf, k: Float) -> Sdf {
+        Sdf::SmoothIntersection { a: Box::new(self), b: Box::new(other), k }
+    }
+
+    pub fn smooth_subtract(self, other: Sdf, k: Float) -> Sdf {
+        Sdf::SmoothSubtraction { a: Box::new(self), b: Box::new(other), k }
+    }
+
+    pub fn round(self, radius: Float) -> Sdf {
+        Sdf::Round { sdf: Box::new(self), radius }
+    }
+
+    pub fn onion(self, thickness: Float) -> Sdf {
+        Sdf::Onion { sdf: Box::new(self), thickness }
+    }
+
+    pub fn translate(self, offset: Vec3) -> Sdf {
+        Sdf::Translate { sdf: Box::new(self), offset }
+    }
+
+    /// Rotate by `degrees` counter-clockwise around `axis`.
+    pub fn rotate(self, axis: Vec3, degrees: Float) -> Sdf {
+        Sdf::Rotate { sdf: Box::new(self), axis: axis.unit_vector(), angle: degrees.to_radians() }
+    }
+
+    /// Scale uniformly by `factor`, which must be positive.
+    pub fn scale(self, factor: Float) -> Sdf {
+        assert!(factor > 0.0, "scale factor must be positive");
+        Sdf::Scale { sdf: Box::new(self), factor }
+    }
+
+    pub fn repeat(self, period: Vec3) -> Sdf {
+        Sdf::Repeat { sdf: Box::new(self), period }
+    }
+
+    /// Signed distance from `p` to the surface.
+    pub fn distance(&self, p: &Point3) -> Float {
+        match self {
+            Sdf::Sphere { radius } => p.length() - radius,
+            Sdf::Cuboid { half_extents } => {
+                let q = abs(p) - *half_extents;
+                max(&q, 0.0).length() + q.x().max(q.y()).max(q.z()).min(0.0)
+            }
+            Sdf::Torus { major_radius, minor_radius } => {
+                let ring = (p.x() * p.x() + p.z() * p.z()).sqrt() - major_radius;
+                (ring * ring + p.y() * p.y()).sqrt() - minor_radius
+            }
+            Sdf::Capsule { a, b, radius } => {
+                let (pa, ba) = (*p - *a, *b - *a);
+                let h = (dot(&pa, &ba) / dot(&ba, &ba)).clamp(0.0, 1.0);
+                (pa - h * ba).length() - radius
+            }
+            Sdf::Mandelbulb { power, iterations } => mandelbulb(p, *power, *iterations),
+            Sdf::Union(a, b) => a.distance(p).min(b.distance(p)),
+            Sdf::Intersection(a, b) => a.distance(p).max(b.distance(p)),
+            Sdf::Subtraction(a, b) => a.distance(p).max(-b.distance(p)),
+            Sdf::SmoothUnion { a, b, k } => smooth_min(a.distance(p), b.distance(p), *k),
+            Sdf::SmoothIntersection { a, b, k } => -smooth_min(-a.distance(p), -b.distance(p), *k),
+            Sdf::SmoothSubtraction { a, b, k } => -smooth_min(-a.distance(p), b.distance(p), *k),
+            Sdf::Round { sdf, radius } => sdf.distance(p) - radius,
+            Sdf::Onion { sdf, thickness } => sdf.distance(p).abs() - thickness,
+            Sdf::Translate { sdf, offset } => sdf.distance(&(*p - *offset)),
+            Sdf::Rotate { sdf, axis, angle } => sdf.distance(&rotate(p, axis, -angle)),
+            Sdf::Scale { sdf, factor } => sdf.distance(&(*p / *factor)) * factor,
+            Sdf::Repeat { sdf, period } => {
+                let cell = |x: Float, period: Float| if period > 0.0 { x - period * (x / period).round() } else { x };
+                sdf.distance(&Vec3::new(cell(p.x(), period.x()), cell(p.y(), period.y()), cell(p.z(), period.z())))
+            }
+            Sdf::Custom { distance, .. } => distance(p),
+        }
+    }
+
+    /// Box around the surface, or `None` if it is unbounded or unknown.
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        let cube = |r: Float| Aabb::around(origin, Vec3::new(r, r, r));
+        match self {
+            Sdf::Sphere { radius } => Some(cube(*radius)),
+            Sdf::Cuboid { half_extents } => Some(Aabb::around(origin, *half_extents)),
+            Sdf::Torus { major_radius, minor_radius } => {
+                let r = major_radius + minor_radius;
+                Some(Aabb::around(origin, Vec3::new(r, *minor_radius, r)))
+            }
+            Sdf::Capsule { a, b, radius } => {
+                let r = Vec3::new(*radius, *radius, *radius);
+                Some(Aabb::around(*a, r).surrounding(&Aabb::around(*b, r)))
+            }
+            // Every point that does not escape lies within a radius of about 1.2
+            Sdf::Mandelbulb { .. } => Some(cube(1.25)),
+            Sdf::Union(a, b) | Sdf::SmoothUnion { a, b, .. } => {
+                let (a, b) = (a.bounding_box()?, b.bounding_box()?);
+                let grown = a.surrounding(&b);
+                // A smooth union can bulge out a little between the shapes
+                Some(match self {
+                    Sdf::SmoothUnion { k, .. } => Aabb::around(center(&grown), half_size(&grown) + Vec3::new(*k, *k, *k)),
+                    _ => grown,
+                })
+            }
+            Sdf::Intersection(a, b) | Sdf::SmoothIntersection { a, b, .. } => match (a.bounding_box(), b.bounding_box()) {
+                (Some(a), Some(b)) => Some(Aabb::new(
+                    Vec3::new(a.min.x().max(b.min.x()), a.min.y().max(b.min.y()), a.min.z().max(b.min.z())),
+                    Vec3::new(a.max.x().min(b.max.x()), a.max.y().min(b.max.y()), a.max.z().min(b.max.z())),
+                )),
+                (a, b) => a.or(b),
+            },
+            Sdf::Subtraction(a, _) | Sdf::SmoothSubtraction { a, .. } => a.bounding_box(),
+            Sdf::Round { sdf, radius: grow } | Sdf::Onion { sdf, thickness: grow } => {
+                let b = sdf.bounding_box()?;
+                Some(Aabb::around(center(&b), half_size(&b) + Vec3::new(*grow, *grow, *grow)))
+            }
+            Sdf::Translate { sdf, offset } => {
+                let b = sdf.bounding_box()?;
+                Some(Aabb::new(b.min + *offset, b.max + *offset))
+            }
+            Sdf::Rotate { sdf, axis, angle } => {
+                let b = sdf.bounding_box()?;
+                let corner = |i: usize| Vec3::new(
+                    if i & 1 == 0 { b.min.x() } else { b.max.x() },
+                    if i & 2 == 0 { b.min.y() } else { b.max.y() },
+                    if i & 4 == 0 { b.min.z() } else { b.max.z() },
+                );
+                let first = rotate(&corner(0), axis, *angle);
+                Some((1..8).map(|i| rotate(&corner(i), axis, *angle)).fold(Aabb::new(first, first), |acc, p| acc.surrounding(&Aabb::new(p, p))))
+            }
+            Sdf::Scale { sdf, factor } => {
+                let b = sdf.bounding_box()?;
+                Some(Aabb::new(*factor * b.min, *factor * b.max))
+            }
+            Sdf::Repeat { .. } => None,
+            Sdf::Custom { bounds, .. } => *bounds,
+        }
+    }
+}
+
+fn abs(v: &Vec3) -> Vec3 {
+    Vec3::new(v.x().abs(), v.y().abs(), v.z().abs())
+}
+
+fn max(v: &Vec3, m: Float) -> Vec3 {
+    Vec3::new(v.x().max(m), v.y().max(m), v.z().max(m))
+}
+
+fn center(b: &Aabb) -> Vec3 {
+    0.5 * (b.min + b.max)
+}
+
+fn half_size(b: &Aabb) -> Vec3 {
+    0.5 * (b.max - b.min)
+}
+
+/// Rodrigues' rotation of `p` by `angle` radians around the unit vector `axis`.
+fn rotate(p: &Vec3, axis: &Vec3, angle: Float) -> Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    cos * *p + sin * cross(axis, p) + (1.0 - cos) * dot(axis, p) * *axis
+}
+
+/// Polynomial smooth minimum, which blends the two values where they are less than `k` apart.
+fn smooth_min(a: Float, b: Float, k: Float) -> Float {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+/// Distance estimate of the Mandelbulb from the running derivative of the iteration
+/// z ← z^power + p, in spherical coordinates.
+fn mandelbulb(p: &Point3, power: Float, iterations: u32) -> Float {
+    let mut z = *p;
+    let mut dr = 1.0;
+    let mut r = z.length();
+    for _ in 0..iterations {
+        if r > 2.0 {
+            break;
+        }
+        let theta = (z.z() / r).clamp(-1.0, 1.0).acos() * power;
+        let phi = z.y().atan2(z.x()) * power;
+        dr = r.powf(power - 1.0) * power * dr + 1.0;
+        let zr = r.powf(power);
+        z = zr * Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()) + *p;
+        r = z.length();
+    }
+    if r == 0.0 {
+        return 0.0;
+    }
+    0.5 * r.ln() * r / dr
+}
+
+/// Surface of a signed distance field, found by sphere tracing: step along the ray by the distance
+/// to the nearest surface, which can never overshoot it, until that distance drops below `epsilon`.
+pub struct SdfHitable {
+    pub sdf: Sdf,
+    pub material: Material,
+    // Distance to the surface that counts as a hit
+    pub epsilon: Float,
+    pub max_steps: u32,
+    // How far rays are traced when the shape has no bounding box
+    pub max_distance: Float,
+    bounds: Option<Aabb>,
+}
+
+impl SdfHitable {
+    pub fn new(sdf: Sdf, material: Material) -> SdfHitable {
+        let bounds = sdf.bounding_box();
+        SdfHitable { sdf, material, epsilon: 1e-4, max_steps: 256, max_distance: 1000.0, bounds }
+    }
+
+    pub fn with_epsilon(mut self, epsilon: Float) -> SdfHitable {
+        self.epsilon = epsilon;
+        self
+    }
+
+    pub fn with_max_steps(mut self, max_steps: u32) -> SdfHitable {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn with_max_distance(mut self, max_distance: Float) -> SdfHitable {
+        self.max_distance = max_distance;
+        self
+    }
+
+    /// Outward normal from the gradient of the distance, by finite differences at the corners of
+    /// a tetrahedron around `p`.
+    fn normal(&self, p: &Point3) -> Vec3 {
+        let h = self.epsilon;
+        let corners = [Vec3::new(1.0, -1.0, -1.0), Vec3::new(-1.0, -1.0, 1.0), Vec3::new(-1.0, 1.0, -1.0), Vec3::new(1.0, 1.0, 1.0)];
+        let gradient = corners.iter().fold(Vec3::new(0.0, 0.0, 0.0), |acc, k| acc + self.sdf.distance(&(*p + h * *k)) * *k);
+        if gradient.squared_length() > 0.0 { gradient.unit_vector() } else { Vec3::new(0.0, 1.0, 0.0) }
+    }
+}
+
+impl Hitable for SdfHitable {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let length = r.direction.length();
+        let (mut t, t_end) = match &self.bounds {
+            Some(bounds) => {
+                // Surfaces can touch the bounding box, so march a little outside of it at both ends
+                let (t0, t1) = bounds.interval(r, t_min, t_max)?;
+                ((t0 - self.epsilon / length).max(t_min), (t1 + self.epsilon / length).min(t_max))
+            }
+            None => (t_min, t_max.min(self.max_distance / length)),
+        };
+        // Secondary rays start on the surface they leave, which must not count as a hit
+        let mut leaving = t <= t_min;
+        for _ in 0..self.max_steps {
+            let p = r.point_at_parameter(t);
+            // Rays that start inside, such as refracted ones, trace the surface from below
+            let distance = self.sdf.distance(&p).abs();
+            if distance < self.epsilon {
+                if leaving {
+                    t += self.epsilon / length;
+                    continue;
+                }
+                let normal = self.normal(&p);
+                // Marching stops anywhere within epsilon of the surface
+                let (p, error) = r.point_with_error(t);
+                let error = error + Vec3::new(self.epsilon, self.epsilon, self.epsilon);
+                return Some(HitRecord {
+                    t,
+                    p,
+                    error,
+                    normal,
+                    u: 0.0,
+                    v: 0.0,
+                    tangent: any_perpendicular(&normal),
+                    on_edge: false,
+                    material: self.material.clone(),
+                });
+            }
+            leaving = false;
+            t += distance / length;
+            if t >= t_end {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn sphere_tracing_finds_surfaces () {
+        let material = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+        let r = Ray::new(Point3::new(-5.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0));
+        let sphere = SdfHitable::new(Sdf::sphere(1.0).translate(Vec3::new(1.0, 0.0, 0.0)), material.clone());
+        let hit = sphere.hit(&r, 0.001, Float::MAX).unwrap();
+        assert!((hit.p.x() - 0.0).abs() < 1e-3 && (hit.t - 2.5).abs() < 1e-3);
+        assert!((hit.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-2);
+
+        // Smooth union fills in between two spheres and never measures further than the union
+        let a = Sdf::sphere(1.0).translate(Vec3::new(-1.2, 0.0, 0.0));
+        let b = Sdf::sphere(1.0).translate(Vec3::new(1.2, 0.0, 0.0));
+        let blob = a.clone().smooth_union(b.clone(), 1.0);
+        let between = Point3::new(0.0, 0.0, 0.0);
+        assert!(blob.distance(&between) < 0.0 && a.union(b).distance(&between) > 0.0);
+
+        let bulb = SdfHitable::new(Sdf::mandelbulb(8.0, 12), material.clone());
+        assert!(bulb.hit(&r, 0.001, Float::MAX).is_some());
+        let miss = Ray::new(Point3::new(-5.0, 3.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(bulb.hit(&miss, 0.001, Float::MAX).is_none());
+
+        let rounded = SdfHitable::new(Sdf::round_box(Vec3::new(1.0, 1.0, 1.0), 0.2), material);
+        let hit = rounded.hit(&r, 0.001, Float::MAX).unwrap();
+        assert!((hit.p.x() + 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn scale_needs_positive_factor () {
+        Sdf::sphere(1.0).scale(-2.0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn round_box_radius_fits_inside () {
+        Sdf::round_box(Vec3::new(1.0, 0.1, 1.0), 0.2);
+    }
+}
@@ -0,0 +1,130 @@
+//! Four floats worked on at once. On x86_64 in single precision these are SSE registers; in
+//! double precision, on other targets, or when built with the `scalar` feature, plain arrays that
+//! the compiler vectorizes as it can. The `scalar` feature is there to measure what SSE gains.
+//!
+//! Ray packets keep one ray per lane. `Vec3` does not use these: padding it to four lanes made it
+//! a third larger without making its arithmetic any faster.
+
+use std::ops::{Add, Sub, Mul, Div};
+
+use crate::float::Float;
+
+// SSE is part of every x86_64 target, which is what makes the unsafe calls below sound
+#[cfg(all(target_arch = "x86_64", not(feature = "f64"), not(feature = "scalar")))]
+use std::arch::x86_64::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C, align(16))]
+pub struct Float4(pub [Float; 4]);
+
+/// Result of comparing two `Float4`s lane by lane.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mask4(pub [bool; 4]);
+
+impl Mask4 {
+    pub fn any(self) -> bool {
+        self.0.iter().any(|&m| m)
+    }
+
+    pub fn and(self, other: Mask4) -> Mask4 {
+        Mask4(std::array::from_fn(|i| self.0[i] && other.0[i]))
+    }
+
+    pub fn or(self, other: Mask4) -> Mask4 {
+        Mask4(std::array::from_fn(|i| self.0[i] || other.0[i]))
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "f64"), not(feature = "scalar")))]
+impl Float4 {
+    fn m(self) -> __m128 {
+        // SAFETY: the pointer is to four f32 aligned to 16 bytes
+        unsafe { _mm_load_ps(self.0.as_ptr()) }
+    }
+
+    fn from_m(m: __m128) -> Float4 {
+        let mut result = Float4([0.0; 4]);
+        // SAFETY: as in `m`
+        unsafe { _mm_store_ps(result.0.as_mut_ptr(), m) };
+        result
+    }
+
+    fn mask(m: __m128) -> Mask4 {
+        // SAFETY: SSE is always available on x86_64, and the instruction only reads a register
+        let bits = unsafe { _mm_movemask_ps(m) };
+        Mask4(std::array::from_fn(|i| bits & (1 << i) != 0))
+    }
+}
+
+// Lane by lane operations, with an SSE instruction where there is one
+macro_rules! lanewise {
+    ($name:ident, $sse:ident, |$a:ident, $b:ident| $scalar:expr) => {
+        pub fn $name(self, other: Float4) -> Float4 {
+            #[cfg(all(target_arch = "x86_64", not(feature = "f64"), not(feature = "scalar")))]
+            return Float4::from_m(unsafe { $sse(self.m(), other.m()) });
+            #[cfg(not(all(target_arch = "x86_64", not(feature = "f64"), not(feature = "scalar"))))]
+            return Float4(std::array::from_fn(|i| {
+                let ($a, $b) = (self.0[i], other.0[i]);
+                $scalar
+            }));
+        }
+    };
+}
+
+// Arithmetic operators, lane by lane
+macro_rules! operator {
+    ($trait:ident, $method:ident, $sse:ident, $op:tt) => {
+        impl $trait for Float4 {
+            type Output = Float4;
+
+            fn $method(self, other: Float4) -> Float4 {
+                #[cfg(all(target_arch = "x86_64", not(feature = "f64"), not(feature = "scalar")))]
+                return Float4::from_m(unsafe { $sse(self.m(), other.m()) });
+                #[cfg(not(all(target_arch = "x86_64", not(feature = "f64"), not(feature = "scalar"))))]
+                return Float4(std::array::from_fn(|i| self.0[i] $op other.0[i]));
+            }
+        }
+    };
+}
+
+// Lane by lane comparisons
+macro_rules! comparison {
+    ($name:ident, $sse:ident, $op:tt) => {
+        pub fn $name(self, other: Float4) -> Mask4 {
+            #[cfg(all(target_arch = "x86_64", not(feature = "f64"), not(feature = "scalar")))]
+            return Float4::mask(unsafe { $sse(self.m(), other.m()) });
+            #[cfg(not(all(target_arch = "x86_64", not(feature = "f64"), not(feature = "scalar"))))]
+            return Mask4(std::array::from_fn(|i| self.0[i] $op other.0[i]));
+        }
+    };
+}
+
+impl Float4 {
+    pub fn splat(x: Float) -> Float4 {
+        Float4([x; 4])
+    }
+
+    lanewise!(min, _mm_min_ps, |a, b| if a < b { a } else { b });
+    lanewise!(max, _mm_max_ps, |a, b| if a > b { a } else { b });
+
+    comparison!(lt, _mm_cmplt_ps, <);
+    comparison!(gt, _mm_cmpgt_ps, >);
+    comparison!(le, _mm_cmple_ps, <=);
+
+    pub fn sqrt(self) -> Float4 {
+        #[cfg(all(target_arch = "x86_64", not(feature = "f64"), not(feature = "scalar")))]
+        return Float4::from_m(unsafe { _mm_sqrt_ps(self.m()) });
+        #[cfg(not(all(target_arch = "x86_64", not(feature = "f64"), not(feature = "scalar"))))]
+        return Float4(self.0.map(Float::sqrt));
+    }
+
+    /// Lanes of `a` where `mask` is set and of `b` elsewhere.
+    pub fn select(mask: Mask4, a: Float4, b: Float4) -> Float4 {
+        Float4(std::array::from_fn(|i| if mask.0[i] { a.0[i] } else { b.0[i] }))
+    }
+}
+
+operator!(Add, add, _mm_add_ps, +);
+operator!(Sub, sub, _mm_sub_ps, -);
+operator!(Mul, mul, _mm_mul_ps, *);
+operator!(Div, div, _mm_div_ps, /);
@@ -0,0 +1,123 @@
+use std::path::Path;
+
+use crate::float::Float;
+use crate::camera::{Camera, CameraBuilder, CameraError};
+use crate::projection::{PanoramaLayout, PanoramicCamera, Projection};
+use crate::image::Image;
+use crate::render::{RenderSettings, render_image};
+use crate::scene::Scene;
+use crate::vec::{Vec3, Point3};
+
+/// How the two eyes of a stereo rig line up on the convergence distance, where objects appear to
+/// be at the depth of the screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Convergence {
+    // Both eyes rotate inwards to look at the same point. Simple, but introduces vertical parallax
+    // towards the corners of the image.
+    ToeIn,
+    // The eyes look parallel, and their image windows are shifted towards each other instead
+    OffAxis,
+}
+
+/// How the left and right views are packed into one output image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoLayout {
+    // Left view on the left, right view on the right, in an image twice as wide
+    SideBySide,
+    // Left view on top, right view at the bottom, in an image twice as tall
+    OverUnder,
+    // Red channel from the left view, green and blue from the right, for red/cyan glasses
+    Anaglyph,
+}
+
+/// Pair of cameras, `interocular` apart, centered on the camera described by a builder.
+#[derive(Clone)]
+pub struct StereoRig {
+    camera: CameraBuilder,
+    pub interocular: Float,
+    pub convergence_distance: Float,
+    pub convergence: Convergence,
+}
+
+impl StereoRig {
+    pub fn new(camera: CameraBuilder, interocular: Float, convergence_distance: Float, convergence: Convergence) -> StereoRig {
+        StereoRig { camera, interocular, convergence_distance, convergence }
+    }
+
+    /// Left and right eye cameras.
+    pub fn eyes(&self) -> Result<(Camera, Camera), CameraError> {
+        let eye = |offset: Float| self.camera.clone().stereo_eye(offset, self.convergence_distance, self.convergence).build();
+        Ok((eye(-self.interocular / 2.0)?, eye(self.interocular / 2.0)?))
+    }
+}
+
+/// Left and right eye equirectangular cameras of an omni-directional stereo panorama, for
+/// viewing in a VR headset. Usually packed over-under.
+pub fn omni_stereo_eyes(lookfrom: Point3, lookat: Point3, vup: Vec3, interocular: Float) -> (PanoramicCamera, PanoramicCamera) {
+    (
+        PanoramicCamera::omni_stereo(lookfrom, lookat, vup, PanoramaLayout::Equirectangular, -interocular / 2.0),
+        PanoramicCamera::omni_stereo(lookfrom, lookat, vup, PanoramaLayout::Equirectangular, interocular / 2.0),
+    )
+}
+
+/// Pack a left and right view of the same size into one image.
+pub fn pack(left: &Image, right: &Image, layout: StereoLayout) -> Image {
+    let (width, height) = (left.width(), left.height());
+    let mut packed = match layout {
+        StereoLayout::SideBySide => Image::new(2 * width, height),
+        StereoLayout::OverUnder => Image::new(width, 2 * height),
+        StereoLayout::Anaglyph => Image::new(width, height),
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let (l, r) = (left.get(x, y), right.get(x, y));
+            match layout {
+                StereoLayout::SideBySide => {
+                    packed.set(x, y, l);
+                    packed.set(width + x, y, r);
+                }
+                StereoLayout::OverUnder => {
+                    packed.set(x, y, l);
+                    packed.set(x, height + y, r);
+                }
+                StereoLayout::Anaglyph => packed.set(x, y, Vec3::new(l.r(), r.g(), r.b())),
+            }
+        }
+    }
+    packed
+}
+
+/// Render both eyes in one run and write them packed into a single image file.
+pub fn render_stereo<P: AsRef<Path>>(
+    filename: P,
+    scene: &Scene,
+    left: &dyn Projection,
+    right: &dyn Projection,
+    layout: StereoLayout,
+    settings: &RenderSettings
+) -> std::io::Result<()> {
+    let left = render_image(scene, left, &settings.for_pass(0));
+    let right = render_image(scene, right, &settings.for_pass(1));
+    pack(&left, &right, layout).save(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+    use crate::vec::dot;
+    #[test]
+    fn off_axis_eyes_converge () {
+        let base = Camera::builder().lookfrom(Point3::new(0.0, 0.0, 5.0)).lookat(Point3::new(0.0, 0.0, 0.0)).vfov(40.0).aspect_ratio(1.5);
+        for convergence in [Convergence::ToeIn, Convergence::OffAxis] {
+            let (left, right) = StereoRig::new(base.clone(), 0.065, 4.0, convergence).eyes().unwrap();
+            let mut sampler = SamplerKind::Independent.create(1, 1);
+            let (l, r) = (left.get_ray(0.5, 0.5, sampler.as_mut()), right.get_ray(0.5, 0.5, sampler.as_mut()));
+            // The center rays of both eyes cross on the convergence plane at z = 1
+            let l_at = l.point_at_parameter((1.0 - l.origin.z()) / l.direction.z());
+            let r_at = r.point_at_parameter((1.0 - r.origin.z()) / r.direction.z());
+            assert!((l_at - r_at).length() < 1e-4);
+            assert!(dot(&(r.origin - l.origin), &Vec3::new(1.0, 0.0, 0.0)) > 0.0);
+        }
+    }
+}
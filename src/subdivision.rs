@@ -0,0 +1,290 @@
+//! Subdivision surfaces: Catmull-Clark for meshes of quads and Loop for triangles. Each level
+//! splits every face and moves the vertices towards a smooth limit surface.
+//!
+//! Boundary edges stay sharp, so open meshes keep their outline. Creased edges follow the rules
+//! for sharp edges for as many levels as their sharpness, and a fractional sharpness blends the
+//! smooth and sharp rules. A vertex with two sharp edges slides along the crease, and one with
+//! more than two is a corner that stays put.
+
+use std::collections::HashMap;
+
+use crate::float::{Float, consts::PI};
+use crate::mesh::{Mesh, edge_key};
+use crate::vec::{Vec3, Point3};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subdivision {
+    // Turns any polygons into quads
+    CatmullClark,
+    // Triangulates the mesh first and keeps it made of triangles
+    Loop,
+}
+
+impl Subdivision {
+    /// Scheme from its name on the command line: catmull-clark or loop.
+    pub fn from_name(name: &str) -> Option<Subdivision> {
+        match name {
+            "catmull-clark" => Some(Subdivision::CatmullClark),
+            "loop" => Some(Subdivision::Loop),
+            _ => None,
+        }
+    }
+
+    /// `mesh` subdivided `levels` times.
+    pub fn apply(self, mesh: &Mesh, levels: u32) -> Mesh {
+        let mut mesh = mesh.clone();
+        for _ in 0..levels {
+            mesh = match self {
+                Subdivision::CatmullClark => catmull_clark(&mesh),
+                Subdivision::Loop => loop_subdivision(&mesh),
+            };
+        }
+        mesh
+    }
+}
+
+struct Edge {
+    a: usize,
+    b: usize,
+    faces: Vec<usize>,
+    // Infinite on the boundary and where more than two faces meet
+    sharpness: Float,
+}
+
+/// Edges of a mesh and what is around each vertex.
+struct Topology {
+    edges: Vec<Edge>,
+    edge_index: HashMap<(usize, usize), usize>,
+    vertex_edges: Vec<Vec<usize>>,
+    vertex_faces: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    fn new(mesh: &Mesh) -> Topology {
+        let mut topology = Topology {
+            edges: Vec::new(),
+            edge_index: HashMap::new(),
+            vertex_edges: vec![Vec::new(); mesh.positions.len()],
+            vertex_faces: vec![Vec::new(); mesh.positions.len()],
+        };
+        for (f, face) in mesh.faces.iter().enumerate() {
+            for (i, &a) in face.iter().enumerate() {
+                let b = face[(i + 1) % face.len()];
+                topology.vertex_faces[a].push(f);
+                let index = match topology.edge_index.get(&edge_key(a, b)) {
+                    Some(&index) => index,
+                    None => {
+                        let index = topology.edges.len();
+                        topology.edges.push(Edge { a, b, faces: Vec::new(), sharpness: 0.0 });
+                        topology.edge_index.insert(edge_key(a, b), index);
+                        topology.vertex_edges[a].push(index);
+                        topology.vertex_edges[b].push(index);
+                        index
+                    }
+                };
+                topology.edges[index].faces.push(f);
+            }
+        }
+        for edge in &mut topology.edges {
+            edge.sharpness = if edge.faces.len() == 2 { mesh.crease(edge.a, edge.b) } else { Float::INFINITY };
+        }
+        topology
+    }
+
+    fn edge(&self, a: usize, b: usize) -> usize {
+        self.edge_index[&edge_key(a, b)]
+    }
+
+    /// New position of vertex `v` from the position the smooth rule gives it. Crease vertices
+    /// average their two sharp neighbours and corners do not move.
+    fn vertex_point(&self, mesh: &Mesh, v: usize, smooth: impl FnOnce() -> Option<Point3>) -> Point3 {
+        let p = mesh.positions[v];
+        let sharp: Vec<&Edge> = self.vertex_edges[v].iter().map(|&e| &self.edges[e]).filter(|e| e.sharpness > 0.0).collect();
+        if sharp.len() < 2 {
+            // Without a smooth rule, as on a vertex of a non-manifold fan, keep it where it is
+            return smooth().unwrap_or(p);
+        }
+        // The corner of a single face on the boundary stays put too
+        let sharp_point = if sharp.len() == 2 && self.vertex_faces[v].len() > 1 {
+            let other = |e: &Edge| mesh.positions[if e.a == v { e.b } else { e.a }];
+            0.75 * p + 0.125 * (other(sharp[0]) + other(sharp[1]))
+        } else {
+            p
+        };
+        let sharpness = sharp.iter().map(|e| e.sharpness).sum::<Float>() / sharp.len() as Float;
+        match smooth() {
+            Some(smooth_point) if sharpness < 1.0 => lerp(smooth_point, sharp_point, sharpness),
+            _ => sharp_point,
+        }
+    }
+
+    /// Creases of the subdivided mesh: each creased edge is split at its new midpoint vertex,
+    /// one level less sharp.
+    fn child_creases(&self, first_edge_vertex: usize) -> HashMap<(usize, usize), Float> {
+        let mut creases = HashMap::new();
+        for (i, edge) in self.edges.iter().enumerate() {
+            if edge.faces.len() == 2 && edge.sharpness > 1.0 {
+                let mid = first_edge_vertex + i;
+                creases.insert(edge_key(edge.a, mid), edge.sharpness - 1.0);
+                creases.insert(edge_key(mid, edge.b), edge.sharpness - 1.0);
+            }
+        }
+        creases
+    }
+}
+
+fn lerp(a: Vec3, b: Vec3, t: Float) -> Vec3 {
+    (1.0 - t) * a + t * b
+}
+
+fn average<'a>(points: impl Iterator<Item = &'a Point3>) -> Point3 {
+    let (sum, count) = points.fold((Vec3::new(0.0, 0.0, 0.0), 0), |(sum, count), p| (sum + *p, count + 1));
+    sum / count as Float
+}
+
+/// One level of Catmull-Clark. The new vertices are the old ones, then one per edge, then one
+/// per face.
+fn catmull_clark(mesh: &Mesh) -> Mesh {
+    let topology = Topology::new(mesh);
+    let face_points: Vec<Point3> = mesh.faces.iter().map(|face| average(face.iter().map(|&v| &mesh.positions[v]))).collect();
+    let edge_points: Vec<Point3> = topology.edges.iter().map(|edge| {
+        let mid = 0.5 * (mesh.positions[edge.a] + mesh.positions[edge.b]);
+        if edge.sharpness >= 1.0 {
+            return mid;
+        }
+        let smooth = 0.5 * mid + 0.5 * average(edge.faces.iter().map(|&f| &face_points[f]));
+        lerp(smooth, mid, edge.sharpness)
+    }).collect();
+
+    let mut positions: Vec<Point3> = (0..mesh.positions.len()).map(|v| {
+        topology.vertex_point(mesh, v, || {
+            let (faces, edges) = (&topology.vertex_faces[v], &topology.vertex_edges[v]);
+            if faces.len() != edges.len() {
+                return None;
+            }
+            let n = edges.len() as Float;
+            let q = average(faces.iter().map(|&f| &face_points[f]));
+            let r = edges.iter().map(|&e| 0.5 * (mesh.positions[topology.edges[e].a] + mesh.positions[topology.edges[e].b])).fold(Vec3::new(0.0, 0.0, 0.0), |acc, m| acc + m) / n;
+            Some((q + 2.0 * r + (n - 3.0) * mesh.positions[v]) / n)
+        })
+    }).collect();
+    let first_edge_vertex = positions.len();
+    let first_face_vertex = first_edge_vertex + edge_points.len();
+    positions.extend(edge_points);
+    positions.extend(face_points);
+
+    let mut faces = Vec::new();
+    for (f, face) in mesh.faces.iter().enumerate() {
+        let k = face.len();
+        for i in 0..k {
+            let (previous, v, next) = (face[(i + k - 1) % k], face[i], face[(i + 1) % k]);
+            faces.push(vec![
+                v,
+                first_edge_vertex + topology.edge(v, next),
+                first_face_vertex + f,
+                first_edge_vertex + topology.edge(previous, v),
+            ]);
+        }
+    }
+    Mesh { positions, faces, creases: topology.child_creases(first_edge_vertex), texcoords: Vec::new() }
+}
+
+/// One level of Loop subdivision, after splitting any polygons into triangles. The new vertices
+/// are the old ones, then one per edge.
+fn loop_subdivision(mesh: &Mesh) -> Mesh {
+    let triangles: Vec<Vec<usize>> = mesh.triangles().into_iter().map(|t| t.to_vec()).collect();
+    let mesh = Mesh { positions: mesh.positions.clone(), faces: triangles, creases: mesh.creases.clone(), texcoords: Vec::new() };
+    let topology = Topology::new(&mesh);
+    let edge_points: Vec<Point3> = topology.edges.iter().map(|edge| {
+        let (a, b) = (mesh.positions[edge.a], mesh.positions[edge.b]);
+        let mid = 0.5 * (a + b);
+        if edge.sharpness >= 1.0 {
+            return mid;
+        }
+        // The vertices across the edge in its two triangles
+        let opposite = |f: usize| {
+            let v = mesh.faces[f].iter().find(|&&v| v != edge.a && v != edge.b).copied().unwrap_or(edge.a);
+            mesh.positions[v]
+        };
+        let smooth = 0.375 * (a + b) + 0.125 * (opposite(edge.faces[0]) + opposite(edge.faces[1]));
+        lerp(smooth, mid, edge.sharpness)
+    }).collect();
+
+    let mut positions: Vec<Point3> = (0..mesh.positions.len()).map(|v| {
+        topology.vertex_point(&mesh, v, || {
+            let edges = &topology.vertex_edges[v];
+            if topology.vertex_faces[v].len() != edges.len() {
+                return None;
+            }
+            let n = edges.len() as Float;
+            let beta = (0.625 - (0.375 + 0.25 * (2.0 * PI / n).cos()).powi(2)) / n;
+            let neighbours = edges.iter().fold(Vec3::new(0.0, 0.0, 0.0), |acc, &e| {
+                let edge = &topology.edges[e];
+                acc + mesh.positions[if edge.a == v { edge.b } else { edge.a }]
+            });
+            Some((1.0 - n * beta) * mesh.positions[v] + beta * neighbours)
+        })
+    }).collect();
+    let first_edge_vertex = positions.len();
+    positions.extend(edge_points);
+
+    let mut faces = Vec::new();
+    for face in &mesh.faces {
+        let (a, b, c) = (face[0], face[1], face[2]);
+        let ab = first_edge_vertex + topology.edge(a, b);
+        let bc = first_edge_vertex + topology.edge(b, c);
+        let ca = first_edge_vertex + topology.edge(c, a);
+        faces.extend([vec![a, ab, ca], vec![ab, b, bc], vec![ca, bc, c], vec![ab, bc, ca]]);
+    }
+    Mesh { positions, faces, creases: topology.child_creases(first_edge_vertex), texcoords: Vec::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn cube () -> Mesh {
+        let positions = (0..8).map(|i| Vec3::new(
+            if i & 1 == 0 { -1.0 } else { 1.0 },
+            if i & 2 == 0 { -1.0 } else { 1.0 },
+            if i & 4 == 0 { -1.0 } else { 1.0 },
+        )).collect();
+        let faces = vec![vec![0, 2, 3, 1], vec![4, 5, 7, 6], vec![0, 1, 5, 4], vec![2, 6, 7, 3], vec![0, 4, 6, 2], vec![1, 3, 7, 5]];
+        Mesh::new(positions, faces)
+    }
+
+    #[test]
+    fn subdivision_smooths_and_keeps_creases () {
+        // A cube becomes rounder with every level, staying inside the cube and outside its
+        // inscribed sphere
+        let smooth = Subdivision::CatmullClark.apply(&cube(), 1);
+        assert_eq!((smooth.positions.len(), smooth.faces.len()), (26, 24));
+        let corner = smooth.positions[7];
+        assert!((corner - Vec3::new(5.0 / 9.0, 5.0 / 9.0, 5.0 / 9.0)).length() < 1e-5);
+        let smoother = Subdivision::CatmullClark.apply(&cube(), 3);
+        assert_eq!(smoother.faces.len(), 6 * 64);
+        assert!(smoother.positions.iter().all(|p| p.length() > 0.5 && p.length() < Float::sqrt(3.0)));
+
+        // With every edge infinitely sharp the cube keeps its shape
+        let mut sharp = cube();
+        for face in cube().faces {
+            for i in 0..4 {
+                sharp = sharp.with_crease(face[i], face[(i + 1) % 4], Float::INFINITY);
+            }
+        }
+        let sharp = Subdivision::CatmullClark.apply(&sharp, 2);
+        assert!(sharp.positions.iter().all(|p| (p.x().abs().max(p.y().abs()).max(p.z().abs()) - 1.0).abs() < 1e-5));
+        assert!((sharp.positions[7] - Vec3::new(1.0, 1.0, 1.0)).length() < 1e-6);
+
+        // Loop on the triangulated cube gives triangles, and a flat open patch stays flat with a
+        // fixed outline
+        let triangles = Subdivision::Loop.apply(&cube(), 2);
+        assert_eq!(triangles.faces.len(), 12 * 16);
+        assert!(triangles.faces.iter().all(|f| f.len() == 3));
+        let square = Mesh::new(cube().positions[..4].to_vec(), vec![vec![0, 1, 3, 2]]);
+        for scheme in [Subdivision::CatmullClark, Subdivision::Loop] {
+            let patch = scheme.apply(&square, 2);
+            assert!(patch.positions.iter().all(|p| (p.z() + 1.0).abs() < 1e-6 && p.x().abs() <= 1.0 && p.y().abs() <= 1.0));
+            assert!((patch.positions[1] - Vec3::new(1.0, -1.0, -1.0)).length() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::float::Float;
+use crate::png;
+use crate::vec::Vec3;
+
+/// A color (or scalar) value that varies over the (u, v) parametrization of a surface.
+#[derive(Clone)]
+pub enum Texture {
+    Solid(Vec3),
+    Image(Arc<ImageTexture>),
+}
+
+impl Texture {
+    pub fn value(&self, u: Float, v: Float) -> Vec3 {
+        match self {
+            Texture::Solid(color) => *color,
+            Texture::Image(image) => image.value(u, v),
+        }
+    }
+
+    /// Scalar value of the texture, the average of the three channels. Used for height maps.
+    pub fn scalar(&self, u: Float, v: Float) -> Float {
+        let c = self.value(u, v);
+        (c.r() + c.g() + c.b()) / 3.0
+    }
+
+    /// Distance in (u, v) between two texels, or a small default for textures without a
+    /// resolution. Used as the step for finite differences.
+    pub fn texel_size(&self) -> (Float, Float) {
+        match self {
+            Texture::Solid(_) => (1e-3, 1e-3),
+            Texture::Image(image) => (1.0 / image.width as Float, 1.0 / image.height as Float),
+        }
+    }
+}
+
+/// An image with linear channel values in [0, 1], stored from the top row to the bottom row.
+pub struct ImageTexture {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3>,
+}
+
+impl ImageTexture {
+    /// Load a PNG image or a Netpbm image, in plain (P2, P3) or binary (P5, P6) grayscale or RGB
+    /// format. The values are not gamma corrected, which is what height and normal maps expect.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ImageTexture> {
+        let bytes = fs::read(path)?;
+        ImageTexture::from_bytes(&bytes)
+    }
+
+    /// Decode an image file already in memory, telling the format from its first bytes.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<ImageTexture> {
+        if png::is_png(bytes) {
+            png::decode(bytes)
+        } else {
+            ImageTexture::from_pnm(bytes)
+        }
+    }
+
+    pub fn from_pnm(bytes: &[u8]) -> io::Result<ImageTexture> {
+        let mut reader = PnmReader { bytes, pos: 0 };
+        let magic = reader.token()?;
+        let (channels, binary) = match magic.as_str() {
+            "P2" => (1, false),
+            "P3" => (3, false),
+            "P5" => (1, true),
+            "P6" => (3, true),
+            _ => return Err(invalid_data(format!("unsupported image format '{}'", magic))),
+        };
+        let width = reader.number()?;
+        let height = reader.number()?;
+        let maxval = reader.number()?;
+        if maxval == 0 || maxval > 65535 {
+            return Err(invalid_data(format!("invalid maximum value {}", maxval)));
+        }
+        let count = width.checked_mul(height).and_then(|n| n.checked_mul(channels))
+            .filter(|&n| n > 0)
+            .ok_or_else(|| invalid_data(format!("invalid image size {}x{}", width, height)))?;
+        // A single whitespace character separates the header from binary data
+        reader.pos += 1;
+        // Every sample takes at least one byte, so a header promising more than the data can hold
+        // is rejected before allocating for it
+        if count > bytes.len().saturating_sub(reader.pos) {
+            return Err(invalid_data(format!("a {}x{} image needs more data than there is", width, height)));
+        }
+
+        let mut samples = Vec::with_capacity(count);
+        for _ in 0..count {
+            let sample = if !binary {
+                reader.number()?
+            } else if maxval < 256 {
+                reader.byte()? as usize
+            } else {
+                (reader.byte()? as usize) << 8 | reader.byte()? as usize
+            };
+            samples.push(sample as Float / maxval as Float);
+        }
+        let pixels = samples
+            .chunks(channels)
+            .map(|c| if channels == 1 { Vec3::new(c[0], c[0], c[0]) } else { Vec3::new(c[0], c[1], c[2]) })
+            .collect();
+        Ok(ImageTexture { width, height, pixels })
+    }
+
+    fn texel(&self, x: isize, y: isize) -> Vec3 {
+        let x = x.rem_euclid(self.width as isize) as usize;
+        let y = y.rem_euclid(self.height as isize) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    /// Bilinearly filtered lookup, repeating the image outside of [0, 1]. `v` = 0 is the bottom
+    /// row of the image.
+    pub fn value(&self, u: Float, v: Float) -> Vec3 {
+        let x = u * self.width as Float - 0.5;
+        let y = (1.0 - v) * self.height as Float - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let top = (1.0 - fx) * self.texel(x0, y0) + fx * self.texel(x0 + 1, y0);
+        let bottom = (1.0 - fx) * self.texel(x0, y0 + 1) + fx * self.texel(x0 + 1, y0 + 1);
+        (1.0 - fy) * top + fy * bottom
+    }
+}
+
+struct PnmReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PnmReader<'a> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let b = *self.bytes.get(self.pos).ok_or_else(|| invalid_data("unexpected end of image data".to_string()))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    /// Next whitespace separated token, skipping `#` comments.
+    fn token(&mut self) -> io::Result<String> {
+        loop {
+            match self.bytes.get(self.pos) {
+                Some(b'#') => {
+                    while let Some(&b) = self.bytes.get(self.pos) {
+                        if b == b'\n' { break; }
+                        self.pos += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => self.pos += 1,
+                _ => break,
+            }
+        }
+        let start = self.pos;
+        while let Some(b) = self.bytes.get(self.pos) {
+            if b.is_ascii_whitespace() { break; }
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(invalid_data("unexpected end of image header".to_string()));
+        }
+        Ok(String::from_utf8_lossy(&self.bytes[start..self.pos]).into_owned())
+    }
+
+    fn number(&mut self) -> io::Result<usize> {
+        let token = self.token()?;
+        token.parse().map_err(|_| invalid_data(format!("expected a number, found '{}'", token)))
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn netpbm_images_need_pixels () {
+        assert!(ImageTexture::from_pnm(b"P3 0 2 255\n").is_err());
+        assert!(ImageTexture::from_pnm(b"P2 2 0 255\n").is_err());
+        assert!(ImageTexture::from_pnm(b"P5 99999999999 99999999999 255\n").is_err());
+        assert!(ImageTexture::from_pnm(b"P5 3000000 3000000 255\n\x00\x00").is_err());
+        let image = ImageTexture::from_pnm(b"P2 2 1 255\n0 255\n").unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.texel(-1, 0).e, [1.0, 1.0, 1.0]);
+    }
+}
@@ -1,270 +1,461 @@
+use std::fmt;
 use std::ops::{Add, AddAssign, Sub, Mul, Div};
-use rand::prelude::ThreadRng;
-use rand::{random, Rng, thread_rng};
+use rand::Rng;
+use crate::float::Float;
+use crate::ray::gamma;
 
 
 // Vec3
 #[derive(Debug, Clone, Copy)]
 pub struct Vec3 {
-    pub e: [f32; 3]
+    pub e: [Float; 3]
 }
 
 impl Vec3 {
-    pub fn new(e0: f32, e1: f32, e2: f32) -> Vec3 {
+    pub fn new(e0: Float, e1: Float, e2: Float) -> Vec3 {
         Vec3 {
             e: [e0, e1, e2]
         }
     }
 
-    pub fn x(&self) -> f32 {
+    pub fn x(&self) -> Float {
         self.e[0]
     }
 
-    pub fn y(&self) -> f32 {
+    pub fn y(&self) -> Float {
         self.e[1]
     }
 
-    pub fn z(&self) -> f32 {
+    pub fn z(&self) -> Float {
         self.e[2]
     }
 
-    pub fn r(&self) -> f32 {
+    pub fn r(&self) -> Float {
         self.e[0]
     }
 
-    pub fn g(&self) -> f32 {
+    pub fn g(&self) -> Float {
         self.e[1]
     }
 
-    pub fn b(&self) -> f32 {
+    pub fn b(&self) -> Float {
         self.e[2]
     }
 
-    pub fn length(&self) -> f32 {
-        (self.e[0] * self.e[0] + self.e[1] * self.e[1] + self.e[2] * self.e[2]).sqrt()
+    pub fn length(&self) -> Float {
+        self.squared_length().sqrt()
     }
 
-    pub fn squared_length(&self) -> f32 {
-        self.e[0] * self.e[0] + self.e[1] * self.e[1] + self.e[2] * self.e[2]
+    pub fn squared_length(&self) -> Float {
+        dot(self, self)
     }
 
     pub fn unit_vector(&self) -> Vec3 {
         self / self.length()
     }
 
-    pub fn random() -> Vec3 {
-        Vec3::new(random(), random(), random())
+    pub fn exp(&self) -> Vec3 {
+        Vec3::new(self.e[0].exp(), self.e[1].exp(), self.e[2].exp())
     }
 
-    pub fn random_range(min: f32, max: f32) -> Vec3 {
-        let mut rng = thread_rng();
+    pub fn abs(&self) -> Vec3 {
+        Vec3::new(self.e[0].abs(), self.e[1].abs(), self.e[2].abs())
+    }
+
+    pub fn random<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
+        Vec3::new(rng.gen(), rng.gen(), rng.gen())
+    }
+
+    pub fn random_range<R: Rng + ?Sized>(rng: &mut R, min: Float, max: Float) -> Vec3 {
         Vec3::new(rng.gen_range(min..max), rng.gen_range(min..max), rng.gen_range(min..max))
     }
 
 }
 
-impl Add for &Vec3 {
-    type Output = Vec3;
-
-    fn add(self, other: Self) -> Self::Output {
-        Vec3::new(
-            self.e[0] + other.e[0],
-            self.e[1] + other.e[1],
-            self.e[2] + other.e[2]
-        )
+impl fmt::Display for Vec3 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match f.precision() {
+            Some(p) => write!(f, "({:.*}, {:.*}, {:.*})", p, self.e[0], p, self.e[1], p, self.e[2]),
+            None => write!(f, "({}, {}, {})", self.e[0], self.e[1], self.e[2]),
+        }
     }
 }
 
-impl Add for Vec3 {
-    type Output = Vec3;
+// Coordinate by coordinate operators between two vectors, owned or borrowed
+macro_rules! vector_operator {
+    ($trait:ident, $method:ident, $op:tt) => {
+        impl $trait for Vec3 {
+            type Output = Vec3;
 
-    fn add(self, other: Self) -> Self::Output {
-        Vec3::new(
-            self.e[0] + other.e[0],
-            self.e[1] + other.e[1],
-            self.e[2] + other.e[2]
-        )
-    }
-}
+            fn $method(self, other: Vec3) -> Vec3 {
+                Vec3 { e: std::array::from_fn(|i| self.e[i] $op other.e[i]) }
+            }
+        }
 
-impl Sub for &Vec3 {
-    type Output = Vec3;
+        impl $trait for &Vec3 {
+            type Output = Vec3;
 
-    fn sub(self, other: Self) -> Self::Output {
-        Vec3::new(
-            self.e[0] - other.e[0],
-            self.e[1] - other.e[1],
-            self.e[2] - other.e[2]
-        )
-    }
+            fn $method(self, other: &Vec3) -> Vec3 {
+                $trait::$method(*self, *other)
+            }
+        }
+    };
 }
 
-impl Sub for Vec3 {
-    type Output = Vec3;
+// Operators between a vector and a scalar, on either side
+macro_rules! scalar_operator {
+    ($trait:ident, $method:ident, $op:tt) => {
+        impl $trait<Float> for Vec3 {
+            type Output = Vec3;
 
-    fn sub(self, other: Self) -> Self::Output {
-        Vec3::new(
-            self.e[0] - other.e[0],
-            self.e[1] - other.e[1],
-            self.e[2] - other.e[2]
-        )
-    }
+            fn $method(self, rhs: Float) -> Vec3 {
+                Vec3 { e: self.e.map(|x| x $op rhs) }
+            }
+        }
+
+        impl $trait<Float> for &Vec3 {
+            type Output = Vec3;
+
+            fn $method(self, rhs: Float) -> Vec3 {
+                $trait::$method(*self, rhs)
+            }
+        }
+
+        impl $trait<Vec3> for Float {
+            type Output = Vec3;
+
+            fn $method(self, rhs: Vec3) -> Vec3 {
+                Vec3 { e: rhs.e.map(|x| self $op x) }
+            }
+        }
+
+        impl $trait<&Vec3> for Float {
+            type Output = Vec3;
+
+            fn $method(self, rhs: &Vec3) -> Vec3 {
+                $trait::$method(self, *rhs)
+            }
+        }
+    };
 }
 
+vector_operator!(Add, add, +);
+vector_operator!(Sub, sub, -);
+vector_operator!(Mul, mul, *);
+vector_operator!(Div, div, /);
+scalar_operator!(Mul, mul, *);
+scalar_operator!(Div, div, /);
+
 impl AddAssign for Vec3 {
     fn add_assign(&mut self, other: Self) {
-        *self = Self {
-            e: [
-                self.e[0] + other.e[0],
-                self.e[1] + other.e[1],
-                self.e[2] + other.e[2]
-            ]
-        };
+        *self = *self + other;
     }
 }
 
-impl Mul for &Vec3 {
-    type Output = Vec3;
+pub fn dot(v1: &Vec3, v2: &Vec3) -> Float {
+    v1.e[0] * v2.e[0] + v1.e[1] * v2.e[1] + v1.e[2] * v2.e[2]
+}
 
-    fn mul(self, rhs: Self) -> Self::Output {
-        Vec3 { e: [
-            self.e[0] * rhs.e[0],
-            self.e[1] * rhs.e[1],
-            self.e[2] * rhs.e[2]
-        ] }
+pub fn cross(v1: &Vec3, v2: &Vec3) -> Vec3 {
+    Vec3::new(
+        v1.e[1] * v2.e[2] - v1.e[2] * v2.e[1],
+        v1.e[2] * v2.e[0] - v1.e[0] * v2.e[2],
+        v1.e[0] * v2.e[1] - v1.e[1] * v2.e[0]
+    )
+}
+
+/// Point in the unit disk for a uniform sample (u1, u2) in [0, 1)², with the concentric mapping of
+/// Shirley and Chiu, which keeps stratified samples evenly spread over the disk.
+pub fn sample_unit_disk(u1: Float, u2: Float) -> Vec3 {
+    let (a, b) = (2.0 * u1 - 1.0, 2.0 * u2 - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return Vec3::new(0.0, 0.0, 0.0);
     }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, crate::float::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, crate::float::consts::FRAC_PI_2 - crate::float::consts::FRAC_PI_4 * (a / b))
+    };
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
 }
 
-impl Mul for Vec3 {
-    type Output = Vec3;
+pub type Point3 = Vec3;
 
-    fn mul(self, rhs: Self) -> Self::Output {
-        Vec3 { e: [
-            self.e[0] * rhs.e[0],
-            self.e[1] * rhs.e[1],
-            self.e[2] * rhs.e[2]
-        ] }
-    }
+/// Row-major 4×4 matrix for affine transforms of points and vectors in homogeneous coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mat4 {
+    pub m: [[Float; 4]; 4]
 }
 
-impl Mul<f32> for &Vec3 {
-    type Output = Vec3;
+impl Mat4 {
+    pub fn identity() -> Mat4 {
+        Mat4 { m: std::array::from_fn(|i| std::array::from_fn(|j| if i == j { 1.0 } else { 0.0 })) }
+    }
 
-    fn mul(self, rhs: f32) -> Self::Output {
-        Vec3 { e: [
-            self.e[0] * rhs,
-            self.e[1] * rhs,
-            self.e[2] * rhs
-        ] }
+    pub fn translation(offset: Vec3) -> Mat4 {
+        let mut matrix = Mat4::identity();
+        for (row, &o) in matrix.m.iter_mut().zip(offset.e.iter()) {
+            row[3] = o;
+        }
+        matrix
     }
-}
 
-impl Mul<f32> for Vec3 {
-    type Output = Vec3;
+    /// Scale by a separate factor along each axis.
+    pub fn scale(factors: Vec3) -> Mat4 {
+        let mut matrix = Mat4::identity();
+        for (i, &f) in factors.e.iter().enumerate() {
+            matrix.m[i][i] = f;
+        }
+        matrix
+    }
 
-    fn mul(self, rhs: f32) -> Self::Output {
-        Vec3 { e: [
-            self.e[0] * rhs,
-            self.e[1] * rhs,
-            self.e[2] * rhs
-        ] }
+    /// Rotation by `degrees` around `axis`, counterclockwise when looking against the axis.
+    pub fn rotation(axis: Vec3, degrees: Float) -> Mat4 {
+        Mat4::from(Quaternion::from_axis_angle(axis, degrees))
     }
-}
 
-impl Mul<&Vec3> for f32 {
-    type Output = Vec3;
+    pub fn transpose(&self) -> Mat4 {
+        Mat4 { m: std::array::from_fn(|i| std::array::from_fn(|j| self.m[j][i])) }
+    }
 
-    fn mul(self, rhs: &Vec3) -> Self::Output {
-        Vec3 { e: [
-            self * rhs.e[0],
-            self * rhs.e[1],
-            self * rhs.e[2]
-        ] }
+    /// Determinant of the 3×3 matrix left after removing `row` and `col`.
+    fn minor(&self, row: usize, col: usize) -> Float {
+        let skip = |skipped: usize, k: usize| if k < skipped { k } else { k + 1 };
+        let a = |i: usize, j: usize| self.m[skip(row, i)][skip(col, j)];
+        a(0, 0) * (a(1, 1) * a(2, 2) - a(1, 2) * a(2, 1))
+            - a(0, 1) * (a(1, 0) * a(2, 2) - a(1, 2) * a(2, 0))
+            + a(0, 2) * (a(1, 0) * a(2, 1) - a(1, 1) * a(2, 0))
     }
-}
 
-impl Mul<Vec3> for f32 {
-    type Output = Vec3;
+    fn cofactor(&self, row: usize, col: usize) -> Float {
+        let sign = if (row + col) & 1 == 0 { 1.0 } else { -1.0 };
+        sign * self.minor(row, col)
+    }
 
-    fn mul(self, rhs: Vec3) -> Self::Output {
-        Vec3 { e: [
-            self * rhs.e[0],
-            self * rhs.e[1],
-            self * rhs.e[2]
-        ] }
+    pub fn determinant(&self) -> Float {
+        (0..4).map(|j| self.m[0][j] * self.cofactor(0, j)).sum()
     }
-}
 
-impl Div for Vec3 {
-    type Output = Self;
+    /// Inverse from the adjugate, or `None` if the matrix is singular. The size of the determinant
+    /// alone says little, as it is tiny for small but valid scales, so it is compared with the
+    /// product of the lengths of the rows, which it reaches when the rows are perpendicular and
+    /// which shrinks with them. The translation column is left out of affine matrices, since it
+    /// does not change whether they can be inverted.
+    pub fn inverse(&self) -> Option<Mat4> {
+        let det = self.determinant();
+        let affine = self.m[3] == [0.0, 0.0, 0.0, 1.0];
+        let columns = if affine { 3 } else { 4 };
+        let row_lengths: Float = (0..columns)
+            .map(|i| (0..columns).map(|j| self.m[i][j] * self.m[i][j]).sum::<Float>().sqrt())
+            .product();
+        if !det.is_finite() || det.abs() <= 64.0 * Float::EPSILON * row_lengths {
+            return None;
+        }
+        Some(Mat4 { m: std::array::from_fn(|i| std::array::from_fn(|j| self.cofactor(j, i) / det)) })
+    }
 
-    fn div(self, rhs: Vec3) -> Self::Output {
-        Vec3 { e: [
-            self.e[0] / rhs.e[0],
-            self.e[1] / rhs.e[1],
-            self.e[2] / rhs.e[2]
-        ] }
+    /// Transpose of the inverse, which transforms normals so that they stay perpendicular to
+    /// transformed surfaces.
+    pub fn inverse_transpose(&self) -> Option<Mat4> {
+        self.inverse().map(|inverse| inverse.transpose())
+    }
+
+    pub fn transform_point(&self, p: &Point3) -> Point3 {
+        let row = |i: usize| self.m[i][0] * p.x() + self.m[i][1] * p.y() + self.m[i][2] * p.z() + self.m[i][3];
+        let w = row(3);
+        let p = Vec3::new(row(0), row(1), row(2));
+        if w == 1.0 { p } else { p / w }
+    }
+
+    /// Transform a direction, which unlike a point is not affected by translation.
+    pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+        let row = |i: usize| self.m[i][0] * v.x() + self.m[i][1] * v.y() + self.m[i][2] * v.z();
+        Vec3::new(row(0), row(1), row(2))
     }
 }
 
-impl Div<f32> for &Vec3 {
-    type Output = Vec3;
+impl Mul for Mat4 {
+    type Output = Mat4;
 
-    fn div(self, rhs: f32) -> Self::Output {
-        Vec3 { e: [
-            self.e[0] / rhs,
-            self.e[1] / rhs,
-            self.e[2] / rhs
-        ] }
+    fn mul(self, other: Mat4) -> Mat4 {
+        Mat4 { m: std::array::from_fn(|i| std::array::from_fn(|j| (0..4).map(|k| self.m[i][k] * other.m[k][j]).sum())) }
     }
 }
 
-impl Div<f32> for Vec3 {
-    type Output = Vec3;
+/// Rotation as a quaternion w + x·i + y·j + z·k.
+#[derive(Debug, Clone, Copy)]
+pub struct Quaternion {
+    pub w: Float,
+    pub xyz: Vec3
+}
+
+impl Quaternion {
+    pub fn new(w: Float, xyz: Vec3) -> Quaternion {
+        Quaternion { w, xyz }
+    }
 
-    fn div(self, rhs: f32) -> Self::Output {
-        Vec3 { e: [
-            self.e[0] / rhs,
-            self.e[1] / rhs,
-            self.e[2] / rhs
-        ] }
+    pub fn from_axis_angle(axis: Vec3, degrees: Float) -> Quaternion {
+        let (sin, cos) = (0.5 * degrees.to_radians()).sin_cos();
+        Quaternion { w: cos, xyz: sin * axis.unit_vector() }
+    }
+
+    pub fn length(&self) -> Float {
+        (self.w * self.w + self.xyz.squared_length()).sqrt()
+    }
+
+    pub fn unit(&self) -> Quaternion {
+        let length = self.length();
+        Quaternion { w: self.w / length, xyz: self.xyz / length }
+    }
+
+    /// Rotate `v` by this unit quaternion.
+    pub fn rotate(&self, v: &Vec3) -> Vec3 {
+        let t = 2.0 * cross(&self.xyz, v);
+        *v + self.w * t + cross(&self.xyz, &t)
     }
 }
 
-impl Div<&Vec3> for f32 {
-    type Output = Vec3;
+// Composition: `a * b` rotates by `b` first, then by `a`
+impl Mul for Quaternion {
+    type Output = Quaternion;
 
-    fn div(self, rhs: &Vec3) -> Self::Output {
-        Vec3 { e: [
-            self / rhs.e[0],
-            self / rhs.e[1],
-            self / rhs.e[2]
+    fn mul(self, other: Quaternion) -> Quaternion {
+        Quaternion {
+            w: self.w * other.w - dot(&self.xyz, &other.xyz),
+            xyz: self.w * other.xyz + other.w * self.xyz + cross(&self.xyz, &other.xyz),
+        }
+    }
+}
+
+impl From<Quaternion> for Mat4 {
+    fn from(q: Quaternion) -> Mat4 {
+        let q = q.unit();
+        let (w, x, y, z) = (q.w, q.xyz.x(), q.xyz.y(), q.xyz.z());
+        Mat4 { m: [
+            [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - w * z), 2.0 * (x * z + w * y), 0.0],
+            [2.0 * (x * y + w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - w * x), 0.0],
+            [2.0 * (x * z - w * y), 2.0 * (y * z + w * x), 1.0 - 2.0 * (x * x + y * y), 0.0],
+            [0.0, 0.0, 0.0, 1.0],
         ] }
     }
 }
 
-pub fn dot(v1: &Vec3, v2: &Vec3) -> f32 {
-    v1.e[0] * v2.e[0] + v1.e[1] * v2.e[1] + v1.e[2] * v2.e[2]
+/// Invertible transform that keeps its inverse, built up step by step:
+/// `Transform::scale(s).then(&Transform::rotation(axis, degrees)).then(&Transform::translation(t))`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub matrix: Mat4,
+    pub inverse: Mat4
 }
 
-pub fn cross(v1: &Vec3, v2: &Vec3) -> Vec3 {
-    Vec3::new(
-        v1.e[1] * v2.e[2] - v1.e[2] * v2.e[1],
-        v1.e[2] * v2.e[0] - v1.e[0] * v2.e[2],
-        v1.e[0] * v2.e[1] - v1.e[1] * v2.e[0]
-    )
+impl Transform {
+    /// Transform with the given matrix, or `None` if it cannot be inverted.
+    pub fn new(matrix: Mat4) -> Option<Transform> {
+        Some(Transform { matrix, inverse: matrix.inverse()? })
+    }
+
+    pub fn identity() -> Transform {
+        Transform { matrix: Mat4::identity(), inverse: Mat4::identity() }
+    }
+
+    pub fn translation(offset: Vec3) -> Transform {
+        Transform { matrix: Mat4::translation(offset), inverse: Mat4::translation(-1.0 * offset) }
+    }
+
+    /// Scale along the axes. Panics if a factor is zero.
+    pub fn scale(factors: Vec3) -> Transform {
+        assert!(factors.e.iter().all(|&f| f != 0.0), "scale {} cannot be inverted", factors);
+        let inverse = Vec3::new(1.0 / factors.x(), 1.0 / factors.y(), 1.0 / factors.z());
+        Transform { matrix: Mat4::scale(factors), inverse: Mat4::scale(inverse) }
+    }
+
+    pub fn rotation(axis: Vec3, degrees: Float) -> Transform {
+        Transform::from(Quaternion::from_axis_angle(axis, degrees))
+    }
+
+    /// This transform followed by `other`.
+    pub fn then(&self, other: &Transform) -> Transform {
+        Transform { matrix: other.matrix * self.matrix, inverse: self.inverse * other.inverse }
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform { matrix: self.inverse, inverse: self.matrix }
+    }
+
+    pub fn point(&self, p: &Point3) -> Point3 {
+        self.matrix.transform_point(p)
+    }
+
+    /// Transform a point that is only known to within `error`, returning the bound on the error
+    /// of the result: the carried error plus the rounding of the transform itself. The transform
+    /// must be affine.
+    pub fn point_with_error(&self, p: &Point3, error: &Vec3) -> (Point3, Vec3) {
+        let m = &self.matrix.m;
+        let bound = |i: usize| {
+            let rounding = (0..3).map(|j| (m[i][j] * p.e[j]).abs()).sum::<Float>() + m[i][3].abs();
+            let carried = (0..3).map(|j| m[i][j].abs() * error.e[j]).sum::<Float>();
+            gamma(3) * rounding + (1.0 + gamma(3)) * carried
+        };
+        (self.point(p), Vec3::new(bound(0), bound(1), bound(2)))
+    }
+
+    pub fn vector(&self, v: &Vec3) -> Vec3 {
+        self.matrix.transform_vector(v)
+    }
+
+    /// Transform a surface normal with the inverse transpose. The result is not normalized.
+    pub fn normal(&self, n: &Vec3) -> Vec3 {
+        self.inverse.transpose().transform_vector(n)
+    }
 }
 
-pub fn random_in_unit_disk(rng: &mut ThreadRng) -> Vec3 {
-    loop {
-        let p = Vec3::new(
-            rng.gen_range(-1.0..1.0),
-            rng.gen_range(-1.0..1.0),
-            0.0,
-        );
-        if p.squared_length() >= 1.0 { continue; }
-        else { break p; };
+impl From<Quaternion> for Transform {
+    fn from(q: Quaternion) -> Transform {
+        let matrix = Mat4::from(q);
+        // Rotations are orthogonal, so the inverse is the transpose
+        Transform { matrix, inverse: matrix.transpose() }
     }
 }
 
-pub type Point3 = Vec3;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn matrices_invert_and_rotations_agree () {
+        // Rotations agree however they are built, and the inverses undo them
+        let axis = Vec3::new(1.0, 2.0, -0.5);
+        let q = Quaternion::from_axis_angle(axis, 30.0) * Quaternion::from_axis_angle(axis, 40.0);
+        let rotation = Transform::from(q);
+        let p = Vec3::new(0.3, -1.2, 2.0);
+        assert!((rotation.point(&p) - Mat4::rotation(axis, 70.0).transform_point(&p)).length() < 1e-5);
+        assert!((rotation.point(&p) - q.rotate(&p)).length() < 1e-5);
+        let transform = Transform::scale(Vec3::new(2.0, 0.5, 3.0)).then(&rotation).then(&Transform::translation(p));
+        let general = Transform::new(transform.matrix).unwrap();
+        assert!((general.inverse().point(&transform.point(&axis)) - axis).length() < 1e-5);
+        assert!(Transform::new(Mat4::scale(Vec3::new(1.0, 0.0, 1.0))).is_none());
+        // Tiny scales invert however small the determinant, nearly dependent rows do not
+        let tiny = Mat4::translation(Vec3::new(100.0, 0.0, 0.0)) * Mat4::scale(Vec3::new(1e-4, 1e-4, 1e-4));
+        let inverse = tiny.inverse().unwrap();
+        assert!((inverse.transform_point(&tiny.transform_point(&axis)) - axis).length() < 1e-3);
+        let mut dependent = Mat4::identity();
+        dependent.m[0][..3].copy_from_slice(&[0.1, 0.2, 0.3]);
+        dependent.m[1][..3].copy_from_slice(&[0.4, 0.5, 0.6]);
+        dependent.m[2][..3].copy_from_slice(&[0.7, 0.8, 0.9]);
+        assert!(dependent.inverse().is_none());
+    }
+
+    #[test]
+    fn large_translations_invert () {
+        let axis = Vec3::new(0.0, 1.0, 0.0);
+        let cases = [
+            (Mat4::translation(Vec3::new(1e4, -2e4, 5e3)) * Mat4::rotation(axis, 30.0), 2e4),
+            (Mat4::translation(Vec3::new(1e3, 1e3, -1e3)) * Mat4::scale(Vec3::new(0.01, 0.01, 0.01)), 1e3 / 0.01),
+        ];
+        for (matrix, size) in cases.iter() {
+            let inverse = matrix.inverse().unwrap();
+            let back = inverse.transform_point(&matrix.transform_point(&axis));
+            // Rounding grows with the size of the translation, and undoing the scale magnifies it
+            assert!((back - axis).length() < 16.0 * Float::EPSILON * size, "{:?}", back);
+        }
+    }
+}